@@ -1,5 +1,7 @@
 use std::env;
 use std::path::PathBuf;
+use std::time::Duration;
+use crate::types::IceServer;
 
 #[derive(Clone)]
 pub struct ServerConfig {
@@ -12,6 +14,13 @@ pub struct ServerConfig {
     pub ws_port: u16,
     pub recording_path: Option<PathBuf>,
     pub sip_config: Option<SipConfig>,
+    /// Bearer token for the admin API; the API is disabled without one
+    pub admin_token: Option<String>,
+    /// Access token verification; joins are unauthenticated without it
+    pub auth: Option<AuthConfig>,
+    /// How long a peer whose socket dropped may resume its session before
+    /// it leaves its room; zero disables resumption
+    pub resume_grace: Duration,
 }
 
 impl ServerConfig {
@@ -39,8 +48,67 @@ impl ServerConfig {
                 .unwrap_or(8080),
             recording_path: Some(PathBuf::from("recordings")),
             sip_config: None,
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty()),
+            auth: AuthConfig::from_env(),
+            resume_grace: Duration::from_secs(
+                env::var("RESUME_GRACE_SECS")
+                    .ok()
+                    .and_then(|secs| secs.parse().ok())
+                    .unwrap_or(30),
+            ),
         }
     }
+
+    /// The STUN and TURN servers clients should use, skipping unset ones.
+    pub fn ice_servers(&self) -> Vec<IceServer> {
+        let mut servers = Vec::new();
+        if !self.stun_server.is_empty() {
+            servers.push(IceServer {
+                urls: vec![format!("stun:{}:{}", self.stun_server, self.stun_port)],
+                username: None,
+                credential: None,
+            });
+        }
+        if !self.turn_server.is_empty() {
+            servers.push(IceServer {
+                urls: vec![format!("turn:{}:{}", self.turn_server, self.turn_port)],
+                username: Some(self.turn_username.clone()),
+                credential: Some(self.turn_password.clone()),
+            });
+        }
+        servers
+    }
+}
+
+#[derive(Clone)]
+pub enum AuthKey {
+    /// Shared secret for HS256/384/512 tokens
+    Hmac(String),
+    /// Path to the PEM public key for RS256/384/512 tokens
+    RsaPem(PathBuf),
+}
+
+#[derive(Clone)]
+pub struct AuthConfig {
+    pub key: AuthKey,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+impl AuthConfig {
+    /// `JWT_SECRET` or `JWT_PUBLIC_KEY` enables authentication; the secret
+    /// wins when both are set.
+    pub fn from_env() -> Option<Self> {
+        let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+        let key = non_empty("JWT_SECRET")
+            .map(AuthKey::Hmac)
+            .or_else(|| non_empty("JWT_PUBLIC_KEY").map(|path| AuthKey::RsaPem(PathBuf::from(path))))?;
+        Some(Self {
+            key,
+            issuer: non_empty("JWT_ISSUER"),
+            audience: non_empty("JWT_AUDIENCE"),
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -146,11 +146,15 @@ async fn main() -> Result<()> {
     // Create routes
     let ws_route = server.ws_route();
     let credentials_route = server.turn_credentials_route();
+    let whip_route = server.whip_route();
+    let whep_route = server.whep_route();
+    let admin_route = server.admin_route();
     
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "OPTIONS"])
-        .allow_headers(vec!["content-type", "upgrade", "connection"])
+        .allow_methods(vec!["GET", "POST", "PATCH", "PUT", "DELETE", "OPTIONS"])
+        .allow_headers(vec!["content-type", "upgrade", "connection", "authorization"])
+        .expose_headers(vec!["location"])
         .allow_credentials(true)
         .max_age(3600);
 
@@ -159,6 +163,9 @@ async fn main() -> Result<()> {
 
     let routes = ws_route
         .or(credentials_route)
+        .or(whip_route)
+        .or(whep_route)
+        .or(admin_route)
         .or(static_files)
         .with(cors);
 
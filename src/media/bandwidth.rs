@@ -0,0 +1,186 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+pub const MIN_BITRATE: u64 = 30_000;
+pub const MAX_BITRATE: u64 = 20_000_000;
+const INITIAL_BITRATE: u64 = 1_000_000;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// Loss based controller from the GCC draft: back off above 10% loss, probe
+// upwards below 2%, hold in between
+const LOSS_DECREASE_THRESHOLD: f64 = 0.10;
+const LOSS_INCREASE_THRESHOLD: f64 = 0.02;
+const UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+// Burst a subscriber may be sent under the room's limit, as time at that rate
+const BUDGET_WINDOW: Duration = Duration::from_secs(1);
+
+/// Bitrate of a packet stream averaged over roughly one second.
+pub struct RateMeter {
+    bitrate: AtomicU64,
+    window: Mutex<(Instant, u64)>,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        Self {
+            bitrate: AtomicU64::new(0),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    pub async fn record(&self, bytes: usize) {
+        let mut window = self.window.lock().await;
+        window.1 += bytes as u64;
+        let elapsed = window.0.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let bitrate = (window.1 as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+            self.bitrate.store(bitrate, Ordering::Relaxed);
+            *window = (Instant::now(), 0);
+        }
+    }
+
+    /// Bits per second over the last completed window.
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Estimate {
+    remb: Option<u64>,
+    loss_based: u64,
+    last_update: Option<Instant>,
+}
+
+/// Downlink of one subscriber. The estimate is the lower of the receiver's
+/// REMB and a loss based estimate fed by transport-wide congestion control
+/// feedback and receiver reports, capped by the room's bandwidth limit.
+/// The limit is also enforced on what is forwarded, with a token bucket.
+pub struct Downlink {
+    // Room bandwidth limit in bps, 0 when unlimited
+    limit: AtomicU64,
+    estimate: Mutex<Estimate>,
+    // Bytes that may still be sent under the limit, and when it was last refilled
+    budget: Mutex<(Instant, f64)>,
+    sent: RateMeter,
+    video_streams: AtomicUsize,
+}
+
+impl Downlink {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit: AtomicU64::new(limit.unwrap_or(0)),
+            estimate: Mutex::new(Estimate {
+                remb: None,
+                loss_based: INITIAL_BITRATE,
+                last_update: None,
+            }),
+            budget: Mutex::new((Instant::now(), f64::INFINITY)),
+            sent: RateMeter::new(),
+            video_streams: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|limit| *limit > 0)
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub async fn on_remb(&self, bitrate: u64) {
+        self.estimate.lock().await.remb = Some(bitrate);
+    }
+
+    /// Feeds the fraction of packets (0.0 - 1.0) the subscriber reported lost.
+    pub async fn on_loss(&self, fraction_lost: f64) {
+        let mut estimate = self.estimate.lock().await;
+        if estimate.last_update.map_or(false, |at| at.elapsed() < UPDATE_INTERVAL) {
+            return;
+        }
+        estimate.last_update = Some(Instant::now());
+
+        if fraction_lost > LOSS_DECREASE_THRESHOLD {
+            estimate.loss_based = (estimate.loss_based as f64 * (1.0 - 0.5 * fraction_lost)) as u64;
+        } else if fraction_lost < LOSS_INCREASE_THRESHOLD {
+            // Don't probe far beyond what is actually being sent
+            let ceiling = (self.sent.bitrate() * 3 / 2).max(INITIAL_BITRATE);
+            let increased = (estimate.loss_based as f64 * 1.08) as u64;
+            estimate.loss_based = increased.min(ceiling.max(estimate.loss_based));
+        }
+        estimate.loss_based = estimate.loss_based.clamp(MIN_BITRATE, MAX_BITRATE);
+    }
+
+    /// Takes `bytes` out of what the room's bandwidth limit lets the
+    /// subscriber be sent. Returns false, taking nothing, if they don't fit.
+    pub async fn try_spend(&self, bytes: usize) -> bool {
+        let Some(limit) = self.limit() else { return true };
+        let mut budget = self.budget.lock().await;
+        let (available, _) = Self::refill(&mut budget, limit);
+        if available < bytes as f64 {
+            return false;
+        }
+        budget.1 -= bytes as f64;
+        true
+    }
+
+    /// Whether the subscriber may be sent a full burst again, as it may once
+    /// nothing was sent to it for the budget window. Always true without a limit.
+    pub async fn is_budget_full(&self) -> bool {
+        let Some(limit) = self.limit() else { return true };
+        let (available, capacity) = Self::refill(&mut *self.budget.lock().await, limit);
+        available >= capacity
+    }
+
+    // Adds what the limit allowed since the last refill, returning what is
+    // available and the most there can be
+    fn refill(budget: &mut (Instant, f64), limit: u64) -> (f64, f64) {
+        let bytes_per_sec = limit as f64 / 8.0;
+        let capacity = bytes_per_sec * BUDGET_WINDOW.as_secs_f64();
+        let (refilled_at, available) = budget;
+        *available = (*available + refilled_at.elapsed().as_secs_f64() * bytes_per_sec).min(capacity);
+        *refilled_at = Instant::now();
+        (*available, capacity)
+    }
+
+    pub async fn record_sent(&self, bytes: usize) {
+        self.sent.record(bytes).await;
+    }
+
+    /// Estimated available bitrate in bps.
+    pub async fn available_bitrate(&self) -> u64 {
+        let estimate = self.estimate.lock().await;
+        let mut bitrate = estimate.loss_based.min(estimate.remb.unwrap_or(MAX_BITRATE));
+        if let Some(limit) = self.limit() {
+            bitrate = bitrate.min(limit);
+        }
+        bitrate.max(MIN_BITRATE)
+    }
+
+    /// Bitrate forwarded to the subscriber over the last second.
+    pub fn sent_bitrate(&self) -> u64 {
+        self.sent.bitrate()
+    }
+
+    /// Share of the estimate available to each video stream the subscriber receives.
+    pub async fn per_stream_bitrate(&self) -> u64 {
+        let streams = self.video_streams.load(Ordering::Relaxed).max(1) as u64;
+        self.available_bitrate().await / streams
+    }
+
+    pub fn add_video_stream(&self) {
+        self.video_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_video_stream(&self) {
+        let _ = self.video_streams.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |streams| {
+            Some(streams.saturating_sub(1))
+        });
+    }
+}
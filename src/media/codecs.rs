@@ -0,0 +1,118 @@
+use crate::room::state::{MediaSettings, MediaType, VideoCodec};
+use crate::utils::Result;
+use webrtc::api::media_engine::{
+    MediaEngine, MIME_TYPE_AV1, MIME_TYPE_G722, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_PCMA,
+    MIME_TYPE_PCMU, MIME_TYPE_VP8, MIME_TYPE_VP9,
+};
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType};
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpHeaderExtensionCapability;
+use webrtc::rtp_transceiver::RTCPFeedback;
+use webrtc::sdp::extmap::{AUDIO_LEVEL_URI, SDES_MID_URI, SDES_REPAIR_RTP_STREAM_ID_URI, SDES_RTP_STREAM_ID_URI};
+
+impl VideoCodec {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            VideoCodec::VP8 => MIME_TYPE_VP8,
+            VideoCodec::VP9 => MIME_TYPE_VP9,
+            VideoCodec::H264 => MIME_TYPE_H264,
+            VideoCodec::AV1 => MIME_TYPE_AV1,
+        }
+    }
+
+    /// Payload types and fmtp lines offered for this codec, matching what
+    /// browsers advertise so their offers can be answered without remapping.
+    fn variants(&self) -> Vec<(u8, &'static str)> {
+        match self {
+            VideoCodec::VP8 => vec![(96, "")],
+            VideoCodec::VP9 => vec![(98, "profile-id=0"), (100, "profile-id=2")],
+            VideoCodec::H264 => vec![
+                (102, "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f"),
+                (127, "level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42001f"),
+                (125, "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"),
+                (108, "level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=42e01f"),
+                (123, "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640032"),
+            ],
+            VideoCodec::AV1 => vec![(41, "profile-id=0")],
+        }
+    }
+}
+
+fn video_rtcp_feedback() -> Vec<RTCPFeedback> {
+    vec![
+        RTCPFeedback { typ: "goog-remb".to_owned(), parameter: "".to_owned() },
+        RTCPFeedback { typ: "ccm".to_owned(), parameter: "fir".to_owned() },
+        RTCPFeedback { typ: "nack".to_owned(), parameter: "".to_owned() },
+        RTCPFeedback { typ: "nack".to_owned(), parameter: "pli".to_owned() },
+    ]
+}
+
+/// Builds a `MediaEngine` that only negotiates the media types and codecs a
+/// room allows. Video codecs are registered in the room's preference order.
+pub fn build_media_engine(settings: &MediaSettings) -> Result<MediaEngine> {
+    let mut media_engine = MediaEngine::default();
+
+    if settings.allows(&MediaType::Audio) {
+        let audio_codecs = [
+            (MIME_TYPE_OPUS, 48000, 2, "minptime=10;useinbandfec=1", 111),
+            (MIME_TYPE_G722, 8000, 0, "", 9),
+            (MIME_TYPE_PCMU, 8000, 0, "", 0),
+            (MIME_TYPE_PCMA, 8000, 0, "", 8),
+        ];
+        for (mime_type, clock_rate, channels, fmtp, payload_type) in audio_codecs {
+            media_engine.register_codec(
+                RTCRtpCodecParameters {
+                    capability: RTCRtpCodecCapability {
+                        mime_type: mime_type.to_owned(),
+                        clock_rate,
+                        channels,
+                        sdp_fmtp_line: fmtp.to_owned(),
+                        rtcp_feedback: vec![],
+                    },
+                    payload_type,
+                    ..Default::default()
+                },
+                RTPCodecType::Audio,
+            )?;
+        }
+
+        // Lets the relay detect active speakers without decoding audio
+        media_engine.register_header_extension(
+            RTCRtpHeaderExtensionCapability { uri: AUDIO_LEVEL_URI.to_owned() },
+            RTPCodecType::Audio,
+            None,
+        )?;
+    }
+
+    // Screen shares are sent as ordinary video tracks
+    if settings.allows(&MediaType::Video) || settings.allows(&MediaType::Screen) {
+        for codec in &settings.video_codecs {
+            for (payload_type, fmtp) in codec.variants() {
+                media_engine.register_codec(
+                    RTCRtpCodecParameters {
+                        capability: RTCRtpCodecCapability {
+                            mime_type: codec.mime_type().to_owned(),
+                            clock_rate: 90000,
+                            channels: 0,
+                            sdp_fmtp_line: fmtp.to_owned(),
+                            rtcp_feedback: video_rtcp_feedback(),
+                        },
+                        payload_type,
+                        ..Default::default()
+                    },
+                    RTPCodecType::Video,
+                )?;
+            }
+        }
+
+        // Needed to demultiplex rid-based simulcast layers
+        for uri in [SDES_MID_URI, SDES_RTP_STREAM_ID_URI, SDES_REPAIR_RTP_STREAM_ID_URI] {
+            media_engine.register_header_extension(
+                RTCRtpHeaderExtensionCapability { uri: uri.to_owned() },
+                RTPCodecType::Video,
+                None,
+            )?;
+        }
+    }
+
+    Ok(media_engine)
+}
@@ -0,0 +1,33 @@
+use webrtc::rtp::packet::Packet as RTPPacket;
+
+// Roughly one second of 720p video; payloads are reference counted so this is cheap
+const HISTORY_SIZE: usize = 512;
+
+/// Recently received packets of one layer, indexed by sequence number, so
+/// NACKs from subscribers can be answered without asking the publisher.
+pub struct PacketHistory {
+    packets: Vec<Option<RTPPacket>>,
+}
+
+impl PacketHistory {
+    pub fn new() -> Self {
+        Self { packets: vec![None; HISTORY_SIZE] }
+    }
+
+    pub fn push(&mut self, packet: &RTPPacket) {
+        let slot = packet.header.sequence_number as usize % HISTORY_SIZE;
+        self.packets[slot] = Some(packet.clone());
+    }
+
+    pub fn get(&self, sequence_number: u16) -> Option<&RTPPacket> {
+        self.packets[sequence_number as usize % HISTORY_SIZE]
+            .as_ref()
+            .filter(|packet| packet.header.sequence_number == sequence_number)
+    }
+}
+
+impl Default for PacketHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,5 +1,15 @@
+pub mod bandwidth;
+pub mod codecs;
+pub mod feedback;
+pub mod negotiation;
 pub mod relay;
 pub mod recording;
+pub mod simulcast;
+pub mod source;
+pub mod speaker;
+pub mod subscription;
 
-pub use relay::{MediaRelay, MediaRelayManager};
+pub use relay::{MediaRelay, MediaRelayManager, RelayMode};
 pub use recording::RecordingManager;
+pub use source::TrackSource;
+pub use speaker::ActiveSpeakerDetector;
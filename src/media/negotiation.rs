@@ -0,0 +1,279 @@
+use crate::types::{SignalingMessage, SERVER_PEER_ID};
+use crate::utils::{Error, Result};
+use std::sync::{Mutex as SyncMutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex};
+use log::{debug, info, warn};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
+use webrtc::peer_connection::RTCPeerConnection;
+
+/// How long an ICE restart may take to bring the connection back.
+pub const ICE_RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Offer/answer exchange between the server and one peer, following the
+/// "perfect negotiation" pattern with the server as the polite side: when
+/// both sides offer at once the server rolls its offer back, answers the
+/// peer's, and offers again afterwards. Should the rollback fail, the peer's
+/// offer is refused instead and the peer is expected to answer the server's.
+///
+/// Server offers are queued rather than dropped. At most one is outstanding;
+/// changes made meanwhile are folded into the next one.
+///
+/// Server-gathered ICE candidates are held back while a local description is
+/// on its way to the peer, so they never arrive ahead of the SDP they belong to.
+///
+/// An ICE restart that has not brought the connection back within
+/// `ICE_RESTART_TIMEOUT` is given up on, leaving the peer to be reaped.
+pub struct Negotiator {
+    room_id: String,
+    peer_id: String,
+    peer_connection: Weak<RTCPeerConnection>,
+    events: broadcast::Sender<SignalingMessage>,
+    // Held for the whole of each exchange step; records what is queued
+    pending: Mutex<Queued>,
+    // When the ICE restart under way was first requested
+    restart_started: SyncMutex<Option<Instant>>,
+    restart_timeout: Duration,
+    outbound_candidates: Mutex<OutboundCandidates>,
+}
+
+/// Server offers waiting for the current exchange to complete.
+#[derive(Default)]
+struct Queued {
+    offer: bool,
+    // An ICE restart, which also carries any queued changes
+    restart_pending: bool,
+}
+
+#[derive(Default)]
+struct OutboundCandidates {
+    held: bool,
+    queued: Vec<RTCIceCandidateInit>,
+}
+
+impl Negotiator {
+    pub fn new(
+        room_id: &str,
+        peer_id: &str,
+        peer_connection: Weak<RTCPeerConnection>,
+        events: broadcast::Sender<SignalingMessage>,
+    ) -> Self {
+        Self {
+            room_id: room_id.to_string(),
+            peer_id: peer_id.to_string(),
+            peer_connection,
+            events,
+            pending: Mutex::new(Queued::default()),
+            restart_started: SyncMutex::new(None),
+            restart_timeout: ICE_RESTART_TIMEOUT,
+            outbound_candidates: Mutex::new(OutboundCandidates::default()),
+        }
+    }
+
+    /// Gives ICE restarts `timeout` instead of `ICE_RESTART_TIMEOUT`.
+    pub fn with_restart_timeout(mut self, timeout: Duration) -> Self {
+        self.restart_timeout = timeout;
+        self
+    }
+
+    /// Sends a server-gathered candidate to the peer, or queues it while a
+    /// local description is being delivered. An empty candidate signals
+    /// end-of-candidates.
+    pub async fn send_candidate(&self, candidate: RTCIceCandidateInit) {
+        let mut outbound = self.outbound_candidates.lock().await;
+        if outbound.held {
+            outbound.queued.push(candidate);
+        } else {
+            self.emit_candidate(candidate);
+        }
+    }
+
+    async fn hold_candidates(&self) {
+        self.outbound_candidates.lock().await.held = true;
+    }
+
+    async fn release_candidates(&self) {
+        let mut outbound = self.outbound_candidates.lock().await;
+        outbound.held = false;
+        for candidate in std::mem::take(&mut outbound.queued) {
+            self.emit_candidate(candidate);
+        }
+    }
+
+    fn emit_candidate(&self, candidate: RTCIceCandidateInit) {
+        let candidate = match serde_json::to_string(&candidate) {
+            Ok(candidate) => candidate,
+            Err(e) => {
+                warn!("Failed to serialize ICE candidate for peer {}: {}", self.peer_id, e);
+                return;
+            }
+        };
+        let _ = self.events.send(SignalingMessage::IceCandidate {
+            room_id: self.room_id.clone(),
+            candidate,
+            from_peer: SERVER_PEER_ID.to_string(),
+            to_peer: self.peer_id.clone(),
+        });
+    }
+
+    /// Sends the peer a server offer now if the connection is idle, otherwise
+    /// queues one for when the current exchange completes.
+    pub async fn request(&self) -> Result<()> {
+        let Some(peer_connection) = self.peer_connection.upgrade() else { return Ok(()) };
+        let mut pending = self.pending.lock().await;
+
+        // The peer always opens the session; our changes ride on the next round
+        let idle = peer_connection.signaling_state() == RTCSignalingState::Stable
+            && peer_connection.remote_description().await.is_some();
+        if !idle {
+            debug!("Queueing renegotiation for peer {}", self.peer_id);
+            pending.offer = true;
+            return Ok(());
+        }
+
+        let ice_restart = std::mem::take(&mut *pending).restart_pending;
+        self.send_offer(&peer_connection, ice_restart).await
+    }
+
+    /// Sends the peer an ICE-restart offer now if the connection is idle,
+    /// otherwise once the exchange in progress completes. Queued changes
+    /// ride along with the restart.
+    pub async fn restart_ice(&self) -> Result<()> {
+        let Some(peer_connection) = self.peer_connection.upgrade() else { return Ok(()) };
+        let mut pending = self.pending.lock().await;
+
+        // Nothing to restart before the first exchange completes
+        if peer_connection.remote_description().await.is_none() {
+            return Ok(());
+        }
+        {
+            let mut started = self.restart_started.lock().unwrap();
+            match *started {
+                Some(at) if at.elapsed() >= self.restart_timeout => {
+                    warn!("ICE restart with peer {} did not recover in {:?}; giving up", self.peer_id, self.restart_timeout);
+                    return Ok(());
+                }
+                // Further restarts count against the first one's deadline
+                Some(_) => {}
+                None => *started = Some(Instant::now()),
+            }
+        }
+        if peer_connection.signaling_state() != RTCSignalingState::Stable {
+            debug!("Queueing ICE restart for peer {}", self.peer_id);
+            pending.restart_pending = true;
+            return Ok(());
+        }
+
+        *pending = Queued::default();
+        info!("Restarting ICE with peer {}", self.peer_id);
+        self.send_offer(&peer_connection, true).await
+    }
+
+    async fn send_offer(&self, peer_connection: &RTCPeerConnection, ice_restart: bool) -> Result<()> {
+        let options = RTCOfferOptions { ice_restart, ..Default::default() };
+        let offer = peer_connection.create_offer(Some(options)).await?;
+        self.hold_candidates().await;
+        if let Err(e) = peer_connection.set_local_description(offer.clone()).await {
+            self.release_candidates().await;
+            return Err(e.into());
+        }
+        debug!("Sending server offer to peer {}", self.peer_id);
+        let message = if ice_restart {
+            SignalingMessage::IceRestart {
+                room_id: self.room_id.clone(),
+                peer_id: self.peer_id.clone(),
+                sdp: Some(offer.sdp),
+            }
+        } else {
+            SignalingMessage::Offer {
+                room_id: self.room_id.clone(),
+                sdp: offer.sdp,
+                from_peer: SERVER_PEER_ID.to_string(),
+                to_peer: self.peer_id.clone(),
+            }
+        };
+        // Delivery fails only when nothing routes signaling, e.g. in tests
+        let _ = self.events.send(message);
+        self.release_candidates().await;
+        Ok(())
+    }
+
+    /// Applies an offer from the peer and returns the answer. A colliding
+    /// server offer is rolled back and queued again; if it can't be, the
+    /// peer's offer is refused.
+    pub async fn handle_offer(&self, sdp: String) -> Result<RTCSessionDescription> {
+        let Some(peer_connection) = self.peer_connection.upgrade() else {
+            return Err(Error::Peer(format!("Peer {} is closed", self.peer_id)));
+        };
+        let mut pending = self.pending.lock().await;
+
+        if peer_connection.signaling_state() == RTCSignalingState::HaveLocalOffer {
+            if let Err(e) = Self::rollback(&peer_connection).await {
+                info!("Offer collision with peer {}, keeping the server offer: {}", self.peer_id, e);
+                return Err(Error::Peer(format!(
+                    "Offer from peer {} collided with a server offer, which it should answer first",
+                    self.peer_id
+                )));
+            }
+            info!("Offer collision with peer {}, rolled back the server offer", self.peer_id);
+            pending.offer = true;
+            // A rolled-back restart is sent again too
+            pending.restart_pending |= self.is_restarting();
+        }
+
+        peer_connection.set_remote_description(RTCSessionDescription::offer(sdp)?).await?;
+        let answer = peer_connection.create_answer(None).await?;
+        // Released by `flush` once the caller has delivered the answer
+        self.hold_candidates().await;
+        if let Err(e) = peer_connection.set_local_description(answer.clone()).await {
+            self.release_candidates().await;
+            return Err(e.into());
+        }
+        Ok(answer)
+    }
+
+    async fn rollback(peer_connection: &RTCPeerConnection) -> Result<()> {
+        let Some(mut rollback) = peer_connection.pending_local_description().await else {
+            return Err(Error::Peer("No local offer to roll back".to_string()));
+        };
+        rollback.sdp_type = RTCSdpType::Rollback;
+        peer_connection.set_local_description(rollback).await?;
+        Ok(())
+    }
+
+    /// Applies the peer's answer to a server offer.
+    pub async fn handle_answer(&self, sdp: String) -> Result<()> {
+        let Some(peer_connection) = self.peer_connection.upgrade() else { return Ok(()) };
+        let _pending = self.pending.lock().await;
+        peer_connection.set_remote_description(RTCSessionDescription::answer(sdp)?).await?;
+        Ok(())
+    }
+
+    /// Sends held candidates and the queued offer, if any. Called once an
+    /// exchange has finished and its answer has reached the peer.
+    pub async fn flush(&self) -> Result<()> {
+        self.release_candidates().await;
+        let queued = std::mem::take(&mut *self.pending.lock().await);
+        if queued.restart_pending {
+            self.restart_ice().await?;
+        } else if queued.offer {
+            self.request().await?;
+        }
+        Ok(())
+    }
+
+    /// Whether an ICE restart is under way, from the moment it is requested
+    /// until `restart_done` or until it times out.
+    pub fn is_restarting(&self) -> bool {
+        matches!(*self.restart_started.lock().unwrap(), Some(at) if at.elapsed() < self.restart_timeout)
+    }
+
+    /// Marks the connection as recovered.
+    pub fn restart_done(&self) {
+        *self.restart_started.lock().unwrap() = None;
+    }
+}
@@ -1,9 +1,25 @@
 use crate::utils::{Error, Result};
 use crate::types::SignalingMessage;
-use std::sync::Arc;
+use crate::media::source::TrackSource;
+use crate::media::bandwidth::{Downlink, MAX_BITRATE};
+use crate::media::codecs::build_media_engine;
+use crate::media::negotiation::Negotiator;
+use crate::media::simulcast::SimulcastQuality;
+use crate::media::speaker::ActiveSpeakerDetector;
+use crate::media::subscription::SubscriptionFilter;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtcp::transport_feedbacks::transport_layer_cc::TransportLayerCc;
+use webrtc::rtcp::transport_feedbacks::transport_layer_nack::TransportLayerNack;
+use webrtc::api::interceptor_registry::{configure_rtcp_reports, configure_twcc};
+use webrtc::interceptor::registry::Registry;
+use crate::room::state::{LobbyEntry, Room, MediaSettings, MediaType, Permission, Role};
+use std::sync::{Arc, Weak};
 use std::fmt;
 use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::api::APIBuilder;
@@ -17,7 +33,8 @@ use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::stats::StatsReportType;
 use bytes::Bytes;
-use tokio::sync::Mutex;
+use serde::Serialize;
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
 use log::{debug, info, warn, error};
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
@@ -25,6 +42,9 @@ use webrtc::track::track_local::TrackLocalWriter;
 use webrtc::util::Marshal;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::track::track_local::TrackLocal;
+use webrtc::track::track_remote::TrackRemote;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 
 pub trait SignalingHandler {
     fn send_to_peer(&self, peer_id: &str, message: &SignalingMessage) -> impl std::future::Future<Output = Result<()>> + Send;
@@ -33,15 +53,48 @@ pub trait SignalingHandler {
 #[derive(Clone)]
 pub struct MediaRelay {
     pub peer_connection: Arc<RTCPeerConnection>,
-    pub audio_track: Option<Arc<TrackLocalStaticRTP>>,
-    pub video_track: Option<Arc<TrackLocalStaticRTP>>,
     pub peer_id: String,
+    pub room_id: String,
+    published: Arc<RwLock<HashMap<String, Arc<TrackSource>>>>,
+    subscriptions: Arc<Mutex<HashMap<String, (Arc<TrackSource>, Arc<RTCRtpSender>)>>>,
+    downlink: Arc<Downlink>,
+    // Publishers whose video this peer always receives under last-N
+    pinned: Arc<RwLock<HashSet<String>>>,
+    filter: Arc<RwLock<SubscriptionFilter>>,
+    negotiator: Arc<Negotiator>,
+    pub mode: RelayMode,
     data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
     ice_candidate_buffer: Arc<Mutex<Vec<RTCIceCandidateInit>>>,
 }
 
+type RoomMap = HashMap<String, Room>;
+
+/// How a relay's peer exchanges media and signaling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayMode {
+    /// Publishes and subscribes, renegotiating over the signaling socket
+    #[default]
+    Interactive,
+    /// Publishes only, negotiated once over WHIP
+    Ingest,
+    /// Receives only, negotiated once over WHEP
+    Egress,
+}
+
+/// A relay's peer connection changed state.
+#[derive(Debug, Clone)]
+pub struct PeerStateChange {
+    pub room_id: String,
+    pub peer_id: String,
+    pub state: RTCPeerConnectionState,
+}
+
 pub struct MediaRelayManager {
-    relays: Arc<tokio::sync::RwLock<std::collections::HashMap<String, MediaRelay>>>,
+    rooms: Arc<RwLock<RoomMap>>,
+    // Room-scoped notifications raised by the media layer, e.g. active speaker changes
+    events: broadcast::Sender<SignalingMessage>,
+    peer_states: broadcast::Sender<PeerStateChange>,
     stun_server: String,
     stun_port: u16,
     turn_server: String,
@@ -56,78 +109,375 @@ pub struct MediaStats {
     pub packets_sent: u64,
     pub bytes_received: u64,
     pub bytes_sent: u64,
+    /// Estimated bitrate the peer can receive, in bps
+    pub available_bitrate: u64,
+    /// Bitrate currently forwarded to the peer, in bps
+    pub forwarded_bitrate: u64,
     pub last_updated: Instant,
 }
 
 impl MediaRelay {
-    pub async fn new(peer_id: String) -> Result<Self> {
-        let mut media_engine = MediaEngine::default();
-        media_engine.register_default_codecs()?;
+    pub async fn new(
+        peer_id: String,
+        room_id: String,
+        config: RTCConfiguration,
+        media_settings: &MediaSettings,
+        events: broadcast::Sender<SignalingMessage>,
+        peer_states: broadcast::Sender<PeerStateChange>,
+        mode: RelayMode,
+    ) -> Result<Self> {
+        let mut media_engine = build_media_engine(media_settings)?;
+
+        // Sender/receiver reports and transport-wide congestion control
+        // feedback in both directions
+        let mut registry = configure_rtcp_reports(Registry::new());
+        registry = configure_twcc(registry, &mut media_engine)?;
 
         let api = APIBuilder::new()
             .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
             .build();
 
-        let peer_connection = Arc::new(api.new_peer_connection(RTCConfiguration::default()).await?);
-
-        // Create audio track
-        let audio_track = Arc::new(TrackLocalStaticRTP::new(
-            RTCRtpCodecCapability {
-                mime_type: "audio/opus".to_owned(),
-                clock_rate: 48000,
-                channels: 2,
-                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
-                ..Default::default()
-            },
-            "audio".to_owned(),
-            "webrtc-rs".to_owned(),
-        ));
+        let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
-        // Add transceiver with explicit direction
-        peer_connection.add_transceiver_from_track(
-            Arc::clone(&audio_track) as Arc<dyn TrackLocal + Send + Sync>, 
-            Some(RTCRtpTransceiverInit {
-                direction: RTCRtpTransceiverDirection::Sendrecv,
-                send_encodings: vec![],
-            }),
-        ).await?;
+        // Subscriptions change as peers come and go; each change is offered
+        // to the peer by the server
+        let negotiator = Arc::new(Negotiator::new(
+            &room_id,
+            &peer_id,
+            Arc::downgrade(&peer_connection),
+            events,
+        ));
+        // HTTP peers negotiate exactly once and have nowhere to receive
+        // server offers or candidates
+        let interactive = mode == RelayMode::Interactive;
+        if interactive {
+            let on_needed = negotiator.clone();
+            peer_connection.on_negotiation_needed(Box::new(move || {
+                let negotiator = on_needed.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = negotiator.request().await {
+                        warn!("Failed to renegotiate: {}", e);
+                    }
+                });
+                Box::pin(async {})
+            }));
+        }
 
-        // Set up track handlers with more detailed logging
-        let track_clone = audio_track.clone();
-        peer_connection.on_track(Box::new(move |track, _, _| {
-            let track_clone = track_clone.clone();
-            Box::pin(async move {
-                debug!("Received track: kind={}, id={}, payload_type={}", 
-                    track.kind(), 
-                    track.id(),
-                    track.payload_type()
-                );
-                
-                while let Ok((rtp, _)) = track.read_rtp().await {
-                    debug!("Forwarding RTP packet: ssrc={}, seq={}, ts={}, payload_size={}", 
-                        rtp.header.ssrc, 
-                        rtp.header.sequence_number,
-                        rtp.header.timestamp,
-                        rtp.payload.len()
-                    );
-                    if let Err(e) = track_clone.write_rtp(&rtp).await {
-                        error!("Failed to forward RTP packet: {}", e);
+        // Network changes (e.g. Wi-Fi to cellular) drop the transport; restart
+        // ICE instead of losing the peer
+        let on_state = negotiator.clone();
+        let state_room = room_id.clone();
+        let state_peer = peer_id.clone();
+        peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+            info!("Peer {} connection state: {}", state_peer, state);
+            let _ = peer_states.send(PeerStateChange {
+                room_id: state_room.clone(),
+                peer_id: state_peer.clone(),
+                state,
+            });
+            if state == RTCPeerConnectionState::Connected {
+                on_state.restart_done();
+            }
+            if interactive && matches!(state, RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed) {
+                let negotiator = on_state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = negotiator.restart_ice().await {
+                        warn!("Failed to restart ICE: {}", e);
                     }
-                }
-                debug!("Track reading loop ended");
-            })
+                });
+            }
+            Box::pin(async {})
         }));
 
+        // Trickle server candidates to the peer; `None` ends gathering
+        if interactive {
+            let on_candidate = negotiator.clone();
+            peer_connection.on_ice_candidate(Box::new(move |candidate| {
+                let negotiator = on_candidate.clone();
+                Box::pin(async move {
+                    let init = match candidate {
+                        Some(candidate) => match candidate.to_json() {
+                            Ok(init) => init,
+                            Err(e) => {
+                                warn!("Failed to encode local ICE candidate: {}", e);
+                                return;
+                            }
+                        },
+                        None => RTCIceCandidateInit::default(),
+                    };
+                    negotiator.send_candidate(init).await;
+                })
+            }));
+        }
+
         Ok(MediaRelay {
             peer_id,
+            room_id,
             peer_connection,
-            audio_track: Some(audio_track),
-            video_track: None,
+            published: Arc::new(RwLock::new(HashMap::new())),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            downlink: Arc::new(Downlink::new(media_settings.bandwidth_limit.map(u64::from))),
+            pinned: Arc::new(RwLock::new(HashSet::new())),
+            filter: Arc::new(RwLock::new(SubscriptionFilter::default())),
+            negotiator,
+            mode,
             data_channel: Arc::new(Mutex::new(None)),
             ice_candidate_buffer: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
+    /// Tracks this peer is currently publishing to the room.
+    pub async fn published_sources(&self) -> Vec<Arc<TrackSource>> {
+        self.published.read().await.values().cloned().collect()
+    }
+
+    /// Stops treating this peer's tracks as published and returns them.
+    pub async fn take_published(&self) -> Vec<Arc<TrackSource>> {
+        self.published.write().await.drain().map(|(_, source)| source).collect()
+    }
+
+    /// Whether this peer is currently publishing video to the room.
+    pub async fn publishes_video(&self) -> bool {
+        self.published.read().await.values().any(|source| source.kind == RTPCodecType::Video)
+    }
+
+    /// Keys of the remote sources this peer is receiving.
+    pub async fn subscription_keys(&self) -> Vec<String> {
+        self.subscriptions.lock().await.keys().cloned().collect()
+    }
+
+    /// Whether this peer's subscription filter lets `source` through.
+    pub async fn wants(&self, source: &TrackSource) -> bool {
+        self.filter.read().await.wants(&source.publisher_id, source.kind)
+    }
+
+    /// Changes which tracks this peer wants; see `SubscriptionFilter::set`.
+    /// Existing subscriptions are not touched until they are refreshed.
+    pub async fn set_subscription_filter(&self, publisher_id: Option<&str>, media_types: &[MediaType], wanted: bool) {
+        self.filter.write().await.set(publisher_id, media_types, wanted);
+    }
+
+    /// Starts sending `source` to this peer unless its subscription filter
+    /// excludes it. Adding the track triggers negotiation-needed on the peer
+    /// connection.
+    pub async fn subscribe(&self, source: &Arc<TrackSource>) -> Result<()> {
+        if self.mode == RelayMode::Ingest || !self.wants(source).await {
+            return Ok(());
+        }
+        let key = source.key();
+        let mut subscriptions = self.subscriptions.lock().await;
+        if subscriptions.contains_key(&key) {
+            return Ok(());
+        }
+
+        let local_track = source.add_subscriber(&self.peer_id, self.downlink.clone()).await;
+        let sender = match self.attach_track(local_track as Arc<dyn TrackLocal + Send + Sync>).await {
+            Ok(Some(sender)) => sender,
+            Ok(None) => {
+                debug!("No free {} slot for {} on viewer {}", source.kind, key, self.peer_id);
+                source.remove_subscriber(&self.peer_id).await;
+                return Ok(());
+            }
+            Err(e) => {
+                source.remove_subscriber(&self.peer_id).await;
+                return Err(e);
+            }
+        };
+
+        // RTCP has to be read for the sender's interceptors to make progress.
+        // REMB, TWCC feedback and receiver reports drive the downlink
+        // estimate; keyframe requests and NACKs are answered by the source.
+        let rtcp_sender = sender.clone();
+        let downlink = self.downlink.clone();
+        let rtcp_source = source.clone();
+        let peer_id = self.peer_id.clone();
+        tokio::spawn(async move {
+            while let Ok((packets, _)) = rtcp_sender.read_rtcp().await {
+                for packet in packets {
+                    let packet = packet.as_any();
+                    if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                        downlink.on_remb(remb.bitrate as u64).await;
+                    } else if let Some(twcc) = packet.downcast_ref::<TransportLayerCc>() {
+                        if twcc.packet_status_count > 0 {
+                            let received = twcc.recv_deltas.len() as f64;
+                            let total = twcc.packet_status_count as f64;
+                            downlink.on_loss(1.0 - (received / total).min(1.0)).await;
+                        }
+                    } else if let Some(report) = packet.downcast_ref::<ReceiverReport>() {
+                        for reception in &report.reports {
+                            downlink.on_loss(reception.fraction_lost as f64 / 256.0).await;
+                        }
+                    } else if packet.is::<PictureLossIndication>() {
+                        rtcp_source.request_keyframe(&peer_id, false).await;
+                    } else if packet.is::<FullIntraRequest>() {
+                        rtcp_source.request_keyframe(&peer_id, true).await;
+                    } else if let Some(nack) = packet.downcast_ref::<TransportLayerNack>() {
+                        let lost: Vec<u16> = nack.nacks.iter().flat_map(|pair| pair.packet_list()).collect();
+                        rtcp_source.retransmit(&peer_id, &lost).await;
+                    }
+                }
+            }
+        });
+
+        if source.kind == RTPCodecType::Video {
+            self.downlink.add_video_stream();
+        }
+        debug!("Peer {} subscribed to {}", self.peer_id, key);
+        subscriptions.insert(key, (source.clone(), sender));
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, source: &Arc<TrackSource>) -> Result<()> {
+        source.remove_subscriber(&self.peer_id).await;
+
+        let key = source.key();
+        let removed = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            // A reconnecting publisher may reuse the key for a new source
+            match subscriptions.get(&key) {
+                Some((subscribed, _)) if Arc::ptr_eq(subscribed, source) => subscriptions.remove(&key),
+                _ => None,
+            }
+        };
+        if let Some((_, sender)) = removed {
+            if source.kind == RTPCodecType::Video {
+                self.downlink.remove_video_stream();
+            }
+            debug!("Peer {} unsubscribed from {}", self.peer_id, key);
+            if self.peer_connection.connection_state() != RTCPeerConnectionState::Closed {
+                if self.mode == RelayMode::Egress {
+                    // Keep the negotiated slot for the next publisher
+                    sender.replace_track(None).await?;
+                } else {
+                    self.peer_connection.remove_track(&sender).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives `track` a sender. Viewers can't renegotiate, so their tracks
+    /// become send-only transceivers matched to the m-lines of the offer
+    /// they are about to make, or fill a negotiated slot that is still empty.
+    async fn attach_track(&self, track: Arc<dyn TrackLocal + Send + Sync>) -> Result<Option<Arc<RTCRtpSender>>> {
+        if self.mode != RelayMode::Egress {
+            return Ok(Some(self.peer_connection.add_track(track).await?));
+        }
+
+        if self.peer_connection.remote_description().await.is_none() {
+            let transceiver = self.peer_connection
+                .add_transceiver_from_track(track, Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Sendonly,
+                    send_encodings: Vec::new(),
+                }))
+                .await?;
+            return Ok(Some(transceiver.sender().await));
+        }
+
+        for transceiver in self.peer_connection.get_transceivers().await {
+            let sends = matches!(
+                transceiver.direction(),
+                RTCRtpTransceiverDirection::Sendonly | RTCRtpTransceiverDirection::Sendrecv
+            );
+            if transceiver.kind() != track.kind() || transceiver.mid().is_none() || !sends {
+                continue;
+            }
+            let sender = transceiver.sender().await;
+            if sender.track().await.is_none() {
+                sender.replace_track(Some(track)).await?;
+                return Ok(Some(sender));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Estimated bitrate this peer can receive in bps, capped by the room's
+    /// bandwidth limit.
+    pub async fn available_bitrate(&self) -> u64 {
+        self.downlink.available_bitrate().await
+    }
+
+    /// Caps the simulcast layer this peer receives from `publisher_id`'s video.
+    pub async fn set_layer_preference(&self, publisher_id: &str, quality: SimulcastQuality) {
+        let subscriptions = self.subscriptions.lock().await;
+        for (source, _) in subscriptions.values() {
+            if source.publisher_id == publisher_id && source.kind == RTPCodecType::Video {
+                source.set_subscriber_quality(&self.peer_id, quality).await;
+            }
+        }
+    }
+
+    pub async fn pinned_peers(&self) -> HashSet<String> {
+        self.pinned.read().await.clone()
+    }
+
+    pub async fn set_pinned_peers(&self, pinned: HashSet<String>) {
+        *self.pinned.write().await = pinned;
+    }
+
+    /// Applies a new room bandwidth limit in bps to this peer in both directions.
+    pub fn set_bandwidth_limit(&self, limit: Option<u64>) {
+        self.downlink.set_limit(limit);
+    }
+
+    /// Mutes or unmutes this peer's published track `track_id`, or all of
+    /// them. Returns the ids of the tracks that changed.
+    pub async fn set_published_muted(&self, track_id: Option<&str>, muted: bool) -> Vec<String> {
+        let mut changed = Vec::new();
+        for source in self.published_sources().await {
+            if track_id.map_or(true, |track_id| track_id == source.track_id) && source.set_muted(muted).await {
+                changed.push(source.track_id.clone());
+            }
+        }
+        changed
+    }
+
+    /// Forwards video only from `publishers`, or from everyone when `None`.
+    /// Paused subscriptions stay negotiated so resuming needs no new offer.
+    pub async fn set_forwarded_publishers(&self, publishers: Option<&HashSet<String>>) {
+        let subscriptions = self.subscriptions.lock().await;
+        for (source, _) in subscriptions.values() {
+            if source.kind != RTPCodecType::Video {
+                continue;
+            }
+            let paused = publishers.map_or(false, |publishers| !publishers.contains(&source.publisher_id));
+            source.set_subscriber_paused(&self.peer_id, paused).await;
+        }
+    }
+
+    /// Queues a server offer to the peer, sent as soon as no other exchange
+    /// is in progress.
+    pub async fn request_negotiation(&self) -> Result<()> {
+        self.negotiator.request().await
+    }
+
+    /// Sends the server offer queued during the last exchange, if any. Call
+    /// once the answer to the peer's offer has been delivered.
+    pub async fn flush_negotiation(&self) -> Result<()> {
+        self.negotiator.flush().await
+    }
+
+    /// Sends the peer an ICE-restart offer.
+    pub async fn restart_ice(&self) -> Result<()> {
+        self.negotiator.restart_ice().await
+    }
+
+    /// Whether an ICE restart is under way and the connection not back yet.
+    pub fn is_restarting(&self) -> bool {
+        self.negotiator.is_restarting()
+    }
+
+    /// Applies a remote offer and returns the local answer, rolling back a
+    /// colliding server offer.
+    pub async fn handle_offer(&self, sdp: String) -> Result<RTCSessionDescription> {
+        // A restart offer from the peer is handled like any other; new ICE
+        // credentials in it restart the transport
+        let answer = self.negotiator.handle_offer(sdp).await?;
+        self.add_buffered_candidates().await?;
+        Ok(answer)
+    }
+
     pub async fn get_stats(&self) -> Result<MediaStats> {
         let stats = self.peer_connection.get_stats().await;
 
@@ -136,6 +486,8 @@ impl MediaRelay {
             packets_sent: 0,
             bytes_received: 0,
             bytes_sent: 0,
+            available_bitrate: self.downlink.available_bitrate().await,
+            forwarded_bitrate: self.downlink.sent_bitrate(),
             last_updated: Instant::now(),
         };
 
@@ -182,10 +534,12 @@ impl MediaRelay {
         buffer.push(candidate);
     }
 
+    /// Applies candidates that arrived before the remote description, in the
+    /// order the peer sent them.
     pub async fn add_buffered_candidates(&self) -> Result<()> {
         let mut buffer = self.ice_candidate_buffer.lock().await;
         
-        while let Some(candidate) = buffer.pop() {
+        for candidate in buffer.drain(..) {
             match self.peer_connection.add_ice_candidate(candidate).await {
                 Ok(_) => {
                     debug!("Successfully added buffered ICE candidate");
@@ -204,27 +558,26 @@ impl MediaRelay {
         self.add_buffered_candidates().await
     }
 
+    /// Applies the peer's answer to a server offer, then sends any offer
+    /// that was queued meanwhile.
     pub async fn set_remote_description(&self, sdp: String) -> Result<()> {
-        let desc = RTCSessionDescription::answer(sdp)?;
-        self.peer_connection.set_remote_description(desc).await?;
-        
-        // Apply any buffered candidates now that we have the remote description
-        let mut buffer = self.ice_candidate_buffer.lock().await;
-        while let Some(candidate) = buffer.pop() {
-            if let Err(e) = self.peer_connection.add_ice_candidate(candidate).await {
-                warn!("Failed to apply buffered ICE candidate: {}", e);
-            }
-        }
-        Ok(())
+        self.negotiator.handle_answer(sdp).await?;
+        self.add_buffered_candidates().await?;
+        self.negotiator.flush().await
     }
 
+    /// Adds a trickled candidate from the peer. Candidates that arrive before
+    /// the remote description are buffered. An empty candidate marks
+    /// end-of-candidates.
     pub async fn add_ice_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
-        // If remote description is not set yet, buffer the candidate
+        // Checked under the buffer lock so a concurrent flush can't miss it
+        let mut buffer = self.ice_candidate_buffer.lock().await;
         if self.peer_connection.remote_description().await.is_none() {
-            let mut buffer = self.ice_candidate_buffer.lock().await;
+            debug!("Buffering ICE candidate for peer {}", self.peer_id);
             buffer.push(candidate);
             return Ok(());
         }
+        drop(buffer);
 
         // Otherwise add it immediately
         self.peer_connection.add_ice_candidate(candidate).await?;
@@ -241,8 +594,12 @@ impl MediaRelayManager {
         turn_username: String,
         turn_password: String,
     ) -> Self {
+        let (events, _) = broadcast::channel(100);
+        let (peer_states, _) = broadcast::channel(100);
         Self {
-            relays: Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+            events,
+            peer_states,
             stun_server,
             stun_port,
             turn_server,
@@ -252,21 +609,22 @@ impl MediaRelayManager {
         }
     }
 
-    pub async fn create_relay(&self, peer_id: String) -> Result<MediaRelay> {
-        let mut media_engine = MediaEngine::default();
-        media_engine.register_default_codecs()?;
-
-        let api = APIBuilder::new()
-            .with_media_engine(media_engine)
-            .build();
+    fn rtc_configuration(&self) -> RTCConfiguration {
+        let mut urls = Vec::new();
+        if !self.stun_server.is_empty() {
+            urls.push(format!("stun:{}:{}", self.stun_server, self.stun_port));
+        }
+        if !self.turn_server.is_empty() {
+            urls.push(format!("turn:{}:{}", self.turn_server, self.turn_port));
+        }
+        if urls.is_empty() {
+            return RTCConfiguration::default();
+        }
 
-        let config = RTCConfiguration {
+        RTCConfiguration {
             ice_servers: vec![
                 RTCIceServer {
-                    urls: vec![
-                        format!("stun:{}:{}", self.stun_server, self.stun_port),
-                        format!("turn:{}:{}", self.turn_server, self.turn_port),
-                    ],
+                    urls,
                     username: self.turn_username.clone(),
                     credential: self.turn_password.clone(),
                     credential_type: RTCIceCredentialType::Password,
@@ -274,186 +632,621 @@ impl MediaRelayManager {
                 },
             ],
             ..Default::default()
+        }
+    }
+
+    /// Notifications the signaling layer should deliver to a room's members.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SignalingMessage> {
+        self.events.subscribe()
+    }
+
+    /// Connection state changes of every relay's peer connection.
+    pub fn subscribe_peer_states(&self) -> broadcast::Receiver<PeerStateChange> {
+        self.peer_states.subscribe()
+    }
+
+    fn new_room(&self, room_id: &str, media_settings: MediaSettings) -> Room {
+        let speakers = ActiveSpeakerDetector::new(room_id, self.events.clone());
+        tokio::spawn(follow_speakers(
+            Arc::downgrade(&self.rooms),
+            room_id.to_string(),
+            speakers.watch_recent_speakers(),
+        ));
+        Room {
+            id: room_id.to_string(),
+            media_settings,
+            speakers: Some(speakers),
+            ..Room::default()
+        }
+    }
+
+    /// Brings `peer_id`'s subscriptions in line with its subscription filter.
+    /// Returns whether any track was added or removed, in which case the peer
+    /// needs to renegotiate.
+    pub async fn refresh_subscriptions(&self, room_id: &str, peer_id: &str) -> Result<bool> {
+        let rooms = self.rooms.read().await;
+        let room = rooms
+            .get(room_id)
+            .ok_or_else(|| Error::NotFound(format!("Room {} not found", room_id)))?;
+        let relay = room
+            .get_peer_relay(peer_id)
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)))?;
+
+        let subscribed: HashSet<String> = relay.subscription_keys().await.into_iter().collect();
+        let mut changed = false;
+        for (other_id, other) in &room.peers {
+            if other_id == peer_id {
+                continue;
+            }
+            for source in other.published_sources().await {
+                let is_subscribed = subscribed.contains(&source.key());
+                if relay.wants(&source).await {
+                    if !is_subscribed {
+                        relay.subscribe(&source).await?;
+                        changed = true;
+                    }
+                } else if is_subscribed {
+                    relay.unsubscribe(&source).await?;
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            room.apply_last_n().await;
+        }
+        Ok(changed)
+    }
+
+    /// Re-applies the room's last-N policy, e.g. after a peer changed its pins.
+    pub async fn apply_last_n(&self, room_id: &str) {
+        let rooms = self.rooms.read().await;
+        if let Some(room) = rooms.get(room_id) {
+            room.apply_last_n().await;
+        }
+    }
+
+    /// Replaces a room's media settings. Limits and last-N apply right away;
+    /// codec changes only affect peers that join afterwards.
+    pub async fn update_media_settings(&self, room_id: &str, media_settings: MediaSettings) -> Result<Room> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_id)
+            .ok_or_else(|| Error::NotFound(format!("Room {} not found", room_id)))?;
+        let limit = media_settings.bandwidth_limit.map(u64::from);
+        room.media_settings = media_settings;
+        for (_, relay) in &room.peers {
+            relay.set_bandwidth_limit(limit);
+        }
+        room.apply_last_n().await;
+        Ok(room.clone())
+    }
+
+    /// Changes a peer's role. Peers that may no longer publish have their
+    /// tracks withdrawn from the room.
+    pub async fn set_role(&self, room_id: &str, peer_id: &str, role: Role) -> Result<()> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms
+            .get_mut(room_id)
+            .ok_or_else(|| Error::NotFound(format!("Room {} not found", room_id)))?;
+        room.set_role(peer_id, role)?;
+        if role.allows(Permission::Publish) {
+            return Ok(());
+        }
+
+        let rooms = rooms.downgrade();
+        if let Some(room) = rooms.get(room_id) {
+            if let Some(relay) = room.get_peer_relay(peer_id) {
+                for source in relay.take_published().await {
+                    info!("Withdrawing {} from room {}", source.key(), room_id);
+                    room.withdraw_track(&source).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn get_role(&self, room_id: &str, peer_id: &str) -> Option<Role> {
+        self.rooms.read().await.get(room_id).and_then(|room| room.role_of(peer_id))
+    }
+
+    /// Applies `change` to a room, such as to its access rules.
+    pub async fn update_room<T>(&self, room_id: &str, change: impl FnOnce(&mut Room) -> T) -> Result<T> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms.get_mut(room_id).ok_or_else(|| Error::NotFound(format!("Room {} not found", room_id)))?;
+        Ok(change(room))
+    }
+
+    pub async fn set_lobby_enabled(&self, room_id: &str, enabled: bool) -> Result<()> {
+        self.rooms
+            .write()
+            .await
+            .get_mut(room_id)
+            .ok_or_else(|| Error::NotFound(format!("Room {} not found", room_id)))?
+            .lobby_enabled = enabled;
+        Ok(())
+    }
+
+    /// Parks a peer in the room's lobby. Returns false when the room does
+    /// not exist or has no lobby, so the peer may join right away.
+    pub async fn park_in_lobby(&self, room_id: &str, entry: LobbyEntry) -> bool {
+        match self.rooms.write().await.get_mut(room_id) {
+            Some(room) => room.park_in_lobby(entry),
+            None => false,
+        }
+    }
+
+    /// Takes a peer out of the room's lobby, removing the room if nobody
+    /// is left in it.
+    pub async fn leave_lobby(&self, room_id: &str, peer_id: &str) -> Option<LobbyEntry> {
+        let mut rooms = self.rooms.write().await;
+        let room = rooms.get_mut(room_id)?;
+        let entry = room.leave_lobby(peer_id)?;
+        if room.is_disposable() {
+            rooms.remove(room_id);
+            info!("Room {} is empty, removing it", room_id);
+        }
+        Some(entry)
+    }
+
+    /// Registers an empty room with the given settings. Rooms are otherwise
+    /// created with default settings when their first peer joins.
+    pub async fn create_room(&self, room_id: &str, media_settings: MediaSettings) -> Result<Room> {
+        let mut rooms = self.rooms.write().await;
+        if rooms.contains_key(room_id) {
+            return Err(Error::Room(format!("Room {} already exists", room_id)));
+        }
+
+        let room = self.new_room(room_id, media_settings);
+        rooms.insert(room_id.to_string(), room.clone());
+        Ok(room)
+    }
+
+    pub async fn get_room(&self, room_id: &str) -> Option<Room> {
+        self.rooms.read().await.get(room_id).cloned()
+    }
+
+    pub async fn get_rooms(&self) -> HashMap<String, Room> {
+        self.rooms.read().await.clone()
+    }
+
+    /// Closes every relay in the room and forgets it.
+    pub async fn remove_room(&self, room_id: &str) -> Result<()> {
+        let room = self.rooms
+            .write()
+            .await
+            .remove(room_id)
+            .ok_or_else(|| Error::NotFound(format!("Room {} not found", room_id)))?;
+
+        for (peer_id, relay) in &room.peers {
+            if let Err(e) = relay.peer_connection.close().await {
+                error!("Error closing connection for peer {}: {}", peer_id, e);
+            }
+        }
+        info!("Removed room {} with {} peers", room_id, room.peers.len());
+        Ok(())
+    }
+
+    /// Creates the relay for `peer_id`, subscribes it to every track already
+    /// published in `room_id`, and publishes its own inbound tracks to the room.
+    pub async fn create_relay(&self, room_id: &str, peer_id: String) -> Result<MediaRelay> {
+        self.create_relay_with_mode(room_id, peer_id, RelayMode::Interactive, None).await
+    }
+
+    /// Like `create_relay`, for a peer that negotiates over HTTP instead of
+    /// the signaling socket, or that joins with `role` rather than the
+    /// default one.
+    pub async fn create_relay_with_mode(&self, room_id: &str, peer_id: String, mode: RelayMode, role: Option<Role>) -> Result<MediaRelay> {
+        let media_settings = self.get_room(room_id)
+            .await
+            .map(|room| room.media_settings)
+            .unwrap_or_default();
+        let relay = MediaRelay::new(
+            peer_id.clone(),
+            room_id.to_string(),
+            self.rtc_configuration(),
+            &media_settings,
+            self.events.clone(),
+            self.peer_states.clone(),
+            mode,
+        ).await?;
+
+        // Weak reference: the registry owns the relay, which owns this handler
+        let registry = Arc::downgrade(&self.rooms);
+        let publisher_id = peer_id.clone();
+        let publisher_room = room_id.to_string();
+        let published = relay.published.clone();
+        let publisher_pc = Arc::downgrade(&relay.peer_connection);
+        tokio::spawn(cap_publisher_bitrate(
+            publisher_pc.clone(),
+            published.clone(),
+            relay.downlink.clone(),
+        ));
+        relay.peer_connection.on_track(Box::new(move |track, _, _| {
+            debug!("Received track from peer {}: kind={}, id={}, payload_type={}",
+                publisher_id,
+                track.kind(),
+                track.id(),
+                track.payload_type()
+            );
+            let registry = registry.clone();
+            let published = published.clone();
+            let publisher_id = publisher_id.clone();
+            let publisher_room = publisher_room.clone();
+            let publisher_pc = publisher_pc.clone();
+            Box::pin(async move {
+                // Simulcast layers of one track arrive as separate remote tracks sharing its id
+                let key = TrackSource::key_for(&publisher_id, &track.id());
+                let speakers = match registry.upgrade() {
+                    Some(rooms) => rooms.read().await.get(&publisher_room).and_then(|room| room.speakers.clone()),
+                    None => None,
+                };
+                let (source, is_new) = {
+                    let mut published = published.write().await;
+                    match published.get(&key) {
+                        Some(source) => (source.clone(), false),
+                        None => {
+                            let source = Arc::new(TrackSource::new(&publisher_room, &publisher_id, &track, publisher_pc, speakers));
+                            published.insert(key, source.clone());
+                            (source, true)
+                        }
+                    }
+                };
+                tokio::spawn(publish_track(registry, published, source, track, is_new));
+            })
+        }));
+
+        let previous = {
+            let mut rooms = self.rooms.write().await;
+            let room = rooms
+                .entry(room_id.to_string())
+                .or_insert_with(|| self.new_room(room_id, MediaSettings::default()));
+
+            let index = room.peers.iter().position(|(id, _)| id == &peer_id);
+            let previous_role = room.role_of(&peer_id);
+            let previous = room.get_peer_relay(&peer_id).cloned();
+            room.remove_peer(&peer_id);
+            if let Err(e) = room.add_peer(peer_id.clone(), relay.clone(), role) {
+                // The peer keeps the relay it had, where it had it
+                if let (Some(index), Some(previous), Some(previous_role)) = (index, previous, previous_role) {
+                    room.peers.insert(index, (peer_id.clone(), previous));
+                    room.roles.insert(peer_id.clone(), previous_role);
+                }
+                drop(rooms);
+                if let Err(close_err) = relay.peer_connection.close().await {
+                    error!("Error closing rejected relay for peer {}: {}", peer_id, close_err);
+                }
+                return Err(e);
+            }
+            previous
         };
+        if let Some(previous) = previous {
+            warn!("Replacing existing relay for peer {} in room {}", peer_id, room_id);
+            self.detach_relay(&previous).await;
+        }
 
-        let peer_connection = Arc::new(api.new_peer_connection(config).await?);
-        let relay = MediaRelay::new(peer_id.clone()).await?;
-        
-        let mut relays = self.relays.write().await;
-        relays.insert(peer_id, relay.clone());
+        let rooms = self.rooms.read().await;
+        if let Some(room) = rooms.get(room_id) {
+            for (other_id, other) in &room.peers {
+                if other_id == &peer_id {
+                    continue;
+                }
+                for source in other.published_sources().await {
+                    if let Err(e) = relay.subscribe(&source).await {
+                        warn!("Failed to subscribe {} to {}: {}", peer_id, source.key(), e);
+                    }
+                }
+            }
+            room.apply_last_n().await;
+        }
 
         Ok(relay)
     }
 
-    pub async fn forward_track(&self, from_peer: &str, to_peer: &str, track: Arc<TrackLocalStaticRTP>) -> Result<()> {
-        let relays = self.relays.read().await;
-        if let Some(relay) = relays.get(to_peer) {
-            relay.peer_connection.add_track(track).await?;
+    /// Subscribes `to_peer` to a published source in the same room.
+    pub async fn forward_track(&self, source: &Arc<TrackSource>, to_peer: &str) -> Result<()> {
+        let rooms = self.rooms.read().await;
+        if let Some(relay) = rooms.get(&source.room_id).and_then(|room| room.get_peer_relay(to_peer)) {
+            relay.subscribe(source).await?;
         }
         Ok(())
     }
 
-    pub async fn broadcast_track(&self, from_peer: &str, track: Arc<TrackLocalStaticRTP>) -> Result<()> {
-        let relays = self.relays.read().await;
-        for (peer_id, relay) in relays.iter() {
-            if peer_id != from_peer {
-                relay.peer_connection.add_track(track.clone()).await?;
-            }
+    /// Subscribes every other peer in the source's room to it.
+    pub async fn broadcast_track(&self, source: &Arc<TrackSource>) -> Result<()> {
+        let rooms = self.rooms.read().await;
+        if let Some(room) = rooms.get(&source.room_id) {
+            room.broadcast_track(source).await?;
         }
         Ok(())
     }
 
-    pub async fn remove_relay(&self, peer_id: &str) -> Result<()> {
-        let mut relays = self.relays.write().await;
-        if let Some(relay) = relays.remove(peer_id) {
-            // Close the peer connection
-            relay.peer_connection.close().await?;
-            info!("Removed relay for peer {}", peer_id);
-            
+    pub async fn remove_relay(&self, room_id: &str, peer_id: &str) -> Result<()> {
+        let removed = {
+            let mut rooms = self.rooms.write().await;
+            let removed = rooms.get(room_id).and_then(|room| room.get_peer_relay(peer_id).cloned());
+            if let Some(room) = rooms.get_mut(room_id) {
+                room.remove_peer(peer_id);
+                if let Some(speakers) = &room.speakers {
+                    speakers.remove_peer(peer_id).await;
+                }
+                if room.is_disposable() {
+                    rooms.remove(room_id);
+                    info!("Room {} is empty, removing it", room_id);
+                }
+            }
+            removed
+        };
+
+        if let Some(relay) = removed {
+            self.detach_relay(&relay).await;
+            // The departed peer may free a last-N slot
+            self.apply_last_n(room_id).await;
+            info!("Removed relay for peer {} in room {}", peer_id, room_id);
+
             // Get current relay count
-            let relay_count = relays.len();
-            info!("Active relays remaining: {}", relay_count);
+            let relay_count = self.get_room_peers(room_id).await.len();
+            info!("Active relays remaining in room {}: {}", room_id, relay_count);
         } else {
-            warn!("Attempted to remove non-existent relay for peer {}", peer_id);
+            warn!("Attempted to remove non-existent relay for peer {} in room {}", peer_id, room_id);
         }
         Ok(())
     }
 
-    pub async fn handle_peer_disconnect(&self, peer_id: &str, room_id: &str) -> Result<()> {
-        // Get the relay and peer list before removing
-        let relays = self.relays.read().await;
-        let room_peers: Vec<String> = relays
-            .iter()
-            .filter_map(|(id, _)| {
-                if id != peer_id {
-                    Some(id.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
+    /// Closes a relay that is no longer registered and removes it from every
+    /// other peer's subscriptions in its room.
+    async fn detach_relay(&self, relay: &MediaRelay) {
+        let rooms = self.rooms.read().await;
+        if let Some(room) = rooms.get(&relay.room_id) {
+            unlink_relay(room, relay).await;
+        }
+        drop(rooms);
 
-        // Close the peer connection if it exists
-        if let Some(relay) = relays.get(peer_id) {
-            relay.peer_connection.close().await?;
+        if let Err(e) = relay.peer_connection.close().await {
+            error!("Error closing connection for peer {}: {}", relay.peer_id, e);
         }
-        drop(relays); // Release the read lock
+    }
 
-        // Now remove the relay
-        self.remove_relay(peer_id).await?;
+    pub async fn handle_peer_disconnect(&self, peer_id: &str, room_id: &str) -> Result<()> {
+        self.remove_relay(room_id, peer_id).await?;
 
-        info!("Peer {} disconnected from room {}. Remaining peers: {:?}", 
+        let room_peers = self.get_room_peers(room_id).await;
+        info!("Peer {} disconnected from room {}. Remaining peers: {:?}",
             peer_id, room_id, room_peers);
 
         Ok(())
     }
 
-    pub async fn cleanup_stale_relays(&self) {
-        let monitor_interval = Duration::from_secs(30); // Check every 30 seconds
-        loop {
-            let mut relays = self.relays.write().await;
-            let mut stale_peers = Vec::new();
+    /// Peers, with their rooms, whose connection failed or closed and isn't
+    /// restarting. Removing them is up to the signaling layer, which also
+    /// keeps track of them.
+    pub async fn stale_relays(&self) -> Vec<(String, String)> {
+        let mut stale_peers = Vec::new();
 
-            // Check each peer connection's state
-            for (peer_id, relay) in relays.iter() {
+        // Check each peer connection's state
+        for (room_id, room) in self.rooms.read().await.iter() {
+            for (peer_id, relay) in &room.peers {
+                // Disconnected while restarting ICE is expected to recover
+                if relay.is_restarting() {
+                    continue;
+                }
                 let connection_state = relay.peer_connection.connection_state();
                 match connection_state {
-                    webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState::Failed |
-                    webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState::Closed |
-                    webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState::Disconnected => {
-                        stale_peers.push(peer_id.clone());
-                        warn!("Found stale peer connection for {}: {:?}", peer_id, connection_state);
+                    RTCPeerConnectionState::Failed |
+                    RTCPeerConnectionState::Closed |
+                    RTCPeerConnectionState::Disconnected => {
+                        stale_peers.push((room_id.clone(), peer_id.clone()));
+                        warn!("Found stale peer connection for {} in room {}: {:?}",
+                            peer_id, room_id, connection_state);
                     }
                     _ => {}
                 }
             }
-
-            // Remove stale peers
-            for peer_id in stale_peers {
-                if let Some(relay) = relays.remove(&peer_id) {
-                    if let Err(e) = relay.peer_connection.close().await {
-                        error!("Error closing connection for stale peer {}: {}", peer_id, e);
-                    }
-                    info!("Removed stale relay for peer {}", peer_id);
-                }
-            }
-
-            drop(relays); // Release the write lock
-            tokio::time::sleep(monitor_interval).await;
         }
+        stale_peers
     }
 
     pub async fn get_active_peer_count(&self) -> usize {
-        self.relays.read().await.len()
+        self.rooms.read().await.values().map(|room| room.peers.len()).sum()
+    }
+
+    pub async fn get_relay(&self, room_id: &str, peer_id: &str) -> Option<MediaRelay> {
+        let rooms = self.rooms.read().await;
+        rooms.get(room_id).and_then(|room| room.get_peer_relay(peer_id).cloned())
+    }
+
+    pub async fn get_room_peers(&self, room_id: &str) -> Vec<String> {
+        let rooms = self.rooms.read().await;
+        rooms
+            .get(room_id)
+            .map(|room| room.peers.iter().map(|(peer_id, _)| peer_id.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    pub async fn get_room_relays(&self, room_id: &str) -> HashMap<String, MediaRelay> {
+        let rooms = self.rooms.read().await;
+        rooms
+            .get(room_id)
+            .map(|room| room.peers.iter().cloned().collect())
+            .unwrap_or_default()
     }
 
-    pub async fn get_relay(&self, peer_id: &str) -> Option<MediaRelay> {
-        let relays = self.relays.read().await;
-        relays.get(peer_id).cloned()
+    pub async fn get_room_stats(&self, room_id: &str) -> HashMap<String, MediaStats> {
+        let mut stats = HashMap::new();
+        for (peer_id, relay) in self.get_room_relays(room_id).await {
+            match relay.get_stats().await {
+                Ok(relay_stats) => {
+                    stats.insert(peer_id, relay_stats);
+                }
+                Err(e) => {
+                    warn!("Failed to get stats for peer {}: {}", peer_id, e);
+                }
+            }
+        }
+        stats
     }
 
     // Add monitoring methods
     pub async fn monitor_relays(&self) {
         let monitor_interval = Duration::from_secs(5);
         loop {
-            let relays = self.relays.read().await;
-            for (peer_id, relay) in relays.iter() {
-                match relay.get_stats().await {
-                    Ok(stats) => {
-                        info!(
-                            "Media relay stats for peer {}: rx_packets={}, tx_packets={}, rx_bytes={}, tx_bytes={}",
-                            peer_id,
-                            stats.packets_received,
-                            stats.packets_sent,
-                            stats.bytes_received,
-                            stats.bytes_sent
-                        );
-                    }
-                    Err(e) => {
-                        warn!("Failed to get stats for peer {}: {}", peer_id, e);
-                    }
+            let room_ids: Vec<String> = self.rooms.read().await.keys().cloned().collect();
+            for room_id in room_ids {
+                for (peer_id, stats) in self.get_room_stats(&room_id).await {
+                    info!(
+                        "Media relay stats for peer {} in room {}: rx_packets={}, tx_packets={}, rx_bytes={}, tx_bytes={}",
+                        peer_id,
+                        room_id,
+                        stats.packets_received,
+                        stats.packets_sent,
+                        stats.bytes_received,
+                        stats.bytes_sent
+                    );
                 }
             }
             tokio::time::sleep(monitor_interval).await;
         }
     }
 
-    pub async fn get_relays(&self) -> Result<HashMap<String, MediaRelay>> {
-        Ok(self.relays.read().await.clone())
+    pub async fn add_peer(&self, room_id: &str, peer_id: String) -> Result<()> {
+        self.create_relay(room_id, peer_id).await?;
+        Ok(())
     }
+}
 
-    pub async fn add_peer(&self, room_id: &str, peer_id: String) -> Result<()> {
-        let mut relays = self.relays.write().await;
-        
-        // Create API and configuration for the peer connection
-        let mut media_engine = MediaEngine::default();
-        media_engine.register_default_codecs()?;
+/// Publishes a new source to the publisher's room and forwards one of its
+/// layers until it ends. The last layer to end withdraws the source from
+/// every subscriber.
+async fn publish_track(
+    registry: Weak<RwLock<RoomMap>>,
+    published: Arc<RwLock<HashMap<String, Arc<TrackSource>>>>,
+    source: Arc<TrackSource>,
+    track: Arc<TrackRemote>,
+    is_new: bool,
+) {
+    if is_new {
+        if let Some(rooms) = registry.upgrade() {
+            let rooms = rooms.read().await;
+            if let Some(room) = rooms.get(&source.room_id) {
+                // Answers can add sending tracks too, so the role is checked here
+                if !room.role_of(&source.publisher_id).map_or(false, |role| role.allows(Permission::Publish)) {
+                    warn!("Peer {} may not publish, dropping {}", source.publisher_id, source.key());
+                    drop(rooms);
+                    remove_published(&published, &source).await;
+                    return;
+                }
+                if let Err(e) = room.broadcast_track(&source).await {
+                    warn!("Failed to publish {} to room {}: {}", source.key(), source.room_id, e);
+                }
+                room.apply_last_n().await;
+            }
+        }
+    }
 
-        let api = APIBuilder::new()
-            .with_media_engine(media_engine)
-            .build();
+    // Other simulcast layers are still feeding this source
+    if source.forward(track).await > 0 {
+        return;
+    }
 
-        let config = RTCConfiguration {
-            ice_servers: vec![
-                RTCIceServer {
-                    urls: vec![
-                        format!("stun:{}:{}", self.stun_server, self.stun_port),
-                        format!("turn:{}:{}", self.turn_server, self.turn_port)
-                    ],
-                    username: self.turn_username.clone(),
-                    credential: self.turn_password.clone(),
-                    credential_type: RTCIceCredentialType::Password,
-                    ..Default::default()
-                },
-            ],
-            ..Default::default()
-        };
+    remove_published(&published, &source).await;
+    if let Some(rooms) = registry.upgrade() {
+        let rooms = rooms.read().await;
+        if let Some(room) = rooms.get(&source.room_id) {
+            room.withdraw_track(&source).await;
+        }
+    }
+}
 
-        let peer_connection = Arc::new(api.new_peer_connection(config).await?);
-        let media_relay = MediaRelay::new(peer_id.clone()).await?;
+/// Forgets `source` unless a reconnecting publisher already replaced it.
+async fn remove_published(published: &RwLock<HashMap<String, Arc<TrackSource>>>, source: &Arc<TrackSource>) {
+    let mut published = published.write().await;
+    if published.get(&source.key()).map_or(false, |current| Arc::ptr_eq(current, source)) {
+        published.remove(&source.key());
+    }
+}
 
-        relays.insert(peer_id, media_relay);
-        Ok(())
+/// Re-applies a room's last-N policy whenever its active speakers change.
+/// Ends when the room's speaker detector is dropped.
+async fn follow_speakers(
+    registry: Weak<RwLock<RoomMap>>,
+    room_id: String,
+    mut recent_speakers: watch::Receiver<Vec<String>>,
+) {
+    while recent_speakers.changed().await.is_ok() {
+        let Some(rooms) = registry.upgrade() else { break };
+        let rooms = rooms.read().await;
+        if let Some(room) = rooms.get(&room_id) {
+            room.apply_last_n().await;
+        }
+    }
+}
+
+/// Periodically sends the publisher a REMB with the bitrate it may send: the
+/// room's bandwidth limit and, when its video isn't simulcast, whatever the
+/// slowest subscriber can take. Runs until the peer connection goes away.
+async fn cap_publisher_bitrate(
+    publisher: Weak<RTCPeerConnection>,
+    published: Arc<RwLock<HashMap<String, Arc<TrackSource>>>>,
+    // Carries the room's per-participant limit, which applies both ways
+    downlink: Arc<Downlink>,
+) {
+    let interval = Duration::from_secs(1);
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(peer_connection) = publisher.upgrade() else { break };
+        if peer_connection.connection_state() == RTCPeerConnectionState::Closed {
+            break;
+        }
+
+        let sources: Vec<Arc<TrackSource>> = published.read().await.values().cloned().collect();
+        if sources.is_empty() {
+            continue;
+        }
+
+        let mut ssrcs = Vec::new();
+        let mut subscriber_cap = Some(0);
+        for source in &sources {
+            ssrcs.extend(source.ssrcs().await);
+            if source.kind != RTPCodecType::Video {
+                continue;
+            }
+            // Simulcast lets each subscriber pick a layer instead
+            subscriber_cap = match (subscriber_cap, source.is_simulcast().await) {
+                (Some(total), false) => source.subscriber_bitrate().await.map(|bitrate| total + bitrate),
+                _ => None,
+            };
+        }
+
+        let mut bitrate = downlink.limit().unwrap_or(MAX_BITRATE).min(MAX_BITRATE);
+        if let Some(cap) = subscriber_cap.filter(|cap| *cap > 0) {
+            bitrate = bitrate.min(cap);
+        }
+
+        let remb = ReceiverEstimatedMaximumBitrate {
+            sender_ssrc: 0,
+            bitrate: bitrate as f32,
+            ssrcs,
+        };
+        if let Err(e) = peer_connection.write_rtcp(&[Box::new(remb)]).await {
+            debug!("Failed to send REMB: {}", e);
+        }
+    }
+}
+
+/// Drops every subscription between `relay` and the other peers in `room`.
+async fn unlink_relay(room: &Room, relay: &MediaRelay) {
+    let published = relay.published_sources().await;
+    for (peer_id, other) in &room.peers {
+        if peer_id == &relay.peer_id {
+            continue;
+        }
+        for source in &published {
+            if let Err(e) = other.unsubscribe(source).await {
+                warn!("Failed to unsubscribe {} from {}: {}", peer_id, source.key(), e);
+            }
+        }
+        for source in other.published_sources().await {
+            source.remove_subscriber(&relay.peer_id).await;
+        }
     }
 }
 
@@ -461,9 +1254,8 @@ impl fmt::Debug for MediaRelay {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MediaRelay")
             .field("peer_id", &self.peer_id)
-            .field("audio_track", &self.audio_track)
-            .field("video_track", &self.video_track)
+            .field("room_id", &self.room_id)
             .field("data_channel", &"<RTCDataChannel>")
             .finish()
     }
-} 
\ No newline at end of file
+}
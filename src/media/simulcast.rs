@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use webrtc::api::media_engine::{MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::rtp::packet::Packet as RTPPacket;
+
+/// Highest simulcast layer a subscriber wants from a publisher. Layers are
+/// ranked by their measured bitrate, so `High` is whatever the publisher's
+/// best encoding currently is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SimulcastQuality {
+    Low,
+    Medium,
+    High,
+}
+
+impl SimulcastQuality {
+    /// Index into `layer_count` layers ordered from lowest to highest bitrate.
+    pub fn layer_index(&self, layer_count: usize) -> usize {
+        let top = layer_count.saturating_sub(1);
+        match self {
+            SimulcastQuality::Low => 0,
+            SimulcastQuality::Medium => top / 2,
+            SimulcastQuality::High => top,
+        }
+    }
+}
+
+impl Default for SimulcastQuality {
+    fn default() -> Self {
+        SimulcastQuality::High
+    }
+}
+
+/// Whether `payload` starts a keyframe. Returns `None` for codecs we cannot
+/// inspect, in which case any packet is treated as a safe switching point.
+pub fn is_keyframe(mime_type: &str, payload: &[u8]) -> Option<bool> {
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+        Some(is_vp8_keyframe(payload))
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        Some(is_vp9_keyframe(payload))
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        Some(is_h264_keyframe(payload))
+    } else {
+        None
+    }
+}
+
+// RFC 7741 payload descriptor followed by the VP8 frame header
+fn is_vp8_keyframe(payload: &[u8]) -> bool {
+    let Some(&first) = payload.first() else { return false };
+    let start_of_partition = first & 0x10 != 0;
+    let partition_id = first & 0x0F;
+    if !start_of_partition || partition_id != 0 {
+        return false;
+    }
+
+    let mut offset = 1;
+    if first & 0x80 != 0 {
+        let Some(&extension) = payload.get(offset) else { return false };
+        offset += 1;
+        if extension & 0x80 != 0 {
+            // Picture ID is one or two bytes depending on its M bit
+            let Some(&picture_id) = payload.get(offset) else { return false };
+            offset += if picture_id & 0x80 != 0 { 2 } else { 1 };
+        }
+        if extension & 0x40 != 0 {
+            offset += 1;
+        }
+        if extension & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+
+    payload.get(offset).map_or(false, |header| header & 0x01 == 0)
+}
+
+// draft-ietf-payload-vp9: B (start of frame) set and P (inter-picture predicted) clear
+fn is_vp9_keyframe(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .map_or(false, |&descriptor| descriptor & 0x08 != 0 && descriptor & 0x40 == 0)
+}
+
+// RFC 6184: IDR or SPS, possibly inside a STAP-A or at the start of an FU-A
+fn is_h264_keyframe(payload: &[u8]) -> bool {
+    const NALU_IDR: u8 = 5;
+    const NALU_SPS: u8 = 7;
+    const NALU_STAP_A: u8 = 24;
+    const NALU_FU_A: u8 = 28;
+
+    let Some(&header) = payload.first() else { return false };
+    match header & 0x1F {
+        NALU_IDR | NALU_SPS => true,
+        NALU_STAP_A => {
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                let nalu_type = payload[offset + 2] & 0x1F;
+                if nalu_type == NALU_IDR || nalu_type == NALU_SPS {
+                    return true;
+                }
+                offset += 2 + size;
+            }
+            false
+        }
+        NALU_FU_A => payload
+            .get(1)
+            .map_or(false, |&fu_header| fu_header & 0x80 != 0 && fu_header & 0x1F == NALU_IDR),
+        _ => false,
+    }
+}
+
+/// Rewrites sequence numbers and timestamps so a subscriber sees a single
+/// continuous stream while the relay switches between simulcast layers.
+#[derive(Debug, Default)]
+pub struct SequenceRewriter {
+    seq_offset: u16,
+    ts_offset: u32,
+    last_seq: Option<u16>,
+    last_ts: u32,
+    last_sent_at: Option<Instant>,
+    // First rewritten sequence number produced by the current offsets
+    anchor_seq: Option<u16>,
+    switching: bool,
+}
+
+impl SequenceRewriter {
+    /// The next packet comes from a different layer; re-anchor the offsets on it.
+    pub fn switch_source(&mut self) {
+        self.switching = true;
+    }
+
+    pub fn rewrite(&mut self, packet: &RTPPacket, clock_rate: u32) -> RTPPacket {
+        if self.switching {
+            if let (Some(last_seq), Some(last_sent_at)) = (self.last_seq, self.last_sent_at) {
+                self.seq_offset = last_seq
+                    .wrapping_add(1)
+                    .wrapping_sub(packet.header.sequence_number);
+
+                // Advance the timestamp by the wall-clock gap since the last packet
+                let elapsed_ticks = (last_sent_at.elapsed().as_secs_f64() * clock_rate as f64) as u32;
+                self.ts_offset = self.last_ts
+                    .wrapping_add(elapsed_ticks.max(1))
+                    .wrapping_sub(packet.header.timestamp);
+            }
+            self.switching = false;
+            self.anchor_seq = Some(packet.header.sequence_number.wrapping_add(self.seq_offset));
+        }
+
+        let rewritten = self.apply(packet);
+
+        // Only move forward; reordered packets keep their rewritten numbers
+        let is_newer = self.last_seq.map_or(true, |last| {
+            rewritten.header.sequence_number.wrapping_sub(last) < 0x8000
+        });
+        if is_newer {
+            self.last_seq = Some(rewritten.header.sequence_number);
+            self.last_ts = rewritten.header.timestamp;
+            self.last_sent_at = Some(Instant::now());
+        }
+
+        rewritten
+    }
+
+    /// Applies the current offsets without advancing the stream, for
+    /// retransmissions of packets that were already sent.
+    pub fn apply(&self, packet: &RTPPacket) -> RTPPacket {
+        let mut rewritten = packet.clone();
+        rewritten.header.sequence_number = packet.header.sequence_number.wrapping_add(self.seq_offset);
+        rewritten.header.timestamp = packet.header.timestamp.wrapping_add(self.ts_offset);
+        rewritten
+    }
+
+    /// Maps a sequence number the subscriber saw back to the current
+    /// layer's numbering. Packets sent before the last switch came from
+    /// another layer and cannot be mapped.
+    pub fn original_sequence(&self, sequence_number: u16) -> Option<u16> {
+        let anchor = self.anchor_seq?;
+        if sequence_number.wrapping_sub(anchor) >= 0x8000 {
+            return None;
+        }
+        Some(sequence_number.wrapping_sub(self.seq_offset))
+    }
+}
@@ -0,0 +1,484 @@
+use crate::media::bandwidth::{Downlink, RateMeter};
+use crate::media::feedback::PacketHistory;
+use crate::media::simulcast::{is_keyframe, SequenceRewriter, SimulcastQuality};
+use crate::media::speaker::ActiveSpeakerDetector;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use log::{debug, info};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::full_intra_request::{FirEntry, FullIntraRequest};
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::transport_feedbacks::transport_layer_nack::{nack_pairs_from_sequence_numbers, TransportLayerNack};
+use webrtc::rtp::packet::Packet as RTPPacket;
+use webrtc::sdp::extmap::AUDIO_LEVEL_URI;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::TrackLocalWriter;
+use webrtc::track::track_remote::TrackRemote;
+use webrtc::util::MarshalSize;
+
+// How often each subscriber's simulcast layer is re-evaluated
+const LAYER_SELECTION_INTERVAL: Duration = Duration::from_millis(500);
+// Subscribers tend to send PLIs in bursts; the publisher only needs one
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// One encoding of a source. Non-simulcast tracks have a single layer with
+/// an empty rid.
+struct Layer {
+    rid: String,
+    ssrc: u32,
+    rate: RateMeter,
+    history: Mutex<PacketHistory>,
+    // Last keyframe request sent upstream and the FIR command sequence number
+    keyframe_request: Mutex<(Option<Instant>, u8)>,
+}
+
+impl Layer {
+    fn new(rid: &str, ssrc: u32) -> Self {
+        Self {
+            rid: rid.to_string(),
+            ssrc,
+            rate: RateMeter::new(),
+            history: Mutex::new(PacketHistory::new()),
+            keyframe_request: Mutex::new((None, 0)),
+        }
+    }
+}
+
+struct SubscriberState {
+    quality: SimulcastQuality,
+    current_layer: Option<String>,
+    target_layer: Option<String>,
+    last_selection: Option<Instant>,
+    // Set when the subscriber is outside the room's last-N selection
+    paused: bool,
+    // Set when video was dropped over the room's bandwidth limit
+    over_budget: bool,
+    rewriter: SequenceRewriter,
+}
+
+struct Subscriber {
+    track: Arc<TrackLocalStaticRTP>,
+    // Shared by every source the subscribing peer receives
+    downlink: Arc<Downlink>,
+    state: Mutex<SubscriberState>,
+}
+
+/// A remote track published by one peer. Every subscriber in the room gets
+/// its own `TrackLocalStaticRTP` fed from the publisher's RTP stream; for
+/// simulcast publishers each subscriber is fed from one layer at a time.
+/// Keyframe requests and unanswerable NACKs go back to the publisher.
+pub struct TrackSource {
+    pub room_id: String,
+    pub publisher_id: String,
+    pub track_id: String,
+    pub kind: RTPCodecType,
+    pub codec: RTCRtpCodecCapability,
+    publisher: Weak<RTCPeerConnection>,
+    // Negotiated id of the ssrc-audio-level extension on audio tracks
+    audio_level_id: Option<u8>,
+    speakers: Option<Arc<ActiveSpeakerDetector>>,
+    layers: RwLock<Vec<Arc<Layer>>>,
+    subscribers: RwLock<HashMap<String, Arc<Subscriber>>>,
+    // Set by moderators; nothing is forwarded while muted
+    muted: AtomicBool,
+}
+
+impl TrackSource {
+    pub fn new(
+        room_id: &str,
+        publisher_id: &str,
+        track: &TrackRemote,
+        publisher: Weak<RTCPeerConnection>,
+        speakers: Option<Arc<ActiveSpeakerDetector>>,
+    ) -> Self {
+        let audio_level_id = track
+            .params()
+            .header_extensions
+            .iter()
+            .find(|extension| extension.uri == AUDIO_LEVEL_URI)
+            .map(|extension| extension.id as u8);
+        Self {
+            room_id: room_id.to_string(),
+            publisher_id: publisher_id.to_string(),
+            track_id: track.id(),
+            kind: track.kind(),
+            codec: track.codec().capability,
+            publisher,
+            audio_level_id,
+            speakers,
+            layers: RwLock::new(Vec::new()),
+            subscribers: RwLock::new(HashMap::new()),
+            muted: AtomicBool::new(false),
+        }
+    }
+
+    /// Key identifying this source within its room.
+    pub fn key(&self) -> String {
+        Self::key_for(&self.publisher_id, &self.track_id)
+    }
+
+    pub fn key_for(publisher_id: &str, track_id: &str) -> String {
+        format!("{}:{}", publisher_id, track_id)
+    }
+
+    /// Rids of the layers currently being received.
+    pub async fn layer_rids(&self) -> Vec<String> {
+        self.layers.read().await.iter().map(|layer| layer.rid.clone()).collect()
+    }
+
+    async fn add_layer(&self, rid: &str, ssrc: u32) -> Arc<Layer> {
+        let mut layers = self.layers.write().await;
+        if let Some(layer) = layers.iter().find(|layer| layer.rid == rid) {
+            return layer.clone();
+        }
+        let layer = Arc::new(Layer::new(rid, ssrc));
+        layers.push(layer.clone());
+        layer
+    }
+
+    async fn layer(&self, rid: &str) -> Option<Arc<Layer>> {
+        self.layers.read().await.iter().find(|layer| layer.rid == rid).cloned()
+    }
+
+    /// Returns the number of layers left.
+    async fn remove_layer(&self, rid: &str) -> usize {
+        let mut layers = self.layers.write().await;
+        layers.retain(|layer| layer.rid != rid);
+        layers.len()
+    }
+
+    /// Creates (or returns the existing) local track that feeds `peer_id`.
+    pub async fn add_subscriber(&self, peer_id: &str, downlink: Arc<Downlink>) -> Arc<TrackLocalStaticRTP> {
+        let mut subscribers = self.subscribers.write().await;
+        subscribers
+            .entry(peer_id.to_string())
+            .or_insert_with(|| {
+                Arc::new(Subscriber {
+                    // Stream id is the publisher so the client can group a peer's tracks together
+                    track: Arc::new(TrackLocalStaticRTP::new(
+                        self.codec.clone(),
+                        self.track_id.clone(),
+                        self.publisher_id.clone(),
+                    )),
+                    downlink,
+                    state: Mutex::new(SubscriberState {
+                        quality: SimulcastQuality::default(),
+                        current_layer: None,
+                        target_layer: None,
+                        last_selection: None,
+                        paused: false,
+                        over_budget: false,
+                        rewriter: SequenceRewriter::default(),
+                    }),
+                })
+            })
+            .track
+            .clone()
+    }
+
+    pub async fn remove_subscriber(&self, peer_id: &str) -> Option<Arc<TrackLocalStaticRTP>> {
+        self.subscribers
+            .write()
+            .await
+            .remove(peer_id)
+            .map(|subscriber| subscriber.track.clone())
+    }
+
+    pub async fn subscriber_ids(&self) -> Vec<String> {
+        self.subscribers.read().await.keys().cloned().collect()
+    }
+
+    pub async fn set_subscriber_quality(&self, peer_id: &str, quality: SimulcastQuality) {
+        if let Some(subscriber) = self.subscribers.read().await.get(peer_id) {
+            let mut state = subscriber.state.lock().await;
+            state.quality = quality;
+            // Re-select on the next packet
+            state.last_selection = None;
+        }
+    }
+
+    /// Stops or resumes forwarding to `peer_id` without renegotiating. A
+    /// resumed subscriber restarts on the next keyframe.
+    pub async fn set_subscriber_paused(&self, peer_id: &str, paused: bool) {
+        let Some(subscriber) = self.subscribers.read().await.get(peer_id).cloned() else { return };
+        let mut state = subscriber.state.lock().await;
+        if state.paused == paused {
+            return;
+        }
+        debug!("{} forwarding {} to {}", if paused { "Pausing" } else { "Resuming" }, self.key(), peer_id);
+        state.paused = paused;
+        if !paused {
+            state.current_layer = None;
+            state.last_selection = None;
+        }
+    }
+
+    /// Stops or resumes forwarding to every subscriber, whatever the
+    /// publisher sends. Returns whether anything changed.
+    pub async fn set_muted(&self, muted: bool) -> bool {
+        if self.muted.swap(muted, Ordering::Relaxed) == muted {
+            return false;
+        }
+        info!("{} track {}", if muted { "Muted" } else { "Unmuted" }, self.key());
+        if !muted {
+            // Restart everyone on the next keyframe
+            for subscriber in self.subscribers.read().await.values() {
+                let mut state = subscriber.state.lock().await;
+                state.current_layer = None;
+                state.last_selection = None;
+            }
+        }
+        true
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub async fn is_subscriber_paused(&self, peer_id: &str) -> bool {
+        match self.subscribers.read().await.get(peer_id).cloned() {
+            Some(subscriber) => subscriber.state.lock().await.paused,
+            None => false,
+        }
+    }
+
+    /// Rid of the layer currently forwarded to `peer_id`.
+    pub async fn subscriber_layer(&self, peer_id: &str) -> Option<String> {
+        let subscriber = self.subscribers.read().await.get(peer_id).cloned()?;
+        let state = subscriber.state.lock().await;
+        state.current_layer.clone()
+    }
+
+    /// Whether the publisher is sending more than one encoding.
+    pub async fn is_simulcast(&self) -> bool {
+        self.layers.read().await.len() > 1
+    }
+
+    /// Lowest per-stream bitrate available to any subscriber, if there are any.
+    pub async fn subscriber_bitrate(&self) -> Option<u64> {
+        let downlinks: Vec<Arc<Downlink>> = self.subscribers
+            .read()
+            .await
+            .values()
+            .map(|subscriber| subscriber.downlink.clone())
+            .collect();
+        let mut lowest = None;
+        for downlink in downlinks {
+            let bitrate = downlink.per_stream_bitrate().await;
+            lowest = Some(lowest.map_or(bitrate, |current: u64| current.min(bitrate)));
+        }
+        lowest
+    }
+
+    /// SSRCs of the layers currently being received.
+    pub async fn ssrcs(&self) -> Vec<u32> {
+        self.layers.read().await.iter().map(|layer| layer.ssrc).collect()
+    }
+
+    /// Picks the highest layer allowed by `quality` whose measured bitrate
+    /// fits the subscriber's available bandwidth, falling back to the lowest.
+    async fn select_layer(&self, quality: SimulcastQuality, available_bitrate: u64) -> Option<String> {
+        let layers = self.layers.read().await;
+        let mut ranked: Vec<(&str, u64)> = layers
+            .iter()
+            .map(|layer| (layer.rid.as_str(), layer.rate.bitrate()))
+            .collect();
+        ranked.sort_by_key(|(_, bitrate)| *bitrate);
+
+        let max_index = quality.layer_index(ranked.len());
+        let mut selected = ranked.first()?.0;
+        for (rid, bitrate) in ranked.iter().take(max_index + 1) {
+            if *bitrate <= available_bitrate {
+                selected = rid;
+            }
+        }
+        Some(selected.to_string())
+    }
+
+    /// Reads RTP from one layer of the publisher and writes it to every
+    /// subscriber currently assigned to that layer, until the track ends.
+    /// Returns the number of layers still being received.
+    pub async fn forward(&self, track: Arc<TrackRemote>) -> usize {
+        let layer = self.add_layer(track.rid(), track.ssrc()).await;
+        info!("Forwarding {} track {} (rid '{}') from peer {} in room {}",
+            self.kind, self.track_id, layer.rid, self.publisher_id, self.room_id);
+
+        while let Ok((rtp, _)) = track.read_rtp().await {
+            layer.rate.record(rtp.marshal_size()).await;
+            layer.history.lock().await.push(&rtp);
+            if self.is_muted() {
+                continue;
+            }
+
+            if let (Some(id), Some(speakers)) = (self.audio_level_id, &self.speakers) {
+                // RFC 6464: voice activity bit followed by the level in -dBov
+                if let Some(&level) = rtp.header.get_extension(id).as_deref().and_then(|ext| ext.first()) {
+                    speakers.record(&self.publisher_id, level & 0x7F).await;
+                }
+            }
+
+            let keyframe = self.kind != RTPCodecType::Video
+                || is_keyframe(&self.codec.mime_type, &rtp.payload).unwrap_or(true);
+
+            let subscribers: Vec<(String, Arc<Subscriber>)> = self.subscribers
+                .read()
+                .await
+                .iter()
+                .map(|(peer_id, subscriber)| (peer_id.clone(), subscriber.clone()))
+                .collect();
+            for (peer_id, subscriber) in subscribers {
+                self.deliver(&peer_id, &subscriber, &layer.rid, &rtp, keyframe).await;
+            }
+        }
+
+        info!("Track {} (rid '{}') from peer {} ended", self.track_id, layer.rid, self.publisher_id);
+        self.remove_layer(&layer.rid).await
+    }
+
+    async fn deliver(&self, peer_id: &str, subscriber: &Subscriber, rid: &str, rtp: &RTPPacket, keyframe: bool) {
+        let mut state = subscriber.state.lock().await;
+        if state.paused {
+            return;
+        }
+
+        if state.last_selection.map_or(true, |at| at.elapsed() >= LAYER_SELECTION_INTERVAL) {
+            let available_bitrate = subscriber.downlink.per_stream_bitrate().await;
+            state.target_layer = self.select_layer(state.quality, available_bitrate).await;
+            state.last_selection = Some(Instant::now());
+        }
+
+        let switching = state.current_layer.as_deref() != Some(rid);
+        if switching && state.target_layer.as_deref() != Some(rid) {
+            return;
+        }
+
+        // Video dropped over the bandwidth limit resumes once the budget has
+        // refilled, at a keyframe the publisher sends anyway: asking for one
+        // would only add to what doesn't fit
+        if state.over_budget {
+            if !keyframe || !subscriber.downlink.is_budget_full().await {
+                return;
+            }
+            debug!("{} is back under its bandwidth limit for {}", peer_id, self.key());
+            state.over_budget = false;
+        }
+
+        if switching {
+            if !keyframe {
+                self.request_layer_keyframe(rid, false).await;
+            }
+            // Switching mid-GOP would leave the decoder without a reference frame
+            let simulcast = self.layers.read().await.len() > 1;
+            if simulcast && !keyframe {
+                return;
+            }
+            debug!("Switching {} to layer '{}' of {}", peer_id, rid, self.key());
+            state.current_layer = Some(rid.to_string());
+            state.rewriter.switch_source();
+        }
+
+        // Over the room's bandwidth limit the packet is dropped, and video
+        // holds off until it can restart cleanly
+        if !subscriber.downlink.try_spend(rtp.marshal_size()).await {
+            if self.kind == RTPCodecType::Video {
+                debug!("{} is over its bandwidth limit, dropping {}", peer_id, self.key());
+                state.over_budget = true;
+            }
+            return;
+        }
+        let packet = state.rewriter.rewrite(rtp, self.codec.clock_rate);
+        match subscriber.track.write_rtp(&packet).await {
+            Ok(bytes) => subscriber.downlink.record_sent(bytes).await,
+            Err(e) => debug!("Failed to forward RTP from {} to {}: {}", self.publisher_id, peer_id, e),
+        }
+    }
+
+    /// Handles a PLI (or FIR when `full_intra` is set) from `peer_id` by
+    /// asking the publisher for a keyframe on the layer that peer receives.
+    pub async fn request_keyframe(&self, peer_id: &str, full_intra: bool) {
+        let Some(subscriber) = self.subscribers.read().await.get(peer_id).cloned() else { return };
+        let rid = {
+            let state = subscriber.state.lock().await;
+            state.current_layer.clone().or_else(|| state.target_layer.clone())
+        };
+        if let Some(rid) = rid {
+            self.request_layer_keyframe(&rid, full_intra).await;
+        }
+    }
+
+    async fn request_layer_keyframe(&self, rid: &str, full_intra: bool) {
+        let Some(layer) = self.layer(rid).await else { return };
+        let Some(publisher) = self.publisher.upgrade() else { return };
+
+        let fir_sequence = {
+            let mut request = layer.keyframe_request.lock().await;
+            if request.0.map_or(false, |at| at.elapsed() < KEYFRAME_REQUEST_INTERVAL) {
+                return;
+            }
+            request.0 = Some(Instant::now());
+            request.1 = request.1.wrapping_add(1);
+            request.1
+        };
+
+        let packet: Box<dyn webrtc::rtcp::packet::Packet + Send + Sync> = if full_intra {
+            Box::new(FullIntraRequest {
+                sender_ssrc: 0,
+                media_ssrc: layer.ssrc,
+                fir: vec![FirEntry { ssrc: layer.ssrc, sequence_number: fir_sequence }],
+            })
+        } else {
+            Box::new(PictureLossIndication { sender_ssrc: 0, media_ssrc: layer.ssrc })
+        };
+        debug!("Requesting keyframe for layer '{}' of {}", rid, self.key());
+        if let Err(e) = publisher.write_rtcp(&[packet]).await {
+            debug!("Failed to send keyframe request to {}: {}", self.publisher_id, e);
+        }
+    }
+
+    /// Answers a NACK from `peer_id` out of the packet history. Packets that
+    /// are no longer buffered are NACKed to the publisher instead.
+    pub async fn retransmit(&self, peer_id: &str, sequence_numbers: &[u16]) {
+        let Some(subscriber) = self.subscribers.read().await.get(peer_id).cloned() else { return };
+        let state = subscriber.state.lock().await;
+        let Some(rid) = state.current_layer.clone() else { return };
+        let Some(layer) = self.layer(&rid).await else { return };
+
+        let mut missing = Vec::new();
+        {
+            let history = layer.history.lock().await;
+            for &sequence_number in sequence_numbers {
+                let Some(original) = state.rewriter.original_sequence(sequence_number) else { continue };
+                match history.get(original) {
+                    Some(packet) if !subscriber.downlink.try_spend(packet.marshal_size()).await => {
+                        debug!("Not retransmitting to {} over its bandwidth limit", peer_id);
+                    }
+                    Some(packet) => {
+                        let packet = state.rewriter.apply(packet);
+                        if let Err(e) = subscriber.track.write_rtp(&packet).await {
+                            debug!("Failed to retransmit RTP to {}: {}", peer_id, e);
+                        }
+                    }
+                    None => missing.push(original),
+                }
+            }
+        }
+        drop(state);
+
+        if missing.is_empty() {
+            return;
+        }
+        let Some(publisher) = self.publisher.upgrade() else { return };
+        let nack = TransportLayerNack {
+            sender_ssrc: 0,
+            media_ssrc: layer.ssrc,
+            nacks: nack_pairs_from_sequence_numbers(&missing),
+        };
+        if let Err(e) = publisher.write_rtcp(&[Box::new(nack)]).await {
+            debug!("Failed to forward NACK to {}: {}", self.publisher_id, e);
+        }
+    }
+}
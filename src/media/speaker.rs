@@ -0,0 +1,170 @@
+use crate::types::SignalingMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, Mutex};
+use log::debug;
+
+const UPDATE_INTERVAL: Duration = Duration::from_millis(300);
+// Weight of each new packet in the moving average (~50 audio packets/s)
+const SMOOTHING: f64 = 0.1;
+// Roughly -60 dBov; quieter participants are never dominant
+const SPEECH_THRESHOLD: f64 = 0.5;
+// A challenger has to be this much louder to take over, to avoid flapping
+const DOMINANCE_MARGIN: f64 = 0.1;
+// Peers that stop sending audio (muted or gone) are forgotten
+const STALE_AFTER: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+struct SpeakerLevel {
+    level: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct SpeakerState {
+    levels: HashMap<String, SpeakerLevel>,
+    dominant: Option<String>,
+    // Peers that have been dominant, most recent first
+    history: Vec<String>,
+    last_sent: HashMap<String, f32>,
+}
+
+/// Tracks a smoothed audio level per participant from the `ssrc-audio-level`
+/// header extension and announces the room's dominant speaker.
+#[derive(Debug)]
+pub struct ActiveSpeakerDetector {
+    room_id: String,
+    state: Mutex<SpeakerState>,
+    events: broadcast::Sender<SignalingMessage>,
+    recent: watch::Sender<Vec<String>>,
+}
+
+impl ActiveSpeakerDetector {
+    /// Creates the detector and starts its evaluation loop, which stops once
+    /// the detector is dropped.
+    pub fn new(room_id: &str, events: broadcast::Sender<SignalingMessage>) -> Arc<Self> {
+        let detector = Arc::new(Self {
+            room_id: room_id.to_string(),
+            state: Mutex::new(SpeakerState::default()),
+            events,
+            recent: watch::channel(Vec::new()).0,
+        });
+        tokio::spawn(Self::run(Arc::downgrade(&detector)));
+        detector
+    }
+
+    async fn run(detector: Weak<Self>) {
+        loop {
+            tokio::time::sleep(UPDATE_INTERVAL).await;
+            let Some(detector) = detector.upgrade() else { break };
+            detector.evaluate().await;
+        }
+    }
+
+    /// Records one packet's audio level in -dBov (0 is loudest, 127 silence).
+    pub async fn record(&self, peer_id: &str, level_dbov: u8) {
+        let loudness = 1.0 - (level_dbov.min(127) as f64 / 127.0);
+        let mut state = self.state.lock().await;
+        let entry = state.levels.entry(peer_id.to_string()).or_insert(SpeakerLevel {
+            level: loudness,
+            updated: Instant::now(),
+        });
+        entry.level += SMOOTHING * (loudness - entry.level);
+        entry.updated = Instant::now();
+    }
+
+    pub async fn remove_peer(&self, peer_id: &str) {
+        let mut state = self.state.lock().await;
+        state.levels.remove(peer_id);
+        if state.dominant.as_deref() == Some(peer_id) {
+            state.dominant = None;
+        }
+        if state.history.iter().any(|speaker| speaker == peer_id) {
+            state.history.retain(|speaker| speaker != peer_id);
+            self.recent.send_replace(state.history.clone());
+        }
+    }
+
+    /// Peers that have been the dominant speaker, most recent first.
+    pub async fn recent_speakers(&self) -> Vec<String> {
+        self.state.lock().await.history.clone()
+    }
+
+    /// Notified whenever the order of recent speakers changes.
+    pub fn watch_recent_speakers(&self) -> watch::Receiver<Vec<String>> {
+        self.recent.subscribe()
+    }
+
+    pub async fn dominant_speaker(&self) -> Option<String> {
+        self.state.lock().await.dominant.clone()
+    }
+
+    /// Smoothed level per participant, from 0.0 (silent) to 1.0.
+    pub async fn levels(&self) -> HashMap<String, f32> {
+        self.state
+            .lock()
+            .await
+            .levels
+            .iter()
+            .map(|(peer_id, speaker)| (peer_id.clone(), speaker.level as f32))
+            .collect()
+    }
+
+    async fn evaluate(&self) {
+        let mut state = self.state.lock().await;
+        state.levels.retain(|_, speaker| speaker.updated.elapsed() < STALE_AFTER);
+        if state.dominant.as_ref().map_or(false, |peer_id| !state.levels.contains_key(peer_id)) {
+            state.dominant = None;
+        }
+
+        let loudest = state
+            .levels
+            .iter()
+            .filter(|(_, speaker)| speaker.level >= SPEECH_THRESHOLD)
+            .max_by(|a, b| a.1.level.total_cmp(&b.1.level))
+            .map(|(peer_id, speaker)| (peer_id.clone(), speaker.level));
+
+        if let Some((peer_id, level)) = loudest {
+            let current_level = state
+                .dominant
+                .as_ref()
+                .and_then(|dominant| state.levels.get(dominant))
+                .map(|speaker| speaker.level);
+            let takes_over = match current_level {
+                None => true,
+                Some(current) => current < SPEECH_THRESHOLD || level > current + DOMINANCE_MARGIN,
+            };
+            if takes_over && state.dominant.as_ref() != Some(&peer_id) {
+                debug!("Dominant speaker in room {} is now {}", self.room_id, peer_id);
+                state.dominant = Some(peer_id.clone());
+                state.history.retain(|speaker| speaker != &peer_id);
+                state.history.insert(0, peer_id.clone());
+                self.recent.send_replace(state.history.clone());
+                self.emit(SignalingMessage::ActiveSpeaker {
+                    room_id: self.room_id.clone(),
+                    peer_id,
+                });
+            }
+        }
+
+        // Quantized so silence doesn't produce a stream of identical updates
+        let levels: HashMap<String, f32> = state
+            .levels
+            .iter()
+            .map(|(peer_id, speaker)| (peer_id.clone(), (speaker.level * 100.0).round() as f32 / 100.0))
+            .collect();
+        if !levels.is_empty() && levels != state.last_sent {
+            state.last_sent = levels.clone();
+            self.emit(SignalingMessage::AudioLevels {
+                room_id: self.room_id.clone(),
+                levels,
+            });
+        }
+    }
+
+    fn emit(&self, message: SignalingMessage) {
+        // No receivers just means nobody is listening for room events yet
+        let _ = self.events.send(message);
+    }
+}
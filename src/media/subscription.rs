@@ -0,0 +1,60 @@
+use crate::room::state::MediaType;
+use std::collections::HashMap;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+
+/// Which published tracks a peer wants to receive. Peers get everything
+/// until they say otherwise; per-publisher choices override the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionFilter {
+    defaults: HashMap<MediaType, bool>,
+    overrides: HashMap<(String, MediaType), bool>,
+}
+
+impl SubscriptionFilter {
+    /// Screen shares travel as video tracks, so they are filtered as video.
+    fn normalize(media_type: &MediaType) -> MediaType {
+        match media_type {
+            MediaType::Audio => MediaType::Audio,
+            MediaType::Video | MediaType::Screen => MediaType::Video,
+        }
+    }
+
+    fn media_type_of(kind: RTPCodecType) -> MediaType {
+        match kind {
+            RTPCodecType::Audio => MediaType::Audio,
+            _ => MediaType::Video,
+        }
+    }
+
+    /// Records whether `publisher_id`'s tracks of `media_types` are wanted.
+    /// Without a publisher the choice applies to everyone, including peers
+    /// that publish later. An empty `media_types` means audio and video.
+    pub fn set(&mut self, publisher_id: Option<&str>, media_types: &[MediaType], wanted: bool) {
+        let media_types: Vec<MediaType> = if media_types.is_empty() {
+            vec![MediaType::Audio, MediaType::Video]
+        } else {
+            media_types.iter().map(Self::normalize).collect()
+        };
+
+        for media_type in media_types {
+            match publisher_id {
+                Some(publisher_id) => {
+                    self.overrides.insert((publisher_id.to_string(), media_type), wanted);
+                }
+                None => {
+                    self.overrides.retain(|(_, overridden), _| overridden != &media_type);
+                    self.defaults.insert(media_type, wanted);
+                }
+            }
+        }
+    }
+
+    pub fn wants(&self, publisher_id: &str, kind: RTPCodecType) -> bool {
+        let media_type = Self::media_type_of(kind);
+        self.overrides
+            .get(&(publisher_id.to_string(), media_type.clone()))
+            .or_else(|| self.defaults.get(&media_type))
+            .copied()
+            .unwrap_or(true)
+    }
+}
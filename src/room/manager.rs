@@ -1,119 +1,99 @@
-use super::state::Room;
+use super::state::{LobbyEntry, Room, MediaSettings, Role};
 use crate::utils::{Error, Result};
-use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use crate::signaling::PeerConnection;
-use crate::media::MediaRelay;
-use webrtc::peer_connection::RTCPeerConnection;
-use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
-use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
-use webrtc::api::APIBuilder;
-use webrtc::api::media_engine::MediaEngine;
-use webrtc::peer_connection::configuration::RTCConfiguration;
-use webrtc::ice_transport::ice_server::RTCIceServer;
-use crate::media::MediaRelayManager;
+use crate::media::{MediaRelay, MediaRelayManager, RelayMode};
 
+/// Room lifecycle on top of the room-keyed relay registry in `MediaRelayManager`.
 pub struct RoomManager {
-    rooms: Arc<RwLock<HashMap<String, Room>>>,
     relay_manager: Arc<MediaRelayManager>,
 }
 
 impl RoomManager {
-    pub fn new(
-        stun_server: String,
-        stun_port: u16,
-        turn_server: String,
-        turn_port: u16,
-        turn_username: String,
-        turn_password: String,
-    ) -> Self {
-        Self {
-            rooms: Arc::new(RwLock::new(HashMap::new())),
-            relay_manager: Arc::new(MediaRelayManager::new(
-                stun_server,
-                stun_port,
-                turn_server,
-                turn_port,
-                turn_username,
-                turn_password,
-            )),
-        }
+    pub fn new(relay_manager: Arc<MediaRelayManager>) -> Self {
+        Self { relay_manager }
+    }
+
+    pub fn relay_manager(&self) -> Arc<MediaRelayManager> {
+        self.relay_manager.clone()
     }
 
     pub async fn create_room(&self, room_id: String) -> Result<Room> {
-        let mut rooms = self.rooms.write().await;
-        if rooms.contains_key(&room_id) {
-            return Err(Error::Room(format!("Room {} already exists", room_id)));
-        }
+        self.relay_manager.create_room(&room_id, MediaSettings::default()).await
+    }
 
-        let room = Room {
-            id: room_id.clone(),
-            ..Room::default()
-        };
-        rooms.insert(room_id, room.clone());
-        Ok(room)
+    pub async fn create_room_with_settings(&self, room_id: String, media_settings: MediaSettings) -> Result<Room> {
+        self.relay_manager.create_room(&room_id, media_settings).await
     }
 
     pub async fn get_room(&self, room_id: &str) -> Result<Room> {
-        let rooms = self.rooms.read().await;
-        rooms
-            .get(room_id)
-            .cloned()
-            .ok_or_else(|| Error::Room(format!("Room {} not found", room_id)))
+        self.relay_manager
+            .get_room(room_id)
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Room {} not found", room_id)))
+    }
+
+    pub async fn update_media_settings(&self, room_id: &str, media_settings: MediaSettings) -> Result<Room> {
+        self.relay_manager.update_media_settings(room_id, media_settings).await
+    }
+
+    pub async fn set_role(&self, room_id: &str, peer_id: &str, role: Role) -> Result<()> {
+        self.relay_manager.set_role(room_id, peer_id, role).await
+    }
+
+    pub async fn get_role(&self, room_id: &str, peer_id: &str) -> Option<Role> {
+        self.relay_manager.get_role(room_id, peer_id).await
+    }
+
+    pub async fn update_room<T>(&self, room_id: &str, change: impl FnOnce(&mut Room) -> T) -> Result<T> {
+        self.relay_manager.update_room(room_id, change).await
+    }
+
+    pub async fn set_lobby_enabled(&self, room_id: &str, enabled: bool) -> Result<()> {
+        self.relay_manager.set_lobby_enabled(room_id, enabled).await
+    }
+
+    /// Parks a peer in the room's lobby, if the room has one enabled.
+    pub async fn park_in_lobby(&self, room_id: &str, entry: LobbyEntry) -> bool {
+        self.relay_manager.park_in_lobby(room_id, entry).await
+    }
+
+    pub async fn leave_lobby(&self, room_id: &str, peer_id: &str) -> Option<LobbyEntry> {
+        self.relay_manager.leave_lobby(room_id, peer_id).await
+    }
+
+    pub async fn list_rooms(&self) -> Vec<Room> {
+        self.relay_manager.get_rooms().await.into_values().collect()
     }
 
     pub async fn remove_room(&self, room_id: &str) -> Result<()> {
-        let mut rooms = self.rooms.write().await;
-        rooms
-            .remove(room_id)
-            .ok_or_else(|| Error::Room(format!("Room {} not found", room_id)))?;
-        Ok(())
-    }
-
-    pub async fn add_peer_to_room(
-        &self,
-        room_id: &str,
-        peer_id: String,
-        peer_connection: PeerConnection,
-    ) -> Result<()> {
-        let mut rooms = self.rooms.write().await;
-        let room = rooms
-            .get_mut(room_id)
-            .ok_or_else(|| Error::Room(format!("Room {} not found", room_id)))?;
-
-        if room.peers.len() >= room.media_settings.max_participants {
-            return Err(Error::Room("Room is full".to_string()));
-        }
-
-        // Create the relay through MediaRelayManager
-        let relay = self.relay_manager.create_relay(peer_id.clone()).await?;
-        
-        room.peers.push((peer_id, relay));
-        Ok(())
+        self.relay_manager.remove_room(room_id).await
     }
 
-    pub async fn remove_peer_from_room(&self, room_id: &str, peer_id: &str) -> Result<()> {
-        let mut rooms = self.rooms.write().await;
-        let room = rooms
-            .get_mut(room_id)
-            .ok_or_else(|| Error::Room(format!("Room {} not found", room_id)))?;
+    /// Adds a peer to a room, creating the room with default settings if it
+    /// does not exist yet. Fails when the room is full.
+    pub async fn add_peer_to_room(&self, room_id: &str, peer_id: String) -> Result<MediaRelay> {
+        self.relay_manager.create_relay(room_id, peer_id).await
+    }
+
+    /// Adds a peer that negotiates over HTTP (WHIP/WHEP) rather than the
+    /// signaling socket, or that has a role other than the default one.
+    pub async fn add_peer_with_mode(&self, room_id: &str, peer_id: String, mode: RelayMode, role: Option<Role>) -> Result<MediaRelay> {
+        self.relay_manager.create_relay_with_mode(room_id, peer_id, mode, role).await
+    }
 
-        room.peers.retain(|(id, _)| id != peer_id);
-        Ok(())
+    pub async fn remove_peer_from_room(&self, room_id: &str, peer_id: &str) -> Result<()> {
+        self.relay_manager.handle_peer_disconnect(peer_id, room_id).await
     }
 
-    pub async fn add_peer(&mut self, room_id: &str, peer_id: String) -> Result<()> {
-        let mut rooms = self.rooms.write().await;
-        let room = rooms
-            .get_mut(room_id)
-            .ok_or_else(|| Error::Room(format!("Room {} not found", room_id)))?;
+    pub async fn get_room_peers(&self, room_id: &str) -> Vec<String> {
+        self.relay_manager.get_room_peers(room_id).await
+    }
 
-        // Create the media relay through MediaRelayManager
-        let relay = self.relay_manager.create_relay(peer_id.clone()).await?;
-        
-        // Add to room
-        room.peers.push((peer_id, relay));
-        Ok(())
+    pub async fn has_peer(&self, room_id: &str, peer_id: &str) -> bool {
+        self.relay_manager
+            .get_room(room_id)
+            .await
+            .map(|room| room.has_peer(peer_id))
+            .unwrap_or(false)
     }
-} 
\ No newline at end of file
+}
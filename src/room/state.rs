@@ -1,42 +1,234 @@
 use crate::signaling::PeerConnection;
-use crate::media::MediaRelay;
+use crate::media::{ActiveSpeakerDetector, MediaRelay, RelayMode, TrackSource};
 use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use uuid::Uuid;
 use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use crate::utils::Error;
+use log::warn;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MediaSettings {
     pub max_participants: usize,
     pub allowed_media_types: Vec<MediaType>,
+    /// Video codecs the room negotiates, in order of preference
+    pub video_codecs: Vec<VideoCodec>,
+    /// Per-participant cap in bps, applied both to what a peer may publish
+    /// and to what the relay forwards to it
     pub bandwidth_limit: Option<u32>,
+    /// When set, each subscriber only receives video from this many of the
+    /// most recent active speakers plus the peers it has pinned
+    pub last_n: Option<usize>,
+}
+
+impl MediaSettings {
+    pub fn allows(&self, media_type: &MediaType) -> bool {
+        self.allowed_media_types.contains(media_type)
+    }
+
+    /// Fails for settings no room could work with, such as a room that
+    /// nobody may join or that negotiates no video codec.
+    pub fn validate(&self) -> Result<(), Error> {
+        let problem = if self.max_participants == 0 {
+            "max_participants must be at least 1"
+        } else if self.allowed_media_types.is_empty() {
+            "allowed_media_types must not be empty"
+        } else if self.video_codecs.is_empty() {
+            "video_codecs must not be empty"
+        } else if self.bandwidth_limit == Some(0) {
+            "bandwidth_limit must be positive, or null for none"
+        } else {
+            return Ok(());
+        };
+        Err(Error::InvalidMessage(problem.to_string()))
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum MediaType {
     Audio,
     Video,
     Screen,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VideoCodec {
+    VP8,
+    VP9,
+    H264,
+    AV1,
+}
+
+/// What a participant may do in a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Send media to the room
+    Publish,
+    /// Receive the room's media
+    Subscribe,
+    /// Kick and mute others, and change their roles
+    Moderate,
+    /// Start recording the room
+    Record,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Host,
+    Moderator,
+    Speaker,
+    Viewer,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Host | Role::Moderator => &[Publish, Subscribe, Moderate, Record],
+            Role::Speaker => &[Publish, Subscribe],
+            Role::Viewer => &[Subscribe],
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+/// A peer waiting in a room's lobby for a moderator to admit it.
+#[derive(Debug, Clone, Serialize)]
+pub struct LobbyEntry {
+    pub peer_id: String,
+    /// What the peer told us about itself, such as a display name
+    pub metadata: HashMap<String, String>,
+    /// Role from the peer's token, applied once admitted
+    pub role: Option<Role>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Room {
     pub id: String,
     pub peers: Vec<(String, MediaRelay)>,
+    pub roles: HashMap<String, Role>,
+    /// Whether joining peers wait in `lobby` until a moderator admits them
+    pub lobby_enabled: bool,
+    pub lobby: Vec<LobbyEntry>,
+    /// Whether the room refuses further joins
+    pub locked: bool,
+    /// Argon2 hash of the password joining peers must give
+    pub password_hash: Option<String>,
+    pub banned_peers: HashSet<String>,
+    pub banned_ips: HashSet<IpAddr>,
     pub media_settings: MediaSettings,
     pub recording_enabled: bool,
+    pub speakers: Option<Arc<ActiveSpeakerDetector>>,
 }
 
 impl Room {
-    pub fn add_peer(&mut self, peer_id: String, relay: MediaRelay) -> Result<(), Error> {
+    /// Adds a peer with `role`, or without one the default role for how it
+    /// connects: WHEP viewers watch and everyone else speaks. Hosts come
+    /// only from a token's role or the admin API.
+    pub fn add_peer(&mut self, peer_id: String, relay: MediaRelay, role: Option<Role>) -> Result<(), Error> {
         if self.peers.len() >= self.media_settings.max_participants {
             return Err(Error::Room("Room is full".to_string()));
         }
+        let role = role.unwrap_or_else(|| match relay.mode {
+            RelayMode::Egress => Role::Viewer,
+            _ => Role::Speaker,
+        });
+        self.roles.insert(peer_id.clone(), role);
         self.peers.push((peer_id, relay));
         Ok(())
     }
 
+    pub fn role_of(&self, peer_id: &str) -> Option<Role> {
+        self.roles.get(peer_id).copied()
+    }
+
+    pub fn set_role(&mut self, peer_id: &str, role: Role) -> Result<(), Error> {
+        let current = self.roles
+            .get_mut(peer_id)
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, self.id)))?;
+        *current = role;
+        Ok(())
+    }
+
+    /// Parks a peer in the lobby if the room has one, replacing any earlier
+    /// request of the same peer. Returns whether the peer has to wait.
+    pub fn park_in_lobby(&mut self, entry: LobbyEntry) -> bool {
+        if !self.lobby_enabled {
+            return false;
+        }
+        self.lobby.retain(|waiting| waiting.peer_id != entry.peer_id);
+        self.lobby.push(entry);
+        true
+    }
+
+    pub fn leave_lobby(&mut self, peer_id: &str) -> Option<LobbyEntry> {
+        let index = self.lobby.iter().position(|waiting| waiting.peer_id == peer_id)?;
+        Some(self.lobby.remove(index))
+    }
+
+    /// Sets or, with `None`, removes the room's password.
+    pub fn set_password(&mut self, password: Option<&str>) -> Result<(), Error> {
+        self.password_hash = match password {
+            Some(password) => {
+                let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())
+                    .map_err(|e| Error::Room(format!("Could not hash password: {}", e)))?;
+                let hash = Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|e| Error::Room(format!("Could not hash password: {}", e)))?;
+                Some(hash.to_string())
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: Option<&str>) -> bool {
+        let Some(hash) = &self.password_hash else { return true };
+        match (password, PasswordHash::new(hash)) {
+            (Some(password), Ok(hash)) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Checks whether a peer may join, explaining why not. Bans apply to
+    /// everyone; moderators get past the lock and the password.
+    pub fn check_admission(&self, peer_id: &str, ip: Option<IpAddr>, password: Option<&str>, moderator: bool) -> Result<(), Error> {
+        self.check_entry(peer_id, ip, moderator)?;
+        if moderator {
+            return Ok(());
+        }
+        if !self.check_password(password) {
+            let reason = match password {
+                Some(_) => format!("Wrong password for room {}", self.id),
+                None => format!("Room {} requires a password", self.id),
+            };
+            return Err(Error::Unauthorized(reason));
+        }
+        Ok(())
+    }
+
+    /// The bans and the lock of `check_admission`, for peers whose password
+    /// was already checked, such as those waiting in the lobby.
+    pub fn check_entry(&self, peer_id: &str, ip: Option<IpAddr>, moderator: bool) -> Result<(), Error> {
+        if self.banned_peers.contains(peer_id) || ip.is_some_and(|ip| self.banned_ips.contains(&ip)) {
+            return Err(Error::Unauthorized(format!("You are banned from room {}", self.id)));
+        }
+        if !moderator && self.locked {
+            return Err(Error::Unauthorized(format!("Room {} is locked", self.id)));
+        }
+        Ok(())
+    }
+
     pub fn get_peer_relay(&self, peer_id: &str) -> Option<&MediaRelay> {
         self.peers.iter()
             .find(|(id, _)| id == peer_id)
@@ -45,17 +237,87 @@ impl Room {
 
     pub fn remove_peer(&mut self, peer_id: &str) {
         self.peers.retain(|(id, _)| id != peer_id);
+        self.roles.remove(peer_id);
+    }
+
+    /// Unsubscribes every peer from `source`, which is no longer published.
+    pub async fn withdraw_track(&self, source: &Arc<TrackSource>) {
+        for (peer_id, relay) in &self.peers {
+            if let Err(e) = relay.unsubscribe(source).await {
+                warn!("Failed to unsubscribe {} from {}: {}", peer_id, source.key(), e);
+            }
+        }
+        self.apply_last_n().await;
     }
 
-    pub async fn broadcast_track(&self, from_peer: &str, track: Arc<TrackLocalStaticRTP>) -> Result<(), Error> {
+    /// Subscribes every peer except the publisher to `source`. A failing
+    /// subscriber does not prevent the others from receiving the track.
+    pub async fn broadcast_track(&self, source: &Arc<TrackSource>) -> Result<(), Error> {
         for (peer_id, relay) in &self.peers {
-            if peer_id != from_peer {
-                relay.peer_connection.add_track(track.clone()).await?;
+            if peer_id != &source.publisher_id {
+                if let Err(e) = relay.subscribe(source).await {
+                    warn!("Failed to subscribe {} to {}: {}", peer_id, source.key(), e);
+                }
             }
         }
         Ok(())
     }
 
+    /// Pauses or resumes video forwarding to each peer according to the
+    /// room's last-N policy: recent speakers first, then peers in join order,
+    /// with pinned peers always forwarded. Only peers publishing video are
+    /// ranked, so viewers and audio-only peers don't take up a slot.
+    pub async fn apply_last_n(&self) {
+        let mut publishers = Vec::new();
+        for (peer_id, relay) in &self.peers {
+            if relay.publishes_video().await {
+                publishers.push(peer_id.as_str());
+            }
+        }
+        let recent = match &self.speakers {
+            Some(speakers) => speakers.recent_speakers().await,
+            None => Vec::new(),
+        };
+        let mut ranking: Vec<&str> = recent
+            .iter()
+            .map(String::as_str)
+            .filter(|speaker| publishers.contains(speaker))
+            .collect();
+        for peer_id in publishers {
+            if !ranking.contains(&peer_id) {
+                ranking.push(peer_id);
+            }
+        }
+
+        for (peer_id, relay) in &self.peers {
+            let forwarded = match self.media_settings.last_n {
+                Some(n) => {
+                    let mut forwarded: HashSet<String> = ranking
+                        .iter()
+                        .filter(|publisher| **publisher != peer_id.as_str())
+                        .take(n)
+                        .map(|publisher| publisher.to_string())
+                        .collect();
+                    forwarded.extend(relay.pinned_peers().await);
+                    Some(forwarded)
+                }
+                None => None,
+            };
+            relay.set_forwarded_publishers(forwarded.as_ref()).await;
+        }
+    }
+
+    /// Whether nothing is left worth keeping the room for: no peers, nobody
+    /// waiting, and no access rules that would be lost with it.
+    pub fn is_disposable(&self) -> bool {
+        self.peers.is_empty()
+            && self.lobby.is_empty()
+            && !self.locked
+            && self.password_hash.is_none()
+            && self.banned_peers.is_empty()
+            && self.banned_ips.is_empty()
+    }
+
     pub fn has_peer(&self, peer_id: &str) -> bool {
         self.peers.iter().any(|(id, _)| id == peer_id)
     }
@@ -65,8 +327,10 @@ impl Default for MediaSettings {
     fn default() -> Self {
         Self {
             max_participants: 10,
-            allowed_media_types: vec![MediaType::Audio],
+            allowed_media_types: vec![MediaType::Audio, MediaType::Video],
+            video_codecs: vec![VideoCodec::VP8, VideoCodec::VP9, VideoCodec::H264, VideoCodec::AV1],
             bandwidth_limit: None,
+            last_n: None,
         }
     }
 }
@@ -76,8 +340,16 @@ impl Default for Room {
         Self {
             id: String::new(),
             peers: Vec::new(),
+            roles: HashMap::new(),
+            lobby_enabled: false,
+            lobby: Vec::new(),
+            locked: false,
+            password_hash: None,
+            banned_peers: HashSet::new(),
+            banned_ips: HashSet::new(),
             media_settings: MediaSettings::default(),
             recording_enabled: false,
+            speakers: None,
         }
     }
 } 
\ No newline at end of file
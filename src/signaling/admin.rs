@@ -0,0 +1,403 @@
+use crate::media::RelayMode;
+use crate::room::state::{LobbyEntry, MediaSettings, Role, Room};
+use crate::signaling::handler::MessageHandler;
+use crate::utils::Error;
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use std::net::IpAddr;
+use std::sync::Arc;
+use warp::http::{header, StatusCode};
+use warp::reply::Response;
+use warp::{reject, Filter, Rejection, Reply};
+
+const KICK_REASON: &str = "Removed from the room by a moderator";
+const CLOSE_REASON: &str = "The room was closed by a moderator";
+const BAN_REASON: &str = "Banned from the room by a moderator";
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl reject::Reject for Unauthorized {}
+
+#[derive(Serialize)]
+struct RoomSummary {
+    id: String,
+    media_settings: MediaSettings,
+    recording_enabled: bool,
+    lobby_enabled: bool,
+    locked: bool,
+    password_protected: bool,
+    banned_peers: Vec<String>,
+    banned_ips: Vec<IpAddr>,
+    participants: Vec<ParticipantSummary>,
+    lobby: Vec<LobbyEntry>,
+}
+
+#[derive(Serialize)]
+struct ParticipantSummary {
+    peer_id: String,
+    mode: RelayMode,
+    role: Option<Role>,
+    connection_state: String,
+    tracks: Vec<TrackSummary>,
+    subscriptions: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct TrackSummary {
+    track_id: String,
+    kind: String,
+    muted: bool,
+    subscribers: usize,
+}
+
+#[derive(Deserialize)]
+struct MuteRequest {
+    /// Every track of the peer when omitted
+    #[serde(default)]
+    track_id: Option<String>,
+    #[serde(default = "default_muted")]
+    muted: bool,
+}
+
+fn default_muted() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct RoleRequest {
+    role: Role,
+}
+
+/// Changes to who may join a room. Omitted fields are left alone.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessRequest {
+    #[serde(default)]
+    locked: Option<bool>,
+    /// `null` removes the password
+    #[serde(default, deserialize_with = "present")]
+    password: Option<Option<String>>,
+    #[serde(default)]
+    ban_peers: Vec<String>,
+    #[serde(default)]
+    unban_peers: Vec<String>,
+    #[serde(default)]
+    ban_ips: Vec<IpAddr>,
+    #[serde(default)]
+    unban_ips: Vec<IpAddr>,
+}
+
+/// Tells a field given as `null` apart from an omitted one.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Admin API for support staff, authenticated with a bearer token:
+///
+/// - `GET /admin/rooms` and `GET /admin/rooms/{room_id}`: rooms and participants
+/// - `DELETE /admin/rooms/{room_id}`: close a room
+/// - `PATCH /admin/rooms/{room_id}/settings`: change `MediaSettings`
+/// - `PATCH /admin/rooms/{room_id}/access`: lock the room, set its password, ban peers or IPs
+/// - `DELETE /admin/rooms/{room_id}/peers/{peer_id}`: kick a peer
+/// - `POST /admin/rooms/{room_id}/peers/{peer_id}/mute`: force-mute tracks
+/// - `PUT /admin/rooms/{room_id}/peers/{peer_id}/role`: change a peer's role
+///
+/// Every request is refused when no token is configured.
+pub fn routes(
+    handler: Arc<MessageHandler>,
+    admin_token: Option<String>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let admin = warp::path("admin").and(authorized(admin_token));
+
+    let list_rooms = admin.clone()
+        .and(warp::path!("rooms"))
+        .and(warp::get())
+        .and(with_handler(handler.clone()))
+        .then(list_rooms);
+
+    let get_room = admin.clone()
+        .and(warp::path!("rooms" / String))
+        .and(warp::get())
+        .and(with_handler(handler.clone()))
+        .then(get_room);
+
+    let close_room = admin.clone()
+        .and(warp::path!("rooms" / String))
+        .and(warp::delete())
+        .and(with_handler(handler.clone()))
+        .then(close_room);
+
+    let update_settings = admin.clone()
+        .and(warp::path!("rooms" / String / "settings"))
+        .and(warp::patch())
+        .and(with_handler(handler.clone()))
+        .and(warp::body::json())
+        .then(update_settings);
+
+    let update_access = admin.clone()
+        .and(warp::path!("rooms" / String / "access"))
+        .and(warp::patch())
+        .and(with_handler(handler.clone()))
+        .and(warp::body::json())
+        .then(update_access);
+
+    let kick_peer = admin.clone()
+        .and(warp::path!("rooms" / String / "peers" / String))
+        .and(warp::delete())
+        .and(with_handler(handler.clone()))
+        .then(kick_peer);
+
+    let mute_peer = admin.clone()
+        .and(warp::path!("rooms" / String / "peers" / String / "mute"))
+        .and(warp::post())
+        .and(with_handler(handler.clone()))
+        .and(warp::body::json())
+        .then(mute_peer);
+
+    let set_role = admin
+        .and(warp::path!("rooms" / String / "peers" / String / "role"))
+        .and(warp::put())
+        .and(with_handler(handler))
+        .and(warp::body::json())
+        .then(set_role);
+
+    list_rooms
+        .or(get_room).unify()
+        .or(close_room).unify()
+        .or(update_settings).unify()
+        .or(update_access).unify()
+        .or(kick_peer).unify()
+        .or(mute_peer).unify()
+        .or(set_role).unify()
+        .recover(handle_rejection)
+        .unify()
+}
+
+fn with_handler(
+    handler: Arc<MessageHandler>,
+) -> impl Filter<Extract = (Arc<MessageHandler>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || handler.clone())
+}
+
+fn authorized(admin_token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                let presented = authorization.as_deref().and_then(|value| value.strip_prefix("Bearer "));
+                match (admin_token, presented) {
+                    (Some(expected), Some(presented)) if constant_time_eq(expected.as_bytes(), presented.trim().as_bytes()) => Ok(()),
+                    _ => Err(reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn handle_rejection(err: Rejection) -> Result<Response, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        let reply = warp::reply::with_header(
+            error_body("Missing or invalid admin token"),
+            header::WWW_AUTHENTICATE,
+            "Bearer",
+        );
+        return Ok(warp::reply::with_status(reply, StatusCode::UNAUTHORIZED).into_response());
+    }
+    if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string()));
+    }
+    Err(err)
+}
+
+async fn list_rooms(handler: Arc<MessageHandler>) -> Response {
+    let mut rooms = handler.room_manager().list_rooms().await;
+    rooms.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut summaries = Vec::new();
+    for room in &rooms {
+        summaries.push(summarize(room).await);
+    }
+    warp::reply::json(&summaries).into_response()
+}
+
+async fn get_room(room_id: String, handler: Arc<MessageHandler>) -> Response {
+    match handler.room_manager().get_room(&room_id).await {
+        Ok(room) => warp::reply::json(&summarize(&room).await).into_response(),
+        Err(e) => failure(e),
+    }
+}
+
+async fn close_room(room_id: String, handler: Arc<MessageHandler>) -> Response {
+    match handler.close_room(&room_id, CLOSE_REASON).await {
+        Ok(()) => {
+            info!("Admin closed room {}", room_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => failure(e),
+    }
+}
+
+/// Merges the fields present in the body into the room's current settings;
+/// `null` clears optional ones such as `bandwidth_limit`.
+async fn update_settings(room_id: String, handler: Arc<MessageHandler>, changes: serde_json::Value) -> Response {
+    let room_manager = handler.room_manager();
+    let room = match room_manager.get_room(&room_id).await {
+        Ok(room) => room,
+        Err(e) => return failure(e),
+    };
+    let Some(changes) = changes.as_object() else {
+        return error_response(StatusCode::BAD_REQUEST, "Expected a JSON object");
+    };
+
+    let mut settings = json!(room.media_settings);
+    if let Some(current) = settings.as_object_mut() {
+        for (field, value) in changes {
+            if !current.contains_key(field) {
+                return error_response(StatusCode::BAD_REQUEST, &format!("Unknown setting '{}'", field));
+            }
+            current.insert(field.clone(), value.clone());
+        }
+    }
+    let settings: MediaSettings = match serde_json::from_value(settings) {
+        Ok(settings) => settings,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    if let Err(e) = settings.validate() {
+        return error_response(StatusCode::BAD_REQUEST, &e.to_string());
+    }
+
+    match room_manager.update_media_settings(&room_id, settings).await {
+        Ok(room) => {
+            info!("Admin updated media settings of room {}", room_id);
+            warp::reply::json(&summarize(&room).await).into_response()
+        }
+        Err(e) => failure(e),
+    }
+}
+
+async fn update_access(room_id: String, handler: Arc<MessageHandler>, request: AccessRequest) -> Response {
+    let result = async {
+        if let Some(locked) = request.locked {
+            handler.set_room_locked(&room_id, locked).await?;
+        }
+        if let Some(password) = &request.password {
+            handler.set_room_password(&room_id, password.as_deref()).await?;
+        }
+        for peer_id in &request.unban_peers {
+            handler.unban_peer(&room_id, peer_id).await?;
+        }
+        for ip in &request.unban_ips {
+            handler.unban_address(&room_id, *ip).await?;
+        }
+        for peer_id in &request.ban_peers {
+            handler.ban_peer(&room_id, peer_id, false, BAN_REASON).await?;
+        }
+        for ip in &request.ban_ips {
+            handler.ban_address(&room_id, *ip, BAN_REASON).await?;
+        }
+        handler.room_manager().get_room(&room_id).await
+    }.await;
+
+    match result {
+        Ok(room) => {
+            info!("Admin updated access rules of room {}", room_id);
+            warp::reply::json(&summarize(&room).await).into_response()
+        }
+        Err(e) => failure(e),
+    }
+}
+
+async fn kick_peer(room_id: String, peer_id: String, handler: Arc<MessageHandler>) -> Response {
+    match handler.kick_peer(&room_id, &peer_id, KICK_REASON).await {
+        Ok(()) => {
+            info!("Admin removed peer {} from room {}", peer_id, room_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => failure(e),
+    }
+}
+
+async fn mute_peer(room_id: String, peer_id: String, handler: Arc<MessageHandler>, request: MuteRequest) -> Response {
+    match handler.set_track_muted(&room_id, &peer_id, request.track_id.as_deref(), request.muted).await {
+        Ok(changed) => {
+            info!("Admin set muted={} on {:?} of peer {} in room {}", request.muted, changed, peer_id, room_id);
+            warp::reply::json(&json!({ "changed": changed })).into_response()
+        }
+        Err(e) => failure(e),
+    }
+}
+
+async fn set_role(room_id: String, peer_id: String, handler: Arc<MessageHandler>, request: RoleRequest) -> Response {
+    match handler.set_role(&room_id, &peer_id, request.role).await {
+        Ok(()) => {
+            info!("Admin made peer {} {:?} in room {}", peer_id, request.role, room_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => failure(e),
+    }
+}
+
+async fn summarize(room: &Room) -> RoomSummary {
+    let mut participants = Vec::new();
+    for (peer_id, relay) in &room.peers {
+        let mut tracks = Vec::new();
+        for source in relay.published_sources().await {
+            tracks.push(TrackSummary {
+                track_id: source.track_id.clone(),
+                kind: source.kind.to_string(),
+                muted: source.is_muted(),
+                subscribers: source.subscriber_ids().await.len(),
+            });
+        }
+        participants.push(ParticipantSummary {
+            peer_id: peer_id.clone(),
+            mode: relay.mode,
+            role: room.role_of(peer_id),
+            connection_state: relay.peer_connection.connection_state().to_string(),
+            tracks,
+            subscriptions: relay.subscription_keys().await,
+        });
+    }
+    RoomSummary {
+        id: room.id.clone(),
+        media_settings: room.media_settings.clone(),
+        recording_enabled: room.recording_enabled,
+        lobby_enabled: room.lobby_enabled,
+        locked: room.locked,
+        password_protected: room.password_hash.is_some(),
+        banned_peers: room.banned_peers.iter().cloned().collect(),
+        banned_ips: room.banned_ips.iter().copied().collect(),
+        participants,
+        lobby: room.lobby.clone(),
+    }
+}
+
+/// Unknown rooms and peers are 404; requests the room's state refuses,
+/// such as a full room or a changed lobby, are 409.
+fn failure(error: Error) -> Response {
+    match error {
+        Error::NotFound(message) => error_response(StatusCode::NOT_FOUND, &message),
+        Error::InvalidMessage(message) => error_response(StatusCode::BAD_REQUEST, &message),
+        Error::Room(message) | Error::Peer(message) | Error::Unauthorized(message) => {
+            error_response(StatusCode::CONFLICT, &message)
+        }
+        other => error_response(StatusCode::INTERNAL_SERVER_ERROR, &other.to_string()),
+    }
+}
+
+fn error_body(message: &str) -> warp::reply::Json {
+    warp::reply::json(&json!({ "error": message }))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(error_body(message), status).into_response()
+}
@@ -0,0 +1,125 @@
+use crate::config::{AuthConfig, AuthKey};
+use crate::utils::{Error, Result};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use crate::room::state::{Permission, Role};
+
+/// Room pattern in `Claims::rooms` that matches every room.
+pub const ANY_ROOM: &str = "*";
+
+/// Claims of a signaling access token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// The peer id the bearer joins as
+    pub sub: String,
+    /// Rooms the bearer may join; `*` allows any
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// Expiry, in seconds since the Unix epoch
+    pub exp: u64,
+    /// Role in the rooms joined; the room's default when omitted
+    #[serde(default)]
+    pub role: Option<Role>,
+}
+
+impl Claims {
+    pub fn allows_room(&self, room_id: &str) -> bool {
+        self.rooms.iter().any(|room| room == ANY_ROOM || room == room_id)
+    }
+
+    /// Whether the token's role allows `permission`. Tokens without a role
+    /// leave that to the room's defaults.
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.role.map_or(true, |role| role.allows(permission))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        self.exp <= now
+    }
+}
+
+/// Checks signatures and standard claims of access tokens, signed either
+/// with a shared HMAC secret or with an RSA key whose public half we hold.
+pub struct TokenVerifier {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl TokenVerifier {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        let (key, algorithms) = match &config.key {
+            AuthKey::Hmac(secret) => (
+                DecodingKey::from_secret(secret.as_bytes()),
+                vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+            ),
+            AuthKey::RsaPem(path) => {
+                let pem = std::fs::read(path)?;
+                let key = DecodingKey::from_rsa_pem(&pem)
+                    .map_err(|e| Error::Unauthorized(format!("Invalid RSA public key {}: {}", path.display(), e)))?;
+                (key, vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512])
+            }
+        };
+
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        validation.validate_aud = config.audience.is_some();
+        if let Some(audience) = &config.audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        Ok(Self { key, validation })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| Error::Unauthorized(format!("Invalid token: {}", e)))
+    }
+}
+
+/// Token a client presented when opening its WebSocket, either as the
+/// `token` query parameter (browsers can't set headers on WebSockets) or
+/// as a bearer `Authorization` header.
+pub fn upgrade_token(query: Option<&str>, authorization: Option<&str>) -> Option<String> {
+    let from_query = query.and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .filter(|token| !token.is_empty())
+    });
+    let from_header = authorization.and_then(|value| value.strip_prefix("Bearer ")).map(str::trim);
+    from_query.or(from_header).map(str::to_string)
+}
+
+const DIRECTIONS: [&str; 4] = ["sendrecv", "sendonly", "recvonly", "inactive"];
+
+/// Whether an SDP offer has any audio or video section sending towards the
+/// server. Sections without a direction attribute take the session's, and
+/// `sendrecv` without either (RFC 8866); rejected sections (port 0) send nothing.
+pub fn offers_media(sdp: &str) -> bool {
+    let mut session_direction = "sendrecv";
+    // Kind, whether enabled, and direction of each media section so far
+    let mut sections: Vec<(&str, bool, Option<&str>)> = Vec::new();
+    for line in sdp.lines().map(str::trim) {
+        if let Some(media) = line.strip_prefix("m=") {
+            let mut fields = media.split_whitespace();
+            let kind = fields.next().unwrap_or_default();
+            let enabled = fields.next().is_some_and(|port| port != "0");
+            sections.push((kind, enabled, None));
+        } else if let Some(direction) = line.strip_prefix("a=").filter(|attr| DIRECTIONS.contains(attr)) {
+            match sections.last_mut() {
+                Some((_, _, section_direction)) => *section_direction = Some(direction),
+                None => session_direction = direction,
+            }
+        }
+    }
+    sections.into_iter().any(|(kind, enabled, direction)| {
+        let direction = direction.unwrap_or(session_direction);
+        (kind == "audio" || kind == "video") && enabled && (direction == "sendrecv" || direction == "sendonly")
+    })
+}
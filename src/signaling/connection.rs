@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use futures_util::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use uuid::Uuid;
+use crate::signaling::connection_state::{ConnectionState, ConnectionStateManager};
+use crate::signaling::handler::MessageHandler;
+use crate::signaling::protocol::Feature;
+use crate::signaling::transport::{Frame, SignalingTransport};
+use crate::types::{envelope_id, SignalingMessage, WebSocketConnection};
+use crate::utils::{Error, Result};
+
+/// Where a connection is in its life.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Phase {
+    /// Known only by its temporary id, before a successful Join or Resume
+    Connecting,
+    /// Speaking for `peer_id`, in `room_id` or its lobby
+    Joined { peer_id: String, room_id: String },
+    /// The peer left with `Disconnect`
+    Left,
+    /// Turned away, e.g. for an unsupported protocol version
+    Refused,
+}
+
+/// One client socket, from its first frame to its cleanup. Every transport
+/// runs this same state machine, so temporary ids, joins, resumption and
+/// cleanup behave alike whichever library accepted the socket.
+pub struct Connection {
+    handler: Arc<MessageHandler>,
+    state_manager: Arc<ConnectionStateManager>,
+    ws_conn: WebSocketConnection,
+    addr: SocketAddr,
+    remote_ip: Option<IpAddr>,
+    // Access token presented when the socket was opened, if any
+    upgrade_token: Option<String>,
+    session_id: String,
+    temp_id: String,
+    features: Vec<Feature>,
+    phase: Phase,
+}
+
+impl Connection {
+    /// Registers a new socket under a temporary id. `remote` is the
+    /// client's address, if the transport knows it.
+    pub async fn open(
+        handler: Arc<MessageHandler>,
+        state_manager: Arc<ConnectionStateManager>,
+        transport: Arc<dyn SignalingTransport>,
+        remote: Option<SocketAddr>,
+        upgrade_token: Option<String>,
+    ) -> Result<Self> {
+        let session_id = Uuid::new_v4().to_string();
+        let temp_id = format!("temp_{}", session_id);
+        let ws_conn = WebSocketConnection::new(transport);
+        handler.set_websocket_sender(temp_id.clone(), ws_conn.clone()).await?;
+
+        let addr = remote.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        info!("New WebSocket connection {} from {}", session_id, addr);
+        Ok(Self {
+            handler,
+            state_manager,
+            ws_conn,
+            addr,
+            remote_ip: remote.map(|addr| addr.ip()),
+            upgrade_token,
+            session_id,
+            temp_id,
+            features: Feature::LEGACY.to_vec(),
+            phase: Phase::Connecting,
+        })
+    }
+
+    /// Handles the client's frames until the socket closes or the client
+    /// leaves, then cleans up.
+    pub async fn serve<S>(mut self, mut frames: S)
+    where
+        S: Stream<Item = Result<Frame>> + Unpin,
+    {
+        while let Some(frame) = frames.next().await {
+            let text = match frame {
+                Ok(Frame::Text(text)) => text,
+                Ok(Frame::Binary(bytes)) => match String::from_utf8(bytes) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        let e = Error::SerializationError(e.to_string());
+                        self.reply_error(None, &e).await;
+                        continue;
+                    }
+                },
+                Ok(Frame::Control) => continue,
+                Ok(Frame::Close) => {
+                    info!("Received close frame from {}", self.addr);
+                    break;
+                }
+                Err(e) => {
+                    error!("WebSocket error for {}: {}", self.addr, e);
+                    break;
+                }
+            };
+            if self.receive(&text).await.is_break() {
+                break;
+            }
+        }
+        self.close().await;
+    }
+
+    fn peer_id(&self) -> Option<&str> {
+        match &self.phase {
+            Phase::Joined { peer_id, .. } => Some(peer_id),
+            _ => None,
+        }
+    }
+
+    async fn receive(&mut self, text: &str) -> ControlFlow<()> {
+        let sender = self.peer_id().unwrap_or(&self.temp_id).to_string();
+        let envelope = match self.handler.receive(&sender, text).await {
+            Ok(Some(envelope)) => envelope,
+            Ok(None) => return ControlFlow::Continue(()),
+            Err(e) => {
+                warn!("Failed to parse message from {}: {}", self.addr, e);
+                self.reply_error(envelope_id(text), &e).await;
+                return ControlFlow::Continue(());
+            }
+        };
+        let (request_id, message) = (envelope.id, envelope.message);
+        debug!("Received message {:?} from {}", message, sender);
+
+        if let Err(e) = self.handler.authorize_sender(self.peer_id(), &message).await {
+            warn!("Rejecting message from {}: {}", self.addr, e);
+            self.reply_error(request_id, &e).await;
+            return ControlFlow::Continue(());
+        }
+
+        let outcome = match message {
+            SignalingMessage::Hello { protocol_version, features } => match self.hello(protocol_version, &features).await {
+                ControlFlow::Continue(outcome) => outcome,
+                ControlFlow::Break(()) => return ControlFlow::Break(()),
+            },
+            SignalingMessage::Join { .. } | SignalingMessage::Resume { .. } if self.phase != Phase::Connecting => {
+                // One identity per socket; switching would strand the first one
+                Err(Error::InvalidMessage(format!("Connection is already joined as {}", self.peer_id().unwrap_or_default())))
+            }
+            SignalingMessage::Join { room_id, peer_id, token, metadata, password } => {
+                let token = token.or_else(|| self.upgrade_token.clone());
+                self.join(room_id, peer_id, token, metadata, password).await
+            }
+            SignalingMessage::Resume { room_id, peer_id, resume_token, last_seq } => {
+                if let Err(e) = self.resume(room_id, peer_id.clone(), resume_token, last_seq).await {
+                    warn!("Peer {} could not resume: {}", peer_id, e);
+                    self.connection_error(&peer_id, format!("{}; join again", e), true).await;
+                    return ControlFlow::Continue(());
+                }
+                Ok(())
+            }
+            SignalingMessage::Disconnect { .. } => {
+                // Whatever the message says, only this connection's peer leaves
+                if let Phase::Joined { peer_id, room_id } = &self.phase {
+                    if let Err(e) = self.handler.handle_disconnect(peer_id, room_id).await {
+                        error!("Error handling disconnect for peer {}: {}", peer_id, e);
+                    }
+                }
+                self.phase = Phase::Left;
+                return ControlFlow::Break(());
+            }
+            message => {
+                // authorize_sender only lets joined connections this far
+                let result = match self.peer_id() {
+                    Some(peer_id) => self.handler.handle_message(message, peer_id).await,
+                    None => Err(Error::Unauthorized("Join a room first".to_string())),
+                };
+                if let Err(e) = &result {
+                    warn!("Failed to handle message from {}: {}", self.addr, e);
+                }
+                result
+            }
+        };
+
+        match outcome {
+            // Only handled requests count as seen, so failed ones may be retried
+            Ok(()) => {
+                if let Some(id) = request_id {
+                    let sender = self.peer_id().unwrap_or(&self.temp_id).to_string();
+                    self.handler.mark_handled(&sender, id).await;
+                }
+            }
+            Err(e) => self.reply_error(request_id, &e).await,
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Answers a Hello, refusing clients whose protocol version is unsupported.
+    async fn hello(&mut self, protocol_version: u32, features: &[Feature]) -> ControlFlow<(), Result<()>> {
+        if self.phase != Phase::Connecting {
+            return ControlFlow::Continue(Err(Error::InvalidMessage("Hello must come before Join".to_string())));
+        }
+        match self.handler.hello(&self.session_id, &self.ws_conn, protocol_version, features).await {
+            Ok(features) => {
+                self.features = features;
+                ControlFlow::Continue(Ok(()))
+            }
+            Err(e) => {
+                warn!("Refusing connection from {}: {}", self.addr, e);
+                self.connection_error("", e.to_string(), false).await;
+                self.phase = Phase::Refused;
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    async fn join(
+        &mut self,
+        room_id: String,
+        peer_id: String,
+        token: Option<String>,
+        metadata: HashMap<String, String>,
+        password: Option<String>,
+    ) -> Result<()> {
+        self.handler.authenticate(&room_id, &peer_id, token.as_deref()).await?;
+        // Taking over a live peer's id would cut that peer off
+        if self.handler.is_connected(&peer_id).await {
+            return Err(Error::Unauthorized(format!("Peer {} is already connected", peer_id)));
+        }
+
+        // Messages sent while joining, such as the resume token, need the
+        // sender and the features that decide how they are sent
+        let previous_sender = self.handler.get_websocket_sender(&peer_id).await?;
+        let previous_addr = self.handler.get_peer_addr(&peer_id).await;
+        let previous_features = self.handler.peer_features(&peer_id).await;
+        self.handler.set_websocket_sender(peer_id.clone(), self.ws_conn.clone()).await?;
+        self.handler.set_peer_features(&peer_id, self.features.clone()).await;
+        if let Some(ip) = self.remote_ip {
+            self.handler.set_peer_addr(&peer_id, ip).await;
+        }
+        if let Err(e) = self.handler.handle_join(room_id.clone(), peer_id.clone(), metadata, password).await {
+            warn!("Peer {} from {} could not join: {}", peer_id, self.addr, e);
+            // Still a bare connection that may try again; whatever the id
+            // stood for before is left as it was
+            let restored = match previous_sender {
+                Some(sender) => self.handler.set_websocket_sender(peer_id.clone(), sender).await,
+                None => self.handler.remove_websocket_sender(&peer_id).await,
+            };
+            if let Err(e) = restored {
+                error!("Failed to restore websocket sender for peer {}: {}", peer_id, e);
+            }
+            match previous_addr {
+                Some(ip) => self.handler.set_peer_addr(&peer_id, ip).await,
+                None => self.handler.remove_peer_addr(&peer_id).await,
+            }
+            // A suspended session of this id keeps its features
+            match self.handler.get_peer_room(&peer_id).await {
+                Some(_) => self.handler.set_peer_features(&peer_id, previous_features).await,
+                None => self.handler.remove_peer_features(&peer_id).await,
+            }
+            return Err(e);
+        }
+
+        info!("Peer {} joined room {}", peer_id, room_id);
+        self.state_manager.transition(&peer_id, ConnectionState::New).await;
+        self.joined(peer_id, room_id).await;
+        Ok(())
+    }
+
+    async fn resume(&mut self, room_id: String, peer_id: String, resume_token: String, last_seq: Option<u64>) -> Result<()> {
+        self.handler.resume_session(&room_id, &peer_id, &resume_token, last_seq, self.ws_conn.clone()).await?;
+        self.handler.set_peer_features(&peer_id, self.features.clone()).await;
+        self.joined(peer_id, room_id).await;
+        Ok(())
+    }
+
+    async fn joined(&mut self, peer_id: String, room_id: String) {
+        if let Err(e) = self.handler.remove_websocket_sender(&self.temp_id).await {
+            error!("Failed to remove temporary connection: {}", e);
+        }
+        self.handler.forget_seen_ids(&self.temp_id).await;
+        self.phase = Phase::Joined { peer_id, room_id };
+    }
+
+    async fn close(self) {
+        match &self.phase {
+            Phase::Joined { peer_id, room_id } => {
+                // The socket dropped without the peer leaving
+                if let Err(e) = self.handler.connection_lost(peer_id, room_id, &self.ws_conn).await {
+                    error!("Error handling lost connection of peer {}: {}", peer_id, e);
+                }
+            }
+            Phase::Left | Phase::Refused => {
+                if let Err(e) = self.ws_conn.close().await {
+                    debug!("Failed to close connection from {}: {}", self.addr, e);
+                }
+            }
+            Phase::Connecting => {}
+        }
+        if let Err(e) = self.handler.remove_websocket_sender(&self.temp_id).await {
+            error!("Failed to remove temporary connection: {}", e);
+        }
+        self.handler.forget_seen_ids(&self.temp_id).await;
+    }
+
+    /// Answers a request that was rejected or failed, keeping the socket open.
+    async fn reply_error(&self, request_id: Option<String>, error: &Error) {
+        self.send(&SignalingMessage::error(error, request_id)).await;
+    }
+
+    async fn connection_error(&self, peer_id: &str, error: String, should_retry: bool) {
+        let notice = SignalingMessage::ConnectionError {
+            peer_id: peer_id.to_string(),
+            error,
+            should_retry,
+        };
+        self.send(&notice).await;
+    }
+
+    /// Sends a message straight down the socket, outside any peer's sequence.
+    async fn send(&self, message: &SignalingMessage) {
+        match serde_json::to_string(message) {
+            Ok(json) => {
+                if let Err(e) = self.ws_conn.send(json).await {
+                    warn!("Failed to send {:?} to {}: {}", message, self.addr, e);
+                }
+            }
+            Err(e) => warn!("Failed to serialize {:?}: {}", message, e),
+        }
+    }
+}
@@ -5,6 +5,9 @@ use log::{debug, info, warn};
 use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 use tokio::sync::broadcast;
+use crate::media::MediaRelayManager;
+use crate::types::SignalingMessage;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ConnectionState {
@@ -14,6 +17,10 @@ pub enum ConnectionState {
     OfferReceived,
     AnswerCreated,
     Connected,
+    /// The transport dropped; ICE may still recover on its own.
+    Disconnected,
+    /// An ICE-restart offer is on its way to the peer.
+    Restarting,
     Failed,
     Closed,
 }
@@ -56,11 +63,29 @@ impl ConnectionStateManager {
 
         let valid_transition = match (&current_state, &new_state) {
             (None, ConnectionState::New) => true,
+            // A peer that left may join again
+            (Some(ConnectionState::Closed), ConnectionState::New) => true,
             (Some(ConnectionState::New), ConnectionState::Joining) => true,
             (Some(ConnectionState::Joining), ConnectionState::WaitingForOffer) => true,
             (Some(ConnectionState::WaitingForOffer), ConnectionState::OfferReceived) => true,
             (Some(ConnectionState::OfferReceived), ConnectionState::AnswerCreated) => true,
-            (Some(ConnectionState::AnswerCreated), ConnectionState::Connected) => true,
+            // The peer connection comes up or is restarted on its own
+            // schedule, whichever side's offer set it up
+            (
+                Some(ConnectionState::New)
+                | Some(ConnectionState::Joining)
+                | Some(ConnectionState::WaitingForOffer)
+                | Some(ConnectionState::OfferReceived)
+                | Some(ConnectionState::AnswerCreated),
+                ConnectionState::Connected | ConnectionState::Restarting,
+            ) => true,
+            (Some(ConnectionState::Connected), ConnectionState::Disconnected) => true,
+            (Some(ConnectionState::Disconnected), ConnectionState::Connected) => true,
+            (Some(ConnectionState::Connected), ConnectionState::Restarting) => true,
+            (Some(ConnectionState::Disconnected), ConnectionState::Restarting) => true,
+            (Some(ConnectionState::Failed), ConnectionState::Restarting) => true,
+            (Some(ConnectionState::Restarting), ConnectionState::Connected) => true,
+            (Some(ConnectionState::Restarting), ConnectionState::Disconnected) => true,
             (Some(_), ConnectionState::Failed) => true,
             (Some(_), ConnectionState::Closed) => true,
             _ => false,
@@ -90,4 +115,39 @@ impl ConnectionStateManager {
         let states = self.states.lock().await;
         states.clone()
     }
+
+    /// Follows the relays' peer connections and the ICE restarts the server
+    /// sends, moving peers between connected, disconnected and restarting.
+    pub fn follow_peer_connections(self: Arc<Self>, relay_manager: &MediaRelayManager) {
+        let mut peer_states = relay_manager.subscribe_peer_states();
+        let mut events = relay_manager.subscribe_events();
+        tokio::spawn(async move {
+            loop {
+                let (peer_id, new_state) = tokio::select! {
+                    change = peer_states.recv() => match change {
+                        Ok(change) => {
+                            let new_state = match change.state {
+                                RTCPeerConnectionState::Connected => ConnectionState::Connected,
+                                RTCPeerConnectionState::Disconnected => ConnectionState::Disconnected,
+                                RTCPeerConnectionState::Failed => ConnectionState::Failed,
+                                RTCPeerConnectionState::Closed => ConnectionState::Closed,
+                                _ => continue,
+                            };
+                            (change.peer_id, new_state)
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    event = events.recv() => match event {
+                        Ok(SignalingMessage::IceRestart { peer_id, .. }) => (peer_id, ConnectionState::Restarting),
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                };
+                if !self.transition(&peer_id, new_state.clone()).await {
+                    debug!("Ignoring {:?} for peer {}", new_state, peer_id);
+                }
+            }
+        });
+    }
 } 
\ No newline at end of file
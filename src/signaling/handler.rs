@@ -1,10 +1,15 @@
 use crate::utils::{Error, Result};
-use crate::room::Room;
+use crate::room::{Room, RoomManager};
+use crate::room::state::{LobbyEntry, MediaType};
 use crate::metrics::ConnectionMetrics;
-use crate::types::{SignalingMessage, WebSocketConnection};
+use crate::signaling::auth::{self, Claims, Permission, Role, TokenVerifier};
+use crate::signaling::protocol::{self, Feature, PROTOCOL_VERSION};
+use crate::signaling::routing::{Delivery, Recipient};
+use crate::types::{Envelope, IceServer, SignalingMessage, WebSocketConnection, SERVER_PEER_ID};
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
-use std::collections::HashMap;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use tokio_tungstenite::tungstenite::Message;
 use futures_util::SinkExt;
 use webrtc::{
@@ -24,10 +29,11 @@ use webrtc::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
     },
 };
-use crate::media::{MediaRelayManager, MediaRelay};
+use crate::media::{MediaRelayManager, MediaRelay, RelayMode};
 use log::{info, error, debug, warn};
 use serde::{Serialize, Deserialize};
 use std::time::Duration;
+use uuid::Uuid;
 use crate::media::recording::RecordingManager;
 use std::path::PathBuf;
 use webrtc::rtp::packet::Packet as RTPPacket;
@@ -35,59 +41,270 @@ use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
 use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
 use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
 
+/// A joined peer's signaling session, which outlives its socket for the
+/// resume grace period.
+#[derive(Debug, Clone)]
+struct Session {
+    room_id: String,
+    resume_token: String,
+    // Whether the peer's socket is gone and it is waiting to be resumed
+    suspended: bool,
+}
+
+/// How many unacknowledged messages are kept per peer for replay.
+const REPLAY_BUFFER_LEN: usize = 256;
+/// How many recent message ids are remembered per peer to drop repeats.
+const SEEN_IDS_LEN: usize = 256;
+
+/// Messages sent to a peer that it has not acknowledged yet.
+#[derive(Debug, Default)]
+struct Outbox {
+    last_seq: u64,
+    unacked: VecDeque<(u64, String)>,
+}
+
+/// Ids of a peer's recent messages, oldest first.
+#[derive(Debug, Default)]
+struct SeenIds {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
 #[derive(Clone)]
 pub struct MessageHandler {
     relay_manager: Arc<MediaRelayManager>,
+    room_manager: Arc<RoomManager>,
     websocket_senders: Arc<RwLock<HashMap<String, WebSocketConnection>>>,
     peer_rooms: Arc<RwLock<HashMap<String, String>>>,
     recording_manager: Option<Arc<RecordingManager>>,
+    token_verifier: Option<Arc<TokenVerifier>>,
+    // Claims of each authenticated peer, while it is connected
+    peer_claims: Arc<RwLock<HashMap<String, Claims>>>,
+    // Address each peer connected from, for IP bans
+    peer_addrs: Arc<RwLock<HashMap<String, IpAddr>>>,
+    sessions: Arc<RwLock<HashMap<String, Session>>>,
+    resume_grace: Duration,
+    outboxes: Arc<Mutex<HashMap<String, Outbox>>>,
+    seen_ids: Arc<Mutex<HashMap<String, SeenIds>>>,
+    ice_servers: Vec<IceServer>,
+    // Features each peer negotiated in its Hello, if it sent one
+    peer_features: Arc<RwLock<HashMap<String, Vec<Feature>>>>,
+    // Peer behind each WHIP/WHEP resource, keyed by the id in its URL
+    http_resources: Arc<RwLock<HashMap<String, String>>>,
 }
 
 impl MessageHandler {
     pub fn new(relay_manager: Arc<MediaRelayManager>, recording_path: Option<PathBuf>) -> Self {
         Self {
+            room_manager: Arc::new(RoomManager::new(relay_manager.clone())),
             relay_manager,
             websocket_senders: Arc::new(RwLock::new(HashMap::new())),
             peer_rooms: Arc::new(RwLock::new(HashMap::new())),
             recording_manager: recording_path.map(|path| Arc::new(RecordingManager::new(path))),
+            token_verifier: None,
+            peer_claims: Arc::new(RwLock::new(HashMap::new())),
+            peer_addrs: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            resume_grace: Duration::ZERO,
+            outboxes: Arc::new(Mutex::new(HashMap::new())),
+            seen_ids: Arc::new(Mutex::new(HashMap::new())),
+            ice_servers: Vec::new(),
+            peer_features: Arc::new(RwLock::new(HashMap::new())),
+            http_resources: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Lets peers whose socket dropped resume their session within `grace`
+    /// instead of leaving their room right away.
+    pub fn with_resume_grace(mut self, grace: Duration) -> Self {
+        self.resume_grace = grace;
+        self
+    }
+
+    /// ICE servers announced to clients in `Welcome`.
+    pub fn with_ice_servers(mut self, ice_servers: Vec<IceServer>) -> Self {
+        self.ice_servers = ice_servers;
+        self
+    }
+
+    /// Requires every peer to present a valid access token to join.
+    pub fn with_token_verifier(mut self, verifier: TokenVerifier) -> Self {
+        self.token_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        self.token_verifier.is_some()
+    }
+
+    /// Verifies a token presented before the peer said which room it wants.
+    pub fn verify_token(&self, token: &str) -> Result<Claims> {
+        let verifier = self.token_verifier.as_ref()
+            .ok_or_else(|| Error::Unauthorized("Authentication is not enabled".to_string()))?;
+        verifier.verify(token)
+    }
+
+    /// Verifies a token and checks that it grants access to `room_id`.
+    /// Without a verifier there is nothing to check and no claims.
+    pub fn authorize_room(&self, room_id: &str, token: Option<&str>) -> Result<Option<Claims>> {
+        let Some(verifier) = &self.token_verifier else { return Ok(None) };
+        let token = token.ok_or_else(|| Error::Unauthorized("An access token is required".to_string()))?;
+        let claims = verifier.verify(token)?;
+        if !claims.allows_room(room_id) {
+            return Err(Error::Unauthorized(format!("Token does not grant access to room {}", room_id)));
+        }
+        Ok(Some(claims))
+    }
+
+    /// Checks that `token` lets `peer_id` join `room_id` and remembers its
+    /// claims for the rest of the peer's session.
+    pub async fn authenticate(&self, room_id: &str, peer_id: &str, token: Option<&str>) -> Result<()> {
+        let Some(claims) = self.authorize_room(room_id, token)? else { return Ok(()) };
+        if claims.sub != peer_id {
+            return Err(Error::Unauthorized(format!("Token is not valid for peer {}", peer_id)));
+        }
+        self.peer_claims.write().await.insert(peer_id.to_string(), claims);
+        Ok(())
+    }
+
+    /// Checks a message arriving on a connection joined as `connection_peer`:
+    /// it may only speak for that peer and, with authentication on, only
+    /// while its token is valid. Joins are checked by `authenticate` instead.
+    pub async fn authorize_sender(&self, connection_peer: Option<&str>, msg: &SignalingMessage) -> Result<()> {
+        if matches!(msg, SignalingMessage::Hello { .. } | SignalingMessage::Join { .. } | SignalingMessage::Resume { .. }) {
+            return Ok(());
+        }
+        let Some(peer_id) = connection_peer else {
+            return Err(Error::Unauthorized("Join a room first".to_string()));
+        };
+        if let Some(claimed) = msg.get_peer_id() {
+            if claimed != peer_id {
+                return Err(Error::Unauthorized(format!("Connection is joined as {}, not {}", peer_id, claimed)));
+            }
+        }
+        if !self.requires_auth() {
+            return Ok(());
+        }
+        match self.peer_claims.read().await.get(peer_id) {
+            Some(claims) if claims.is_expired() => Err(Error::Unauthorized("Access token expired".to_string())),
+            Some(_) => Ok(()),
+            None => Err(Error::Unauthorized(format!("Peer {} is not authenticated", peer_id))),
         }
     }
 
-    pub async fn handle_offer(
+    /// Answers a client's `Hello` on `ws_conn` with a `Welcome`, switching the
+    /// socket to binary frames if both sides support them. Returns the
+    /// negotiated features, to be recorded for the peer once it joins.
+    pub async fn hello(
         &self,
-        room_id: String,
-        from_peer: String,
-        to_peer: String,
-        sdp: String,
-    ) -> Result<()> {
-        let relays = self.relay_manager.get_relays().await?;
-        
-        if let Some(relay) = relays.get(&to_peer) {
-            debug!("Setting remote description for peer {}", to_peer);
-            
-            let offer = RTCSessionDescription::offer(sdp)?;
-            relay.peer_connection.set_remote_description(offer).await?;
-            
-            debug!("Creating answer for peer {}", to_peer);
-            let answer = relay.peer_connection.create_answer(None).await?;
-            
-            debug!("Setting local description for peer {}", to_peer);
-            relay.peer_connection.set_local_description(answer.clone()).await?;
-            
-            let answer_msg = SignalingMessage::Answer {
-                room_id,
-                sdp: answer.sdp,
-                from_peer: to_peer,
-                to_peer: from_peer.clone(),
-            };
-            
-            self.send_message(&answer_msg).await?;
-            debug!("Sent answer to peer {}", from_peer);
+        session_id: &str,
+        ws_conn: &WebSocketConnection,
+        protocol_version: u32,
+        client_features: &[Feature],
+    ) -> Result<Vec<Feature>> {
+        protocol::check_version(protocol_version)?;
+        let features = protocol::negotiate(client_features);
+        let welcome = SignalingMessage::Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: features.clone(),
+            ice_servers: self.ice_servers.clone(),
+            session_id: session_id.to_string(),
+        };
+        ws_conn.send(serde_json::to_string(&welcome)?).await?;
+        if features.contains(&Feature::BinaryFrames) {
+            ws_conn.use_binary_frames();
+        }
+        info!("Session {} speaks protocol {} with {:?}", session_id, protocol_version, features);
+        Ok(features)
+    }
+
+    pub async fn set_peer_features(&self, peer_id: &str, features: Vec<Feature>) {
+        self.peer_features.write().await.insert(peer_id.to_string(), features);
+    }
+
+    pub async fn remove_peer_features(&self, peer_id: &str) {
+        self.peer_features.write().await.remove(peer_id);
+    }
+
+    /// What `peer_id` negotiated, or the legacy features if it sent no `Hello`.
+    pub async fn peer_features(&self, peer_id: &str) -> Vec<Feature> {
+        self.peer_features.read().await.get(peer_id).cloned().unwrap_or_else(|| Feature::LEGACY.to_vec())
+    }
+
+    async fn require_feature(&self, peer_id: &str, feature: Feature) -> Result<()> {
+        if !self.peer_features(peer_id).await.contains(&feature) {
+            return Err(Error::InvalidMessage(format!("Peer {} did not negotiate {:?}", peer_id, feature)));
+        }
+        Ok(())
+    }
+
+    /// Fails unless `peer_id`'s role in `room_id` grants `permission`.
+    pub async fn require_permission(&self, room_id: &str, peer_id: &str, permission: Permission) -> Result<Role> {
+        let role = self.room_manager.get_role(room_id, peer_id).await
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)))?;
+        if !role.allows(permission) {
+            return Err(Error::Unauthorized(format!("Peer {} ({:?}) lacks the {:?} permission", peer_id, role, permission)));
+        }
+        Ok(role)
+    }
+
+    /// Fails unless `actor` may moderate `target`. Only hosts moderate hosts.
+    async fn require_authority(&self, room_id: &str, actor: &str, target: &str) -> Result<Role> {
+        let actor_role = self.require_permission(room_id, actor, Permission::Moderate).await?;
+        let target_role = self.room_manager.get_role(room_id, target).await
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", target, room_id)))?;
+        if target_role == Role::Host && actor_role != Role::Host {
+            return Err(Error::Unauthorized(format!("Only hosts may moderate host {}", target)));
         }
+        Ok(actor_role)
+    }
 
+    /// Fails unless all of `peers` are in `room_id`, so peers can neither
+    /// reach nor probe for peers of other rooms.
+    async fn require_members(&self, room_id: &str, peers: &[&str]) -> Result<()> {
+        for peer_id in peers {
+            if !self.room_manager.has_peer(room_id, peer_id).await {
+                return Err(Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_publish(&self, room_id: &str, peer_id: &str, sdp: &str) -> Result<()> {
+        if auth::offers_media(sdp) {
+            self.require_permission(room_id, peer_id, Permission::Publish).await?;
+        }
         Ok(())
     }
 
+    /// Answers an offer a peer made to its own relay, then sends any server
+    /// offer that was queued or rolled back while the exchange was running.
+    pub async fn handle_offer(&self, room_id: String, from_peer: String, sdp: String) -> Result<()> {
+        let relay = self.relay_manager.get_relay(&room_id, &from_peer).await
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", from_peer, room_id)))?;
+        debug!("Creating answer for peer {}", from_peer);
+        let answer = relay.handle_offer(sdp).await?;
+
+        let answer_msg = SignalingMessage::Answer {
+            room_id,
+            sdp: answer.sdp,
+            from_peer: SERVER_PEER_ID.to_string(),
+            to_peer: from_peer.clone(),
+        };
+        let sent = self.send_message(&answer_msg).await;
+        // Flushed whether or not the answer went out, or queued server offers
+        // would stay stuck for the rest of the session
+        let flushed = relay.flush_negotiation().await;
+        if sent?.is_delivered() {
+            debug!("Sent answer to peer {}", from_peer);
+        }
+        flushed
+    }
+
+    /// Applies a trickled candidate from `from_peer` to its server connection.
+    /// Candidates are always exchanged with the server, whatever `to_peer`
+    /// says; an empty candidate signals end-of-candidates.
     pub async fn handle_ice_candidate(
         &self,
         room_id: String,
@@ -95,64 +312,163 @@ impl MessageHandler {
         to_peer: String,
         candidate: String,
     ) -> Result<()> {
-        let relays = self.relay_manager.get_relays().await?;
-        
         // Parse the candidate string to remove extra encoding
         let parsed_candidate = if candidate.starts_with('"') {
             serde_json::from_str::<String>(&candidate)?
         } else {
             candidate.clone()
         };
-        
-        // Forward the ICE candidate to the remote peer first
-        if let Some(sender) = self.get_websocket_sender(&to_peer).await? {
-            let message = SignalingMessage::IceCandidate {
-                room_id: room_id.clone(),
-                from_peer: from_peer.clone(),
-                to_peer: to_peer.clone(),
-                candidate: parsed_candidate.clone(),
+
+        let relay = self.relay_manager.get_relay(&room_id, &from_peer).await
+            .ok_or_else(|| Error::Peer(format!("No media relay for peer {} in room {}", from_peer, room_id)))?;
+        let ice_candidate = if parsed_candidate.is_empty() {
+            RTCIceCandidateInit::default()
+        } else {
+            serde_json::from_str::<RTCIceCandidateInit>(&parsed_candidate)?
+        };
+
+        if ice_candidate.candidate.is_empty() {
+            debug!("End of ICE candidates from peer {} (to {})", from_peer, to_peer);
+        }
+        if let Err(e) = relay.add_ice_candidate(ice_candidate).await {
+            warn!("Could not add ICE candidate for {}: {}", from_peer, e);
+        }
+
+        Ok(())
+    }
+
+    /// Sends `msg` to whoever it is addressed to; see `SignalingMessage::recipient`.
+    pub async fn send_message(&self, msg: &SignalingMessage) -> Result<Delivery> {
+        let recipient = msg.recipient()
+            .ok_or_else(|| Error::InvalidMessage("Message has no recipient".to_string()))?;
+        self.route(&recipient, msg).await
+    }
+
+    /// Sends `msg` to the connected peers `recipient` covers, and nobody else.
+    /// Peers whose socket is gone but whose session may still resume count
+    /// as delivered to: they get the message when they resume, unless it is
+    /// ephemeral.
+    pub async fn route(&self, recipient: &Recipient, msg: &SignalingMessage) -> Result<Delivery> {
+        let peers = self.recipient_peers(recipient).await;
+        let id = Uuid::new_v4().to_string();
+        let senders = self.websocket_senders.read().await;
+
+        let mut delivered = 0;
+        for peer_id in &peers {
+            let sender = senders.get(peer_id);
+            // Only peers that acknowledge messages get them sequenced and kept
+            let acks = !msg.is_ephemeral() && self.peer_features(peer_id).await.contains(&Feature::Acks);
+            if sender.is_none() && !(acks && self.is_suspended(peer_id).await) {
+                debug!("Peer {} is not connected", peer_id);
+                continue;
+            }
+            let json = match acks {
+                true => self.sequence(peer_id, &id, msg).await?,
+                false => serde_json::to_string(&Envelope { id: Some(id.clone()), seq: None, message: msg })?,
             };
-            sender.send(serde_json::to_string(&message)?).await?;
-        }
-
-        // Then handle it for the local peer connection
-        if let Some(relay) = relays.get(&to_peer) {
-            match serde_json::from_str::<RTCIceCandidateInit>(&parsed_candidate) {
-                Ok(ice_candidate) => {
-                    // Only try to add the candidate if we have a remote description
-                    if relay.peer_connection.remote_description().await.is_some() {
-                        match relay.peer_connection.add_ice_candidate(ice_candidate).await {
-                            Ok(_) => {
-                                debug!("Successfully added ICE candidate for peer {}", to_peer);
-                            }
-                            Err(e) => {
-                                warn!("Could not add ICE candidate for {}: {}", to_peer, e);
-                            }
-                        }
-                    } else {
-                        debug!("Buffering ICE candidate for peer {} until remote description is set", to_peer);
-                        // Here you might want to implement a buffer mechanism in the MediaRelay struct
-                    }
-                }
-                Err(e) => {
-                    warn!("Failed to parse ICE candidate: {}", e);
+            match sender {
+                Some(ws_conn) => match ws_conn.send(json).await {
+                    Ok(()) => delivered += 1,
+                    Err(e) => warn!("Failed to send message to peer {}: {}", peer_id, e),
+                },
+                None => {
+                    debug!("Queued message for suspended peer {}", peer_id);
+                    delivered += 1;
                 }
             }
         }
+        if delivered == 0 {
+            debug!("Message for {:?} was undeliverable", recipient);
+            return Ok(Delivery::Undeliverable);
+        }
+        Ok(Delivery::Delivered(delivered))
+    }
 
-        Ok(())
+    /// Wraps `msg` for `peer_id` with the next sequence number of its
+    /// stream, keeping it until the peer acknowledges it.
+    async fn sequence(&self, peer_id: &str, id: &str, msg: &SignalingMessage) -> Result<String> {
+        let mut outboxes = self.outboxes.lock().await;
+        let outbox = outboxes.entry(peer_id.to_string()).or_default();
+        let seq = outbox.last_seq + 1;
+        let json = serde_json::to_string(&Envelope { id: Some(id.to_string()), seq: Some(seq), message: msg })?;
+        outbox.last_seq = seq;
+        outbox.unacked.push_back((seq, json.clone()));
+        if outbox.unacked.len() > REPLAY_BUFFER_LEN {
+            outbox.unacked.pop_front();
+            debug!("Replay buffer of peer {} is full, dropping its oldest message", peer_id);
+        }
+        Ok(json)
     }
 
-    pub async fn send_message(&self, msg: &SignalingMessage) -> Result<()> {
-        let json = serde_json::to_string(msg)?;
-        let senders = self.websocket_senders.read().await;
-        
-        for ws_conn in senders.values() {
-            if let Err(e) = ws_conn.send(json.clone()).await {
-                warn!("Failed to send message to peer: {}", e);
+    /// Forgets the messages `peer_id` acknowledged, up to and including `seq`.
+    pub async fn acknowledge(&self, peer_id: &str, seq: u64) {
+        if let Some(outbox) = self.outboxes.lock().await.get_mut(peer_id) {
+            while outbox.unacked.front().is_some_and(|(sent, _)| *sent <= seq) {
+                outbox.unacked.pop_front();
             }
         }
-        Ok(())
+    }
+
+    async fn is_suspended(&self, peer_id: &str) -> bool {
+        self.sessions.read().await.get(peer_id).is_some_and(|session| session.suspended)
+    }
+
+    /// Parses a message received from `sender`, which is the peer id of the
+    /// connection or of its Join. Returns `None` for a repeat of a message
+    /// id already handled for that peer, which must be dropped.
+    pub async fn receive(&self, sender: &str, text: &str) -> Result<Option<Envelope<SignalingMessage>>> {
+        let envelope: Envelope<SignalingMessage> = serde_json::from_str(text)?;
+        let Some(id) = envelope.id.as_deref() else { return Ok(Some(envelope)) };
+
+        if self.seen_ids.lock().await.get(sender).is_some_and(|seen| seen.ids.contains(id)) {
+            debug!("Dropping repeated message {} from {}", id, sender);
+            return Ok(None);
+        }
+        Ok(Some(envelope))
+    }
+
+    /// Records that the message `id` from `sender` was handled, so repeats
+    /// of it are dropped. Failed requests are not recorded and may be
+    /// retried with the same id.
+    pub async fn mark_handled(&self, sender: &str, id: String) {
+        let mut seen_ids = self.seen_ids.lock().await;
+        let seen = seen_ids.entry(sender.to_string()).or_default();
+        if !seen.ids.insert(id.clone()) {
+            return;
+        }
+        seen.order.push_back(id);
+        if seen.order.len() > SEEN_IDS_LEN {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.ids.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forgets the message ids seen from `sender`, e.g. the temporary id of
+    /// a connection that joined or closed.
+    pub async fn forget_seen_ids(&self, sender: &str) {
+        self.seen_ids.lock().await.remove(sender);
+    }
+
+    async fn recipient_peers(&self, recipient: &Recipient) -> Vec<String> {
+        match recipient {
+            Recipient::Peer(peer_id) => vec![peer_id.clone()],
+            Recipient::Room(room_id) => self.peer_rooms
+                .read()
+                .await
+                .iter()
+                .filter(|(_, peer_room)| *peer_room == room_id)
+                .map(|(peer_id, _)| peer_id.clone())
+                .collect(),
+            Recipient::Roles { room_id, roles } => match self.room_manager.get_room(room_id).await {
+                Ok(room) => room.roles
+                    .into_iter()
+                    .filter(|(_, role)| roles.contains(role))
+                    .map(|(peer_id, _)| peer_id)
+                    .collect(),
+                Err(_) => Vec::new(),
+            },
+        }
     }
 
     pub async fn remove_websocket_sender(&self, peer_id: &str) -> Result<()> {
@@ -167,195 +483,704 @@ impl MessageHandler {
         match msg {
             SignalingMessage::CallRequest { room_id, from_peer, to_peers, sdp } => {
                 debug!("Handling call request from {} to {:?}", from_peer, to_peers);
+                let peers: Vec<&str> = std::iter::once(&from_peer).chain(&to_peers).map(String::as_str).collect();
+                self.require_members(&room_id, &peers).await?;
                 // Forward the call request to each target peer
                 for to_peer in to_peers {
-                    if let Some(ws_sender) = self.websocket_senders.read().await.get(&to_peer) {
-                        let message = SignalingMessage::CallRequest {
-                            room_id: room_id.clone(),
-                            from_peer: from_peer.clone(),
-                            to_peers: vec![to_peer.clone()],
-                            sdp: sdp.clone(),
-                        };
-                        ws_sender.send(serde_json::to_string(&message)?).await?;
+                    let message = SignalingMessage::CallRequest {
+                        room_id: room_id.clone(),
+                        from_peer: from_peer.clone(),
+                        to_peers: vec![to_peer.clone()],
+                        sdp: sdp.clone(),
+                    };
+                    if self.send_to_peer(&to_peer, &message).await?.is_delivered() {
                         debug!("Forwarded call request to {}", to_peer);
                     }
                 }
                 Ok(())
             },
             SignalingMessage::CallResponse { room_id, from_peer, to_peer, accepted, reason, sdp } => {
-                self.handle_call_response(&room_id, &from_peer, &to_peer, accepted).await?;
                 debug!("Handling call response from {} to {}: accepted={}", from_peer, to_peer, accepted);
-                if let Some(ws_sender) = self.websocket_senders.read().await.get(&to_peer) {
-                    let message = SignalingMessage::CallResponse {
-                        room_id,
-                        from_peer,
-                        to_peer: to_peer.clone(),
-                        accepted,
-                        reason,
-                        sdp,
-                    };
-                    ws_sender.send(serde_json::to_string(&message)?).await?;
+                self.require_members(&room_id, &[&from_peer, &to_peer]).await?;
+                let message = SignalingMessage::CallResponse {
+                    room_id,
+                    from_peer,
+                    to_peer: to_peer.clone(),
+                    accepted,
+                    reason,
+                    sdp,
+                };
+                if self.send_message(&message).await?.is_delivered() {
                     debug!("Forwarded call response to {}", to_peer);
                 }
                 Ok(())
             },
-            SignalingMessage::Join { room_id, peer_id } => {
-                self.handle_join(room_id, peer_id).await
+            SignalingMessage::Join { room_id, peer_id, token, metadata, password } => {
+                self.authenticate(&room_id, &peer_id, token.as_deref()).await?;
+                self.handle_join(room_id, peer_id, metadata, password).await
             },
             SignalingMessage::RequestPeerList { room_id } => {
-                self.handle_peer_list_request(room_id).await
+                self.handle_peer_list_request(room_id, peer_id).await
             },
-            SignalingMessage::Offer { room_id, sdp, from_peer, to_peer } => {
-                self.handle_offer(room_id, from_peer, to_peer, sdp).await
+            SignalingMessage::Offer { room_id, sdp, from_peer, .. } => {
+                self.check_publish(&room_id, &from_peer, &sdp).await?;
+                self.handle_offer(room_id, from_peer, sdp).await
             },
             SignalingMessage::IceCandidate { room_id, candidate, from_peer, to_peer } => {
                 self.handle_ice_candidate(room_id, from_peer, to_peer, candidate).await
             },
+            SignalingMessage::LayerPreference { room_id, peer_id, publisher_id, quality } => {
+                self.require_feature(&peer_id, Feature::Simulcast).await?;
+                let relay = self.relay_manager.get_relay(&room_id, &peer_id).await
+                    .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)))?;
+                relay.set_layer_preference(&publisher_id, quality).await;
+                debug!("Peer {} prefers {:?} video from {}", peer_id, quality, publisher_id);
+                Ok(())
+            },
+            SignalingMessage::PinPeers { room_id, peer_id, pinned } => {
+                let relay = self.relay_manager.get_relay(&room_id, &peer_id).await
+                    .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)))?;
+                debug!("Peer {} pinned {:?}", peer_id, pinned);
+                relay.set_pinned_peers(pinned.into_iter().collect()).await;
+                self.relay_manager.apply_last_n(&room_id).await;
+                Ok(())
+            },
+            SignalingMessage::Subscribe { room_id, peer_id, publisher_id, media_types } => {
+                self.require_permission(&room_id, &peer_id, Permission::Subscribe).await?;
+                self.handle_subscription(room_id, peer_id, publisher_id, media_types, true).await
+            },
+            SignalingMessage::Unsubscribe { room_id, peer_id, publisher_id, media_types } => {
+                self.handle_subscription(room_id, peer_id, publisher_id, media_types, false).await
+            },
+            SignalingMessage::Answer { room_id, sdp, from_peer, .. } => {
+                let relay = self.relay_manager.get_relay(&room_id, &from_peer).await
+                    .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", from_peer, room_id)))?;
+                relay.set_remote_description(sdp).await
+            },
+            SignalingMessage::SetRole { room_id, peer_id, target_peer, role } => {
+                let actor_role = self.require_authority(&room_id, &peer_id, &target_peer).await?;
+                if role == Role::Host && actor_role != Role::Host {
+                    return Err(Error::Unauthorized("Only hosts may appoint hosts".to_string()));
+                }
+                self.set_role(&room_id, &target_peer, role).await
+            },
+            SignalingMessage::KickPeer { room_id, peer_id, target_peer, reason } => {
+                self.require_authority(&room_id, &peer_id, &target_peer).await?;
+                let reason = reason.unwrap_or_else(|| format!("Removed from the room by {}", peer_id));
+                self.kick_peer(&room_id, &target_peer, &reason).await
+            },
+            SignalingMessage::MutePeer { room_id, peer_id, target_peer, track_id, muted } => {
+                self.require_authority(&room_id, &peer_id, &target_peer).await?;
+                self.set_track_muted(&room_id, &target_peer, track_id.as_deref(), muted).await?;
+                Ok(())
+            },
+            SignalingMessage::StartRecording { room_id, peer_id } => {
+                self.require_permission(&room_id, &peer_id, Permission::Record).await?;
+                let recording_manager = self.recording_manager.as_ref()
+                    .ok_or_else(|| Error::Media("Recording is not enabled on this server".to_string()))?;
+                info!("Peer {} started recording room {}", peer_id, room_id);
+                recording_manager.start_call_recording(&room_id, self.room_manager.get_room_peers(&room_id).await).await
+            },
+            SignalingMessage::SetLobby { room_id, peer_id, enabled } => {
+                self.require_permission(&room_id, &peer_id, Permission::Moderate).await?;
+                self.set_lobby_enabled(&room_id, enabled).await
+            },
+            SignalingMessage::AdmitPeer { room_id, peer_id, target_peer } => {
+                self.require_permission(&room_id, &peer_id, Permission::Moderate).await?;
+                self.admit_peer(&room_id, &target_peer).await
+            },
+            SignalingMessage::DenyPeer { room_id, peer_id, target_peer, reason } => {
+                self.require_permission(&room_id, &peer_id, Permission::Moderate).await?;
+                let reason = reason.unwrap_or_else(|| format!("Not admitted to the room by {}", peer_id));
+                self.deny_peer(&room_id, &target_peer, &reason).await
+            },
+            SignalingMessage::Ack { peer_id, last_seq } => {
+                self.acknowledge(&peer_id, last_seq).await;
+                Ok(())
+            },
+            SignalingMessage::LockRoom { room_id, peer_id, locked } => {
+                self.require_permission(&room_id, &peer_id, Permission::Moderate).await?;
+                self.set_room_locked(&room_id, locked).await
+            },
+            SignalingMessage::SetRoomPassword { room_id, peer_id, password } => {
+                self.require_permission(&room_id, &peer_id, Permission::Moderate).await?;
+                self.set_room_password(&room_id, password.as_deref()).await
+            },
+            SignalingMessage::BanPeer { room_id, peer_id, target_peer, ban_address, reason } => {
+                if self.room_manager.has_peer(&room_id, &target_peer).await {
+                    self.require_authority(&room_id, &peer_id, &target_peer).await?;
+                } else {
+                    self.require_permission(&room_id, &peer_id, Permission::Moderate).await?;
+                }
+                let reason = reason.unwrap_or_else(|| format!("Banned from the room by {}", peer_id));
+                self.ban_peer(&room_id, &target_peer, ban_address, &reason).await
+            },
+            SignalingMessage::UnbanPeer { room_id, peer_id, target_peer } => {
+                self.require_permission(&room_id, &peer_id, Permission::Moderate).await?;
+                self.unban_peer(&room_id, &target_peer).await?;
+                Ok(())
+            },
+            SignalingMessage::IceRestart { room_id, peer_id, sdp } => {
+                info!("Peer {} requested an ICE restart", peer_id);
+                match sdp {
+                    Some(sdp) => {
+                        self.check_publish(&room_id, &peer_id, &sdp).await?;
+                        self.handle_offer(room_id, peer_id, sdp).await
+                    }
+                    None => {
+                        let relay = self.relay_manager.get_relay(&room_id, &peer_id).await
+                            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)))?;
+                        relay.restart_ice().await
+                    }
+                }
+            },
             _ => Ok(()),
         }
     }
 
+    /// Updates which publishers' tracks `peer_id` receives and renegotiates
+    /// if that added or removed any.
+    async fn handle_subscription(
+        &self,
+        room_id: String,
+        peer_id: String,
+        publisher_id: Option<String>,
+        media_types: Vec<MediaType>,
+        wanted: bool,
+    ) -> Result<()> {
+        let relay = self.relay_manager.get_relay(&room_id, &peer_id).await
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)))?;
+        relay.set_subscription_filter(publisher_id.as_deref(), &media_types, wanted).await;
+        debug!("Peer {} {} {:?} from {}",
+            peer_id,
+            if wanted { "subscribed to" } else { "unsubscribed from" },
+            media_types,
+            publisher_id.as_deref().unwrap_or("everyone")
+        );
+
+        // Added or removed tracks raise negotiation-needed on the relay,
+        // which sends the peer a server offer
+        self.relay_manager.refresh_subscriptions(&room_id, &peer_id).await?;
+        Ok(())
+    }
+
     pub async fn handle_disconnect(&self, peer_id: &str, room_id: &str) -> Result<()> {
         info!("Starting disconnect process for peer {} from room {}", peer_id, room_id);
         
         // Remove WebSocket sender first
         self.remove_websocket_sender(peer_id).await?;
         
-        // Remove from relay manager
-        self.relay_manager.handle_peer_disconnect(peer_id, room_id).await?;
+        // Remove from the room and its relay layer, or from its lobby
+        self.room_manager.leave_lobby(room_id, peer_id).await;
+        self.room_manager.remove_peer_from_room(room_id, peer_id).await?;
         
         // Remove from peer_rooms tracking
         let removed = self.peer_rooms.write().await.remove(peer_id);
+        self.peer_claims.write().await.remove(peer_id);
+        self.peer_addrs.write().await.remove(peer_id);
+        self.sessions.write().await.remove(peer_id);
+        self.outboxes.lock().await.remove(peer_id);
+        self.seen_ids.lock().await.remove(peer_id);
+        self.peer_features.write().await.remove(peer_id);
+        self.http_resources.write().await.retain(|_, resource_peer| resource_peer != peer_id);
         info!("Removed peer {} from room tracking: {:?}", peer_id, removed);
         
-        // Get remaining peers after removal
-        let remaining_peers: Vec<String> = self.peer_rooms
-            .read()
-            .await
-            .iter()
-            .filter_map(|(pid, rid)| {
-                if rid == room_id {
-                    Some(pid.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        
-        // Create peer list message
-        let peer_list_msg = SignalingMessage::PeerList {
-            room_id: room_id.to_string(),
-            peers: remaining_peers.clone(),
-        };
-        
+        // Tell the remaining peers
+        let peer_list_msg = self.peer_list(room_id).await;
         info!("Broadcasting updated peer list: {:?}", peer_list_msg);
+        self.send_message(&peer_list_msg).await?;
         
-        // Directly send to remaining peers instead of using broadcast_message
-        let json = serde_json::to_string(&peer_list_msg)?;
-        let senders = self.websocket_senders.read().await;
-        
-        for remaining_peer in &remaining_peers {
-            if let Some(ws_conn) = senders.get(remaining_peer) {
-                if let Err(e) = ws_conn.send(json.clone()).await {
-                    warn!("Failed to send updated peer list to {}: {}", remaining_peer, e);
+        Ok(())
+    }
+
+    pub async fn set_websocket_sender(&self, peer_id: String, ws_conn: WebSocketConnection) -> Result<()> {
+        let mut senders = self.websocket_senders.write().await;
+        senders.insert(peer_id, ws_conn);
+        Ok(())
+    }
+
+    /// Records the address a peer connects from, before it joins.
+    pub async fn set_peer_addr(&self, peer_id: &str, ip: IpAddr) {
+        self.peer_addrs.write().await.insert(peer_id.to_string(), ip);
+    }
+
+    pub async fn get_peer_addr(&self, peer_id: &str) -> Option<IpAddr> {
+        self.peer_addrs.read().await.get(peer_id).copied()
+    }
+
+    pub async fn remove_peer_addr(&self, peer_id: &str) {
+        self.peer_addrs.write().await.remove(peer_id);
+    }
+
+    /// Whether some client currently speaks for `peer_id`: it has a socket,
+    /// or is in a room without waiting to resume its session.
+    pub async fn is_connected(&self, peer_id: &str) -> bool {
+        if self.websocket_senders.read().await.contains_key(peer_id) {
+            return true;
+        }
+        if !self.peer_rooms.read().await.contains_key(peer_id) {
+            return false;
+        }
+        !self.sessions.read().await.get(peer_id).is_some_and(|session| session.suspended)
+    }
+
+    /// Joins a peer to a room, or parks it in the room's lobby unless its
+    /// token makes it a moderator. Fails with `Error::Unauthorized` if the
+    /// room's bans, lock or password keep the peer out.
+    pub async fn handle_join(
+        &self,
+        room_id: String,
+        peer_id: String,
+        metadata: HashMap<String, String>,
+        password: Option<String>,
+    ) -> Result<()> {
+        let role = self.peer_claims.read().await.get(&peer_id).and_then(|claims| claims.role);
+        let moderator = role.is_some_and(|role| role.allows(Permission::Moderate));
+        // A fresh join starts a fresh message stream
+        self.outboxes.lock().await.remove(&peer_id);
+        if let Ok(room) = self.room_manager.get_room(&room_id).await {
+            let ip = self.peer_addrs.read().await.get(&peer_id).copied();
+            if let Err(e) = room.check_admission(&peer_id, ip, password.as_deref(), moderator) {
+                warn!("Peer {} may not join room {}: {}", peer_id, room_id, e);
+                self.peer_addrs.write().await.remove(&peer_id);
+                return Err(e);
+            }
+        }
+
+        if !moderator {
+            let entry = LobbyEntry { peer_id: peer_id.clone(), metadata: metadata.clone(), role };
+            if self.room_manager.park_in_lobby(&room_id, entry).await {
+                info!("Peer {} is waiting in the lobby of room {}", peer_id, room_id);
+                let waiting = SignalingMessage::LobbyWaiting { room_id: room_id.clone(), peer_id: peer_id.clone() };
+                self.send_to_peer(&peer_id, &waiting).await?;
+                let request = SignalingMessage::LobbyRequest { room_id: room_id.clone(), peer_id, metadata };
+                self.send_message(&request).await?;
+                return Ok(());
+            }
+        }
+
+        self.join_room(room_id.clone(), peer_id.clone(), RelayMode::Interactive, role).await?;
+        self.start_session(&room_id, &peer_id).await?;
+
+        // Moderators see who is already waiting
+        if self.room_manager.get_role(&room_id, &peer_id).await.is_some_and(|role| role.allows(Permission::Moderate)) {
+            let room = self.room_manager.get_room(&room_id).await?;
+            for waiting in room.lobby {
+                let request = SignalingMessage::LobbyRequest {
+                    room_id: room_id.clone(),
+                    peer_id: waiting.peer_id,
+                    metadata: waiting.metadata,
+                };
+                self.send_to_peer(&peer_id, &request).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a peer joining over HTTP (WHIP/WHEP) from `ip` may enter
+    /// `room_id`, as `handle_join` does for socket peers. HTTP peers can
+    /// neither wait in a lobby nor give a password, so rooms with either
+    /// turn them away unless their token makes them moderators.
+    pub async fn check_http_admission(&self, room_id: &str, peer_id: &str, ip: Option<IpAddr>, role: Option<Role>) -> Result<()> {
+        let Ok(room) = self.room_manager.get_room(room_id).await else { return Ok(()) };
+        let moderator = role.is_some_and(|role| role.allows(Permission::Moderate));
+        room.check_admission(peer_id, ip, None, moderator)?;
+        if room.lobby_enabled && !moderator {
+            return Err(Error::Unauthorized(format!("Room {} admits peers through its lobby only", room_id)));
+        }
+        Ok(())
+    }
+
+    /// Turns a room's lobby on or off, admitting everyone waiting when it
+    /// is turned off. Those the room's bans or lock now keep out are turned
+    /// away instead.
+    pub async fn set_lobby_enabled(&self, room_id: &str, enabled: bool) -> Result<()> {
+        self.room_manager.set_lobby_enabled(room_id, enabled).await?;
+        info!("Lobby of room {} is now {}", room_id, if enabled { "on" } else { "off" });
+        if !enabled {
+            let room = self.room_manager.get_room(room_id).await?;
+            for waiting in room.lobby {
+                match self.admit_peer(room_id, &waiting.peer_id).await {
+                    Err(Error::Unauthorized(_)) => {}
+                    result => result?,
                 }
             }
         }
-        
         Ok(())
     }
 
-    pub async fn set_websocket_sender(&self, peer_id: String, ws_conn: WebSocketConnection) -> Result<()> {
+    pub async fn set_room_locked(&self, room_id: &str, locked: bool) -> Result<()> {
+        self.room_manager.update_room(room_id, |room| room.locked = locked).await?;
+        info!("Room {} is now {}", room_id, if locked { "locked" } else { "unlocked" });
+        Ok(())
+    }
+
+    pub async fn set_room_password(&self, room_id: &str, password: Option<&str>) -> Result<()> {
+        self.room_manager.update_room(room_id, |room| room.set_password(password)).await??;
+        info!("Password of room {} {}", room_id, if password.is_some() { "set" } else { "removed" });
+        Ok(())
+    }
+
+    /// Bans a peer id, and the address it connected from when
+    /// `ban_address` is set, removing the peer from the room or its lobby.
+    pub async fn ban_peer(&self, room_id: &str, peer_id: &str, ban_address: bool, reason: &str) -> Result<()> {
+        let ip = match ban_address {
+            true => self.peer_addrs.read().await.get(peer_id).copied(),
+            false => None,
+        };
+        self.room_manager.update_room(room_id, |room| {
+            room.banned_peers.insert(peer_id.to_string());
+            room.banned_ips.extend(ip);
+        }).await?;
+        info!("Banned peer {} (address {:?}) from room {}", peer_id, ip, room_id);
+        self.remove_banned(room_id, reason).await
+    }
+
+    /// Bans an IP address, removing peers connected from it.
+    pub async fn ban_address(&self, room_id: &str, ip: IpAddr, reason: &str) -> Result<()> {
+        self.room_manager.update_room(room_id, |room| room.banned_ips.insert(ip)).await?;
+        info!("Banned address {} from room {}", ip, room_id);
+        self.remove_banned(room_id, reason).await
+    }
+
+    /// Lifts a ban on a peer id. Returns whether it was banned.
+    pub async fn unban_peer(&self, room_id: &str, peer_id: &str) -> Result<bool> {
+        self.room_manager.update_room(room_id, |room| room.banned_peers.remove(peer_id)).await
+    }
+
+    pub async fn unban_address(&self, room_id: &str, ip: IpAddr) -> Result<bool> {
+        self.room_manager.update_room(room_id, |room| room.banned_ips.remove(&ip)).await
+    }
+
+    /// Removes everyone in a room or its lobby whom its bans now cover.
+    async fn remove_banned(&self, room_id: &str, reason: &str) -> Result<()> {
+        let room = self.room_manager.get_room(room_id).await?;
+        let addrs = self.peer_addrs.read().await.clone();
+        let banned = |peer_id: &str| {
+            room.banned_peers.contains(peer_id) || addrs.get(peer_id).is_some_and(|ip| room.banned_ips.contains(ip))
+        };
+        for waiting in room.lobby.iter().filter(|waiting| banned(&waiting.peer_id)) {
+            self.deny_peer(room_id, &waiting.peer_id, reason).await?;
+        }
+        for (peer_id, _) in room.peers.iter().filter(|(peer_id, _)| banned(peer_id)) {
+            self.kick_peer(room_id, peer_id, reason).await?;
+        }
+        Ok(())
+    }
+
+    /// Lets a peer waiting in the lobby into the room. If the room was
+    /// locked or the peer banned while it waited, it is turned away and
+    /// this fails with `Error::Unauthorized`.
+    pub async fn admit_peer(&self, room_id: &str, peer_id: &str) -> Result<()> {
+        let room = self.room_manager.get_room(room_id).await?;
+        if room.lobby.iter().any(|waiting| waiting.peer_id == peer_id) {
+            let ip = self.peer_addrs.read().await.get(peer_id).copied();
+            if let Err(e) = room.check_entry(peer_id, ip, false) {
+                warn!("Peer {} may no longer join room {}: {}", peer_id, room_id, e);
+                self.deny_peer(room_id, peer_id, &e.to_string()).await?;
+                return Err(e);
+            }
+        }
+        let entry = self.room_manager.leave_lobby(room_id, peer_id).await
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not waiting to join room {}", peer_id, room_id)))?;
+        info!("Admitting peer {} to room {}", peer_id, room_id);
+        self.join_room(room_id.to_string(), entry.peer_id, RelayMode::Interactive, entry.role).await?;
+        self.start_session(room_id, peer_id).await
+    }
+
+    /// Issues a joined peer the token it can resume its session with.
+    async fn start_session(&self, room_id: &str, peer_id: &str) -> Result<()> {
+        if self.resume_grace.is_zero() {
+            return Ok(());
+        }
+        let session = Session {
+            room_id: room_id.to_string(),
+            resume_token: Uuid::new_v4().to_string(),
+            suspended: false,
+        };
+        let notice = SignalingMessage::ResumeToken {
+            room_id: room_id.to_string(),
+            peer_id: peer_id.to_string(),
+            resume_token: session.resume_token.clone(),
+            grace_period_secs: self.resume_grace.as_secs(),
+        };
+        self.sessions.write().await.insert(peer_id.to_string(), session);
+        self.send_message(&notice).await?;
+        Ok(())
+    }
+
+    /// Handles the loss of `ws_conn`, the socket `peer_id` joined `room_id`
+    /// on. A peer with a session is suspended for the grace period and only
+    /// leaves its room if it does not resume in time; others leave now.
+    /// Does nothing if the peer has since moved to another socket or was
+    /// already removed, e.g. kicked.
+    pub async fn connection_lost(&self, peer_id: &str, room_id: &str, ws_conn: &WebSocketConnection) -> Result<()> {
+        match self.get_websocket_sender(peer_id).await? {
+            Some(current) if current.same_socket(ws_conn) => {}
+            _ => return Ok(()),
+        }
+
+        let resume_token = {
+            let mut sessions = self.sessions.write().await;
+            match sessions.get_mut(peer_id) {
+                Some(session) if session.room_id == room_id => {
+                    session.suspended = true;
+                    Some(session.resume_token.clone())
+                }
+                _ => None,
+            }
+        };
+        let Some(resume_token) = resume_token else {
+            return self.handle_disconnect(peer_id, room_id).await;
+        };
+
+        self.remove_websocket_sender(peer_id).await?;
+        info!("Peer {} lost its connection, holding its session for {:?}", peer_id, self.resume_grace);
+        let handler = self.clone();
+        let (peer_id, room_id) = (peer_id.to_string(), room_id.to_string());
+        tokio::spawn(async move {
+            tokio::time::sleep(handler.resume_grace).await;
+            // A resumed or restarted session has a new token
+            let expired = matches!(
+                handler.sessions.read().await.get(&peer_id),
+                Some(session) if session.suspended && session.resume_token == resume_token
+            );
+            if expired {
+                info!("Session of peer {} expired", peer_id);
+                if let Err(e) = handler.handle_disconnect(&peer_id, &room_id).await {
+                    error!("Error handling disconnect for peer {}: {}", peer_id, e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Moves a suspended peer's session to a new socket, keeping its room
+    /// membership and peer connection. The peer first gets the messages
+    /// after `last_seq` again, or all unacknowledged ones, then a fresh
+    /// resume token and the current peer list.
+    pub async fn resume_session(
+        &self,
+        room_id: &str,
+        peer_id: &str,
+        resume_token: &str,
+        last_seq: Option<u64>,
+        ws_conn: WebSocketConnection,
+    ) -> Result<()> {
+        let session = self.sessions.read().await.get(peer_id).cloned();
+        let valid = session.as_ref().is_some_and(|session| session.room_id == room_id && session.resume_token == resume_token);
+        if !valid || !self.room_manager.has_peer(room_id, peer_id).await {
+            return Err(Error::Unauthorized(format!("No session of peer {} to resume in room {}", peer_id, room_id)));
+        }
+        let still_connected = Error::Unauthorized(format!("Peer {} is still connected", peer_id));
+        // The old socket would otherwise go on speaking for the peer beside the new one
+        if !session.is_some_and(|session| session.suspended) {
+            return Err(still_connected);
+        }
+        if self.peer_claims.read().await.get(peer_id).is_some_and(Claims::is_expired) {
+            return Err(Error::Unauthorized("Access token expired".to_string()));
+        }
+
+        if let Some(seq) = last_seq {
+            self.acknowledge(peer_id, seq).await;
+        }
+        // Messages routed meanwhile wait for the outbox, so they follow the replay
         let mut senders = self.websocket_senders.write().await;
-        senders.insert(peer_id, ws_conn);
+        // Another socket may have resumed the session since
+        if senders.contains_key(peer_id) {
+            return Err(still_connected);
+        }
+        let outboxes = self.outboxes.lock().await;
+        senders.insert(peer_id.to_string(), ws_conn.clone());
+        drop(senders);
+        let missed = outboxes.get(peer_id).map_or(0, |outbox| outbox.unacked.len());
+        for (_, json) in outboxes.get(peer_id).into_iter().flat_map(|outbox| &outbox.unacked) {
+            ws_conn.send(json.clone()).await?;
+        }
+        drop(outboxes);
+        info!("Peer {} resumed its session in room {}, replayed {} messages", peer_id, room_id, missed);
+        self.start_session(room_id, peer_id).await?;
+        self.handle_peer_list_request(room_id.to_string(), peer_id).await
+    }
+
+    /// Turns a peer waiting in the lobby away, telling it not to retry.
+    pub async fn deny_peer(&self, room_id: &str, peer_id: &str, reason: &str) -> Result<()> {
+        self.room_manager.leave_lobby(room_id, peer_id).await
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not waiting to join room {}", peer_id, room_id)))?;
+        info!("Denying peer {} entry to room {}: {}", peer_id, room_id, reason);
+        let notice = SignalingMessage::ConnectionError {
+            peer_id: peer_id.to_string(),
+            error: reason.to_string(),
+            should_retry: false,
+        };
+        self.send_message(&notice).await?;
         Ok(())
     }
 
-    pub async fn handle_join(&self, room_id: String, peer_id: String) -> Result<()> {
-        // Add to relay manager
-        self.relay_manager.add_peer(&room_id, peer_id.clone()).await?;
+    /// Adds a peer to a room and tells the room, returning the peer's relay.
+    /// Without a `role` the peer gets the room's default for its mode.
+    pub async fn join_room(&self, room_id: String, peer_id: String, mode: RelayMode, role: Option<Role>) -> Result<MediaRelay> {
+        // A peer can only be in one room at a time
+        if let Some(previous_room) = self.get_peer_room(&peer_id).await {
+            if previous_room != room_id {
+                self.room_manager.remove_peer_from_room(&previous_room, &peer_id).await?;
+            }
+        }
+
+        // Add to the room, which creates the peer's relay. The role is given
+        // right away, so the peer never holds the default one
+        let relay = self.room_manager.add_peer_with_mode(&room_id, peer_id.clone(), mode, role).await?;
         
         // Add to peer_rooms tracking
         self.peer_rooms.write().await.insert(peer_id.clone(), room_id.clone());
         
-        // Get updated peer list from peer_rooms
-        let peer_ids: Vec<String> = self.peer_rooms
-            .read()
-            .await
-            .iter()
-            .filter_map(|(pid, rid)| {
-                if rid == &room_id {
-                    Some(pid.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        
         // Create peer list message
-        let peer_list_msg = SignalingMessage::PeerList {
-            room_id: room_id.clone(),
-            peers: peer_ids,
-        };
+        let peer_list_msg = self.peer_list(&room_id).await;
         
         // Broadcast to all connected peers
         self.broadcast_message(&peer_list_msg).await?;
         
+        Ok(relay)
+    }
+
+    /// Opens the HTTP resource of a WHIP or WHEP peer and returns its id.
+    /// Other participants learn the peer's id, so the resource gets a
+    /// random one that only its creator sees.
+    pub async fn open_http_resource(&self, peer_id: &str) -> String {
+        let resource_id = Uuid::new_v4().to_string();
+        self.http_resources.write().await.insert(resource_id.clone(), peer_id.to_string());
+        resource_id
+    }
+
+    /// The peer behind an HTTP resource, until that peer leaves.
+    pub async fn http_resource_peer(&self, resource_id: &str) -> Option<String> {
+        self.http_resources.read().await.get(resource_id).cloned()
+    }
+
+    pub async fn handle_peer_list_request(&self, room_id: String, peer_id: &str) -> Result<()> {
+        // Only members may see who else is in a room
+        if !self.room_manager.has_peer(&room_id, peer_id).await {
+            return Err(Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)));
+        }
+
+        let peer_list_msg = self.peer_list(&room_id).await;
+        self.send_to_peer(peer_id, &peer_list_msg).await?;
         Ok(())
     }
 
-    pub async fn handle_peer_list_request(&self, room_id: String) -> Result<()> {
-        let relays = self.relay_manager.get_relays().await?;
-        let peer_ids: Vec<String> = relays.keys().cloned().collect();
-        
-        let peer_list_msg = SignalingMessage::PeerList {
-            room_id,
-            peers: peer_ids,
+    /// The room's members and their roles.
+    async fn peer_list(&self, room_id: &str) -> SignalingMessage {
+        let roles = match self.room_manager.get_room(room_id).await {
+            Ok(room) => room.roles,
+            Err(_) => HashMap::new(),
         };
-        
-        self.send_message(&peer_list_msg).await?;
+        SignalingMessage::PeerList {
+            room_id: room_id.to_string(),
+            peers: self.room_manager.get_room_peers(room_id).await,
+            roles,
+        }
+    }
+
+    /// Changes a participant's role and tells the room. Tracks of peers that
+    /// may no longer publish are muted and withdrawn from the room.
+    pub async fn set_role(&self, room_id: &str, peer_id: &str, role: Role) -> Result<()> {
+        // Muted while still published, so the room hears which tracks go away
+        if !role.allows(Permission::Publish) {
+            self.set_track_muted(room_id, peer_id, None, true).await?;
+        }
+        self.room_manager.set_role(room_id, peer_id, role).await?;
+        info!("Peer {} is now {:?} in room {}", peer_id, role, room_id);
+        let notice = SignalingMessage::RoleChanged {
+            room_id: room_id.to_string(),
+            peer_id: peer_id.to_string(),
+            role,
+        };
+        self.send_message(&notice).await?;
         Ok(())
     }
 
-    pub async fn broadcast_message(&self, msg: &SignalingMessage) -> Result<()> {
-        let json = serde_json::to_string(msg)?;
-        let senders = self.websocket_senders.read().await;
-        let peer_rooms = self.peer_rooms.read().await;
-        
-        info!("Broadcasting message: {:?}", msg);
-        info!("Current peer rooms state: {:?}", *peer_rooms);
-        
-        let mut failed_peers = Vec::new();
-        
-        if let SignalingMessage::PeerList { room_id, .. } = msg {
-            for (peer_id, ws_conn) in senders.iter() {
-                if let Some(peer_room) = peer_rooms.get(peer_id) {
-                    if peer_room == room_id {
-                        info!("Sending to peer {} in room {}", peer_id, room_id);
-                        if let Err(e) = ws_conn.send(json.clone()).await {
-                            warn!("Failed to send message to peer {}: {}", peer_id, e);
-                            failed_peers.push(peer_id.clone());
-                        }
-                    }
-                }
+    /// Removes a peer on a moderator's behalf, telling it not to reconnect.
+    pub async fn kick_peer(&self, room_id: &str, peer_id: &str, reason: &str) -> Result<()> {
+        self.evict_peer(room_id, peer_id, reason, false).await
+    }
+
+    /// Removes peers whose media connection failed or closed for good, as if
+    /// they had left. They are told they may join again.
+    pub async fn remove_stale_peers(&self) {
+        for (room_id, peer_id) in self.relay_manager.stale_relays().await {
+            if let Err(e) = self.evict_peer(&room_id, &peer_id, "Media connection lost", true).await {
+                error!("Error removing stale peer {}: {}", peer_id, e);
             }
         }
-        
-        // Clean up any failed connections
-        drop(senders);  // Release the read lock before taking write lock
-        if !failed_peers.is_empty() {
-            let mut senders = self.websocket_senders.write().await;
-            for peer_id in failed_peers {
-                senders.remove(&peer_id);
-                if let Some(room_id) = peer_rooms.get(&peer_id) {
-                    // Handle disconnect for failed peers
-                    if let Err(e) = self.handle_disconnect(&peer_id, room_id).await {
-                        error!("Error handling disconnect for failed peer {}: {}", peer_id, e);
-                    }
-                }
+    }
+
+    async fn evict_peer(&self, room_id: &str, peer_id: &str, reason: &str, should_retry: bool) -> Result<()> {
+        if !self.room_manager.has_peer(room_id, peer_id).await {
+            return Err(Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)));
+        }
+        let notice = SignalingMessage::ConnectionError {
+            peer_id: peer_id.to_string(),
+            error: reason.to_string(),
+            should_retry,
+        };
+        if let Err(e) = self.send_message(&notice).await {
+            warn!("Failed to notify {} of removal: {}", peer_id, e);
+        }
+        info!("Removing peer {} from room {}: {}", peer_id, room_id, reason);
+        let socket = self.get_websocket_sender(peer_id).await?;
+        self.handle_disconnect(peer_id, room_id).await?;
+        // Its connection would otherwise go on speaking for a peer that left
+        if let Some(socket) = socket {
+            if let Err(e) = socket.close().await {
+                warn!("Failed to close the connection of {}: {}", peer_id, e);
             }
         }
-        
+        Ok(())
+    }
+
+    /// Removes every peer from a room, turns away those waiting in its lobby
+    /// and closes it.
+    pub async fn close_room(&self, room_id: &str, reason: &str) -> Result<()> {
+        let room = self.room_manager.get_room(room_id).await?;
+        // Before the peers leave, as the room may go with the last of them
+        for waiting in room.lobby {
+            self.deny_peer(room_id, &waiting.peer_id, reason).await?;
+        }
+        for peer_id in self.room_manager.get_room_peers(room_id).await {
+            self.kick_peer(room_id, &peer_id, reason).await?;
+        }
+        // Rooms created ahead of time outlive their last peer
+        if self.room_manager.get_room(room_id).await.is_ok() {
+            self.room_manager.remove_room(room_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Mutes or unmutes a peer's published track, or all of its tracks, and
+    /// tells the room. Returns the ids of the tracks that changed.
+    pub async fn set_track_muted(&self, room_id: &str, peer_id: &str, track_id: Option<&str>, muted: bool) -> Result<Vec<String>> {
+        let relay = self.relay_manager.get_relay(room_id, peer_id).await
+            .ok_or_else(|| Error::NotFound(format!("Peer {} is not in room {}", peer_id, room_id)))?;
+        let changed = relay.set_published_muted(track_id, muted).await;
+        for track_id in &changed {
+            let notice = SignalingMessage::TrackMuted {
+                room_id: room_id.to_string(),
+                peer_id: peer_id.to_string(),
+                track_id: track_id.clone(),
+                muted,
+            };
+            self.send_message(&notice).await?;
+        }
+        Ok(changed)
+    }
+
+    pub fn room_manager(&self) -> Arc<RoomManager> {
+        self.room_manager.clone()
+    }
+
+    /// Sends a room-addressed message, such as a `PeerList`, to the room.
+    pub async fn broadcast_message(&self, msg: &SignalingMessage) -> Result<()> {
+        self.send_message(msg).await?;
         Ok(())
     }
 
@@ -368,7 +1193,51 @@ impl MessageHandler {
         self.peer_rooms.read().await.get(peer_id).cloned()
     }
 
+    /// Delivers what the media layer raises: server offers go to the peer they
+    /// are addressed to, room notifications to every member of the room.
+    pub async fn start_room_events(self: Arc<Self>) {
+        let mut events = self.relay_manager.subscribe_events();
+        tokio::spawn(async move {
+            loop {
+                let message = match events.recv().await {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Dropped {} room events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                match self.send_message(&message).await {
+                    Ok(Delivery::Undeliverable) if matches!(message.recipient(), Some(Recipient::Peer(_))) => {
+                        warn!("Server signaling {:?} was undeliverable", message.recipient());
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to deliver room event: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Sends `msg` to a single peer, if it is connected.
+    pub async fn send_to_peer(&self, peer_id: &str, msg: &SignalingMessage) -> Result<Delivery> {
+        self.route(&Recipient::Peer(peer_id.to_string()), msg).await
+    }
+
+    /// Sends `msg` to every peer currently in `room_id`.
+    pub async fn send_to_room(&self, room_id: &str, msg: &SignalingMessage) -> Result<Delivery> {
+        self.route(&Recipient::Room(room_id.to_string()), msg).await
+    }
+
     pub async fn start_stale_peer_cleanup(self: Arc<Self>) {
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let monitor_interval = Duration::from_secs(30);
+            loop {
+                tokio::time::sleep(monitor_interval).await;
+                handler.remove_stale_peers().await;
+            }
+        });
+
         tokio::spawn(async move {
             let cleanup_interval = Duration::from_secs(2);
             loop {
@@ -385,15 +1254,15 @@ impl MessageHandler {
                 
                 for (peer_id, ws_conn) in senders.iter() {
                     if let Err(_) = ws_conn.ping().await {
-                        stale_peers.push(peer_id.clone());
+                        stale_peers.push((peer_id.clone(), ws_conn.clone()));
                         warn!("Detected stale connection for peer: {}", peer_id);
                     }
                 }
                 drop(senders);
                 
-                for peer_id in stale_peers {
+                for (peer_id, ws_conn) in stale_peers {
                     if let Some(room_id) = self.peer_rooms.read().await.get(&peer_id).cloned() {
-                        if let Err(e) = self.handle_disconnect(&peer_id, &room_id).await {
+                        if let Err(e) = self.connection_lost(&peer_id, &room_id, &ws_conn).await {
                             error!("Error cleaning up stale peer {}: {}", peer_id, e);
                         }
                     }
@@ -408,45 +1277,21 @@ impl MessageHandler {
         
         for (peer_id, ws_conn) in senders.iter() {
             if let Err(_) = ws_conn.ping().await {
-                stale_peers.push(peer_id.clone());
+                stale_peers.push((peer_id.clone(), ws_conn.clone()));
             }
         }
         drop(senders);
         
-        for peer_id in stale_peers {
+        for (peer_id, ws_conn) in stale_peers {
             if let Some(room_id) = self.peer_rooms.read().await.get(&peer_id).cloned() {
                 info!("Removing stale peer {} from room {}", peer_id, room_id);
-                self.handle_disconnect(&peer_id, &room_id).await?;
+                self.connection_lost(&peer_id, &room_id, &ws_conn).await?;
             }
         }
         
         Ok(())
     }
 
-    async fn handle_call_request(&self, room_id: &str, from_peer: &str, to_peer: &str) -> Result<()> {
-        // Start recording if recording manager exists
-        if let Some(recording_manager) = &self.recording_manager {
-            recording_manager.start_call_recording(
-                room_id,
-                vec![from_peer.to_string(), to_peer.to_string()]
-            ).await?;
-        }
-
-        // Forward the call request to the target peer
-        if let Some(ws_sender) = self.websocket_senders.read().await.get(to_peer) {
-            let message = SignalingMessage::CallRequest {
-                room_id: room_id.to_string(),
-                from_peer: from_peer.to_string(),
-                to_peers: vec![to_peer.to_string()],
-                sdp: String::new(), // You might want to pass the actual SDP here
-            };
-            ws_sender.send(serde_json::to_string(&message)?).await?;
-            debug!("Forwarded call request to {}", to_peer);
-        }
-
-        Ok(())  // Add explicit return
-    }
-
     async fn handle_participant_join(&self, room_id: &str, peer_id: &str) -> Result<()> {
         if let Some(recording_manager) = &self.recording_manager {
             recording_manager.add_participant(room_id, peer_id).await?;
@@ -462,24 +1307,6 @@ impl MessageHandler {
         Ok(())
     }
 
-    pub async fn handle_call_response(
-        &self,
-        room_id: &str,
-        from_peer: &str,
-        to_peer: &str,
-        accepted: bool,
-    ) -> Result<()> {
-        if accepted {
-            if let Some(recording_manager) = &self.recording_manager {
-                recording_manager.start_call_recording(
-                    room_id,
-                    vec![from_peer.to_string(), to_peer.to_string()]
-                ).await?;
-            }
-        }
-        Ok(())
-    }
-
     pub(crate) async fn handle_voip_rtp(&self, peer_id: &str, packet: RTPPacket) -> Result<()> {
         self.handle_rtp_packet("default", peer_id, &packet).await?;
         Ok(())
@@ -487,7 +1314,7 @@ impl MessageHandler {
 
     pub async fn handle_voip_connect(&self, peer_id: &str, room_id: &str) -> Result<()> {
         // Create a new WebRTC peer connection for the VoIP call
-        let relay = self.relay_manager.create_relay(peer_id.to_string()).await?;
+        let relay = self.relay_manager.create_relay(room_id, peer_id.to_string()).await?;
         
         // Add audio transceiver for VoIP
         let _ = relay.peer_connection.add_transceiver_from_kind(
@@ -510,9 +1337,7 @@ impl MessageHandler {
             sdp: offer.sdp,
         };
 
-        if let Some(ws_sender) = self.get_websocket_sender(peer_id).await? {
-            ws_sender.send(serde_json::to_string(&message)?).await?;
-        }
+        self.send_message(&message).await?;
 
         Ok(())
     }
@@ -1,7 +1,14 @@
+pub mod admin;
+pub mod auth;
+pub mod connection;
 pub mod handler;
+pub mod protocol;
+pub mod routing;
 pub mod server;
 pub mod stun;
+pub mod transport;
 pub mod turn;
+pub mod whip;
 pub mod connection_state;
 
 pub use crate::types::PeerConnection;
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use crate::utils::{Error, Result};
+
+/// Signaling protocol version this server speaks.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest client protocol version still served. Clients that send no
+/// `Hello` are taken to speak this one.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol behaviour a client or the server supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// ICE candidates are exchanged as they are gathered
+    Trickle,
+    /// Either side may send new offers on an established connection
+    Renegotiation,
+    /// Publishers may send several encodings of a video track
+    Simulcast,
+    /// Messages may arrive in binary frames holding UTF-8 JSON
+    BinaryFrames,
+    /// Server messages carry a `seq`, are acknowledged with `Ack` and replayed on `Resume`
+    Acks,
+    /// Anything a newer client declares that this server does not know
+    #[serde(other)]
+    Unknown,
+}
+
+impl Feature {
+    /// Everything this server supports.
+    pub const SERVER: [Feature; 5] = [Feature::Trickle, Feature::Renegotiation, Feature::Simulcast, Feature::BinaryFrames, Feature::Acks];
+    /// What clients that send no `Hello` are assumed to support: everything
+    /// the server offered before the handshake existed.
+    pub const LEGACY: [Feature; 3] = [Feature::Trickle, Feature::Renegotiation, Feature::Simulcast];
+}
+
+/// Fails unless a client speaking `version` can be served.
+pub fn check_version(version: u32) -> Result<()> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(Error::UnsupportedProtocol(format!(
+            "Protocol version {} is not supported, use {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// The client's features the server supports too.
+pub fn negotiate(client_features: &[Feature]) -> Vec<Feature> {
+    Feature::SERVER.into_iter().filter(|feature| client_features.contains(feature)).collect()
+}
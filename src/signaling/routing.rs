@@ -0,0 +1,67 @@
+use crate::room::state::Role;
+use crate::types::SignalingMessage;
+
+/// Who a signaling message is for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recipient {
+    Peer(String),
+    /// Every peer connected to the room
+    Room(String),
+    /// The room's peers that have one of `roles`
+    Roles { room_id: String, roles: Vec<Role> },
+}
+
+impl Recipient {
+    /// The room's hosts and moderators.
+    pub fn moderators(room_id: &str) -> Self {
+        Recipient::Roles { room_id: room_id.to_string(), roles: vec![Role::Host, Role::Moderator] }
+    }
+}
+
+/// Outcome of routing a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Sent to this many peers
+    Delivered(usize),
+    /// None of the addressed peers is connected
+    Undeliverable,
+}
+
+impl Delivery {
+    pub fn is_delivered(&self) -> bool {
+        matches!(self, Delivery::Delivered(_))
+    }
+}
+
+impl SignalingMessage {
+    /// Who the server delivers this message to when it sends it. Messages
+    /// only clients send, and call requests with several targets, have none.
+    pub fn recipient(&self) -> Option<Recipient> {
+        match self {
+            SignalingMessage::CallResponse { to_peer, .. }
+            | SignalingMessage::Offer { to_peer, .. }
+            | SignalingMessage::Answer { to_peer, .. }
+            | SignalingMessage::IceCandidate { to_peer, .. } => Some(Recipient::Peer(to_peer.clone())),
+            SignalingMessage::ConnectionError { peer_id, .. }
+            | SignalingMessage::MediaError { peer_id, .. }
+            | SignalingMessage::IceRestart { peer_id, .. }
+            | SignalingMessage::ResumeToken { peer_id, .. }
+            | SignalingMessage::LobbyWaiting { peer_id, .. } => Some(Recipient::Peer(peer_id.clone())),
+            SignalingMessage::PeerList { room_id, .. }
+            | SignalingMessage::PeerDisconnected { room_id, .. }
+            | SignalingMessage::ActiveSpeaker { room_id, .. }
+            | SignalingMessage::AudioLevels { room_id, .. }
+            | SignalingMessage::RoleChanged { room_id, .. }
+            | SignalingMessage::TrackMuted { room_id, .. } => Some(Recipient::Room(room_id.clone())),
+            SignalingMessage::LobbyRequest { room_id, .. } => Some(Recipient::moderators(room_id)),
+            _ => None,
+        }
+    }
+
+    /// Whether this message is soon superseded by the next of its kind. Such
+    /// messages are sent unsequenced and never replayed, so they can't push
+    /// what a resuming peer needs out of its replay buffer.
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, SignalingMessage::ActiveSpeaker { .. } | SignalingMessage::AudioLevels { .. })
+    }
+}
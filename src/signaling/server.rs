@@ -1,9 +1,11 @@
 use crate::utils::{Error, Result};
 use crate::signaling::handler::MessageHandler;
-use crate::types::{SignalingMessage, WebSocketConnection, TurnCredentials};
+use crate::types::{SignalingMessage, TurnCredentials};
 use tokio::net::{TcpListener, TcpStream};
 use std::sync::Arc;
-use tokio_tungstenite::{accept_async, WebSocketStream};
+use tokio_tungstenite::{accept_hdr_async, WebSocketStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use futures_util::{StreamExt, SinkExt};
 use log::{info, warn, error, debug};
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
@@ -20,11 +22,12 @@ use crate::monitoring::{ConnectionMonitor, StateChangeBroadcaster, StateChangeEv
 use crate::signaling::connection_state::{ConnectionState, ConnectionStateManager};
 use warp::Rejection;
 use std::collections::HashMap;
-use uuid::Uuid;
 use chrono::Utc;
-use warp::ws::Message as WarpMessage;
 use std::path::PathBuf;
 use crate::config::ServerConfig;
+use crate::signaling::auth::{self, TokenVerifier};
+use crate::signaling::connection::Connection;
+use crate::signaling::transport::Frame;
 
 pub struct SignalingServer {
     pub address: String,
@@ -52,13 +55,26 @@ impl SignalingServer {
         let connection_monitor = Arc::new(ConnectionMonitor::new());
         let state_manager = Arc::new(ConnectionStateManager::new());
         let state_broadcaster = Arc::new(StateChangeBroadcaster::new());
+
+        state_manager.clone().follow_peer_connections(&relay_manager);
+
+        let mut handler = MessageHandler::new(
+            relay_manager,
+            config.recording_path.clone()
+        );
+        match &config.auth {
+            Some(auth) => handler = handler.with_token_verifier(TokenVerifier::new(auth)?),
+            None => warn!("No JWT_SECRET or JWT_PUBLIC_KEY set, peers join without authentication"),
+        }
+        let handler = handler
+            .with_resume_grace(config.resume_grace)
+            .with_ice_servers(config.ice_servers());
+        let handler = Arc::new(handler);
+        handler.clone().start_room_events().await;
         
         Ok(SignalingServer {
             address: format!("0.0.0.0:{}", config.ws_port),
-            handler: Arc::new(MessageHandler::new(
-                relay_manager,
-                config.recording_path.clone()
-            )),
+            handler,
             config,
             turn_secret,
             turn_server,
@@ -82,10 +98,27 @@ impl SignalingServer {
             let state_manager = self.state_manager.clone();
             
             tokio::spawn(async move {
-                let ws_stream = accept_async(stream).await
+                let mut upgrade_token = None;
+                let ws_stream = accept_hdr_async(stream, |request: &Request, response: Response| {
+                    let authorization = request.headers()
+                        .get("authorization")
+                        .and_then(|value| value.to_str().ok());
+                    upgrade_token = auth::upgrade_token(request.uri().query(), authorization);
+                    match upgrade_token.as_deref().filter(|_| handler.requires_auth()) {
+                        Some(token) => match handler.verify_token(token) {
+                            Ok(_) => Ok(response),
+                            Err(e) => {
+                                let mut refusal = ErrorResponse::new(Some(e.to_string()));
+                                *refusal.status_mut() = StatusCode::UNAUTHORIZED;
+                                Err(refusal)
+                            }
+                        },
+                        None => Ok(response),
+                    }
+                }).await
                     .map_err(|e| Error::WebSocketError(e.to_string()))?;
                 
-                if let Err(e) = Self::handle_connection(ws_stream, addr, handler, state_manager).await {
+                if let Err(e) = Self::handle_connection(ws_stream, addr, upgrade_token, handler, state_manager).await {
                     error!("Connection error: {}", e);
                 }
                 Ok::<_, Error>(())
@@ -95,76 +128,18 @@ impl SignalingServer {
         Ok(())
     }
 
+    /// Serves one tungstenite connection. `upgrade_token` is the access
+    /// token presented when the socket was opened, if any.
     pub async fn handle_connection(
         ws: WebSocketStream<TcpStream>,
         addr: SocketAddr,
+        upgrade_token: Option<String>,
         handler: Arc<MessageHandler>,
         state_manager: Arc<ConnectionStateManager>,
     ) -> Result<()> {
-        let (ws_sender, mut ws_receiver) = ws.split();
-        let ws_sender = Arc::new(Mutex::new(ws_sender));
-        let temp_id = format!("temp_{}", Uuid::new_v4());
-        let mut current_peer_id = temp_id.clone();
-        let mut current_room_id = String::new();
-        
-        info!("New WebSocket connection from: {}", addr);
-        
-        let ws_conn = WebSocketConnection::new_tungstenite(ws_sender.clone());
-        handler.set_websocket_sender(temp_id.clone(), ws_conn).await?;
-
-        while let Some(msg) = ws_receiver.next().await {
-            match msg {
-                Ok(msg) => {
-                    if msg.is_close() {
-                        info!("Received close frame from {}", addr);
-                        if current_peer_id != temp_id && !current_room_id.is_empty() {
-                            if let Err(e) = handler.handle_disconnect(&current_peer_id, &current_room_id).await {
-                                error!("Error handling disconnect for peer {}: {}", current_peer_id, e);
-                            }
-                        }
-                        break;
-                    }
-                    
-                    if let Message::Text(text) = msg {
-                        let message: SignalingMessage = serde_json::from_str(&text)?;
-                        
-                        // Add debug logging for all messages
-                        debug!("Received message type: {:?} from peer {}", message, current_peer_id);
-                        
-                        // Update current peer and room IDs when joining
-                        if let SignalingMessage::Join { ref peer_id, ref room_id } = message {
-                            current_peer_id = peer_id.clone();
-                            current_room_id = room_id.clone();
-                            info!("Peer {} joined room {}", peer_id, room_id);
-                        }
-                        
-                        if state_manager.transition(&current_peer_id, ConnectionState::New).await {
-                            handler.handle_message(message, &current_peer_id).await?;
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("WebSocket error for {}: {}", addr, e);
-                    if current_peer_id != temp_id && !current_room_id.is_empty() {
-                        if let Err(e) = handler.handle_disconnect(&current_peer_id, &current_room_id).await {
-                            error!("Error handling disconnect for peer {}: {}", current_peer_id, e);
-                        }
-                    }
-                    break;
-                }
-            }
-        }
-
-        // Ensure cleanup happens on any type of disconnection
-        if current_peer_id != temp_id && !current_room_id.is_empty() {
-            if let Err(e) = handler.handle_disconnect(&current_peer_id, &current_room_id).await {
-                error!("Error handling disconnect for peer {}: {}", current_peer_id, e);
-            }
-        }
-
-        // Clean up temp connection if it still exists
-        handler.remove_websocket_sender(&temp_id).await?;
-
+        let (ws_sender, ws_receiver) = ws.split();
+        let connection = Connection::open(handler, state_manager, Arc::new(Mutex::new(ws_sender)), Some(addr), upgrade_token).await?;
+        connection.serve(ws_receiver.map(|frame| frame.map(Frame::from).map_err(Error::from))).await;
         Ok(())
     }
 
@@ -187,84 +162,56 @@ impl SignalingServer {
 
     pub fn ws_route(&self) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
         let handler = self.handler.clone();
+        let state_manager = self.state_manager.clone();
+        let raw_query = warp::query::raw()
+            .map(Some)
+            .or(warp::any().map(|| None))
+            .unify();
         
         warp::ws()
             .and(warp::addr::remote())
-            .map(move |ws: warp::ws::Ws, addr: Option<SocketAddr>| {
+            .and(raw_query)
+            .and(warp::header::optional::<String>("authorization"))
+            .map(move |ws: warp::ws::Ws, remote: Option<SocketAddr>, query: Option<String>, authorization: Option<String>| {
                 let handler = handler.clone();
-                let addr = addr.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+                let state_manager = state_manager.clone();
+
+                // A token presented here is checked before upgrading and
+                // stands in for one missing from the Join
+                let upgrade_token = auth::upgrade_token(query.as_deref(), authorization.as_deref());
+                if let Some(token) = upgrade_token.as_deref().filter(|_| handler.requires_auth()) {
+                    if let Err(e) = handler.verify_token(token) {
+                        warn!("Refused WebSocket upgrade from {:?}: {}", remote, e);
+                        return warp::reply::with_status(e.to_string(), warp::http::StatusCode::UNAUTHORIZED).into_response();
+                    }
+                }
                 
                 ws.on_upgrade(move |websocket| async move {
-                    let (ws_sender, mut ws_receiver) = websocket.split();
-                    let ws_sender = Arc::new(Mutex::new(ws_sender));
-                    let ws_conn = WebSocketConnection::new_warp(ws_sender.clone());
-                    
-                    let temp_id = format!("temp_{}", addr);
-                    if let Err(e) = handler.set_websocket_sender(temp_id.clone(), ws_conn).await {
-                        error!("Failed to set websocket sender: {}", e);
-                        return;
-                    }
-                    
-                    while let Some(result) = ws_receiver.next().await {
-                        match result {
-                            Ok(msg) => {
-                                if let Ok(text) = msg.to_str() {
-                                    match serde_json::from_str::<SignalingMessage>(text) {
-                                        Ok(message) => {
-                                            match &message {
-                                                SignalingMessage::Join { peer_id, room_id } => {
-                                                    // Create new WebSocket connection with actual peer ID
-                                                    let new_ws_conn = WebSocketConnection::new_warp(ws_sender.clone());
-                                                    if let Err(e) = handler.set_websocket_sender(peer_id.clone(), new_ws_conn).await {
-                                                        error!("Failed to set websocket sender for peer {}: {}", peer_id, e);
-                                                        continue;
-                                                    }
-                                                    
-                                                    // Remove temporary connection
-                                                    if let Err(e) = handler.remove_websocket_sender(&temp_id).await {
-                                                        error!("Failed to remove temporary connection: {}", e);
-                                                    }
-                                                    
-                                                    // Handle the join message
-                                                    let join_msg = SignalingMessage::Join {
-                                                        peer_id: peer_id.clone(),
-                                                        room_id: room_id.clone(),
-                                                    };
-                                                    if let Err(e) = handler.handle_message(join_msg, &peer_id).await {
-                                                        error!("Failed to handle join message: {}", e);
-                                                    }
-                                                },
-                                                SignalingMessage::Disconnect { peer_id, room_id } => {
-                                                    if let Err(e) = handler.handle_disconnect(&peer_id, &room_id).await {
-                                                        error!("Error handling disconnect for peer {}: {}", peer_id, e);
-                                                    }
-                                                    break;
-                                                },
-                                                _ => {
-                                                    if let Some(peer_id) = message.get_peer_id() {
-                                                        if let Err(e) = handler.handle_message(message, &peer_id).await {
-                                                            error!("Error handling message: {}", e);
-                                                        }
-                                                    } else {
-                                                        error!("Message missing peer_id: {:?}", message);
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        Err(e) => error!("Failed to parse message: {}", e),
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!("WebSocket error: {}", e);
-                                break;
-                            }
-                        }
+                    let (ws_sender, ws_receiver) = websocket.split();
+                    let transport = Arc::new(Mutex::new(ws_sender));
+                    match Connection::open(handler, state_manager, transport, remote, upgrade_token).await {
+                        Ok(connection) => connection.serve(ws_receiver.map(|frame| frame.map(Frame::from).map_err(Error::from))).await,
+                        Err(e) => error!("Failed to open connection: {}", e),
                     }
-                })
+                }).into_response()
             })
     }
 
+    /// WHIP ingest endpoints for encoders that publish over HTTP.
+    pub fn whip_route(&self) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        crate::signaling::whip::routes(self.handler.clone())
+    }
+
+    /// Authenticated admin API for managing live rooms.
+    pub fn admin_route(&self) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        crate::signaling::admin::routes(self.handler.clone(), self.config.admin_token.clone())
+    }
+
+    /// WHEP endpoints for receive-only viewers.
+    pub fn whep_route(&self) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+        crate::signaling::whip::whep_routes(self.handler.clone())
+    }
+
     pub fn monitoring_routes(&self) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         let monitor = Arc::clone(&self.connection_monitor);
         let broadcaster = Arc::clone(&self.state_broadcaster);
@@ -388,17 +335,20 @@ impl reject::Reject for ServerError {}
 
 async fn handle_media_stats_internal(media_relay: Arc<MediaRelayManager>) -> Result<Vec<serde_json::Value>> {
     let mut stats = Vec::new();
-    let relays = media_relay.get_relays().await?;
+    let rooms = media_relay.get_rooms().await;
     
-    for (peer_id, relay) in relays {
-        if let Ok(relay_stats) = relay.get_stats().await {
+    for room_id in rooms.keys() {
+        for (peer_id, relay_stats) in media_relay.get_room_stats(room_id).await {
             stats.push(json!({
+                "room_id": room_id,
                 "peer_id": peer_id,
                 "stats": {
                     "packets_received": relay_stats.packets_received,
                     "packets_sent": relay_stats.packets_sent,
                     "bytes_received": relay_stats.bytes_received,
                     "bytes_sent": relay_stats.bytes_sent,
+                    "available_bitrate": relay_stats.available_bitrate,
+                    "forwarded_bitrate": relay_stats.forwarded_bitrate,
                     "last_updated": relay_stats.last_updated.elapsed().as_secs()
                 }
             }));
@@ -414,82 +364,6 @@ fn with_media_relay(
     warp::any().map(move || media_relay.clone())
 } 
 
-async fn handle_websocket_connection(
-    websocket: warp::ws::WebSocket,
-    handler: Arc<MessageHandler>,
-    monitor: Arc<ConnectionMonitor>,
-    state_manager: Arc<ConnectionStateManager>,
-) {
-    let (ws_sender, mut ws_receiver) = websocket.split();
-    let ws_sender = Arc::new(Mutex::new(ws_sender));
-    let temp_id = format!("temp_{}", Uuid::new_v4());
-    
-    // Set up initial temporary connection
-    let ws_conn = WebSocketConnection::new_warp(ws_sender.clone());
-    if let Err(e) = handler.set_websocket_sender(temp_id.clone(), ws_conn).await {
-        error!("Failed to set websocket sender: {}", e);
-        return;
-    }
-
-    while let Some(result) = ws_receiver.next().await {
-        match result {
-            Ok(msg) => {
-                if let Ok(text) = msg.to_str() {
-                    match serde_json::from_str::<SignalingMessage>(text) {
-                        Ok(message) => {
-                            debug!("Received message: {:?}", message.clone());
-                            match message {
-                                SignalingMessage::Join { peer_id, room_id } => {
-                                    // Create new WebSocket connection with actual peer ID
-                                    let new_ws_conn = WebSocketConnection::new_warp(ws_sender.clone());
-                                    if let Err(e) = handler.set_websocket_sender(peer_id.clone(), new_ws_conn).await {
-                                        error!("Failed to set websocket sender for peer {}: {}", peer_id, e);
-                                        continue;
-                                    }
-                                    
-                                    // Remove temporary connection
-                                    if let Err(e) = handler.remove_websocket_sender(&temp_id).await {
-                                        error!("Failed to remove temporary connection: {}", e);
-                                    }
-                                    
-                                    // Handle the join message
-                                    let join_msg = SignalingMessage::Join {
-                                        peer_id: peer_id.clone(),
-                                        room_id: room_id.clone(),
-                                    };
-                                    if let Err(e) = handler.handle_message(join_msg, &peer_id).await {
-                                        error!("Failed to handle join message: {}", e);
-                                    }
-                                },
-                                SignalingMessage::Disconnect { peer_id, room_id } => {
-                                    if let Err(e) = handler.handle_disconnect(&peer_id, &room_id).await {
-                                        error!("Error handling disconnect for peer {}: {}", peer_id, e);
-                                    }
-                                    break;
-                                },
-                                _ => {
-                                    if let Some(peer_id) = message.get_peer_id() {
-                                        if let Err(e) = handler.handle_message(message, &peer_id).await {
-                                            error!("Error handling message: {}", e);
-                                        }
-                                    } else {
-                                        error!("Message missing peer_id: {:?}", message);
-                                    }
-                                }
-                            }
-                        },
-                        Err(e) => error!("Failed to parse message: {}", e)
-                    }
-                }
-            },
-            Err(e) => {
-                error!("WebSocket error: {}", e);
-                break;
-            }
-        }
-    }
-}
-
 fn with_turn_config(
     server: String,
     port: u16,
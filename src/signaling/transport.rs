@@ -0,0 +1,88 @@
+use async_trait::async_trait;
+use futures_util::SinkExt;
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
+use warp::ws::Message as WarpMessage;
+use crate::types::{TungsteniteWebSocketSender, WarpWebSocketSender};
+use crate::utils::{Error, Result};
+
+/// The sending half of a client socket. Each WebSocket library plugs in
+/// here; `Connection` drives every transport with the same state machine.
+#[async_trait]
+pub trait SignalingTransport: Send + Sync {
+    /// Sends one JSON message, in a binary frame if `binary`.
+    async fn send(&self, text: String, binary: bool) -> Result<()>;
+    async fn ping(&self) -> Result<()>;
+    async fn close(&self) -> Result<()>;
+}
+
+/// A frame received from a client, whatever the transport.
+#[derive(Debug)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Close,
+    /// Pings, pongs and anything else without a message in it
+    Control,
+}
+
+#[async_trait]
+impl SignalingTransport for Mutex<TungsteniteWebSocketSender> {
+    async fn send(&self, text: String, binary: bool) -> Result<()> {
+        let frame = if binary { TungsteniteMessage::Binary(text.into_bytes()) } else { TungsteniteMessage::Text(text) };
+        self.lock().await.send(frame).await.map_err(|e| Error::WebSocketError(e.to_string()))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.lock().await.send(TungsteniteMessage::Ping(vec![]))
+            .await
+            .map_err(|e| Error::WebSocketError(format!("Tungstenite ping failed: {}", e)))
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.lock().await.close().await.map_err(|e| Error::WebSocketError(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl SignalingTransport for Mutex<WarpWebSocketSender> {
+    async fn send(&self, text: String, binary: bool) -> Result<()> {
+        let frame = if binary { WarpMessage::binary(text) } else { WarpMessage::text(text) };
+        self.lock().await.send(frame).await.map_err(|e| Error::WebSocketError(e.to_string()))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.lock().await.send(WarpMessage::ping(vec![]))
+            .await
+            .map_err(|e| Error::WebSocketError(format!("Warp ping failed: {}", e)))
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.lock().await.close().await.map_err(|e| Error::WebSocketError(e.to_string()))
+    }
+}
+
+impl From<TungsteniteMessage> for Frame {
+    fn from(message: TungsteniteMessage) -> Self {
+        match message {
+            TungsteniteMessage::Text(text) => Frame::Text(text),
+            TungsteniteMessage::Binary(bytes) => Frame::Binary(bytes),
+            TungsteniteMessage::Close(_) => Frame::Close,
+            _ => Frame::Control,
+        }
+    }
+}
+
+impl From<WarpMessage> for Frame {
+    fn from(message: WarpMessage) -> Self {
+        if message.is_close() {
+            Frame::Close
+        } else if message.is_text() {
+            Frame::Text(message.to_str().unwrap_or_default().to_string())
+        } else if message.is_binary() {
+            Frame::Binary(message.into_bytes())
+        } else {
+            Frame::Control
+        }
+    }
+}
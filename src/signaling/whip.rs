@@ -0,0 +1,306 @@
+use crate::media::{MediaRelay, RelayMode};
+use crate::signaling::auth::{Permission, Role};
+use crate::signaling::handler::MessageHandler;
+use crate::utils::{Error, Result};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use warp::http::{header, StatusCode};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+const SDP_CONTENT_TYPE: &str = "application/sdp";
+const SDPFRAG_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+// Clients such as OBS don't trickle, so the answer carries our candidates
+const GATHER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// WHIP ingest (RFC 9725): an encoder POSTs its offer to `/whip/{room_id}`
+/// and publishes to the room as a regular participant. The returned resource
+/// takes trickled candidates via PATCH and ends the session on DELETE.
+/// Its `ETag` names the ICE session; PATCHes for another one get 412, and
+/// ICE restarts are not supported.
+///
+/// With authentication on, the POST carries the access token as a bearer
+/// `Authorization` header; the resource URL then stands for the session.
+/// Its id is not the peer's id, which the rest of the room sees.
+pub fn routes(handler: Arc<MessageHandler>) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    session_routes("whip", RelayMode::Ingest, handler)
+}
+
+/// WHEP playback: a viewer POSTs a receive-only offer to `/whep/{room_id}`
+/// and gets the room's media back. Viewers take a place in the room like
+/// any other participant; the resource works as it does for WHIP.
+pub fn whep_routes(handler: Arc<MessageHandler>) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    session_routes("whep", RelayMode::Egress, handler)
+}
+
+fn session_routes(
+    protocol: &'static str,
+    mode: RelayMode,
+    handler: Arc<MessageHandler>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let session = warp::any().map(move || (protocol, mode));
+
+    let create = warp::path(protocol)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(session.clone())
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_handler(handler.clone()))
+        .then(create_session);
+
+    let trickle = warp::path(protocol)
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::patch())
+        .and(session.clone())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("if-match"))
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_handler(handler.clone()))
+        .then(trickle_candidates);
+
+    let delete = warp::path(protocol)
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(session)
+        .and(with_handler(handler))
+        .then(delete_session);
+
+    create.or(trickle).unify().or(delete).unify()
+}
+
+fn with_handler(
+    handler: Arc<MessageHandler>,
+) -> impl Filter<Extract = (Arc<MessageHandler>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || handler.clone())
+}
+
+async fn create_session(
+    room_id: String,
+    (protocol, mode): (&'static str, RelayMode),
+    remote: Option<SocketAddr>,
+    authorization: Option<String>,
+    content_type: Option<String>,
+    body: Bytes,
+    handler: Arc<MessageHandler>,
+) -> Response {
+    let role = match authorize(&handler, &room_id, mode, authorization.as_deref()) {
+        Ok(role) => role,
+        Err(e) => {
+            warn!("Refused {} peer for room {}: {}", protocol, room_id, e);
+            let reply = warp::reply::with_header(e.to_string(), header::WWW_AUTHENTICATE, "Bearer");
+            return warp::reply::with_status(reply, StatusCode::UNAUTHORIZED).into_response();
+        }
+    };
+    if !has_content_type(content_type.as_deref(), SDP_CONTENT_TYPE) {
+        return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected application/sdp");
+    }
+    let Ok(offer) = String::from_utf8(body.to_vec()) else {
+        return error_response(StatusCode::BAD_REQUEST, "Offer is not valid UTF-8");
+    };
+
+    let peer_id = format!("{}-{}", protocol, Uuid::new_v4());
+    let ip = remote.map(|addr| addr.ip());
+    if let Err(e) = handler.check_http_admission(&room_id, &peer_id, ip, role).await {
+        warn!("{} peer from {:?} may not join room {}: {}", protocol, ip, room_id, e);
+        return error_response(StatusCode::FORBIDDEN, &e.to_string());
+    }
+    let relay = match handler.join_room(room_id.clone(), peer_id.clone(), mode, role).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            warn!("Rejected {} peer for room {}: {}", protocol, room_id, e);
+            return error_response(StatusCode::SERVICE_UNAVAILABLE, &e.to_string());
+        }
+    };
+
+    // So that banning its address later removes it too
+    if let Some(ip) = ip {
+        handler.set_peer_addr(&peer_id, ip).await;
+    }
+
+    let answer = match answer_offer(&relay, &offer).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            warn!("Failed to answer {} offer for room {}: {}", protocol, room_id, e);
+            if let Err(e) = handler.handle_disconnect(&peer_id, &room_id).await {
+                warn!("Failed to remove {} peer {}: {}", protocol, peer_id, e);
+            }
+            return error_response(StatusCode::BAD_REQUEST, &e.to_string());
+        }
+    };
+
+    info!("{} peer {} joined room {}", protocol, peer_id, room_id);
+    let resource_id = handler.open_http_resource(&peer_id).await;
+    let location = format!("/{}/{}/{}", protocol, room_id, resource_id);
+    let reply = warp::reply::with_header(answer, header::CONTENT_TYPE, SDP_CONTENT_TYPE);
+    let reply = warp::reply::with_header(reply, header::LOCATION, location);
+    let reply = warp::reply::with_header(reply, header::ETAG, entity_tag(ice_credentials(&offer).0));
+    warp::reply::with_status(reply, StatusCode::CREATED).into_response()
+}
+
+/// Ingest needs a token that may publish, playback one that may subscribe.
+/// Returns the role the token assigns, if any.
+fn authorize(handler: &MessageHandler, room_id: &str, mode: RelayMode, authorization: Option<&str>) -> Result<Option<Role>> {
+    let token = authorization.and_then(|value| value.strip_prefix("Bearer ")).map(str::trim);
+    let Some(claims) = handler.authorize_room(room_id, token)? else { return Ok(None) };
+    let needed = match mode {
+        RelayMode::Egress => Permission::Subscribe,
+        _ => Permission::Publish,
+    };
+    if !claims.allows(needed) {
+        return Err(Error::Unauthorized(format!("Token does not allow {:?} in room {}", needed, room_id)));
+    }
+    Ok(claims.role)
+}
+
+/// Answers the offer and waits for ICE gathering so the answer is complete.
+async fn answer_offer(relay: &MediaRelay, offer: &str) -> Result<String> {
+    relay.handle_offer(offer.to_string()).await?;
+    let mut gathered = relay.peer_connection.gathering_complete_promise().await;
+    if tokio::time::timeout(GATHER_TIMEOUT, gathered.recv()).await.is_err() {
+        warn!("ICE gathering for {} timed out, answering with partial candidates", relay.peer_id);
+    }
+    relay.flush_negotiation().await?;
+
+    relay.peer_connection
+        .local_description()
+        .await
+        .map(|answer| answer.sdp)
+        .ok_or_else(|| Error::Peer(format!("No local description for {}", relay.peer_id)))
+}
+
+async fn trickle_candidates(
+    room_id: String,
+    resource_id: String,
+    (protocol, mode): (&'static str, RelayMode),
+    content_type: Option<String>,
+    if_match: Option<String>,
+    body: Bytes,
+    handler: Arc<MessageHandler>,
+) -> Response {
+    let Some(relay) = find_session(&handler, &room_id, &resource_id, mode).await else {
+        return error_response(StatusCode::NOT_FOUND, "Unknown resource");
+    };
+    if !has_content_type(content_type.as_deref(), SDPFRAG_CONTENT_TYPE) {
+        return error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Expected application/trickle-ice-sdpfrag");
+    }
+
+    let Some(remote) = relay.peer_connection.remote_description().await else {
+        return error_response(StatusCode::CONFLICT, "Session has no offer");
+    };
+    let (ufrag, pwd) = ice_credentials(&remote.sdp);
+    let if_match = if_match.as_deref().map(str::trim);
+    if if_match.is_some_and(|tag| tag != "*" && tag != entity_tag(ufrag)) {
+        return error_response(StatusCode::PRECONDITION_FAILED, "ICE session has changed");
+    }
+    let fragment = String::from_utf8_lossy(&body);
+    let (fragment_ufrag, fragment_pwd) = ice_credentials(&fragment);
+    if fragment_ufrag.is_some_and(|value| Some(value) != ufrag) || fragment_pwd.is_some_and(|value| Some(value) != pwd) {
+        // `If-Match: *` with new credentials asks for an ICE restart
+        if if_match == Some("*") {
+            return error_response(StatusCode::NOT_IMPLEMENTED, "ICE restarts are not supported");
+        }
+        return error_response(StatusCode::PRECONDITION_FAILED, "Candidates are for another ICE session");
+    }
+
+    for candidate in parse_sdpfrag(&fragment) {
+        if let Err(e) = relay.add_ice_candidate(candidate).await {
+            warn!("Could not add ICE candidate for {}: {}", relay.peer_id, e);
+        }
+    }
+    debug!("Applied trickled candidates for {} peer {}", protocol, relay.peer_id);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn delete_session(
+    room_id: String,
+    resource_id: String,
+    (protocol, mode): (&'static str, RelayMode),
+    handler: Arc<MessageHandler>,
+) -> Response {
+    let Some(relay) = find_session(&handler, &room_id, &resource_id, mode).await else {
+        return error_response(StatusCode::NOT_FOUND, "Unknown resource");
+    };
+    if let Err(e) = handler.handle_disconnect(&relay.peer_id, &room_id).await {
+        warn!("Failed to remove {} peer {}: {}", protocol, relay.peer_id, e);
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    info!("{} peer {} left room {}", protocol, relay.peer_id, room_id);
+    StatusCode::OK.into_response()
+}
+
+async fn find_session(handler: &MessageHandler, room_id: &str, resource_id: &str, mode: RelayMode) -> Option<MediaRelay> {
+    let peer_id = handler.http_resource_peer(resource_id).await?;
+    handler
+        .room_manager()
+        .relay_manager()
+        .get_relay(room_id, &peer_id)
+        .await
+        .filter(|relay| relay.mode == mode)
+}
+
+/// Candidates in a trickle-ice-sdpfrag body, tagged with the media section
+/// they follow. `a=end-of-candidates` becomes an empty candidate.
+fn parse_sdpfrag(fragment: &str) -> Vec<RTCIceCandidateInit> {
+    let mut candidates = Vec::new();
+    let mut mid = None;
+    let mut mline_index: Option<u16> = None;
+
+    for line in fragment.lines().map(str::trim) {
+        if line.starts_with("m=") {
+            mline_index = Some(mline_index.map_or(0, |index| index + 1));
+            mid = None;
+        } else if let Some(value) = line.strip_prefix("a=mid:") {
+            mid = Some(value.to_string());
+        } else if let Some(candidate) = line.strip_prefix("a=") {
+            if candidate.starts_with("candidate:") {
+                candidates.push(RTCIceCandidateInit {
+                    candidate: candidate.to_string(),
+                    sdp_mid: mid.clone(),
+                    sdp_mline_index: mline_index,
+                    ..Default::default()
+                });
+            } else if candidate == "end-of-candidates" {
+                candidates.push(RTCIceCandidateInit::default());
+            }
+        }
+    }
+    candidates
+}
+
+/// The `a=ice-ufrag` and `a=ice-pwd` values of an SDP or sdpfrag. Bundled
+/// sessions share them, so the first of each will do.
+fn ice_credentials(sdp: &str) -> (Option<&str>, Option<&str>) {
+    let value = |prefix: &str| sdp.lines().find_map(|line| line.trim().strip_prefix(prefix));
+    (value("a=ice-ufrag:"), value("a=ice-pwd:"))
+}
+
+/// The `ETag` of the ICE session with `ufrag`.
+fn entity_tag(ufrag: Option<&str>) -> String {
+    format!("\"{}\"", ufrag.unwrap_or_default())
+}
+
+fn has_content_type(content_type: Option<&str>, expected: &str) -> bool {
+    content_type
+        .and_then(|value| value.split(';').next())
+        .map_or(false, |value| value.trim().eq_ignore_ascii_case(expected))
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    warp::reply::with_status(message.to_string(), status).into_response()
+}
@@ -2,6 +2,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::{Mutex, RwLock};
 use tokio_tungstenite::tungstenite::Message;
 use futures_util::stream::SplitSink;
@@ -17,82 +18,87 @@ use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_remote::TrackRemote;
 use std::time::SystemTime;
-use crate::utils::{Error, Result};
-use futures_util::SinkExt;
+use crate::utils::{Error, ErrorCode, Result};
+use crate::media::simulcast::SimulcastQuality;
+use crate::signaling::protocol::Feature;
+use crate::signaling::transport::SignalingTransport;
 use warp::ws::WebSocket;
-use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
-use warp::ws::Message as WarpMessage;
 use std::path::PathBuf;
 use std::fs::File;
 
 // Re-export room types
-pub use crate::room::state::{Room, MediaSettings, MediaType};
+pub use crate::room::state::{Room, MediaSettings, MediaType, Role, VideoCodec};
 
 // Define WebSocketSender type
 pub type TungsteniteWebSocketSender = SplitSink<WebSocketStream<TcpStream>, Message>;
 pub type WarpWebSocketSender = SplitSink<WebSocket, warp::ws::Message>;
 
-// Create an enum to handle both types
-#[derive(Debug, Clone)]
-pub enum WebSocketSenderType {
-    Tungstenite(Arc<Mutex<TungsteniteWebSocketSender>>),
-    Warp(Arc<Mutex<WarpWebSocketSender>>),
+/// A client socket the server can send to, over any transport.
+#[derive(Clone)]
+pub struct WebSocketConnection {
+    transport: Arc<dyn SignalingTransport>,
+    /// Shared by every clone, so switching applies to the whole socket
+    binary_frames: Arc<AtomicBool>,
 }
 
-#[derive(Debug, Clone)]
-pub struct WebSocketConnection {
-    sender: WebSocketSenderType,
+impl std::fmt::Debug for WebSocketConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketConnection")
+            .field("binary_frames", &self.binary_frames)
+            .finish_non_exhaustive()
+    }
 }
 
 impl WebSocketConnection {
-    pub fn new_tungstenite(sender: Arc<Mutex<TungsteniteWebSocketSender>>) -> Self {
+    pub fn new(transport: Arc<dyn SignalingTransport>) -> Self {
         Self {
-            sender: WebSocketSenderType::Tungstenite(sender),
+            transport,
+            binary_frames: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn new_warp(sender: Arc<Mutex<WarpWebSocketSender>>) -> Self {
-        Self {
-            sender: WebSocketSenderType::Warp(sender),
-        }
+    /// Sends messages in binary rather than text frames from now on.
+    pub fn use_binary_frames(&self) {
+        self.binary_frames.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether both wrap the same socket.
+    pub fn same_socket(&self, other: &WebSocketConnection) -> bool {
+        std::ptr::addr_eq(Arc::as_ptr(&self.transport), Arc::as_ptr(&other.transport))
     }
 
     pub async fn send(&self, text: String) -> Result<()> {
-        match &self.sender {
-            WebSocketSenderType::Tungstenite(sender) => {
-                let mut sender = sender.lock().await;
-                sender.send(Message::Text(text)).await.map_err(|e| Error::WebSocketError(e.to_string()))?;
-            }
-            WebSocketSenderType::Warp(sender) => {
-                let mut sender = sender.lock().await;
-                sender.send(warp::ws::Message::text(text)).await.map_err(|e| Error::WebSocketError(e.to_string()))?;
-            }
-        }
-        Ok(())
+        self.transport.send(text, self.binary_frames.load(Ordering::Relaxed)).await
     }
 
     pub async fn ping(&self) -> Result<()> {
-        match &self.sender {
-            WebSocketSenderType::Tungstenite(sender) => {
-                let mut sender = sender.lock().await;
-                sender.send(TungsteniteMessage::Ping(vec![]))
-                    .await
-                    .map_err(|e| Error::WebSocketError(format!("Tungstenite ping failed: {}", e)))?;
-            }
-            WebSocketSenderType::Warp(sender) => {
-                let mut sender = sender.lock().await;
-                sender.send(WarpMessage::ping(vec![]))
-                    .await
-                    .map_err(|e| Error::WebSocketError(format!("Warp ping failed: {}", e)))?;
-            }
-        }
-        Ok(())
+        self.transport.ping().await
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.transport.close().await
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "message_type")]
 pub enum SignalingMessage {
+    /// Optional first message on a socket, declaring the client's protocol
+    /// version and features. Clients that skip it get the oldest protocol.
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        features: Vec<Feature>,
+    },
+    /// Reply to `Hello`: the server's versions, the features both sides
+    /// support, the ICE servers to use and the id of this connection.
+    Welcome {
+        protocol_version: u32,
+        server_version: String,
+        features: Vec<Feature>,
+        ice_servers: Vec<IceServer>,
+        session_id: String,
+    },
     CallRequest {
         room_id: String,
         from_peer: String,
@@ -107,9 +113,43 @@ pub enum SignalingMessage {
         reason: Option<String>,
         sdp: Option<String>,
     },
+    /// `token` is the peer's access token, unless it was presented when
+    /// the WebSocket was opened. `metadata`, such as a display name, is
+    /// shown to moderators if the peer has to wait in the room's lobby.
+    /// `password` is needed for password-protected rooms.
     Join {
         room_id: String,
         peer_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        metadata: HashMap<String, String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// Sent to a peer once it joined; `resume_token` lets it reclaim its
+    /// session with `Resume` for `grace_period_secs` after its socket drops.
+    ResumeToken {
+        room_id: String,
+        peer_id: String,
+        resume_token: String,
+        grace_period_secs: u64,
+    },
+    /// First message on a new socket of a peer whose previous one dropped.
+    /// The peer keeps its room membership and peer connection, and gets
+    /// the messages after `last_seq` it missed.
+    Resume {
+        room_id: String,
+        peer_id: String,
+        resume_token: String,
+        #[serde(default)]
+        last_seq: Option<u64>,
+    },
+    /// Acknowledges every server message up to and including `last_seq`.
+    /// Not `seq`, which the envelope uses.
+    Ack {
+        peer_id: String,
+        last_seq: u64,
     },
     RequestPeerList {
         room_id: String,
@@ -117,6 +157,8 @@ pub enum SignalingMessage {
     PeerList {
         room_id: String,
         peers: Vec<String>,
+        #[serde(default)]
+        roles: HashMap<String, Role>,
     },
     Disconnect {
         room_id: String,
@@ -158,13 +200,209 @@ pub enum SignalingMessage {
         error: String,
         should_retry: bool,
     },
+    /// Reply to a request the server rejected or failed to handle; the
+    /// socket stays open. `request_id` is the request's envelope `id`.
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    LayerPreference {
+        room_id: String,
+        peer_id: String,
+        publisher_id: String,
+        quality: SimulcastQuality,
+    },
+    ActiveSpeaker {
+        room_id: String,
+        peer_id: String,
+    },
+    AudioLevels {
+        room_id: String,
+        levels: HashMap<String, f32>,
+    },
+    PinPeers {
+        room_id: String,
+        peer_id: String,
+        pinned: Vec<String>,
+    },
+    /// Asks for a publisher's tracks, or everyone's when `publisher_id` is
+    /// omitted. An empty `media_types` means audio and video.
+    Subscribe {
+        room_id: String,
+        peer_id: String,
+        #[serde(default)]
+        publisher_id: Option<String>,
+        #[serde(default)]
+        media_types: Vec<MediaType>,
+    },
+    Unsubscribe {
+        room_id: String,
+        peer_id: String,
+        #[serde(default)]
+        publisher_id: Option<String>,
+        #[serde(default)]
+        media_types: Vec<MediaType>,
+    },
+    /// Restarts ICE on the peer's server connection. From the server `sdp`
+    /// is an ICE-restart offer, answered with `Answer`. A peer either sends
+    /// its own restart offer, answered with `Answer`, or omits `sdp` to ask
+    /// the server for one.
+    IceRestart {
+        room_id: String,
+        peer_id: String,
+        #[serde(default)]
+        sdp: Option<String>,
+    },
+    /// Asks to give `target_peer` another role. Moderators may change the
+    /// roles of everyone but hosts; only hosts may appoint or demote hosts.
+    SetRole {
+        room_id: String,
+        peer_id: String,
+        target_peer: String,
+        role: Role,
+    },
+    /// `peer_id` now has `role` in the room.
+    RoleChanged {
+        room_id: String,
+        peer_id: String,
+        role: Role,
+    },
+    /// A moderator removes `target_peer` from the room.
+    KickPeer {
+        room_id: String,
+        peer_id: String,
+        target_peer: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// A moderator mutes or unmutes one of `target_peer`'s tracks, or all
+    /// of them when `track_id` is omitted.
+    MutePeer {
+        room_id: String,
+        peer_id: String,
+        target_peer: String,
+        #[serde(default)]
+        track_id: Option<String>,
+        muted: bool,
+    },
+    StartRecording {
+        room_id: String,
+        peer_id: String,
+    },
+    /// A moderator turns the room's lobby on or off. Turning it off admits
+    /// everyone waiting.
+    SetLobby {
+        room_id: String,
+        peer_id: String,
+        enabled: bool,
+    },
+    /// Tells a joining peer it waits in the lobby until a moderator admits it.
+    LobbyWaiting {
+        room_id: String,
+        peer_id: String,
+    },
+    /// Tells moderators that `peer_id` is waiting in the lobby.
+    LobbyRequest {
+        room_id: String,
+        peer_id: String,
+        #[serde(default)]
+        metadata: HashMap<String, String>,
+    },
+    AdmitPeer {
+        room_id: String,
+        peer_id: String,
+        target_peer: String,
+    },
+    /// Turns `target_peer` away from the lobby; it gets a `ConnectionError`.
+    DenyPeer {
+        room_id: String,
+        peer_id: String,
+        target_peer: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// A moderator locks the room against further joins, or unlocks it.
+    LockRoom {
+        room_id: String,
+        peer_id: String,
+        locked: bool,
+    },
+    /// A moderator sets the password joining peers must give, or removes
+    /// it when `password` is omitted.
+    SetRoomPassword {
+        room_id: String,
+        peer_id: String,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// A moderator bans `target_peer` from the room, and its IP address too
+    /// when `ban_address` is set, removing it if present.
+    BanPeer {
+        room_id: String,
+        peer_id: String,
+        target_peer: String,
+        #[serde(default)]
+        ban_address: bool,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    UnbanPeer {
+        room_id: String,
+        peer_id: String,
+        target_peer: String,
+    },
+    /// A moderator muted or unmuted one of `peer_id`'s tracks.
+    TrackMuted {
+        room_id: String,
+        peer_id: String,
+        track_id: String,
+        muted: bool,
+    },
+}
+
+/// A `SignalingMessage` on the wire, with its fields alongside delivery
+/// metadata. Both are optional from clients, so a bare message is a valid
+/// envelope.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<M> {
+    /// Unique per message; the server drops repeats of an id it has seen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Position in the server's stream of messages to a peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub message: M,
+}
+
+/// The `id` of a message that may not parse, so a rejection can name it.
+pub fn envelope_id(text: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(text).ok()?;
+    value.get("id")?.as_str().map(str::to_string)
 }
 
+/// Peer id the server uses for messages it originates, such as its own offers.
+pub const SERVER_PEER_ID: &str = "server";
+
 impl SignalingMessage {
+    /// The reply to a request that failed with `error`.
+    pub fn error(error: &Error, request_id: Option<String>) -> Self {
+        SignalingMessage::Error {
+            code: error.code(),
+            message: error.to_string(),
+            request_id,
+        }
+    }
+
     pub fn get_peer_id(&self) -> Option<String> {
         match self {
             SignalingMessage::Join { peer_id, .. } => Some(peer_id.clone()),
             SignalingMessage::Disconnect { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::ResumeToken { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::Resume { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::Ack { peer_id, .. } => Some(peer_id.clone()),
             SignalingMessage::CallRequest { from_peer, .. } => Some(from_peer.clone()),
             SignalingMessage::CallResponse { from_peer, .. } => Some(from_peer.clone()),
             SignalingMessage::Offer { from_peer, .. } => Some(from_peer.clone()),
@@ -174,8 +412,33 @@ impl SignalingMessage {
             SignalingMessage::EndCall { peer_id, .. } => Some(peer_id.clone()),
             SignalingMessage::PeerDisconnected { peer_id, .. } => Some(peer_id.clone()),
             SignalingMessage::ConnectionError { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::LayerPreference { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::ActiveSpeaker { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::AudioLevels { .. } => None,
+            SignalingMessage::PinPeers { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::Subscribe { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::Unsubscribe { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::IceRestart { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::TrackMuted { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::SetRole { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::RoleChanged { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::KickPeer { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::MutePeer { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::StartRecording { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::SetLobby { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::LobbyWaiting { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::LobbyRequest { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::AdmitPeer { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::DenyPeer { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::LockRoom { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::SetRoomPassword { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::BanPeer { peer_id, .. } => Some(peer_id.clone()),
+            SignalingMessage::UnbanPeer { peer_id, .. } => Some(peer_id.clone()),
             SignalingMessage::PeerList { .. } => None,
             SignalingMessage::RequestPeerList { .. } => None,
+            SignalingMessage::Hello { .. } => None,
+            SignalingMessage::Error { .. } => None,
+            SignalingMessage::Welcome { .. } => None,
         }
     }
 }
@@ -187,7 +450,8 @@ impl Default for SignalingMessage {
     fn default() -> Self {
         SignalingMessage::PeerList { 
             peers: Vec::new(), 
-            room_id: String::new() 
+            room_id: String::new(),
+            roles: HashMap::new(),
         }
     }
 }
@@ -209,6 +473,16 @@ pub struct CallMetadata {
     pub recording_path: Option<String>,
 }
 
+/// An ICE server as browsers' `RTCIceServer` takes it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
 #[derive(Serialize, Clone)]
 pub struct TurnCredentials {
     pub stun_server: String,
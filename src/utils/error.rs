@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::error::Error as StdError;
 use std::fmt;
 use std::net::AddrParseError;
@@ -16,12 +17,16 @@ pub enum Error {
     SerializationError(String),
     InvalidMessage(String),
     Room(String),
+    /// A room, peer or lobby entry that does not exist
+    NotFound(String),
     Peer(String),
     Media(String),
     IO(String),
     Turn(String),
     AddrParse(String),
     WarpError(String),
+    Unauthorized(String),
+    UnsupportedProtocol(String),
 }
 
 impl fmt::Display for Error {
@@ -32,19 +37,64 @@ impl fmt::Display for Error {
             Error::ConnectionError(msg) => write!(f, "Connection error: {}", msg),
             Error::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
             Error::InvalidMessage(msg) => write!(f, "Invalid message: {}", msg),
-            Error::Room(msg) => write!(f, "Room error: {}", msg),
+            Error::Room(msg) | Error::NotFound(msg) => write!(f, "Room error: {}", msg),
             Error::Peer(msg) => write!(f, "Peer error: {}", msg),
             Error::Media(msg) => write!(f, "Media error: {}", msg),
             Error::IO(msg) => write!(f, "IO error: {}", msg),
             Error::Turn(msg) => write!(f, "TURN error: {}", msg),
             Error::AddrParse(msg) => write!(f, "Address parse error: {}", msg),
             Error::WarpError(msg) => write!(f, "Warp error: {}", msg),
+            Error::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            Error::UnsupportedProtocol(msg) => write!(f, "Unsupported protocol: {}", msg),
         }
     }
 }
 
 impl StdError for Error {}
 
+/// Stable error codes sent to clients in `SignalingMessage::Error`. New
+/// codes may be added, existing ones keep their meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message is not valid JSON or not a known message
+    MalformedMessage,
+    /// The message is well-formed but cannot be acted on
+    InvalidMessage,
+    /// The sender may not do this
+    Unauthorized,
+    /// The client's protocol version is not served
+    UnsupportedProtocol,
+    /// The room does not exist or the peer is not in it
+    RoomError,
+    /// The peer or its connection is in the wrong state
+    PeerError,
+    /// Media or WebRTC negotiation failed
+    MediaError,
+    /// Something failed on the server's side
+    InternalError,
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::SerializationError(_) => ErrorCode::MalformedMessage,
+            Error::InvalidMessage(_) => ErrorCode::InvalidMessage,
+            Error::Unauthorized(_) => ErrorCode::Unauthorized,
+            Error::UnsupportedProtocol(_) => ErrorCode::UnsupportedProtocol,
+            Error::Room(_) | Error::NotFound(_) => ErrorCode::RoomError,
+            Error::Peer(_) => ErrorCode::PeerError,
+            Error::Media(_) | Error::WebRTCError(_) => ErrorCode::MediaError,
+            Error::WebSocketError(_)
+            | Error::ConnectionError(_)
+            | Error::IO(_)
+            | Error::Turn(_)
+            | Error::AddrParse(_)
+            | Error::WarpError(_) => ErrorCode::InternalError,
+        }
+    }
+}
+
 impl From<WebRTCError> for Error {
     fn from(err: WebRTCError) -> Self {
         Error::WebRTCError(err.to_string())
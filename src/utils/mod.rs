@@ -1,4 +1,4 @@
 pub mod error;
 
-pub use error::Error;
+pub use error::{Error, ErrorCode};
 pub type Result<T> = std::result::Result<T, Error>;
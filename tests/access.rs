@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use webrtc_server::room::state::Role;
+use webrtc_server::signaling::handler::MessageHandler;
+use webrtc_server::types::SignalingMessage;
+use webrtc_server::utils::{Error, Result};
+
+mod common;
+use common::message_handler;
+
+const ROOM: &str = "private";
+
+async fn join(handler: &MessageHandler, peer_id: &str, password: Option<&str>) -> Result<()> {
+    let join = SignalingMessage::Join {
+        room_id: ROOM.to_string(),
+        peer_id: peer_id.to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: password.map(str::to_string),
+    };
+    handler.handle_message(join, peer_id).await
+}
+
+fn refusal(result: Result<()>) -> String {
+    match result {
+        Err(Error::Unauthorized(reason)) => reason,
+        other => panic!("join was not refused: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn password_and_lock_keep_peers_out() {
+    let handler = message_handler();
+    let rooms = handler.room_manager();
+    join(&handler, "host", None).await.unwrap();
+    handler.set_role(ROOM, "host", Role::Host).await.unwrap();
+    let password = SignalingMessage::SetRoomPassword {
+        room_id: ROOM.to_string(),
+        peer_id: "host".to_string(),
+        password: Some("hunter2".to_string()),
+    };
+    handler.handle_message(password, "host").await.unwrap();
+    assert!(!rooms.get_room(ROOM).await.unwrap().password_hash.unwrap().contains("hunter2"));
+
+    assert!(refusal(join(&handler, "guest", None).await).contains("requires a password"));
+    assert!(refusal(join(&handler, "guest", Some("hunter3")).await).contains("Wrong password"));
+    join(&handler, "guest", Some("hunter2")).await.unwrap();
+    assert!(rooms.has_peer(ROOM, "guest").await);
+
+    let lock = SignalingMessage::LockRoom { room_id: ROOM.to_string(), peer_id: "guest".to_string(), locked: true };
+    assert!(handler.handle_message(lock, "guest").await.is_err());
+    let lock = SignalingMessage::LockRoom { room_id: ROOM.to_string(), peer_id: "host".to_string(), locked: true };
+    handler.handle_message(lock, "host").await.unwrap();
+    assert!(refusal(join(&handler, "late", Some("hunter2")).await).contains("locked"));
+
+    // Access rules outlive the room's last peer
+    handler.handle_disconnect("guest", ROOM).await.unwrap();
+    handler.handle_disconnect("host", ROOM).await.unwrap();
+    assert!(refusal(join(&handler, "host", Some("hunter2")).await).contains("locked"));
+}
+
+#[tokio::test]
+async fn banned_peers_and_addresses_are_removed_and_refused() {
+    let handler = message_handler();
+    let rooms = handler.room_manager();
+    let troll_ip: IpAddr = "203.0.113.7".parse().unwrap();
+    join(&handler, "host", None).await.unwrap();
+    handler.set_role(ROOM, "host", Role::Host).await.unwrap();
+    handler.set_peer_addr("troll", troll_ip).await;
+    join(&handler, "troll", None).await.unwrap();
+
+    let ban = SignalingMessage::BanPeer {
+        room_id: ROOM.to_string(),
+        peer_id: "host".to_string(),
+        target_peer: "troll".to_string(),
+        ban_address: true,
+        reason: None,
+    };
+    handler.handle_message(ban, "host").await.unwrap();
+    assert!(!rooms.has_peer(ROOM, "troll").await);
+    assert!(refusal(join(&handler, "troll", None).await).contains("banned"));
+
+    // A new name from the same address is still banned
+    handler.set_peer_addr("troll2", troll_ip).await;
+    assert!(refusal(join(&handler, "troll2", None).await).contains("banned"));
+
+    let unban = SignalingMessage::UnbanPeer {
+        room_id: ROOM.to_string(),
+        peer_id: "host".to_string(),
+        target_peer: "troll".to_string(),
+    };
+    handler.handle_message(unban, "host").await.unwrap();
+    assert!(handler.unban_address(ROOM, troll_ip).await.unwrap());
+    join(&handler, "troll", None).await.unwrap();
+    assert!(rooms.has_peer(ROOM, "troll").await);
+}
@@ -0,0 +1,187 @@
+use webrtc_server::media::RelayMode;
+use webrtc_server::room::state::Role;
+use webrtc_server::signaling::admin;
+
+mod common;
+use common::message_handler;
+
+const ROOM: &str = "admin-room";
+const TOKEN: &str = "secret-token";
+
+fn body_json(response: &warp::http::Response<bytes::Bytes>) -> serde_json::Value {
+    serde_json::from_slice(response.body()).unwrap()
+}
+
+#[tokio::test]
+async fn admin_api_requires_configured_token() {
+    let handler = message_handler();
+
+    let routes = admin::routes(handler.clone(), Some(TOKEN.to_string()));
+    let anonymous = warp::test::request().path("/admin/rooms").reply(&routes).await;
+    assert_eq!(anonymous.status(), 401);
+    assert_eq!(anonymous.headers()["www-authenticate"], "Bearer");
+    let wrong = warp::test::request()
+        .path("/admin/rooms")
+        .header("authorization", "Bearer not-the-token")
+        .reply(&routes)
+        .await;
+    assert_eq!(wrong.status(), 401);
+
+    let disabled = admin::routes(handler, None);
+    let response = warp::test::request()
+        .path("/admin/rooms")
+        .header("authorization", format!("Bearer {}", TOKEN))
+        .reply(&disabled)
+        .await;
+    assert_eq!(response.status(), 401);
+}
+
+#[tokio::test]
+async fn admin_api_manages_rooms_and_peers() {
+    let handler = message_handler();
+    let routes = admin::routes(handler.clone(), Some(TOKEN.to_string()));
+    let bearer = format!("Bearer {}", TOKEN);
+    handler.join_room(ROOM.to_string(), "alice".to_string(), RelayMode::Interactive, None).await.unwrap();
+    handler.join_room(ROOM.to_string(), "bob".to_string(), RelayMode::Interactive, None).await.unwrap();
+
+    let rooms = warp::test::request().path("/admin/rooms").header("authorization", &bearer).reply(&routes).await;
+    assert_eq!(rooms.status(), 200);
+    let rooms = body_json(&rooms);
+    assert_eq!(rooms[0]["id"], ROOM);
+    assert_eq!(rooms[0]["participants"].as_array().unwrap().len(), 2);
+
+    let settings = warp::test::request()
+        .method("PATCH")
+        .path(&format!("/admin/rooms/{}/settings", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({ "bandwidth_limit": 500_000, "last_n": 1 }))
+        .reply(&routes)
+        .await;
+    assert_eq!(settings.status(), 200);
+    let room = handler.room_manager().get_room(ROOM).await.unwrap();
+    assert_eq!(room.media_settings.bandwidth_limit, Some(500_000));
+    assert_eq!(room.media_settings.last_n, Some(1));
+
+    let unknown_setting = warp::test::request()
+        .method("PATCH")
+        .path(&format!("/admin/rooms/{}/settings", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({ "volume": 11 }))
+        .reply(&routes)
+        .await;
+    assert_eq!(unknown_setting.status(), 400);
+
+    for invalid in [
+        serde_json::json!({ "max_participants": 0 }),
+        serde_json::json!({ "video_codecs": [] }),
+        serde_json::json!({ "allowed_media_types": [] }),
+        serde_json::json!({ "bandwidth_limit": 0 }),
+    ] {
+        let rejected = warp::test::request()
+            .method("PATCH")
+            .path(&format!("/admin/rooms/{}/settings", ROOM))
+            .header("authorization", &bearer)
+            .json(&invalid)
+            .reply(&routes)
+            .await;
+        assert_eq!(rejected.status(), 400, "{} was accepted", invalid);
+    }
+    let room = handler.room_manager().get_room(ROOM).await.unwrap();
+    assert_eq!(room.media_settings.max_participants, 10);
+    assert!(!room.media_settings.video_codecs.is_empty());
+
+    let promote = warp::test::request()
+        .method("PUT")
+        .path(&format!("/admin/rooms/{}/peers/bob/role", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({ "role": "moderator" }))
+        .reply(&routes)
+        .await;
+    assert_eq!(promote.status(), 204);
+    assert_eq!(handler.room_manager().get_role(ROOM, "bob").await, Some(Role::Moderator));
+    let promote_missing = warp::test::request()
+        .method("PUT")
+        .path(&format!("/admin/rooms/{}/peers/nobody/role", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({ "role": "moderator" }))
+        .reply(&routes)
+        .await;
+    assert_eq!(promote_missing.status(), 404);
+
+    let kick = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/admin/rooms/{}/peers/bob", ROOM))
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(kick.status(), 204);
+    assert!(!handler.room_manager().has_peer(ROOM, "bob").await);
+
+    let mute_missing = warp::test::request()
+        .method("POST")
+        .path(&format!("/admin/rooms/{}/peers/bob/mute", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({}))
+        .reply(&routes)
+        .await;
+    assert_eq!(mute_missing.status(), 404);
+
+    let close = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/admin/rooms/{}", ROOM))
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(close.status(), 204);
+    let gone = warp::test::request()
+        .path(&format!("/admin/rooms/{}", ROOM))
+        .header("authorization", &bearer)
+        .reply(&routes)
+        .await;
+    assert_eq!(gone.status(), 404);
+}
+
+#[tokio::test]
+async fn admin_api_controls_room_access() {
+    let handler = message_handler();
+    let routes = admin::routes(handler.clone(), Some(TOKEN.to_string()));
+    let bearer = format!("Bearer {}", TOKEN);
+    handler.join_room(ROOM.to_string(), "alice".to_string(), RelayMode::Interactive, None).await.unwrap();
+    handler.join_room(ROOM.to_string(), "bob".to_string(), RelayMode::Interactive, None).await.unwrap();
+
+    let restrict = warp::test::request()
+        .method("PATCH")
+        .path(&format!("/admin/rooms/{}/access", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({ "locked": true, "password": "s3cret", "ban_peers": ["bob"], "ban_ips": ["198.51.100.1"] }))
+        .reply(&routes)
+        .await;
+    assert_eq!(restrict.status(), 200);
+    let room = body_json(&restrict);
+    assert_eq!(room["locked"], true);
+    assert_eq!(room["password_protected"], true);
+    assert_eq!(room["banned_peers"], serde_json::json!(["bob"]));
+    assert_eq!(room["banned_ips"], serde_json::json!(["198.51.100.1"]));
+    assert!(!handler.room_manager().has_peer(ROOM, "bob").await);
+
+    let relax = warp::test::request()
+        .method("PATCH")
+        .path(&format!("/admin/rooms/{}/access", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({ "password": null, "unban_peers": ["bob"] }))
+        .reply(&routes)
+        .await;
+    let room = body_json(&relax);
+    assert_eq!(room["locked"], true);
+    assert_eq!(room["password_protected"], false);
+    assert_eq!(room["banned_peers"], serde_json::json!([]));
+
+    let unknown = warp::test::request()
+        .method("PATCH")
+        .path(&format!("/admin/rooms/{}/access", ROOM))
+        .header("authorization", &bearer)
+        .json(&serde_json::json!({ "open": true }))
+        .reply(&routes)
+        .await;
+    assert_eq!(unknown.status(), 400);
+}
@@ -0,0 +1,111 @@
+use jsonwebtoken::{encode, EncodingKey, Header};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use webrtc_server::config::{AuthConfig, AuthKey, ServerConfig};
+use webrtc_server::signaling::auth::{Claims, Role, TokenVerifier};
+use webrtc_server::signaling::handler::MessageHandler;
+use webrtc_server::types::SignalingMessage;
+use webrtc_server::utils::Error;
+
+mod common;
+use common::{handler, server_config, server_with};
+
+const ROOM: &str = "auth-room";
+const SECRET: &str = "test-secret";
+
+fn auth_config() -> AuthConfig {
+    AuthConfig { key: AuthKey::Hmac(SECRET.to_string()), issuer: None, audience: None }
+}
+
+fn message_handler() -> Arc<MessageHandler> {
+    let verifier = TokenVerifier::new(&auth_config()).unwrap();
+    Arc::new(handler().with_token_verifier(verifier))
+}
+
+fn token(sub: &str, rooms: &[&str], valid_for: i64, role: Option<Role>) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let claims = Claims {
+        sub: sub.to_string(),
+        rooms: rooms.iter().map(|room| room.to_string()).collect(),
+        exp: (now + valid_for) as u64,
+        role,
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+}
+
+fn join(peer_id: &str, token: Option<String>) -> SignalingMessage {
+    SignalingMessage::Join { room_id: ROOM.to_string(), peer_id: peer_id.to_string(), token, metadata: HashMap::new(), password: None }
+}
+
+#[tokio::test]
+async fn join_requires_matching_unexpired_token() {
+    let handler = message_handler();
+    let refused = [
+        join("alice", None),
+        join("alice", Some("not-a-jwt".to_string())),
+        join("alice", Some(token("mallory", &[ROOM], 600, None))),
+        join("alice", Some(token("alice", &["other-room"], 600, None))),
+        join("alice", Some(token("alice", &[ROOM], -600, None))),
+    ];
+    for message in refused {
+        let result = handler.handle_message(message.clone(), "alice").await;
+        assert!(matches!(result, Err(Error::Unauthorized(_))), "{:?} was not refused", message);
+    }
+    assert!(!handler.room_manager().has_peer(ROOM, "alice").await);
+
+    handler.handle_message(join("alice", Some(token("alice", &[ROOM], 600, None))), "alice").await.unwrap();
+    handler.handle_message(join("bob", Some(token("bob", &["*"], 600, None))), "bob").await.unwrap();
+    assert!(handler.room_manager().has_peer(ROOM, "alice").await);
+    assert!(handler.room_manager().has_peer(ROOM, "bob").await);
+    // Joining first makes nobody host
+    assert_eq!(handler.room_manager().get_role(ROOM, "alice").await, Some(Role::Speaker));
+
+    handler.handle_message(join("carol", Some(token("carol", &[ROOM], 600, Some(Role::Host)))), "carol").await.unwrap();
+    assert_eq!(handler.room_manager().get_role(ROOM, "carol").await, Some(Role::Host));
+}
+
+#[tokio::test]
+async fn authenticated_connection_cannot_speak_for_others() {
+    let handler = message_handler();
+    let viewer = token("viewer", &[ROOM], 600, Some(Role::Viewer));
+    handler.handle_message(join("viewer", Some(viewer)), "viewer").await.unwrap();
+
+    let impersonation = SignalingMessage::Disconnect { room_id: ROOM.to_string(), peer_id: "alice".to_string() };
+    assert!(handler.authorize_sender(Some("viewer"), &impersonation).await.is_err());
+    assert!(handler.authorize_sender(None, &impersonation).await.is_err());
+    let own = SignalingMessage::RequestPeerList { room_id: ROOM.to_string() };
+    assert!(handler.authorize_sender(Some("viewer"), &own).await.is_ok());
+
+    // Viewers may only receive
+    let publish = SignalingMessage::Offer {
+        room_id: ROOM.to_string(),
+        sdp: "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=sendrecv\r\n".to_string(),
+        from_peer: "viewer".to_string(),
+        to_peer: "server".to_string(),
+    };
+    assert!(matches!(handler.handle_message(publish, "viewer").await, Err(Error::Unauthorized(_))));
+    assert_eq!(handler.room_manager().get_role(ROOM, "viewer").await, Some(Role::Viewer));
+}
+
+#[tokio::test]
+async fn websocket_upgrade_checks_presented_token() {
+    let server = server_with(ServerConfig { auth: Some(auth_config()), ..server_config() }).await;
+    let routes = server.ws_route();
+
+    let refused = warp::test::ws().path("/?token=forged").handshake(routes.clone()).await;
+    assert!(refused.is_err());
+
+    // A token given at upgrade covers a Join that carries none
+    let alice = token("alice", &[ROOM], 600, None);
+    let mut client = warp::test::ws()
+        .path(&format!("/?token={}", alice))
+        .handshake(routes)
+        .await
+        .unwrap();
+    client.send_text(serde_json::to_string(&join("alice", None)).unwrap()).await;
+    let reply = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+    let reply: SignalingMessage = serde_json::from_str(reply.to_str().unwrap()).unwrap();
+    assert!(matches!(reply, SignalingMessage::PeerList { ref peers, ref roles, .. }
+        if peers == &vec!["alice".to_string()] && roles["alice"] == Role::Speaker));
+}
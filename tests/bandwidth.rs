@@ -0,0 +1,91 @@
+use std::time::Duration;
+use webrtc_server::media::bandwidth::{Downlink, RateMeter, MAX_BITRATE, MIN_BITRATE};
+
+// Loss reports closer together than this are ignored
+const LOSS_UPDATE_SPACING: Duration = Duration::from_millis(210);
+
+#[tokio::test]
+async fn estimate_is_the_lowest_of_remb_loss_and_limit() {
+    let downlink = Downlink::new(None);
+    assert_eq!(downlink.available_bitrate().await, 1_000_000);
+
+    downlink.on_remb(600_000).await;
+    assert_eq!(downlink.available_bitrate().await, 600_000);
+    downlink.set_limit(Some(250_000));
+    assert_eq!(downlink.limit(), Some(250_000));
+    assert_eq!(downlink.available_bitrate().await, 250_000);
+    downlink.set_limit(None);
+
+    downlink.on_remb(MAX_BITRATE * 2).await;
+    assert_eq!(downlink.available_bitrate().await, 1_000_000);
+    downlink.on_remb(1_000).await;
+    assert_eq!(downlink.available_bitrate().await, MIN_BITRATE);
+}
+
+#[tokio::test]
+async fn loss_lowers_the_estimate_and_recovery_raises_it() {
+    let downlink = Downlink::new(None);
+    downlink.on_loss(0.5).await;
+    assert_eq!(downlink.available_bitrate().await, 750_000);
+    // Too soon after the last update
+    downlink.on_loss(0.5).await;
+    assert_eq!(downlink.available_bitrate().await, 750_000);
+
+    tokio::time::sleep(LOSS_UPDATE_SPACING).await;
+    downlink.on_loss(0.05).await;
+    assert_eq!(downlink.available_bitrate().await, 750_000);
+
+    tokio::time::sleep(LOSS_UPDATE_SPACING).await;
+    downlink.on_loss(0.0).await;
+    assert_eq!(downlink.available_bitrate().await, 810_000);
+
+    // Probing stops where nothing is being sent to back it up
+    for _ in 0..3 {
+        tokio::time::sleep(LOSS_UPDATE_SPACING).await;
+        downlink.on_loss(0.0).await;
+    }
+    assert_eq!(downlink.available_bitrate().await, 1_000_000);
+}
+
+#[tokio::test]
+async fn video_streams_share_the_estimate() {
+    let downlink = Downlink::new(Some(900_000));
+    assert_eq!(downlink.per_stream_bitrate().await, 900_000);
+    downlink.add_video_stream();
+    downlink.add_video_stream();
+    downlink.add_video_stream();
+    assert_eq!(downlink.per_stream_bitrate().await, 300_000);
+
+    for _ in 0..4 {
+        downlink.remove_video_stream();
+    }
+    assert_eq!(downlink.per_stream_bitrate().await, 900_000);
+}
+
+#[tokio::test]
+async fn spending_is_capped_by_the_limit() {
+    let unlimited = Downlink::new(None);
+    assert!(unlimited.try_spend(10_000_000).await);
+
+    // 10 kB per second, and at most a second's worth at once
+    let downlink = Downlink::new(Some(80_000));
+    assert!(downlink.try_spend(6_000).await);
+    assert!(!downlink.try_spend(6_000).await);
+    assert!(downlink.try_spend(3_900).await);
+    assert!(!downlink.try_spend(1_000).await);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(downlink.try_spend(1_000).await);
+}
+
+#[tokio::test]
+async fn rate_meter_averages_over_a_second() {
+    let meter = RateMeter::new();
+    meter.record(1_000).await;
+    assert_eq!(meter.bitrate(), 0);
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    meter.record(0).await;
+    let bitrate = meter.bitrate();
+    assert!((7_000..=8_000).contains(&bitrate), "measured {} bps", bitrate);
+}
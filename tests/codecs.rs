@@ -0,0 +1,83 @@
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc_server::room::state::{MediaSettings, MediaType, VideoCodec};
+
+mod common;
+use common::{client_peer_connection, relay_manager};
+
+/// The server's answer to a browser-like offer sending audio and video.
+async fn answer(room_id: &str, settings: MediaSettings) -> String {
+    let manager = relay_manager();
+    manager.create_room(room_id, settings).await.unwrap();
+    let relay = manager.create_relay(room_id, "alice".to_string()).await.unwrap();
+
+    let client = client_peer_connection().await;
+    client.add_transceiver_from_kind(RTPCodecType::Audio, None).await.unwrap();
+    client.add_transceiver_from_kind(RTPCodecType::Video, None).await.unwrap();
+    let offer = client.create_offer(None).await.unwrap();
+
+    relay.handle_offer(offer.sdp).await.unwrap();
+    let answer = relay.peer_connection.local_description().await.unwrap().sdp;
+    let _ = client.close().await;
+    manager.remove_relay(room_id, "alice").await.unwrap();
+    answer
+}
+
+/// Media sections of `sdp`, each as its lines.
+fn sections(sdp: &str) -> Vec<Vec<&str>> {
+    let mut sections: Vec<Vec<&str>> = Vec::new();
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            sections.push(Vec::new());
+        }
+        if let Some(section) = sections.last_mut() {
+            section.push(line);
+        }
+    }
+    sections
+}
+
+fn rtpmaps<'a>(section: &[&'a str]) -> Vec<&'a str> {
+    section
+        .iter()
+        .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+        .filter_map(|rtpmap| rtpmap.split_whitespace().nth(1))
+        .collect()
+}
+
+#[tokio::test]
+async fn answers_with_the_rooms_video_codecs() {
+    let settings = MediaSettings { video_codecs: vec![VideoCodec::H264, VideoCodec::VP9], ..Default::default() };
+    let answer = answer("codec-room", settings).await;
+    let sections = sections(&answer);
+    assert_eq!(sections.len(), 2);
+
+    assert!(rtpmaps(&sections[0]).contains(&"opus/48000/2"));
+    let video = rtpmaps(&sections[1]);
+    assert!(video.contains(&"H264/90000"), "{:?}", video);
+    assert!(video.contains(&"VP9/90000"), "{:?}", video);
+    assert!(!video.contains(&"VP8/90000") && !video.contains(&"AV1/90000"), "{:?}", video);
+}
+
+#[tokio::test]
+async fn audio_only_rooms_reject_video() {
+    let settings = MediaSettings { allowed_media_types: vec![MediaType::Audio], ..Default::default() };
+    let answer = answer("audio-room", settings).await;
+    let sections = sections(&answer);
+
+    assert!(rtpmaps(&sections[0]).contains(&"opus/48000/2"));
+    assert!(sections[1][0].starts_with("m=video 0 "), "{}", sections[1][0]);
+}
+
+#[tokio::test]
+async fn screen_shares_negotiate_as_video() {
+    let settings = MediaSettings {
+        allowed_media_types: vec![MediaType::Screen],
+        video_codecs: vec![VideoCodec::VP8],
+        ..Default::default()
+    };
+    let answer = answer("screen-room", settings).await;
+    let sections = sections(&answer);
+
+    assert!(sections[0][0].starts_with("m=audio 0 "), "{}", sections[0][0]);
+    assert_eq!(rtpmaps(&sections[1]), vec!["VP8/90000"]);
+}
@@ -0,0 +1,104 @@
+//! Fixtures shared by the integration tests. Each test crate uses only
+//! some of them.
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::time::Duration;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::APIBuilder;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc_server::config::ServerConfig;
+use webrtc_server::media::{MediaRelay, MediaRelayManager};
+use webrtc_server::signaling::handler::MessageHandler;
+use webrtc_server::SignalingServer;
+
+pub fn relay_manager() -> MediaRelayManager {
+    // No STUN/TURN: host candidates on loopback are enough in-process
+    MediaRelayManager::new(String::new(), 0, String::new(), 0, String::new(), String::new())
+}
+
+/// A handler without recording, to be configured before it is shared.
+pub fn handler() -> MessageHandler {
+    MessageHandler::new(Arc::new(relay_manager()), None)
+}
+
+pub fn message_handler() -> Arc<MessageHandler> {
+    Arc::new(handler())
+}
+
+/// A server without STUN, TURN, recording or authentication.
+pub fn server_config() -> ServerConfig {
+    ServerConfig {
+        stun_server: String::new(),
+        stun_port: 0,
+        turn_server: String::new(),
+        turn_port: 0,
+        turn_username: String::new(),
+        turn_password: String::new(),
+        ws_port: 0,
+        recording_path: None,
+        sip_config: None,
+        admin_token: None,
+        auth: None,
+        resume_grace: Duration::ZERO,
+    }
+}
+
+pub async fn server() -> SignalingServer {
+    server_with(server_config()).await
+}
+
+pub async fn server_with(config: ServerConfig) -> SignalingServer {
+    SignalingServer::new(config, String::new(), 0, String::new()).await.unwrap()
+}
+
+pub async fn client_peer_connection() -> Arc<RTCPeerConnection> {
+    client_peer_connection_with(SettingEngine::default()).await
+}
+
+pub async fn client_peer_connection_with(setting_engine: SettingEngine) -> Arc<RTCPeerConnection> {
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs().unwrap();
+    let api = APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_setting_engine(setting_engine)
+        .build();
+    Arc::new(api.new_peer_connection(RTCConfiguration::default()).await.unwrap())
+}
+
+/// Full (non-trickle) offer/answer between a client and its server-side relay.
+pub async fn negotiate(client: &RTCPeerConnection, relay: &MediaRelay) {
+    let offer = client.create_offer(None).await.unwrap();
+    let mut client_gathered = client.gathering_complete_promise().await;
+    client.set_local_description(offer).await.unwrap();
+    let _ = client_gathered.recv().await;
+    let offer = client.local_description().await.unwrap();
+
+    let mut relay_gathered = relay.peer_connection.gathering_complete_promise().await;
+    relay.handle_offer(offer.sdp).await.unwrap();
+    let _ = relay_gathered.recv().await;
+    let answer = relay.peer_connection.local_description().await.unwrap();
+
+    client
+        .set_remote_description(RTCSessionDescription::answer(answer.sdp).unwrap())
+        .await
+        .unwrap();
+}
+
+/// Polls `check` for up to ten seconds, returning whether it ever held.
+pub async fn wait_for<F, Fut>(mut check: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    for _ in 0..100 {
+        if check().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
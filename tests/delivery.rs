@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use warp::test::WsClient;
+use webrtc_server::config::ServerConfig;
+use webrtc_server::signaling::protocol::{Feature, PROTOCOL_VERSION};
+use webrtc_server::signaling::routing::Delivery;
+use webrtc_server::types::{Envelope, SignalingMessage};
+use webrtc_server::SignalingServer;
+
+mod common;
+use common::{server_config, server_with};
+
+async fn server() -> SignalingServer {
+    server_with(ServerConfig { resume_grace: Duration::from_secs(5), ..server_config() }).await
+}
+
+const ROOM: &str = "support";
+
+async fn send(client: &mut WsClient, message: &SignalingMessage) {
+    client.send_text(serde_json::to_string(message).unwrap()).await;
+}
+
+async fn recv(client: &mut WsClient, wait: Duration) -> Option<Envelope<SignalingMessage>> {
+    let reply = tokio::time::timeout(wait, client.recv()).await.ok()?.unwrap();
+    Some(serde_json::from_str(reply.to_str().unwrap()).unwrap())
+}
+
+/// Opens a socket, first negotiating `acks` if `acks` is set.
+async fn connect(server: &SignalingServer, acks: bool) -> WsClient {
+    let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    if acks {
+        send(&mut client, &SignalingMessage::Hello { protocol_version: PROTOCOL_VERSION, features: vec![Feature::Acks] }).await;
+        let welcome = recv(&mut client, Duration::from_secs(5)).await.unwrap();
+        assert!(matches!(welcome.message, SignalingMessage::Welcome { features, .. } if features == [Feature::Acks]));
+    }
+    client
+}
+
+fn join_message(peer_id: &str) -> SignalingMessage {
+    SignalingMessage::Join {
+        room_id: ROOM.to_string(),
+        peer_id: peer_id.to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    }
+}
+
+/// Joins with `acks` and reads everything sent meanwhile, returning the
+/// resume token and the highest sequence number seen.
+async fn join(server: &SignalingServer, peer_id: &str) -> (WsClient, String, u64) {
+    let mut client = connect(server, true).await;
+    send(&mut client, &join_message(peer_id)).await;
+
+    let mut token = None;
+    let mut last_seq = 0;
+    while let Some(envelope) = recv(&mut client, Duration::from_millis(300)).await {
+        assert!(envelope.id.is_some());
+        let seq = envelope.seq.unwrap();
+        assert!(seq > last_seq, "sequence numbers must increase");
+        last_seq = seq;
+        if let SignalingMessage::ResumeToken { resume_token, .. } = envelope.message {
+            token = Some(resume_token);
+        }
+    }
+    (client, token.unwrap(), last_seq)
+}
+
+fn resume(peer_id: &str, resume_token: &str, last_seq: Option<u64>) -> SignalingMessage {
+    SignalingMessage::Resume {
+        room_id: ROOM.to_string(),
+        peer_id: peer_id.to_string(),
+        resume_token: resume_token.to_string(),
+        last_seq,
+    }
+}
+
+fn notice(peer_id: &str, text: &str) -> SignalingMessage {
+    SignalingMessage::ConnectionError { peer_id: peer_id.to_string(), error: text.to_string(), should_retry: true }
+}
+
+#[tokio::test]
+async fn missed_messages_are_replayed_on_resume() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let (alice, resume_token, last_seq) = join(&server, "alice").await;
+
+    drop(alice);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(handler.send_message(&notice("alice", "while away")).await.unwrap(), Delivery::Delivered(1));
+
+    let mut alice = connect(&server, true).await;
+    send(&mut alice, &resume("alice", &resume_token, Some(last_seq))).await;
+
+    // The missed message comes first, keeping its place in the stream
+    let replayed = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert_eq!(replayed.seq, Some(last_seq + 1));
+    assert!(matches!(replayed.message, SignalingMessage::ConnectionError { error, .. } if error == "while away"));
+    let fresh = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert_eq!(fresh.seq, Some(last_seq + 2));
+    assert!(matches!(fresh.message, SignalingMessage::ResumeToken { .. }));
+}
+
+#[tokio::test]
+async fn acknowledged_messages_are_not_replayed() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let (mut alice, resume_token, last_seq) = join(&server, "alice").await;
+
+    send(&mut alice, &SignalingMessage::Ack { peer_id: "alice".to_string(), last_seq }).await;
+    handler.send_message(&notice("alice", "unacknowledged")).await.unwrap();
+    let unacked = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert_eq!(unacked.seq, Some(last_seq + 1));
+
+    drop(alice);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut alice = connect(&server, true).await;
+    send(&mut alice, &resume("alice", &resume_token, None)).await;
+
+    let replayed = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert!(matches!(replayed.message, SignalingMessage::ConnectionError { error, .. } if error == "unacknowledged"));
+    let fresh = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert!(matches!(fresh.message, SignalingMessage::ResumeToken { .. }));
+}
+
+#[tokio::test]
+async fn repeated_message_ids_are_dropped() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let ack = SignalingMessage::Ack { peer_id: "alice".to_string(), last_seq: 1 };
+    let envelope = |id: &str| {
+        serde_json::to_string(&Envelope { id: Some(id.to_string()), seq: None, message: &ack }).unwrap()
+    };
+
+    assert!(handler.receive("alice", &envelope("m-1")).await.unwrap().is_some());
+    // Until it is handled an id may come again, e.g. as a retry
+    assert!(handler.receive("alice", &envelope("m-1")).await.unwrap().is_some());
+    handler.mark_handled("alice", "m-1".to_string()).await;
+    assert!(handler.receive("alice", &envelope("m-1")).await.unwrap().is_none());
+    assert!(handler.receive("alice", &envelope("m-2")).await.unwrap().is_some());
+    // Ids are per sender, and messages without one are never dropped
+    assert!(handler.receive("bob", &envelope("m-1")).await.unwrap().is_some());
+    let bare = serde_json::to_string(&ack).unwrap();
+    assert!(handler.receive("alice", &bare).await.unwrap().is_some());
+    assert!(handler.receive("alice", &bare).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn failed_requests_can_be_retried_with_the_same_id() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let (_alice, _, _) = join(&server, "alice").await;
+    handler.set_room_locked(ROOM, true).await.unwrap();
+
+    let mut bob = connect(&server, true).await;
+    let join = serde_json::to_string(&Envelope { id: Some("join-1".to_string()), seq: None, message: &join_message("bob") }).unwrap();
+    bob.send_text(join.clone()).await;
+    let refused = recv(&mut bob, Duration::from_secs(5)).await.unwrap();
+    assert!(matches!(refused.message, SignalingMessage::Error { request_id: Some(id), .. } if id == "join-1"));
+
+    handler.set_room_locked(ROOM, false).await.unwrap();
+    bob.send_text(join).await;
+    let mut joined = false;
+    while let Some(envelope) = recv(&mut bob, Duration::from_millis(300)).await {
+        joined |= matches!(envelope.message, SignalingMessage::ResumeToken { .. });
+    }
+    assert!(joined, "the retried join must be handled");
+}
+
+#[tokio::test]
+async fn peers_without_acks_get_nothing_kept_for_them() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let mut bob = connect(&server, false).await;
+    send(&mut bob, &join_message("bob")).await;
+
+    let mut resume_token = None;
+    while let Some(envelope) = recv(&mut bob, Duration::from_millis(300)).await {
+        assert!(envelope.id.is_some());
+        assert_eq!(envelope.seq, None);
+        if let SignalingMessage::ResumeToken { resume_token: token, .. } = envelope.message {
+            resume_token = Some(token);
+        }
+    }
+
+    // Bob may still resume, but nothing waits for him meanwhile
+    drop(bob);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(handler.send_message(&notice("bob", "while away")).await.unwrap(), Delivery::Undeliverable);
+    let mut bob = connect(&server, false).await;
+    send(&mut bob, &resume("bob", &resume_token.unwrap(), None)).await;
+    let fresh = recv(&mut bob, Duration::from_secs(5)).await.unwrap();
+    assert!(matches!(fresh.message, SignalingMessage::ResumeToken { .. }));
+}
+
+#[tokio::test]
+async fn audio_levels_are_neither_sequenced_nor_replayed() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let (mut alice, resume_token, last_seq) = join(&server, "alice").await;
+    let levels = SignalingMessage::AudioLevels { room_id: ROOM.to_string(), levels: HashMap::from([("alice".to_string(), 0.5)]) };
+
+    handler.send_message(&levels).await.unwrap();
+    let sent = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert!(matches!(sent.message, SignalingMessage::AudioLevels { .. }));
+    assert_eq!(sent.seq, None);
+
+    // A long stretch of talk while alice is away leaves her missed messages alone
+    drop(alice);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    handler.send_message(&notice("alice", "while away")).await.unwrap();
+    for _ in 0..300 {
+        assert_eq!(handler.send_message(&levels).await.unwrap(), Delivery::Undeliverable);
+    }
+
+    let mut alice = connect(&server, true).await;
+    send(&mut alice, &resume("alice", &resume_token, Some(last_seq))).await;
+    let replayed = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert_eq!(replayed.seq, Some(last_seq + 1));
+    assert!(matches!(replayed.message, SignalingMessage::ConnectionError { error, .. } if error == "while away"));
+    let fresh = recv(&mut alice, Duration::from_secs(5)).await.unwrap();
+    assert!(matches!(fresh.message, SignalingMessage::ResumeToken { .. }));
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use warp::test::WsClient;
+use webrtc_server::types::{Envelope, SignalingMessage};
+use webrtc_server::utils::{Error, ErrorCode};
+
+mod common;
+use common::server;
+
+const ROOM: &str = "triage";
+
+fn join(peer_id: &str) -> SignalingMessage {
+    SignalingMessage::Join {
+        room_id: ROOM.to_string(),
+        peer_id: peer_id.to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    }
+}
+
+/// Sends `message` with the envelope id `id`.
+async fn request(client: &mut WsClient, id: &str, message: &SignalingMessage) {
+    let envelope = Envelope { id: Some(id.to_string()), seq: None, message };
+    client.send_text(serde_json::to_string(&envelope).unwrap()).await;
+}
+
+/// The next error reply, skipping other messages.
+async fn next_error(client: &mut WsClient) -> (ErrorCode, Option<String>) {
+    loop {
+        let reply = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        let message: SignalingMessage = serde_json::from_str(reply.to_str().unwrap()).unwrap();
+        if let SignalingMessage::Error { code, request_id, .. } = message {
+            return (code, request_id);
+        }
+    }
+}
+
+#[tokio::test]
+async fn malformed_messages_are_answered_without_closing() {
+    let server = server().await;
+    let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+
+    client.send_text(r#"{"id":"req-1","message_type":"Join","room_id":"#).await;
+    assert_eq!(next_error(&mut client).await, (ErrorCode::MalformedMessage, None));
+    client.send_text(r#"{"id":"req-2","message_type":"Teleport"}"#).await;
+    assert_eq!(next_error(&mut client).await, (ErrorCode::MalformedMessage, Some("req-2".to_string())));
+
+    // The same socket can still join
+    request(&mut client, "req-3", &join("alice")).await;
+    tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+    assert!(server.handler.room_manager().has_peer(ROOM, "alice").await);
+}
+
+#[tokio::test]
+async fn rejected_requests_name_the_request() {
+    let server = server().await;
+    let mut alice = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    request(&mut alice, "join-alice", &join("alice")).await;
+    tokio::time::timeout(Duration::from_secs(5), alice.recv()).await.unwrap().unwrap();
+    let mut bob = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    request(&mut bob, "join-bob", &join("bob")).await;
+
+    // Only moderators lock rooms
+    let lock = SignalingMessage::LockRoom { room_id: ROOM.to_string(), peer_id: "bob".to_string(), locked: true };
+    request(&mut bob, "lock-1", &lock).await;
+    assert_eq!(next_error(&mut bob).await, (ErrorCode::Unauthorized, Some("lock-1".to_string())));
+
+    let unknown_room = SignalingMessage::LockRoom { room_id: "nowhere".to_string(), peer_id: "bob".to_string(), locked: true };
+    request(&mut bob, "lock-2", &unknown_room).await;
+    assert_eq!(next_error(&mut bob).await, (ErrorCode::RoomError, Some("lock-2".to_string())));
+    assert!(server.handler.room_manager().has_peer(ROOM, "bob").await);
+}
+
+#[test]
+fn error_codes_are_stable() {
+    let codes = [
+        (Error::SerializationError(String::new()), "malformed_message"),
+        (Error::InvalidMessage(String::new()), "invalid_message"),
+        (Error::Unauthorized(String::new()), "unauthorized"),
+        (Error::UnsupportedProtocol(String::new()), "unsupported_protocol"),
+        (Error::Room(String::new()), "room_error"),
+        (Error::NotFound(String::new()), "room_error"),
+        (Error::Peer(String::new()), "peer_error"),
+        (Error::WebRTCError(String::new()), "media_error"),
+        (Error::IO(String::new()), "internal_error"),
+    ];
+    for (error, code) in codes {
+        assert_eq!(serde_json::to_value(error.code()).unwrap(), code);
+    }
+
+    let reply = SignalingMessage::error(&Error::Room("Room x not found".to_string()), Some("r1".to_string()));
+    let json = serde_json::to_value(&reply).unwrap();
+    assert_eq!(json["message_type"], "Error");
+    assert_eq!(json["code"], "room_error");
+    assert_eq!(json["request_id"], "r1");
+}
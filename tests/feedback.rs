@@ -0,0 +1,159 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::transport_feedbacks::transport_layer_nack::{nack_pairs_from_sequence_numbers, TransportLayerNack};
+use webrtc::rtp::header::Header;
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+use webrtc_server::media::feedback::PacketHistory;
+
+mod common;
+use common::{client_peer_connection_with, negotiate, relay_manager};
+
+const ROOM: &str = "feedback-room";
+
+fn packet(sequence_number: u16) -> Packet {
+    Packet {
+        header: Header { version: 2, sequence_number, ..Default::default() },
+        payload: vec![sequence_number as u8].into(),
+    }
+}
+
+#[test]
+fn history_keeps_recent_packets_by_sequence_number() {
+    let mut history = PacketHistory::new();
+    assert!(history.get(0).is_none());
+
+    for sequence_number in 65000..=u16::MAX {
+        history.push(&packet(sequence_number));
+    }
+    for sequence_number in 0..100 {
+        history.push(&packet(sequence_number));
+    }
+    assert_eq!(history.get(65500).unwrap().header.sequence_number, 65500);
+    assert_eq!(history.get(99).unwrap().payload[0], 99);
+    // Overwritten by newer packets sharing its slot
+    assert!(history.get(65000).is_none());
+    assert!(history.get(100).is_none());
+}
+
+async fn client_peer_connection() -> Arc<RTCPeerConnection> {
+    // Retransmissions repeat sequence numbers the receiver has already seen
+    let mut setting_engine = SettingEngine::default();
+    setting_engine.disable_srtp_replay_protection(true);
+    client_peer_connection_with(setting_engine).await
+}
+
+#[tokio::test]
+async fn keyframe_requests_and_nacks_are_answered() {
+    let manager = relay_manager();
+    let relay_a = manager.create_relay(ROOM, "alice".to_string()).await.unwrap();
+    let relay_b = manager.create_relay(ROOM, "bob".to_string()).await.unwrap();
+
+    let alice = client_peer_connection().await;
+    let video = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability { mime_type: "video/VP8".to_owned(), clock_rate: 90000, ..Default::default() },
+        "alice-video".to_owned(),
+        "alice".to_owned(),
+    ));
+    let sender = alice
+        .add_track(Arc::clone(&video) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .unwrap();
+    let (pli_tx, mut pli_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((packets, _)) = sender.read_rtcp().await {
+            for packet in packets {
+                if packet.as_any().is::<PictureLossIndication>() {
+                    let _ = pli_tx.send(());
+                }
+            }
+        }
+    });
+    negotiate(&alice, &relay_a).await;
+
+    // Every frame is a keyframe so bob can start at any packet
+    let writer = Arc::clone(&video);
+    let publishing = tokio::spawn(async move {
+        let mut sequence_number: u16 = 1000;
+        loop {
+            let packet = Packet {
+                header: Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 3000,
+                    marker: true,
+                    ..Default::default()
+                },
+                payload: vec![0x10, 0x00, 0x9D, 0x01, 0x2A].into(),
+            };
+            let _ = writer.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+    let subscribed = async {
+        while relay_b.subscription_keys().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), subscribed).await.unwrap();
+
+    let bob = client_peer_connection().await;
+    bob.add_transceiver_from_kind(
+        RTPCodecType::Video,
+        Some(RTCRtpTransceiverInit { direction: RTCRtpTransceiverDirection::Recvonly, send_encodings: vec![] }),
+    )
+    .await
+    .unwrap();
+    let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+    bob.on_track(Box::new(move |track, _, _| {
+        let received_tx = received_tx.clone();
+        Box::pin(async move {
+            while let Ok((packet, _)) = track.read_rtp().await {
+                let _ = received_tx.send((packet.header.ssrc, packet.header.sequence_number));
+            }
+        })
+    }));
+    negotiate(&bob, &relay_b).await;
+
+    let (ssrc, lost) = tokio::time::timeout(Duration::from_secs(10), received_rx.recv())
+        .await
+        .expect("bob did not receive forwarded RTP in time")
+        .unwrap();
+
+    // Bob's keyframe request reaches the publisher, once requests made
+    // while he was joining are no longer throttled
+    while pli_rx.try_recv().is_ok() {}
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    bob.write_rtcp(&[Box::new(PictureLossIndication { sender_ssrc: 0, media_ssrc: ssrc })]).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), pli_rx.recv())
+        .await
+        .expect("alice was not asked for a keyframe")
+        .unwrap();
+
+    // A packet bob reports lost is sent again from the history
+    let nack = TransportLayerNack { sender_ssrc: 0, media_ssrc: ssrc, nacks: nack_pairs_from_sequence_numbers(&[lost]) };
+    bob.write_rtcp(&[Box::new(nack)]).await.unwrap();
+    let resent = async {
+        while let Some((_, sequence_number)) = received_rx.recv().await {
+            if sequence_number == lost {
+                return;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), resent).await.expect("the lost packet was not retransmitted");
+
+    publishing.abort();
+    let _ = alice.close().await;
+    let _ = bob.close().await;
+    manager.remove_relay(ROOM, "alice").await.unwrap();
+    manager.remove_relay(ROOM, "bob").await.unwrap();
+}
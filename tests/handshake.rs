@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use warp::test::WsClient;
+use webrtc_server::config::ServerConfig;
+use webrtc_server::signaling::protocol::{Feature, PROTOCOL_VERSION};
+use webrtc_server::types::{Envelope, IceServer, SignalingMessage};
+use webrtc_server::media::simulcast::SimulcastQuality;
+use webrtc_server::utils::Error;
+use webrtc_server::SignalingServer;
+
+mod common;
+use common::{server_config, server_with};
+
+async fn server() -> SignalingServer {
+    let config = ServerConfig {
+        stun_server: "stun.example.org".to_string(),
+        stun_port: 3478,
+        ..server_config()
+    };
+    server_with(config).await
+}
+
+async fn recv(client: &mut WsClient) -> warp::ws::Message {
+    tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap()
+}
+
+fn parse(message: &warp::ws::Message) -> SignalingMessage {
+    serde_json::from_slice::<Envelope<SignalingMessage>>(message.as_bytes()).unwrap().message
+}
+
+#[tokio::test]
+async fn welcome_announces_negotiated_features() {
+    let server = server().await;
+    let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    let hello = r#"{"message_type":"Hello","protocol_version":2,"features":["trickle","binary_frames","holograms"]}"#;
+    client.send_text(hello).await;
+
+    let welcome = recv(&mut client).await;
+    assert!(welcome.is_text());
+    let SignalingMessage::Welcome { protocol_version, features, ice_servers, session_id, .. } = parse(&welcome) else {
+        panic!("expected a Welcome");
+    };
+    assert_eq!(protocol_version, PROTOCOL_VERSION);
+    assert_eq!(features, vec![Feature::Trickle, Feature::BinaryFrames]);
+    let stun = IceServer { urls: vec!["stun:stun.example.org:3478".to_string()], username: None, credential: None };
+    assert_eq!(ice_servers, vec![stun]);
+    assert!(!session_id.is_empty());
+
+    // Everything after the Welcome comes in binary frames
+    let join = SignalingMessage::Join {
+        room_id: "lab".to_string(),
+        peer_id: "alice".to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    };
+    client.send(warp::ws::Message::binary(serde_json::to_vec(&join).unwrap())).await;
+    let reply = recv(&mut client).await;
+    assert!(reply.is_binary());
+    parse(&reply);
+
+    // Recorded once the join completes, which may trail its first reply
+    let handler = server.handler.clone();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handler.peer_features("alice").await != [Feature::Trickle, Feature::BinaryFrames] {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let layers = SignalingMessage::LayerPreference {
+        room_id: "lab".to_string(),
+        peer_id: "alice".to_string(),
+        publisher_id: "bob".to_string(),
+        quality: SimulcastQuality::Low,
+    };
+    assert!(matches!(handler.handle_message(layers, "alice").await, Err(Error::InvalidMessage(_))));
+}
+
+#[tokio::test]
+async fn clients_without_hello_get_legacy_features() {
+    let server = server().await;
+    let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    let join = SignalingMessage::Join {
+        room_id: "lab".to_string(),
+        peer_id: "bob".to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    };
+    client.send_text(serde_json::to_string(&join).unwrap()).await;
+
+    assert!(recv(&mut client).await.is_text());
+    assert_eq!(server.handler.peer_features("bob").await, Feature::LEGACY.to_vec());
+
+    // Layer preferences predate the handshake
+    let layers = SignalingMessage::LayerPreference {
+        room_id: "lab".to_string(),
+        peer_id: "bob".to_string(),
+        publisher_id: "alice".to_string(),
+        quality: SimulcastQuality::Low,
+    };
+    server.handler.handle_message(layers, "bob").await.unwrap();
+}
+
+#[tokio::test]
+async fn incompatible_versions_are_refused() {
+    let server = server().await;
+    let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    let hello = SignalingMessage::Hello { protocol_version: PROTOCOL_VERSION + 1, features: vec![] };
+    client.send_text(serde_json::to_string(&hello).unwrap()).await;
+
+    let refusal = parse(&recv(&mut client).await);
+    assert!(matches!(refusal, SignalingMessage::ConnectionError { should_retry: false, .. }));
+    tokio::time::timeout(Duration::from_secs(5), client.recv_closed()).await.unwrap().unwrap();
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use warp::test::WsClient;
+use webrtc_server::room::state::Role;
+use webrtc_server::types::SignalingMessage;
+use webrtc_server::utils::Error;
+
+mod common;
+use common::{message_handler, server};
+
+const ROOM: &str = "meeting";
+
+fn join(peer_id: &str, display_name: &str) -> SignalingMessage {
+    let metadata = HashMap::from([("display_name".to_string(), display_name.to_string())]);
+    SignalingMessage::Join { room_id: ROOM.to_string(), peer_id: peer_id.to_string(), token: None, metadata, password: None }
+}
+
+async fn recv(client: &mut WsClient) -> SignalingMessage {
+    let reply = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+    serde_json::from_str(reply.to_str().unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn lobby_holds_guests_until_admitted() {
+    let handler = message_handler();
+    let rooms = handler.room_manager();
+    handler.handle_message(join("host", "Host"), "host").await.unwrap();
+    handler.set_role(ROOM, "host", Role::Host).await.unwrap();
+    let lobby_on = SignalingMessage::SetLobby { room_id: ROOM.to_string(), peer_id: "host".to_string(), enabled: true };
+    handler.handle_message(lobby_on, "host").await.unwrap();
+
+    handler.handle_message(join("guest", "Guest"), "guest").await.unwrap();
+    handler.handle_message(join("crasher", "Crasher"), "crasher").await.unwrap();
+    assert!(!rooms.has_peer(ROOM, "guest").await);
+    assert!(rooms.relay_manager().get_relay(ROOM, "guest").await.is_none());
+    let room = rooms.get_room(ROOM).await.unwrap();
+    assert_eq!(room.lobby.len(), 2);
+    assert_eq!(room.lobby[0].metadata["display_name"], "Guest");
+
+    // Waiting peers can't see who is inside
+    let peek = SignalingMessage::RequestPeerList { room_id: ROOM.to_string() };
+    assert!(handler.handle_message(peek, "guest").await.is_err());
+
+    // Only moderators decide who gets in
+    let self_admit = SignalingMessage::AdmitPeer {
+        room_id: ROOM.to_string(),
+        peer_id: "guest".to_string(),
+        target_peer: "guest".to_string(),
+    };
+    assert!(handler.handle_message(self_admit, "guest").await.is_err());
+
+    let admit = SignalingMessage::AdmitPeer {
+        room_id: ROOM.to_string(),
+        peer_id: "host".to_string(),
+        target_peer: "guest".to_string(),
+    };
+    handler.handle_message(admit, "host").await.unwrap();
+    let deny = SignalingMessage::DenyPeer {
+        room_id: ROOM.to_string(),
+        peer_id: "host".to_string(),
+        target_peer: "crasher".to_string(),
+        reason: None,
+    };
+    handler.handle_message(deny, "host").await.unwrap();
+
+    assert!(rooms.has_peer(ROOM, "guest").await);
+    assert!(!rooms.has_peer(ROOM, "crasher").await);
+    assert!(rooms.get_room(ROOM).await.unwrap().lobby.is_empty());
+}
+
+#[tokio::test]
+async fn turning_the_lobby_off_admits_everyone_waiting() {
+    let handler = message_handler();
+    let rooms = handler.room_manager();
+    handler.handle_message(join("host", "Host"), "host").await.unwrap();
+    handler.set_role(ROOM, "host", Role::Host).await.unwrap();
+    rooms.set_lobby_enabled(ROOM, true).await.unwrap();
+    handler.handle_message(join("guest", "Guest"), "guest").await.unwrap();
+
+    // A waiting peer that leaves withdraws its request
+    handler.handle_message(join("quitter", "Quitter"), "quitter").await.unwrap();
+    handler.handle_disconnect("quitter", ROOM).await.unwrap();
+
+    let lobby_off = SignalingMessage::SetLobby { room_id: ROOM.to_string(), peer_id: "host".to_string(), enabled: false };
+    handler.handle_message(lobby_off, "host").await.unwrap();
+    assert_eq!(rooms.get_room_peers(ROOM).await.len(), 2);
+    assert!(rooms.has_peer(ROOM, "guest").await);
+    assert!(!rooms.has_peer(ROOM, "quitter").await);
+}
+
+#[tokio::test]
+async fn admission_rechecks_the_lock() {
+    let handler = message_handler();
+    let rooms = handler.room_manager();
+    handler.handle_message(join("host", "Host"), "host").await.unwrap();
+    handler.set_role(ROOM, "host", Role::Host).await.unwrap();
+    rooms.set_lobby_enabled(ROOM, true).await.unwrap();
+    handler.handle_message(join("guest", "Guest"), "guest").await.unwrap();
+    handler.handle_message(join("latecomer", "Latecomer"), "latecomer").await.unwrap();
+
+    // The room is locked while they wait
+    handler.set_room_locked(ROOM, true).await.unwrap();
+    let admit = SignalingMessage::AdmitPeer {
+        room_id: ROOM.to_string(),
+        peer_id: "host".to_string(),
+        target_peer: "guest".to_string(),
+    };
+    assert!(matches!(handler.handle_message(admit, "host").await, Err(Error::Unauthorized(_))));
+    assert!(!rooms.has_peer(ROOM, "guest").await);
+
+    let lobby_off = SignalingMessage::SetLobby { room_id: ROOM.to_string(), peer_id: "host".to_string(), enabled: false };
+    handler.handle_message(lobby_off, "host").await.unwrap();
+    assert_eq!(rooms.get_room_peers(ROOM).await, vec!["host".to_string()]);
+    assert!(rooms.get_room(ROOM).await.unwrap().lobby.is_empty());
+}
+
+#[tokio::test]
+async fn admission_rechecks_bans() {
+    let handler = message_handler();
+    let rooms = handler.room_manager();
+    handler.handle_message(join("host", "Host"), "host").await.unwrap();
+    handler.set_role(ROOM, "host", Role::Host).await.unwrap();
+    rooms.set_lobby_enabled(ROOM, true).await.unwrap();
+    let spammer: std::net::IpAddr = "203.0.113.7".parse().unwrap();
+    handler.set_peer_addr("spammer", spammer).await;
+    handler.handle_message(join("spammer", "Spammer"), "spammer").await.unwrap();
+
+    // Banned without being turned away, as a ban made directly on the room is
+    rooms.update_room(ROOM, |room| room.banned_ips.insert(spammer)).await.unwrap();
+    assert!(matches!(handler.admit_peer(ROOM, "spammer").await, Err(Error::Unauthorized(_))));
+    assert!(!rooms.has_peer(ROOM, "spammer").await);
+    assert!(rooms.get_room(ROOM).await.unwrap().lobby.is_empty());
+}
+
+#[tokio::test]
+async fn closing_the_room_turns_away_everyone_waiting() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let rooms = handler.room_manager();
+    handler.handle_message(join("host", "Host"), "host").await.unwrap();
+    rooms.set_lobby_enabled(ROOM, true).await.unwrap();
+
+    let mut guest = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    guest.send_text(serde_json::to_string(&join("guest", "Guest")).unwrap()).await;
+    assert!(matches!(recv(&mut guest).await, SignalingMessage::LobbyWaiting { .. }));
+
+    handler.close_room(ROOM, "Room closed").await.unwrap();
+    let denied = recv(&mut guest).await;
+    assert!(matches!(denied, SignalingMessage::ConnectionError { should_retry: false, .. }), "{:?}", denied);
+    assert!(rooms.get_room(ROOM).await.is_err());
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use tokio::sync::{broadcast, mpsc};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc_server::media::negotiation::Negotiator;
+use webrtc_server::signaling::connection::Connection;
+use webrtc_server::signaling::connection_state::{ConnectionState, ConnectionStateManager};
+use webrtc_server::signaling::transport::{Frame, SignalingTransport};
+use webrtc_server::types::SignalingMessage;
+use webrtc_server::utils::Error;
+
+mod common;
+use common::{client_peer_connection, message_handler, relay_manager};
+
+/// A client and the server side of its session, after the client's first offer.
+async fn negotiated() -> (Arc<RTCPeerConnection>, Arc<RTCPeerConnection>, Negotiator, broadcast::Receiver<SignalingMessage>) {
+    let (client, server) = (client_peer_connection().await, client_peer_connection().await);
+    let (events, received) = broadcast::channel(16);
+    let negotiator = Negotiator::new("room", "client", Arc::downgrade(&server), events);
+
+    client.add_transceiver_from_kind(RTPCodecType::Audio, None).await.unwrap();
+    let offer = client.create_offer(None).await.unwrap();
+    client.set_local_description(offer.clone()).await.unwrap();
+    let answer = negotiator.handle_offer(offer.sdp).await.unwrap();
+    client.set_remote_description(answer).await.unwrap();
+    negotiator.flush().await.unwrap();
+    (client, server, negotiator, received)
+}
+
+/// Server offers sent so far, as (sdp, whether it restarts ICE).
+fn server_offers(received: &mut broadcast::Receiver<SignalingMessage>) -> Vec<(String, bool)> {
+    let mut offers = Vec::new();
+    while let Ok(message) = received.try_recv() {
+        match message {
+            SignalingMessage::Offer { sdp, .. } => offers.push((sdp, false)),
+            SignalingMessage::IceRestart { sdp: Some(sdp), .. } => offers.push((sdp, true)),
+            _ => {}
+        }
+    }
+    offers
+}
+
+fn ice_ufrag(sdp: &str) -> &str {
+    sdp.lines().find_map(|line| line.strip_prefix("a=ice-ufrag:")).unwrap()
+}
+
+#[tokio::test]
+async fn restart_requested_mid_exchange_is_sent_afterwards() {
+    let (client, server, negotiator, mut received) = negotiated().await;
+    let first_ufrag = ice_ufrag(&server.local_description().await.unwrap().sdp).to_string();
+
+    // The peer's next offer is being answered when the restart is asked for
+    let offer = client.create_offer(None).await.unwrap();
+    client.set_local_description(offer.clone()).await.unwrap();
+    server.set_remote_description(RTCSessionDescription::offer(offer.sdp).unwrap()).await.unwrap();
+    negotiator.restart_ice().await.unwrap();
+    assert!(negotiator.is_restarting());
+    assert!(server_offers(&mut received).is_empty());
+
+    let answer = server.create_answer(None).await.unwrap();
+    server.set_local_description(answer.clone()).await.unwrap();
+    client.set_remote_description(answer).await.unwrap();
+    negotiator.flush().await.unwrap();
+
+    let offers = server_offers(&mut received);
+    assert_eq!(offers.len(), 1);
+    let (sdp, ice_restart) = &offers[0];
+    assert!(ice_restart);
+    assert_ne!(ice_ufrag(sdp), first_ufrag);
+
+    negotiator.restart_done();
+    assert!(!negotiator.is_restarting());
+}
+
+/// ICE candidates the server has sent so far.
+fn server_candidates(received: &mut broadcast::Receiver<SignalingMessage>) -> Vec<String> {
+    let mut candidates = Vec::new();
+    while let Ok(message) = received.try_recv() {
+        if let SignalingMessage::IceCandidate { candidate, from_peer, to_peer, .. } = message {
+            assert_eq!((from_peer.as_str(), to_peer.as_str()), ("server", "client"));
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+#[tokio::test]
+async fn server_candidates_wait_for_the_answer_to_be_sent() {
+    let (client, _server, negotiator, mut received) = negotiated().await;
+    let candidate = |n: u32| RTCIceCandidateInit { candidate: format!("candidate:{} 1 udp 1 127.0.0.1 9 typ host", n), ..Default::default() };
+
+    negotiator.send_candidate(candidate(1)).await;
+    assert_eq!(server_candidates(&mut received).len(), 1);
+
+    // Gathered while the answer to the next offer is on its way
+    let offer = client.create_offer(None).await.unwrap();
+    client.set_local_description(offer.clone()).await.unwrap();
+    negotiator.handle_offer(offer.sdp).await.unwrap();
+    negotiator.send_candidate(candidate(2)).await;
+    negotiator.send_candidate(RTCIceCandidateInit::default()).await;
+    assert!(server_candidates(&mut received).is_empty());
+
+    negotiator.flush().await.unwrap();
+    let candidates = server_candidates(&mut received);
+    assert_eq!(candidates.len(), 2);
+    assert!(candidates[0].contains("candidate:2 "));
+    let end: RTCIceCandidateInit = serde_json::from_str(&candidates[1]).unwrap();
+    assert!(end.candidate.is_empty());
+}
+
+#[tokio::test]
+async fn candidates_before_the_offer_are_applied_with_it() {
+    let manager = relay_manager();
+    let relay = manager.create_relay("trickle-room", "client".to_string()).await.unwrap();
+
+    let client = client_peer_connection().await;
+    client.add_transceiver_from_kind(RTPCodecType::Audio, None).await.unwrap();
+    let (candidate_tx, mut candidate_rx) = mpsc::unbounded_channel();
+    client.on_ice_candidate(Box::new(move |candidate| {
+        let candidate_tx = candidate_tx.clone();
+        Box::pin(async move {
+            let _ = candidate_tx.send(candidate.map(|candidate| candidate.to_json().unwrap()));
+        })
+    }));
+    let (connected_tx, mut connected_rx) = mpsc::channel(1);
+    client.on_peer_connection_state_change(Box::new(move |state| {
+        let connected_tx = connected_tx.clone();
+        Box::pin(async move {
+            if state == RTCPeerConnectionState::Connected {
+                let _ = connected_tx.try_send(());
+            }
+        })
+    }));
+
+    let offer = client.create_offer(None).await.unwrap();
+    client.set_local_description(offer.clone()).await.unwrap();
+    while let Some(Some(candidate)) = candidate_rx.recv().await {
+        relay.add_ice_candidate(candidate).await.unwrap();
+    }
+
+    // The client never learns the server's candidates, so only the server's
+    // checks toward the buffered ones can connect them
+    let answer = relay.handle_offer(offer.sdp).await.unwrap();
+    let answer: String = answer
+        .sdp
+        .lines()
+        .filter(|line| !line.starts_with("a=candidate:"))
+        .map(|line| format!("{}\r\n", line))
+        .collect();
+    client.set_remote_description(RTCSessionDescription::answer(answer).unwrap()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), connected_rx.recv())
+        .await
+        .expect("buffered candidates were not applied");
+
+    let _ = client.close().await;
+    manager.remove_relay("trickle-room", "client").await.unwrap();
+}
+
+#[tokio::test]
+async fn colliding_client_offer_yields_to_the_server_offer() {
+    let (client, server, negotiator, mut received) = negotiated().await;
+    server.add_transceiver_from_kind(RTPCodecType::Video, None).await.unwrap();
+    negotiator.request().await.unwrap();
+    let offers = server_offers(&mut received);
+    assert_eq!(offers.len(), 1);
+
+    // Sent without being applied, as if the client had since rolled it back.
+    // webrtc 0.11 refuses rollbacks outside `stable`, so the server can't yield
+    let colliding = client.create_offer(None).await.unwrap();
+    assert!(matches!(negotiator.handle_offer(colliding.sdp).await, Err(Error::Peer(_))));
+    assert_eq!(server.signaling_state(), RTCSignalingState::HaveLocalOffer);
+
+    client.set_remote_description(RTCSessionDescription::offer(offers[0].0.clone()).unwrap()).await.unwrap();
+    let answer = client.create_answer(None).await.unwrap();
+    client.set_local_description(answer.clone()).await.unwrap();
+    negotiator.handle_answer(answer.sdp).await.unwrap();
+    negotiator.flush().await.unwrap();
+    assert!(server_offers(&mut received).is_empty());
+
+    // Its offer goes through once the server's exchange is done
+    let offer = client.create_offer(None).await.unwrap();
+    client.set_local_description(offer.clone()).await.unwrap();
+    let answer = negotiator.handle_offer(offer.sdp).await.unwrap();
+    client.set_remote_description(answer).await.unwrap();
+    assert_eq!(server.signaling_state(), RTCSignalingState::Stable);
+}
+
+#[tokio::test]
+async fn restart_sends_an_offer_with_new_credentials() {
+    let server = client_peer_connection().await;
+    let (events, mut received) = broadcast::channel(16);
+    let negotiator = Negotiator::new("room", "client", Arc::downgrade(&server), events);
+    // Nothing to restart before the first exchange
+    negotiator.restart_ice().await.unwrap();
+    assert!(!negotiator.is_restarting());
+    assert!(server_offers(&mut received).is_empty());
+
+    let (_client, server, negotiator, mut received) = negotiated().await;
+    let first_ufrag = ice_ufrag(&server.local_description().await.unwrap().sdp).to_string();
+    negotiator.restart_ice().await.unwrap();
+    assert!(negotiator.is_restarting());
+    let offers = server_offers(&mut received);
+    assert_eq!(offers.len(), 1);
+    assert!(offers[0].1);
+    assert_ne!(ice_ufrag(&offers[0].0), first_ufrag);
+}
+
+#[tokio::test]
+async fn restart_that_never_completes_is_given_up() {
+    let (_client, server, _, _) = negotiated().await;
+    let (events, mut received) = broadcast::channel(16);
+    let negotiator = Negotiator::new("room", "client", Arc::downgrade(&server), events)
+        .with_restart_timeout(Duration::from_millis(200));
+    negotiator.restart_ice().await.unwrap();
+    assert!(negotiator.is_restarting());
+    assert_eq!(server_offers(&mut received).len(), 1);
+
+    // The connection never comes back, so the peer is left to be reaped
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!negotiator.is_restarting());
+    negotiator.restart_ice().await.unwrap();
+    assert!(!negotiator.is_restarting());
+    assert!(server_offers(&mut received).is_empty());
+}
+
+#[tokio::test]
+async fn restart_waits_for_an_outstanding_server_offer() {
+    let (client, server, negotiator, mut received) = negotiated().await;
+    server.add_transceiver_from_kind(RTPCodecType::Video, None).await.unwrap();
+    negotiator.request().await.unwrap();
+    let offers = server_offers(&mut received);
+    assert_eq!(offers.len(), 1);
+    assert!(!offers[0].1);
+    let first_ufrag = ice_ufrag(&offers[0].0).to_string();
+
+    // The client may still answer the offer it has, so it stays outstanding
+    negotiator.restart_ice().await.unwrap();
+    assert!(negotiator.is_restarting());
+    assert!(server_offers(&mut received).is_empty());
+
+    client.set_remote_description(RTCSessionDescription::offer(offers[0].0.clone()).unwrap()).await.unwrap();
+    let answer = client.create_answer(None).await.unwrap();
+    client.set_local_description(answer.clone()).await.unwrap();
+    negotiator.handle_answer(answer.sdp).await.unwrap();
+    negotiator.flush().await.unwrap();
+
+    let offers = server_offers(&mut received);
+    assert_eq!(offers.len(), 1);
+    let (sdp, ice_restart) = &offers[0];
+    assert!(ice_restart);
+    assert_ne!(ice_ufrag(sdp), first_ufrag);
+    assert!(sdp.contains("m=video"));
+}
+
+/// A transport that drops whatever the server sends.
+struct Silent;
+
+#[async_trait]
+impl SignalingTransport for Silent {
+    async fn send(&self, _text: String, _binary: bool) -> webrtc_server::utils::Result<()> {
+        Ok(())
+    }
+
+    async fn ping(&self) -> webrtc_server::utils::Result<()> {
+        Ok(())
+    }
+
+    async fn close(&self) -> webrtc_server::utils::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn peer_states_follow_ice_restarts() {
+    let handler = message_handler();
+    let relay_manager = handler.room_manager().relay_manager();
+    let states = Arc::new(ConnectionStateManager::new());
+    states.clone().follow_peer_connections(&relay_manager);
+
+    // The socket stays open after its Join
+    let connection = Connection::open(handler, states.clone(), Arc::new(Silent), None, None).await.unwrap();
+    let join = SignalingMessage::Join {
+        room_id: "restart-room".to_string(),
+        peer_id: "client".to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    };
+    let frames = stream::iter(vec![Ok(Frame::Text(serde_json::to_string(&join).unwrap()))]).chain(stream::pending());
+    let serving = tokio::spawn(connection.serve(frames));
+
+    let reached = |expected: ConnectionState| {
+        let states = states.clone();
+        async move {
+            while states.get_state("client").await != Some(expected.clone()) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), reached(ConnectionState::New)).await.unwrap();
+    let relay = relay_manager.get_relay("restart-room", "client").await.unwrap();
+
+    let client = client_peer_connection().await;
+    client.add_transceiver_from_kind(RTPCodecType::Audio, None).await.unwrap();
+    let offer = client.create_offer(None).await.unwrap();
+    let mut gathered = client.gathering_complete_promise().await;
+    client.set_local_description(offer).await.unwrap();
+    let _ = gathered.recv().await;
+    let offer = client.local_description().await.unwrap();
+    let mut relay_gathered = relay.peer_connection.gathering_complete_promise().await;
+    relay.handle_offer(offer.sdp).await.unwrap();
+    let _ = relay_gathered.recv().await;
+    let answer = relay.peer_connection.local_description().await.unwrap();
+    client.set_remote_description(RTCSessionDescription::answer(answer.sdp).unwrap()).await.unwrap();
+    relay.flush_negotiation().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), reached(ConnectionState::Connected)).await.unwrap();
+
+    relay.restart_ice().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), reached(ConnectionState::Restarting)).await.unwrap();
+    assert!(relay.is_restarting());
+    // A restarting peer does not go back to negotiating its first offer
+    assert!(!states.transition("client", ConnectionState::OfferReceived).await);
+
+    serving.abort();
+    let _ = client.close().await;
+    relay_manager.remove_relay("restart-room", "client").await.unwrap();
+}
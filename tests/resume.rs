@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use warp::test::WsClient;
+use webrtc_server::config::ServerConfig;
+use webrtc_server::types::{Envelope, SignalingMessage};
+use webrtc_server::SignalingServer;
+
+mod common;
+use common::{server_config, server_with};
+
+async fn server(resume_grace: Duration) -> SignalingServer {
+    server_with(ServerConfig { resume_grace, ..server_config() }).await
+}
+
+const ROOM: &str = "standup";
+
+async fn send(client: &mut WsClient, message: &SignalingMessage) {
+    client.send_text(serde_json::to_string(message).unwrap()).await;
+}
+
+/// Reads messages until one matches `wanted`, also returning its sequence number.
+async fn expect_seq<T>(client: &mut WsClient, wanted: impl Fn(SignalingMessage) -> Option<T>) -> (T, Option<u64>) {
+    loop {
+        let reply = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        let envelope: Envelope<SignalingMessage> = serde_json::from_str(reply.to_str().unwrap()).unwrap();
+        if let Some(found) = wanted(envelope.message) {
+            return (found, envelope.seq);
+        }
+    }
+}
+
+/// Reads messages until one matches `wanted`.
+async fn expect<T>(client: &mut WsClient, wanted: impl Fn(SignalingMessage) -> Option<T>) -> T {
+    expect_seq(client, wanted).await.0
+}
+
+/// Joins `peer_id`, returning its resume token and the sequence number it came with.
+async fn join(server: &SignalingServer, peer_id: &str) -> (WsClient, String, Option<u64>) {
+    let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    let join = SignalingMessage::Join {
+        room_id: ROOM.to_string(),
+        peer_id: peer_id.to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    };
+    send(&mut client, &join).await;
+    let (resume_token, seq) = expect_seq(&mut client, |message| match message {
+        SignalingMessage::ResumeToken { resume_token, grace_period_secs, .. } => {
+            assert_eq!(grace_period_secs, 1);
+            Some(resume_token)
+        }
+        _ => None,
+    })
+    .await;
+    (client, resume_token, seq)
+}
+
+fn resume(peer_id: &str, resume_token: &str, last_seq: Option<u64>) -> SignalingMessage {
+    SignalingMessage::Resume {
+        room_id: ROOM.to_string(),
+        peer_id: peer_id.to_string(),
+        resume_token: resume_token.to_string(),
+        last_seq,
+    }
+}
+
+#[tokio::test]
+async fn dropped_peer_resumes_within_grace_period() {
+    let server = server(Duration::from_secs(1)).await;
+    let rooms = server.handler.room_manager();
+    let (alice, resume_token, last_seq) = join(&server, "alice").await;
+    let (_bob, _, _) = join(&server, "bob").await;
+
+    drop(alice);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(rooms.has_peer(ROOM, "alice").await);
+
+    let mut alice = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    send(&mut alice, &resume("alice", "not-the-token", last_seq)).await;
+    let should_retry = expect(&mut alice, |message| match message {
+        SignalingMessage::ConnectionError { should_retry, .. } => Some(should_retry),
+        _ => None,
+    })
+    .await;
+    assert!(should_retry);
+
+    send(&mut alice, &resume("alice", &resume_token, last_seq)).await;
+    let fresh_token = expect(&mut alice, |message| match message {
+        SignalingMessage::ResumeToken { resume_token, .. } => Some(resume_token),
+        _ => None,
+    })
+    .await;
+    assert_ne!(fresh_token, resume_token);
+    let peers = expect(&mut alice, |message| match message {
+        SignalingMessage::PeerList { peers, .. } => Some(peers),
+        _ => None,
+    })
+    .await;
+    assert_eq!(peers.len(), 2);
+
+    // Outliving the grace period on the new socket is fine
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(rooms.has_peer(ROOM, "alice").await);
+}
+
+#[tokio::test]
+async fn session_expires_after_grace_period() {
+    let server = server(Duration::from_secs(1)).await;
+    let rooms = server.handler.room_manager();
+    let (alice, resume_token, last_seq) = join(&server, "alice").await;
+    let (_bob, _, _) = join(&server, "bob").await;
+
+    drop(alice);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(!rooms.has_peer(ROOM, "alice").await);
+
+    let mut alice = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    send(&mut alice, &resume("alice", &resume_token, last_seq)).await;
+    expect(&mut alice, |message| match message {
+        SignalingMessage::ConnectionError { should_retry: true, .. } => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn live_sessions_are_not_resumed() {
+    let server = server(Duration::from_secs(1)).await;
+    let (mut alice, resume_token, last_seq) = join(&server, "alice").await;
+
+    let mut other = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    send(&mut other, &resume("alice", &resume_token, last_seq)).await;
+    expect(&mut other, |message| match message {
+        SignalingMessage::ConnectionError { should_retry: true, .. } => Some(()),
+        _ => None,
+    })
+    .await;
+    let peer_list = SignalingMessage::RequestPeerList { room_id: ROOM.to_string() };
+    send(&mut other, &peer_list).await;
+    expect(&mut other, |message| match message {
+        SignalingMessage::Error { .. } => Some(()),
+        _ => None,
+    })
+    .await;
+
+    // The first socket still speaks for alice
+    send(&mut alice, &peer_list).await;
+    let peers = expect(&mut alice, |message| match message {
+        SignalingMessage::PeerList { peers, .. } => Some(peers),
+        _ => None,
+    })
+    .await;
+    assert_eq!(peers, vec!["alice".to_string()]);
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+use webrtc_server::media::{MediaRelay, RelayMode};
+use webrtc_server::room::state::Role;
+use webrtc_server::signaling::handler::MessageHandler;
+use webrtc_server::types::SignalingMessage;
+use webrtc_server::utils::Error;
+
+mod common;
+use common::{client_peer_connection, message_handler, negotiate, relay_manager, wait_for};
+
+const ROOM: &str = "class";
+
+async fn join(handler: &MessageHandler, peer_id: &str) {
+    let join = SignalingMessage::Join { room_id: ROOM.to_string(), peer_id: peer_id.to_string(), token: None, metadata: HashMap::new(), password: None };
+    handler.handle_message(join, peer_id).await.unwrap();
+}
+
+fn set_role(actor: &str, target: &str, role: Role) -> SignalingMessage {
+    SignalingMessage::SetRole {
+        room_id: ROOM.to_string(),
+        peer_id: actor.to_string(),
+        target_peer: target.to_string(),
+        role,
+    }
+}
+
+fn kick(actor: &str, target: &str) -> SignalingMessage {
+    SignalingMessage::KickPeer {
+        room_id: ROOM.to_string(),
+        peer_id: actor.to_string(),
+        target_peer: target.to_string(),
+        reason: None,
+    }
+}
+
+fn refused(result: Result<(), Error>) -> bool {
+    matches!(result, Err(Error::Unauthorized(_)))
+}
+
+fn video_track(peer_id: &str) -> Arc<TrackLocalStaticRTP> {
+    Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: "video/VP8".to_owned(),
+            clock_rate: 90000,
+            ..Default::default()
+        },
+        format!("{}-video", peer_id),
+        peer_id.to_owned(),
+    ))
+}
+
+/// Sends a VP8 keyframe on `video` every frame until aborted.
+fn send_video(video: Arc<TrackLocalStaticRTP>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut sequence_number: u16 = 0;
+        loop {
+            let packet = webrtc::rtp::packet::Packet {
+                header: webrtc::rtp::header::Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 3000,
+                    marker: true,
+                    ..Default::default()
+                },
+                payload: vec![0x10, 0x00, 0x9D, 0x01, 0x2A].into(),
+            };
+            let _ = video.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(33)).await;
+        }
+    })
+}
+
+/// Publishes a VP8 track from a new client on `relay`.
+async fn publish_video(relay: &MediaRelay) -> (Arc<RTCPeerConnection>, tokio::task::JoinHandle<()>) {
+    let client = client_peer_connection().await;
+    let video = video_track(&relay.peer_id);
+    client
+        .add_track(Arc::clone(&video) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .unwrap();
+    negotiate(&client, relay).await;
+    (client, send_video(video))
+}
+
+#[tokio::test]
+async fn participants_speak_and_viewers_watch() {
+    let handler = message_handler();
+    join(&handler, "teacher").await;
+    join(&handler, "student").await;
+    handler.join_room(ROOM.to_string(), "whep-viewer".to_string(), RelayMode::Egress, None).await.unwrap();
+
+    // Joining first makes nobody host
+    let rooms = handler.room_manager();
+    assert_eq!(rooms.get_role(ROOM, "teacher").await, Some(Role::Speaker));
+    assert_eq!(rooms.get_role(ROOM, "student").await, Some(Role::Speaker));
+    assert_eq!(rooms.get_role(ROOM, "whep-viewer").await, Some(Role::Viewer));
+}
+
+#[tokio::test]
+async fn only_moderators_manage_the_room() {
+    let handler = message_handler();
+    join(&handler, "teacher").await;
+    handler.set_role(ROOM, "teacher", Role::Host).await.unwrap();
+    join(&handler, "assistant").await;
+    join(&handler, "student").await;
+    let rooms = handler.room_manager();
+
+    // Speakers can neither kick nor promote themselves
+    assert!(refused(handler.handle_message(kick("student", "assistant"), "student").await));
+    assert!(refused(handler.handle_message(set_role("student", "student", Role::Moderator), "student").await));
+    let record = SignalingMessage::StartRecording { room_id: ROOM.to_string(), peer_id: "student".to_string() };
+    assert!(refused(handler.handle_message(record, "student").await));
+
+    handler.handle_message(set_role("teacher", "assistant", Role::Moderator), "teacher").await.unwrap();
+    assert_eq!(rooms.get_role(ROOM, "assistant").await, Some(Role::Moderator));
+
+    // Moderators can't touch hosts or appoint them
+    assert!(refused(handler.handle_message(kick("assistant", "teacher"), "assistant").await));
+    assert!(refused(handler.handle_message(set_role("assistant", "student", Role::Host), "assistant").await));
+
+    handler.handle_message(set_role("assistant", "student", Role::Viewer), "assistant").await.unwrap();
+    assert_eq!(rooms.get_role(ROOM, "student").await, Some(Role::Viewer));
+    let publish = SignalingMessage::Offer {
+        room_id: ROOM.to_string(),
+        sdp: "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=sendonly\r\n".to_string(),
+        from_peer: "student".to_string(),
+        to_peer: "server".to_string(),
+    };
+    assert!(refused(handler.handle_message(publish, "student").await));
+
+    handler.handle_message(kick("assistant", "student"), "assistant").await.unwrap();
+    assert!(!rooms.has_peer(ROOM, "student").await);
+}
+
+#[test]
+fn publishing_is_read_from_each_media_section() {
+    use webrtc_server::signaling::auth::offers_media;
+
+    // Sections without a direction send and receive
+    assert!(offers_media("v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n"));
+    assert!(offers_media("v=0\r\na=sendonly\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n"));
+    assert!(offers_media("v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=recvonly\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n"));
+
+    assert!(!offers_media("v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=recvonly\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=inactive\r\n"));
+    assert!(!offers_media("v=0\r\na=recvonly\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n"));
+    // Rejected sections and data channels carry no media
+    assert!(!offers_media("v=0\r\nm=video 0 UDP/TLS/RTP/SAVPF 96\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n"));
+}
+
+#[tokio::test]
+async fn viewers_cannot_publish_through_an_answer() {
+    let handler = message_handler();
+    join(&handler, "teacher").await;
+    join(&handler, "student").await;
+    join(&handler, "observer").await;
+    handler.set_role(ROOM, "student", Role::Viewer).await.unwrap();
+    let relays = handler.room_manager().relay_manager();
+    let mut events = relays.subscribe_events();
+    let teacher_relay = relays.get_relay(ROOM, "teacher").await.unwrap();
+    let student_relay = relays.get_relay(ROOM, "student").await.unwrap();
+    let observer_relay = relays.get_relay(ROOM, "observer").await.unwrap();
+
+    // The student connects with only a data channel
+    let student = client_peer_connection().await;
+    student.create_data_channel("signaling", None).await.unwrap();
+    negotiate(&student, &student_relay).await;
+    student_relay.flush_negotiation().await.unwrap();
+
+    let (teacher, teaching) = publish_video(&teacher_relay).await;
+    let offer = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let SignalingMessage::Offer { to_peer, sdp, .. } = events.recv().await.unwrap() {
+                if to_peer == "student" {
+                    return sdp;
+                }
+            }
+        }
+    })
+    .await
+    .expect("server never offered the teacher's track to the student");
+
+    // The student answers the server's offer with a video track of its own
+    student.set_remote_description(RTCSessionDescription::offer(offer).unwrap()).await.unwrap();
+    let video = video_track("student");
+    let transceiver = student.get_transceivers().await.pop().unwrap();
+    transceiver.sender().await.replace_track(Some(Arc::clone(&video) as Arc<dyn TrackLocal + Send + Sync>)).await.unwrap();
+    transceiver.set_direction(RTCRtpTransceiverDirection::Sendrecv).await;
+    let answer = student.create_answer(None).await.unwrap();
+    assert!(answer.sdp.split("m=video").nth(1).unwrap().contains("a=sendrecv"), "the answer should send video");
+    student.set_local_description(answer.clone()).await.unwrap();
+    student_relay.set_remote_description(answer.sdp).await.unwrap();
+    let sending = send_video(video);
+
+    assert!(
+        wait_for(|| async { student_relay.get_stats().await.unwrap().packets_received > 0 }).await,
+        "the student's video never reached the server"
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(student_relay.published_sources().await.is_empty());
+    assert!(observer_relay.subscription_keys().await.iter().all(|key| !key.starts_with("student:")));
+    assert!(teacher_relay.subscription_keys().await.is_empty());
+
+    sending.abort();
+    teaching.abort();
+    let _ = student.close().await;
+    let _ = teacher.close().await;
+}
+
+#[tokio::test]
+async fn demoted_peers_stop_publishing() {
+    let handler = message_handler();
+    join(&handler, "teacher").await;
+    join(&handler, "student").await;
+    let relays = handler.room_manager().relay_manager();
+    let teacher_relay = relays.get_relay(ROOM, "teacher").await.unwrap();
+    let student_relay = relays.get_relay(ROOM, "student").await.unwrap();
+
+    let (student, sending) = publish_video(&student_relay).await;
+    assert!(
+        wait_for(|| async { !teacher_relay.subscription_keys().await.is_empty() }).await,
+        "the teacher was never subscribed to the student"
+    );
+
+    handler.set_role(ROOM, "student", Role::Viewer).await.unwrap();
+    assert!(teacher_relay.subscription_keys().await.is_empty());
+    assert!(student_relay.published_sources().await.is_empty());
+
+    sending.abort();
+    let _ = student.close().await;
+}
+
+#[tokio::test]
+async fn accepting_a_call_records_nothing() {
+    let recordings = std::env::temp_dir().join(format!("roles-{}", uuid::Uuid::new_v4()));
+    let handler = MessageHandler::new(Arc::new(relay_manager()), Some(recordings.clone()));
+    join(&handler, "teacher").await;
+    join(&handler, "student").await;
+    handler.set_role(ROOM, "student", Role::Viewer).await.unwrap();
+
+    let response = SignalingMessage::CallResponse {
+        room_id: ROOM.to_string(),
+        from_peer: "student".to_string(),
+        to_peer: "teacher".to_string(),
+        accepted: true,
+        reason: None,
+        sdp: None,
+    };
+    handler.handle_message(response, "student").await.unwrap();
+    assert_eq!(std::fs::read_dir(&recordings).unwrap().count(), 0);
+
+    let _ = std::fs::remove_dir_all(&recordings);
+}
+
+#[tokio::test]
+async fn connections_only_speak_for_their_own_peer() {
+    // Without tokens too
+    let handler = message_handler();
+    join(&handler, "alice").await;
+    join(&handler, "mallory").await;
+
+    let leave = SignalingMessage::Disconnect { room_id: ROOM.to_string(), peer_id: "alice".to_string() };
+    assert!(refused(handler.authorize_sender(None, &leave).await));
+    assert!(refused(handler.authorize_sender(Some("mallory"), &leave).await));
+    assert!(handler.authorize_sender(Some("alice"), &leave).await.is_ok());
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc_server::room::state::{MediaSettings, Role};
+use webrtc_server::types::SignalingMessage;
+
+mod common;
+use common::{relay_manager, server};
+
+#[tokio::test]
+async fn relays_are_kept_per_room() {
+    let manager = relay_manager();
+    manager.create_relay("room-a", "alice".to_string()).await.unwrap();
+    manager.create_relay("room-a", "bob".to_string()).await.unwrap();
+    // The same peer id may be used in another room
+    manager.create_relay("room-b", "alice".to_string()).await.unwrap();
+
+    assert_eq!(manager.get_room_peers("room-a").await, vec!["alice".to_string(), "bob".to_string()]);
+    assert_eq!(manager.get_room_peers("room-b").await, vec!["alice".to_string()]);
+    assert_eq!(manager.get_active_peer_count().await, 3);
+    let stats = manager.get_room_stats("room-b").await;
+    assert_eq!(stats.keys().collect::<Vec<_>>(), vec!["alice"]);
+
+    manager.handle_peer_disconnect("alice", "room-a").await.unwrap();
+    assert_eq!(manager.get_room_peers("room-a").await, vec!["bob".to_string()]);
+    assert!(manager.get_relay("room-b", "alice").await.is_some());
+
+    manager.remove_relay("room-a", "bob").await.unwrap();
+    manager.remove_relay("room-b", "alice").await.unwrap();
+    assert!(manager.get_room_peers("room-a").await.is_empty());
+    assert_eq!(manager.get_active_peer_count().await, 0);
+}
+
+#[tokio::test]
+async fn peer_lists_name_only_room_members() {
+    let server = server().await;
+
+    let mut clients = Vec::new();
+    let mut lists = Vec::new();
+    for (room_id, peer_id) in [("room-a", "alice"), ("room-a", "bob"), ("room-b", "carol")] {
+        let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+        let join = SignalingMessage::Join {
+            room_id: room_id.to_string(),
+            peer_id: peer_id.to_string(),
+            token: None,
+            metadata: HashMap::new(),
+            password: None,
+        };
+        client.send_text(serde_json::to_string(&join).unwrap()).await;
+        let reply = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        let reply: SignalingMessage = serde_json::from_str(reply.to_str().unwrap()).unwrap();
+        let SignalingMessage::PeerList { room_id: listed, mut peers, .. } = reply else {
+            panic!("expected a PeerList");
+        };
+        assert_eq!(listed, room_id);
+        peers.sort();
+        lists.push(peers);
+        clients.push(client);
+    }
+
+    assert_eq!(lists[1], vec!["alice".to_string(), "bob".to_string()]);
+    assert_eq!(lists[2], vec!["carol".to_string()]);
+}
+
+#[tokio::test]
+async fn a_refused_replacement_keeps_the_peers_relay() {
+    let manager = relay_manager();
+    manager.create_relay("room-a", "alice".to_string()).await.unwrap();
+    manager.create_relay("room-a", "bob".to_string()).await.unwrap();
+    let alice = manager.get_relay("room-a", "alice").await.unwrap();
+    let settings = MediaSettings { max_participants: 1, ..Default::default() };
+    manager.update_media_settings("room-a", settings).await.unwrap();
+
+    // The room is now over capacity, so alice's new relay doesn't fit
+    assert!(manager.create_relay("room-a", "alice".to_string()).await.is_err());
+    assert_eq!(manager.get_room_peers("room-a").await, vec!["alice".to_string(), "bob".to_string()]);
+    let kept = manager.get_relay("room-a", "alice").await.unwrap();
+    assert!(std::sync::Arc::ptr_eq(&kept.peer_connection, &alice.peer_connection));
+    assert_ne!(kept.peer_connection.connection_state(), RTCPeerConnectionState::Closed);
+    assert_eq!(manager.get_role("room-a", "alice").await, Some(Role::Speaker));
+}
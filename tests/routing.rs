@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use warp::test::WsClient;
+use webrtc_server::room::state::Role;
+use webrtc_server::signaling::routing::{Delivery, Recipient};
+use webrtc_server::types::SignalingMessage;
+use webrtc_server::utils::ErrorCode;
+use webrtc_server::SignalingServer;
+
+mod common;
+use common::server;
+
+async fn join(server: &SignalingServer, room_id: &str, peer_id: &str) -> WsClient {
+    let mut client = warp::test::ws().handshake(server.ws_route()).await.unwrap();
+    let join = SignalingMessage::Join {
+        room_id: room_id.to_string(),
+        peer_id: peer_id.to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    };
+    client.send_text(serde_json::to_string(&join).unwrap()).await;
+    tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+    client
+}
+
+/// The next message other than a peer list update.
+async fn next(client: &mut WsClient) -> SignalingMessage {
+    loop {
+        let reply = tokio::time::timeout(Duration::from_secs(5), client.recv()).await.unwrap().unwrap();
+        let message: SignalingMessage = serde_json::from_str(reply.to_str().unwrap()).unwrap();
+        if !matches!(message, SignalingMessage::PeerList { .. }) {
+            return message;
+        }
+    }
+}
+
+fn error_for(peer_id: &str) -> SignalingMessage {
+    SignalingMessage::ConnectionError { peer_id: peer_id.to_string(), error: "test".to_string(), should_retry: true }
+}
+
+#[tokio::test]
+async fn messages_reach_only_their_recipients() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let mut alice = join(&server, "room-a", "alice").await;
+    let mut bob = join(&server, "room-a", "bob").await;
+    let mut carol = join(&server, "room-b", "carol").await;
+    handler.room_manager().set_role("room-a", "alice", Role::Host).await.unwrap();
+
+    assert_eq!(handler.send_message(&error_for("alice")).await.unwrap(), Delivery::Delivered(1));
+    assert!(matches!(next(&mut alice).await, SignalingMessage::ConnectionError { peer_id, .. } if peer_id == "alice"));
+
+    let speaking = SignalingMessage::ActiveSpeaker { room_id: "room-a".to_string(), peer_id: "bob".to_string() };
+    assert_eq!(handler.send_message(&speaking).await.unwrap(), Delivery::Delivered(2));
+    let hosts = Recipient::Roles { room_id: "room-a".to_string(), roles: vec![Role::Host] };
+    assert_eq!(handler.route(&hosts, &error_for("alice")).await.unwrap(), Delivery::Delivered(1));
+
+    // Bob sees the room event but not Alice's error; Carol sees neither
+    assert!(matches!(next(&mut bob).await, SignalingMessage::ActiveSpeaker { .. }));
+    assert!(matches!(next(&mut alice).await, SignalingMessage::ActiveSpeaker { .. }));
+    assert!(matches!(next(&mut alice).await, SignalingMessage::ConnectionError { .. }));
+    handler.send_message(&error_for("carol")).await.unwrap();
+    assert!(matches!(next(&mut carol).await, SignalingMessage::ConnectionError { peer_id, .. } if peer_id == "carol"));
+    handler.send_message(&error_for("bob")).await.unwrap();
+    assert!(matches!(next(&mut bob).await, SignalingMessage::ConnectionError { peer_id, .. } if peer_id == "bob"));
+}
+
+#[tokio::test]
+async fn missing_targets_are_undeliverable() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let _alice = join(&server, "room-a", "alice").await;
+
+    assert_eq!(handler.send_message(&error_for("ghost")).await.unwrap(), Delivery::Undeliverable);
+    let empty_room = SignalingMessage::ActiveSpeaker { room_id: "nowhere".to_string(), peer_id: "ghost".to_string() };
+    assert_eq!(handler.send_message(&empty_room).await.unwrap(), Delivery::Undeliverable);
+    let viewers = Recipient::Roles { room_id: "room-a".to_string(), roles: vec![Role::Viewer] };
+    assert_eq!(handler.route(&viewers, &error_for("alice")).await.unwrap(), Delivery::Undeliverable);
+
+    // Client requests have no recipient of their own
+    let request = SignalingMessage::RequestPeerList { room_id: "room-a".to_string() };
+    assert!(handler.send_message(&request).await.is_err());
+}
+
+fn call(room_id: &str, from_peer: &str, to_peer: &str) -> String {
+    let request = SignalingMessage::CallRequest {
+        room_id: room_id.to_string(),
+        from_peer: from_peer.to_string(),
+        to_peers: vec![to_peer.to_string()],
+        sdp: String::new(),
+    };
+    serde_json::to_string(&request).unwrap()
+}
+
+#[tokio::test]
+async fn calls_stay_within_the_room() {
+    let server = server().await;
+    let handler = server.handler.clone();
+    let mut alice = join(&server, "room-a", "alice").await;
+    let mut bob = join(&server, "room-a", "bob").await;
+    let mut carol = join(&server, "room-b", "carol").await;
+
+    // Carol is in another room, whichever room Alice names
+    alice.send_text(call("room-a", "alice", "carol")).await;
+    assert!(matches!(next(&mut alice).await, SignalingMessage::Error { code: ErrorCode::RoomError, .. }));
+    alice.send_text(call("room-b", "alice", "carol")).await;
+    assert!(matches!(next(&mut alice).await, SignalingMessage::Error { code: ErrorCode::RoomError, .. }));
+
+    alice.send_text(call("room-a", "alice", "bob")).await;
+    assert!(matches!(next(&mut bob).await, SignalingMessage::CallRequest { from_peer, .. } if from_peer == "alice"));
+    handler.send_message(&error_for("carol")).await.unwrap();
+    assert!(matches!(next(&mut carol).await, SignalingMessage::ConnectionError { peer_id, .. } if peer_id == "carol"));
+}
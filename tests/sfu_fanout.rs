@@ -0,0 +1,520 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+use webrtc_server::media::MediaRelay;
+use webrtc_server::room::state::{MediaSettings, MediaType};
+use webrtc_server::types::SignalingMessage;
+
+mod common;
+use common::{client_peer_connection, negotiate, relay_manager, wait_for};
+
+const ROOM: &str = "fanout-room";
+
+/// Publishes a VP8 track from a new client on `relay`, sending a keyframe
+/// every frame until the returned task is aborted.
+async fn publish_video(relay: &MediaRelay) -> (Arc<RTCPeerConnection>, tokio::task::JoinHandle<()>) {
+    let client = client_peer_connection().await;
+    let video = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: "video/VP8".to_owned(),
+            clock_rate: 90000,
+            ..Default::default()
+        },
+        format!("{}-video", relay.peer_id),
+        relay.peer_id.clone(),
+    ));
+    client
+        .add_track(Arc::clone(&video) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .unwrap();
+    negotiate(&client, relay).await;
+
+    let publishing = tokio::spawn(async move {
+        let mut sequence_number: u16 = 0;
+        loop {
+            let packet = webrtc::rtp::packet::Packet {
+                header: webrtc::rtp::header::Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 3000,
+                    marker: true,
+                    ..Default::default()
+                },
+                payload: vec![0x10, 0x00, 0x9D, 0x01, 0x2A].into(),
+            };
+            let _ = video.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(33)).await;
+        }
+    });
+    (client, publishing)
+}
+
+#[tokio::test]
+async fn forwards_publisher_rtp_to_other_room_member() {
+    let manager = relay_manager();
+    let relay_a = manager.create_relay(ROOM, "alice".to_string()).await.unwrap();
+    let relay_b = manager.create_relay(ROOM, "bob".to_string()).await.unwrap();
+    let relay_c = manager.create_relay("other-room", "carol".to_string()).await.unwrap();
+
+    // Alice publishes an Opus track
+    let alice = client_peer_connection().await;
+    let audio = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: "audio/opus".to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        },
+        "alice-audio".to_owned(),
+        "alice".to_owned(),
+    ));
+    alice
+        .add_track(Arc::clone(&audio) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .unwrap();
+    negotiate(&alice, &relay_a).await;
+
+    let writer = Arc::clone(&audio);
+    let publishing = tokio::spawn(async move {
+        let mut sequence_number: u16 = 0;
+        loop {
+            let packet = webrtc::rtp::packet::Packet {
+                header: webrtc::rtp::header::Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 960,
+                    ..Default::default()
+                },
+                payload: vec![0xFA; 40].into(),
+            };
+            let _ = writer.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+
+    // The server publishes Alice's track into the room and subscribes Bob
+    assert!(
+        wait_for(|| async { !relay_b.subscription_keys().await.is_empty() }).await,
+        "bob was never subscribed to alice's track"
+    );
+    assert!(relay_a.subscription_keys().await.is_empty(), "publisher must not receive its own track");
+    assert!(relay_c.subscription_keys().await.is_empty(), "media must not leak into other rooms");
+
+    // Bob only receives
+    let bob = client_peer_connection().await;
+    bob.add_transceiver_from_kind(
+        RTPCodecType::Audio,
+        Some(RTCRtpTransceiverInit {
+            direction: RTCRtpTransceiverDirection::Recvonly,
+            send_encodings: vec![],
+        }),
+    )
+    .await
+    .unwrap();
+
+    let (received_tx, mut received_rx) = mpsc::channel(1);
+    bob.on_track(Box::new(move |track, _, _| {
+        let received_tx = received_tx.clone();
+        Box::pin(async move {
+            if let Ok((packet, _)) = track.read_rtp().await {
+                let _ = received_tx.send((track.stream_id(), packet.payload)).await;
+            }
+        })
+    }));
+    negotiate(&bob, &relay_b).await;
+
+    let (stream_id, payload) = tokio::time::timeout(Duration::from_secs(10), received_rx.recv())
+        .await
+        .expect("bob did not receive forwarded RTP in time")
+        .unwrap();
+    assert_eq!(stream_id, "alice");
+    assert_eq!(&payload[..], &[0xFA; 40][..]);
+
+    // Alice leaving withdraws her track from Bob
+    manager.handle_peer_disconnect("alice", ROOM).await.unwrap();
+    assert!(relay_b.subscription_keys().await.is_empty());
+    assert_eq!(manager.get_room_peers(ROOM).await, vec!["bob".to_string()]);
+
+    publishing.abort();
+    let _ = alice.close().await;
+    let _ = bob.close().await;
+    manager.remove_relay(ROOM, "bob").await.unwrap();
+}
+
+#[tokio::test]
+async fn offers_new_tracks_to_peers_that_already_negotiated() {
+    let manager = relay_manager();
+    let mut events = manager.subscribe_events();
+    let relay_b = manager.create_relay(ROOM, "bob".to_string()).await.unwrap();
+
+    // Bob connects before anyone publishes, with only a data channel
+    let bob = client_peer_connection().await;
+    bob.create_data_channel("signaling", None).await.unwrap();
+    let (received_tx, mut received_rx) = mpsc::channel(1);
+    bob.on_track(Box::new(move |track, _, _| {
+        let received_tx = received_tx.clone();
+        Box::pin(async move {
+            if let Ok((packet, _)) = track.read_rtp().await {
+                let _ = received_tx.send((track.stream_id(), packet.payload)).await;
+            }
+        })
+    }));
+    negotiate(&bob, &relay_b).await;
+    relay_b.flush_negotiation().await.unwrap();
+
+    // Alice publishes afterwards
+    let relay_a = manager.create_relay(ROOM, "alice".to_string()).await.unwrap();
+    let alice = client_peer_connection().await;
+    let audio = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: "audio/opus".to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        },
+        "alice-audio".to_owned(),
+        "alice".to_owned(),
+    ));
+    alice
+        .add_track(Arc::clone(&audio) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .unwrap();
+    negotiate(&alice, &relay_a).await;
+
+    let writer = Arc::clone(&audio);
+    let publishing = tokio::spawn(async move {
+        let mut sequence_number: u16 = 0;
+        loop {
+            let packet = webrtc::rtp::packet::Packet {
+                header: webrtc::rtp::header::Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 960,
+                    ..Default::default()
+                },
+                payload: vec![0xAB; 40].into(),
+            };
+            let _ = writer.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+
+    // The server offers Alice's track to Bob on its own
+    let offer = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let SignalingMessage::Offer { to_peer, sdp, .. } = events.recv().await.unwrap() {
+                if to_peer == "bob" {
+                    return sdp;
+                }
+            }
+        }
+    })
+    .await
+    .expect("server never offered alice's track to bob");
+
+    bob.set_remote_description(RTCSessionDescription::offer(offer).unwrap())
+        .await
+        .unwrap();
+    let answer = bob.create_answer(None).await.unwrap();
+    bob.set_local_description(answer.clone()).await.unwrap();
+    relay_b.set_remote_description(answer.sdp).await.unwrap();
+
+    let (stream_id, payload) = tokio::time::timeout(Duration::from_secs(10), received_rx.recv())
+        .await
+        .expect("bob did not receive forwarded RTP after renegotiation")
+        .unwrap();
+    assert_eq!(stream_id, "alice");
+    assert_eq!(&payload[..], &[0xAB; 40][..]);
+
+    publishing.abort();
+    let _ = alice.close().await;
+    let _ = bob.close().await;
+    manager.remove_relay(ROOM, "alice").await.unwrap();
+    manager.remove_relay(ROOM, "bob").await.unwrap();
+}
+
+#[tokio::test]
+async fn forwarding_is_capped_by_the_room_bandwidth_limit() {
+    const LIMIT: u32 = 64_000;
+    let manager = relay_manager();
+    let settings = MediaSettings { bandwidth_limit: Some(LIMIT), ..Default::default() };
+    manager.create_room("capped-room", settings).await.unwrap();
+    let relay_a = manager.create_relay("capped-room", "alice".to_string()).await.unwrap();
+    let relay_b = manager.create_relay("capped-room", "bob".to_string()).await.unwrap();
+
+    let alice = client_peer_connection().await;
+    let audio = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: "audio/opus".to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            ..Default::default()
+        },
+        "alice-audio".to_owned(),
+        "alice".to_owned(),
+    ));
+    alice
+        .add_track(Arc::clone(&audio) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .unwrap();
+    negotiate(&alice, &relay_a).await;
+
+    // About 1.6 Mbps, far above the limit
+    let writer = Arc::clone(&audio);
+    let publishing = tokio::spawn(async move {
+        let mut sequence_number: u16 = 0;
+        loop {
+            let packet = webrtc::rtp::packet::Packet {
+                header: webrtc::rtp::header::Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 240,
+                    ..Default::default()
+                },
+                payload: vec![0xCA; 1000].into(),
+            };
+            let _ = writer.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    });
+    assert!(wait_for(|| async { !relay_b.subscription_keys().await.is_empty() }).await);
+
+    let bob = client_peer_connection().await;
+    bob.add_transceiver_from_kind(
+        RTPCodecType::Audio,
+        Some(RTCRtpTransceiverInit {
+            direction: RTCRtpTransceiverDirection::Recvonly,
+            send_encodings: vec![],
+        }),
+    )
+    .await
+    .unwrap();
+    let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+    bob.on_track(Box::new(move |track, _, _| {
+        let received_tx = received_tx.clone();
+        Box::pin(async move {
+            while let Ok((packet, _)) = track.read_rtp().await {
+                let _ = received_tx.send(packet.payload.len());
+            }
+        })
+    }));
+    negotiate(&bob, &relay_b).await;
+
+    let first = tokio::time::timeout(Duration::from_secs(10), received_rx.recv())
+        .await
+        .expect("bob did not receive forwarded RTP in time")
+        .unwrap();
+    let window = Duration::from_secs(3);
+    let mut received = first;
+    let _ = tokio::time::timeout(window, async {
+        while let Some(bytes) = received_rx.recv().await {
+            received += bytes;
+        }
+    })
+    .await;
+
+    // One second of burst plus the window at the limit, with room for headers
+    let allowed = (LIMIT as usize / 8) * (1 + window.as_secs() as usize);
+    assert!(received <= allowed, "forwarded {} bytes, the limit allows {}", received, allowed);
+
+    publishing.abort();
+    let _ = alice.close().await;
+    let _ = bob.close().await;
+    manager.remove_relay("capped-room", "alice").await.unwrap();
+    manager.remove_relay("capped-room", "bob").await.unwrap();
+}
+
+#[tokio::test]
+async fn subscribers_over_the_limit_ask_for_no_keyframes() {
+    let manager = relay_manager();
+    let settings = MediaSettings { bandwidth_limit: Some(64_000), ..Default::default() };
+    manager.create_room("capped-room", settings).await.unwrap();
+    let relay_a = manager.create_relay("capped-room", "alice".to_string()).await.unwrap();
+    let relay_b = manager.create_relay("capped-room", "bob".to_string()).await.unwrap();
+
+    let alice = client_peer_connection().await;
+    let video = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability { mime_type: "video/VP8".to_owned(), clock_rate: 90000, ..Default::default() },
+        "alice-video".to_owned(),
+        "alice".to_owned(),
+    ));
+    let sender = alice
+        .add_track(Arc::clone(&video) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .unwrap();
+    let (pli_tx, mut pli_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((packets, _)) = sender.read_rtcp().await {
+            for packet in packets {
+                if packet.as_any().is::<PictureLossIndication>() {
+                    let _ = pli_tx.send(());
+                }
+            }
+        }
+    });
+    negotiate(&alice, &relay_a).await;
+
+    // About 1.6 Mbps, far above the limit, with a keyframe twice a second
+    let writer = Arc::clone(&video);
+    let publishing = tokio::spawn(async move {
+        let mut sequence_number: u16 = 0;
+        loop {
+            let mut payload = match sequence_number % 100 {
+                0 => vec![0x10, 0x00, 0x9D, 0x01, 0x2A],
+                _ => vec![0x10, 0x01],
+            };
+            payload.resize(1000, 0xCA);
+            let packet = webrtc::rtp::packet::Packet {
+                header: webrtc::rtp::header::Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 450,
+                    marker: true,
+                    ..Default::default()
+                },
+                payload: payload.into(),
+            };
+            let _ = writer.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    });
+    assert!(wait_for(|| async { !relay_b.subscription_keys().await.is_empty() }).await);
+
+    let bob = client_peer_connection().await;
+    bob.add_transceiver_from_kind(
+        RTPCodecType::Video,
+        Some(RTCRtpTransceiverInit {
+            direction: RTCRtpTransceiverDirection::Recvonly,
+            send_encodings: vec![],
+        }),
+    )
+    .await
+    .unwrap();
+    let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+    bob.on_track(Box::new(move |track, _, _| {
+        let received_tx = received_tx.clone();
+        Box::pin(async move {
+            while track.read_rtp().await.is_ok() {
+                let _ = received_tx.send(());
+            }
+        })
+    }));
+    negotiate(&bob, &relay_b).await;
+    tokio::time::timeout(Duration::from_secs(10), received_rx.recv())
+        .await
+        .expect("bob did not receive forwarded RTP in time")
+        .unwrap();
+
+    // Past the burst the budget allows, bob's video is dropped without
+    // asking alice for keyframes, and resumes at her own
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    while pli_rx.try_recv().is_ok() {}
+    while received_rx.try_recv().is_ok() {}
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(pli_rx.try_recv().is_err(), "alice was asked for a keyframe");
+    assert!(received_rx.try_recv().is_ok(), "bob's video never resumed");
+
+    publishing.abort();
+    let _ = alice.close().await;
+    let _ = bob.close().await;
+    manager.remove_relay("capped-room", "alice").await.unwrap();
+    manager.remove_relay("capped-room", "bob").await.unwrap();
+}
+
+#[tokio::test]
+async fn last_n_ranks_only_video_publishers() {
+    let manager = relay_manager();
+    let settings = MediaSettings { last_n: Some(1), ..Default::default() };
+    manager.create_room("last-n-room", settings).await.unwrap();
+    // Carol joins first but never publishes, so she must not take the slot
+    let _relay_c = manager.create_relay("last-n-room", "carol".to_string()).await.unwrap();
+    let relay_b = manager.create_relay("last-n-room", "bob".to_string()).await.unwrap();
+    let relay_a = manager.create_relay("last-n-room", "alice".to_string()).await.unwrap();
+
+    let (alice, publishing) = publish_video(&relay_a).await;
+
+    assert!(wait_for(|| async { !relay_b.subscription_keys().await.is_empty() }).await);
+    assert!(relay_a.publishes_video().await);
+    let source = relay_a.published_sources().await.pop().unwrap();
+    assert!(!source.is_subscriber_paused("bob").await, "bob should get the only video publisher");
+    assert!(!source.is_subscriber_paused("carol").await);
+
+    publishing.abort();
+    let _ = alice.close().await;
+    manager.remove_relay("last-n-room", "alice").await.unwrap();
+    manager.remove_relay("last-n-room", "bob").await.unwrap();
+    manager.remove_relay("last-n-room", "carol").await.unwrap();
+}
+
+#[tokio::test]
+async fn pinned_peers_are_forwarded_beyond_last_n() {
+    let manager = relay_manager();
+    let settings = MediaSettings { last_n: Some(0), ..Default::default() };
+    manager.create_room("pinned-room", settings).await.unwrap();
+    let relay_a = manager.create_relay("pinned-room", "alice".to_string()).await.unwrap();
+    let relay_b = manager.create_relay("pinned-room", "bob".to_string()).await.unwrap();
+    let (alice, publishing) = publish_video(&relay_a).await;
+
+    assert!(wait_for(|| async { !relay_b.subscription_keys().await.is_empty() }).await);
+    let source = relay_a.published_sources().await.pop().unwrap();
+    assert!(source.is_subscriber_paused("bob").await);
+
+    relay_b.set_pinned_peers(["alice".to_string()].into_iter().collect()).await;
+    manager.apply_last_n("pinned-room").await;
+    assert!(!source.is_subscriber_paused("bob").await);
+
+    // Without a last-N policy everyone is forwarded
+    relay_b.set_pinned_peers(Default::default()).await;
+    manager.apply_last_n("pinned-room").await;
+    assert!(source.is_subscriber_paused("bob").await);
+    let settings = MediaSettings { last_n: None, ..Default::default() };
+    manager.update_media_settings("pinned-room", settings).await.unwrap();
+    assert!(!source.is_subscriber_paused("bob").await);
+
+    publishing.abort();
+    let _ = alice.close().await;
+    manager.remove_relay("pinned-room", "alice").await.unwrap();
+    manager.remove_relay("pinned-room", "bob").await.unwrap();
+}
+
+#[tokio::test]
+async fn subscriptions_follow_the_peers_filter() {
+    let manager = relay_manager();
+    let relay_a = manager.create_relay("filter-room", "alice".to_string()).await.unwrap();
+    let relay_b = manager.create_relay("filter-room", "bob".to_string()).await.unwrap();
+    let relay_c = manager.create_relay("filter-room", "carol".to_string()).await.unwrap();
+    // Carol opts out before anything is published
+    relay_c.set_subscription_filter(None, &[MediaType::Video], false).await;
+    let (alice, publishing) = publish_video(&relay_a).await;
+
+    assert!(wait_for(|| async { !relay_b.subscription_keys().await.is_empty() }).await);
+    assert!(relay_c.subscription_keys().await.is_empty());
+
+    relay_b.set_subscription_filter(Some("alice"), &[], false).await;
+    assert!(manager.refresh_subscriptions("filter-room", "bob").await.unwrap());
+    assert!(relay_b.subscription_keys().await.is_empty());
+    assert!(!manager.refresh_subscriptions("filter-room", "bob").await.unwrap());
+
+    relay_c.set_subscription_filter(Some("alice"), &[MediaType::Video], true).await;
+    assert!(manager.refresh_subscriptions("filter-room", "carol").await.unwrap());
+    assert_eq!(relay_c.subscription_keys().await.len(), 1);
+
+    publishing.abort();
+    let _ = alice.close().await;
+    for peer_id in ["alice", "bob", "carol"] {
+        manager.remove_relay("filter-room", peer_id).await.unwrap();
+    }
+}
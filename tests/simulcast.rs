@@ -0,0 +1,97 @@
+use webrtc::rtp::header::Header;
+use webrtc::rtp::packet::Packet;
+use webrtc_server::media::simulcast::{is_keyframe, SequenceRewriter, SimulcastQuality};
+
+fn packet(sequence_number: u16, timestamp: u32) -> Packet {
+    Packet {
+        header: Header { version: 2, sequence_number, timestamp, ..Default::default() },
+        payload: vec![0u8; 10].into(),
+    }
+}
+
+#[test]
+fn quality_maps_onto_available_layers() {
+    assert_eq!(SimulcastQuality::Low.layer_index(3), 0);
+    assert_eq!(SimulcastQuality::Medium.layer_index(3), 1);
+    assert_eq!(SimulcastQuality::High.layer_index(3), 2);
+    assert_eq!(SimulcastQuality::Medium.layer_index(2), 0);
+    assert_eq!(SimulcastQuality::High.layer_index(1), 0);
+    assert_eq!(SimulcastQuality::High.layer_index(0), 0);
+}
+
+#[test]
+fn keyframes_are_detected_per_codec() {
+    // VP8: start of partition 0, then a frame header with the P bit clear
+    assert_eq!(is_keyframe("video/VP8", &[0x10, 0x00, 0x9D]), Some(true));
+    assert_eq!(is_keyframe("video/vp8", &[0x10, 0x01, 0x9D]), Some(false));
+    assert_eq!(is_keyframe("video/VP8", &[0x00, 0x00]), Some(false));
+    // Extended descriptor with a two byte picture id
+    assert_eq!(is_keyframe("video/VP8", &[0x90, 0x80, 0x81, 0x23, 0x00]), Some(true));
+    assert_eq!(is_keyframe("video/VP8", &[0x90, 0x80]), Some(false));
+
+    // VP9: start of frame and not inter-predicted
+    assert_eq!(is_keyframe("video/VP9", &[0x08]), Some(true));
+    assert_eq!(is_keyframe("video/VP9", &[0x48]), Some(false));
+
+    // H264: IDR, SPS in a STAP-A, and the first fragment of an IDR
+    assert_eq!(is_keyframe("video/H264", &[0x65]), Some(true));
+    assert_eq!(is_keyframe("video/H264", &[0x41]), Some(false));
+    assert_eq!(is_keyframe("video/H264", &[0x78, 0x00, 0x02, 0x09, 0xF0, 0x00, 0x02, 0x67, 0x42]), Some(true));
+    assert_eq!(is_keyframe("video/H264", &[0x7C, 0x85]), Some(true));
+    assert_eq!(is_keyframe("video/H264", &[0x7C, 0x05]), Some(false));
+
+    assert_eq!(is_keyframe("video/VP8", &[]), Some(false));
+    assert_eq!(is_keyframe("video/AV1", &[0x10]), None);
+}
+
+#[test]
+fn switching_layers_keeps_the_stream_continuous() {
+    let mut rewriter = SequenceRewriter::default();
+    rewriter.switch_source();
+    assert_eq!(rewriter.rewrite(&packet(100, 9000), 90000).header.sequence_number, 100);
+    let last = rewriter.rewrite(&packet(101, 12000), 90000);
+    assert_eq!(last.header.timestamp, 12000);
+
+    // The new layer numbers its packets independently
+    rewriter.switch_source();
+    let first = rewriter.rewrite(&packet(5000, 700_000), 90000);
+    assert_eq!(first.header.sequence_number, 102);
+    assert!(first.header.timestamp > 12000 && first.header.timestamp < 12000 + 90000);
+    let next = rewriter.rewrite(&packet(5001, 703_000), 90000);
+    assert_eq!(next.header.sequence_number, 103);
+    assert_eq!(next.header.timestamp, first.header.timestamp + 3000);
+
+    // Reordered packets keep their place without moving the stream back
+    assert_eq!(rewriter.rewrite(&packet(4999, 697_000), 90000).header.sequence_number, 101);
+    assert_eq!(rewriter.rewrite(&packet(5002, 706_000), 90000).header.sequence_number, 104);
+}
+
+#[test]
+fn sequence_numbers_wrap() {
+    let mut rewriter = SequenceRewriter::default();
+    rewriter.switch_source();
+    rewriter.rewrite(&packet(u16::MAX, 0), 90000);
+    rewriter.switch_source();
+    assert_eq!(rewriter.rewrite(&packet(40000, 0), 90000).header.sequence_number, 0);
+    assert_eq!(rewriter.rewrite(&packet(40001, 0), 90000).header.sequence_number, 1);
+}
+
+#[test]
+fn retransmissions_map_back_to_the_current_layer() {
+    let mut rewriter = SequenceRewriter::default();
+    rewriter.switch_source();
+    rewriter.rewrite(&packet(100, 0), 90000);
+    rewriter.rewrite(&packet(101, 0), 90000);
+    rewriter.switch_source();
+    rewriter.rewrite(&packet(5000, 0), 90000);
+    rewriter.rewrite(&packet(5001, 0), 90000);
+
+    assert_eq!(rewriter.original_sequence(103), Some(5001));
+    assert_eq!(rewriter.original_sequence(102), Some(5000));
+    // Sent from the previous layer
+    assert_eq!(rewriter.original_sequence(101), None);
+
+    // Applying offsets does not advance the stream
+    assert_eq!(rewriter.apply(&packet(5000, 0)).header.sequence_number, 102);
+    assert_eq!(rewriter.rewrite(&packet(5002, 0), 90000).header.sequence_number, 104);
+}
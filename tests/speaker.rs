@@ -0,0 +1,79 @@
+use std::time::Duration;
+use tokio::sync::broadcast;
+use webrtc_server::media::ActiveSpeakerDetector;
+use webrtc_server::types::SignalingMessage;
+
+const ROOM: &str = "speaker-room";
+const LOUD: u8 = 0;
+const QUIET: u8 = 100;
+
+async fn next_speaker(events: &mut broadcast::Receiver<SignalingMessage>) -> String {
+    let speaker = async {
+        loop {
+            if let SignalingMessage::ActiveSpeaker { room_id, peer_id } = events.recv().await.unwrap() {
+                assert_eq!(room_id, ROOM);
+                return peer_id;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(2), speaker).await.expect("no active speaker was announced")
+}
+
+#[tokio::test]
+async fn loudest_peer_becomes_dominant_with_hysteresis() {
+    let (events_tx, mut events) = broadcast::channel(64);
+    let detector = ActiveSpeakerDetector::new(ROOM, events_tx);
+    let mut recent = detector.watch_recent_speakers();
+
+    detector.record("bob", QUIET).await;
+    detector.record("alice", LOUD).await;
+    assert_eq!(next_speaker(&mut events).await, "alice");
+    assert_eq!(detector.dominant_speaker().await.as_deref(), Some("alice"));
+    assert!(recent.has_changed().unwrap());
+    assert_eq!(*recent.borrow_and_update(), vec!["alice".to_string()]);
+
+    let levels = detector.levels().await;
+    assert_eq!(levels["alice"], 1.0);
+    assert!(levels["bob"] < 0.5);
+
+    // Being as loud as the dominant speaker is not enough to take over
+    detector.record("bob", LOUD).await;
+    for _ in 0..30 {
+        detector.record("bob", LOUD).await;
+        detector.record("alice", LOUD).await;
+    }
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    assert_eq!(detector.dominant_speaker().await.as_deref(), Some("alice"));
+
+    for _ in 0..30 {
+        detector.record("alice", QUIET).await;
+    }
+    assert_eq!(next_speaker(&mut events).await, "bob");
+    assert_eq!(detector.recent_speakers().await, vec!["bob".to_string(), "alice".to_string()]);
+
+    detector.remove_peer("bob").await;
+    assert_eq!(detector.dominant_speaker().await, None);
+    assert_eq!(*recent.borrow_and_update(), vec!["alice".to_string()]);
+}
+
+#[tokio::test]
+async fn levels_are_announced_and_silent_peers_forgotten() {
+    let (events_tx, mut events) = broadcast::channel(64);
+    let detector = ActiveSpeakerDetector::new(ROOM, events_tx);
+
+    detector.record("alice", QUIET).await;
+    let levels = async {
+        loop {
+            if let SignalingMessage::AudioLevels { levels, .. } = events.recv().await.unwrap() {
+                return levels;
+            }
+        }
+    };
+    let levels = tokio::time::timeout(Duration::from_secs(2), levels).await.unwrap();
+    assert_eq!(levels.keys().collect::<Vec<_>>(), vec!["alice"]);
+    // Too quiet to count as speech
+    assert_eq!(detector.dominant_speaker().await, None);
+
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(detector.levels().await.is_empty());
+}
@@ -0,0 +1,44 @@
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc_server::media::subscription::SubscriptionFilter;
+use webrtc_server::room::state::MediaType;
+
+#[test]
+fn everything_is_wanted_by_default() {
+    let filter = SubscriptionFilter::default();
+    assert!(filter.wants("alice", RTPCodecType::Audio));
+    assert!(filter.wants("alice", RTPCodecType::Video));
+}
+
+#[test]
+fn publisher_choices_override_defaults() {
+    let mut filter = SubscriptionFilter::default();
+    filter.set(None, &[MediaType::Video], false);
+    assert!(!filter.wants("alice", RTPCodecType::Video));
+    assert!(filter.wants("alice", RTPCodecType::Audio));
+
+    filter.set(Some("alice"), &[MediaType::Video], true);
+    assert!(filter.wants("alice", RTPCodecType::Video));
+    assert!(!filter.wants("bob", RTPCodecType::Video));
+
+    // An empty list covers both kinds
+    filter.set(Some("bob"), &[], false);
+    assert!(!filter.wants("bob", RTPCodecType::Audio));
+    assert!(!filter.wants("bob", RTPCodecType::Video));
+}
+
+#[test]
+fn room_wide_choices_reset_publisher_overrides() {
+    let mut filter = SubscriptionFilter::default();
+    filter.set(Some("alice"), &[MediaType::Audio, MediaType::Video], false);
+    filter.set(None, &[MediaType::Audio], true);
+    assert!(filter.wants("alice", RTPCodecType::Audio));
+    assert!(!filter.wants("alice", RTPCodecType::Video));
+}
+
+#[test]
+fn screen_shares_are_filtered_as_video() {
+    let mut filter = SubscriptionFilter::default();
+    filter.set(Some("alice"), &[MediaType::Screen], false);
+    assert!(!filter.wants("alice", RTPCodecType::Video));
+    assert!(filter.wants("alice", RTPCodecType::Audio));
+}
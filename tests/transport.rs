@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use webrtc_server::signaling::connection::Connection;
+use webrtc_server::signaling::connection_state::{ConnectionState, ConnectionStateManager};
+use webrtc_server::signaling::handler::MessageHandler;
+use webrtc_server::signaling::transport::{Frame, SignalingTransport};
+use webrtc_server::types::{Envelope, SignalingMessage};
+use webrtc_server::utils::{ErrorCode, Result};
+
+mod common;
+use common::handler;
+
+fn message_handler(resume_grace: Duration) -> Arc<MessageHandler> {
+    Arc::new(handler().with_resume_grace(resume_grace))
+}
+
+const ROOM: &str = "bench";
+
+/// A transport that records what the server sends.
+#[derive(Default)]
+struct Recorder {
+    sent: Mutex<Vec<(String, bool)>>,
+    closed: AtomicBool,
+}
+
+#[async_trait]
+impl SignalingTransport for Recorder {
+    async fn send(&self, text: String, binary: bool) -> Result<()> {
+        self.sent.lock().unwrap().push((text, binary));
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Recorder {
+    fn messages(&self) -> Vec<SignalingMessage> {
+        self.sent.lock().unwrap().iter()
+            .map(|(text, _)| serde_json::from_str::<Envelope<SignalingMessage>>(text).unwrap().message)
+            .collect()
+    }
+}
+
+fn frame(message: &SignalingMessage) -> Result<Frame> {
+    Ok(Frame::Text(serde_json::to_string(message).unwrap()))
+}
+
+fn join(peer_id: &str) -> SignalingMessage {
+    SignalingMessage::Join {
+        room_id: ROOM.to_string(),
+        peer_id: peer_id.to_string(),
+        token: None,
+        metadata: HashMap::new(),
+        password: None,
+    }
+}
+
+#[tokio::test]
+async fn any_transport_runs_the_same_connection() {
+    let handler = message_handler(Duration::ZERO);
+    let states = Arc::new(ConnectionStateManager::new());
+    let recorder = Arc::new(Recorder::default());
+
+    let connection = Connection::open(handler.clone(), states.clone(), recorder.clone(), None, None).await.unwrap();
+    let frames = vec![
+        Ok(Frame::Text("not json".to_string())),
+        frame(&join("alice")),
+        Ok(Frame::Control),
+        frame(&SignalingMessage::RequestPeerList { room_id: ROOM.to_string() }),
+    ];
+    connection.serve(stream::iter(frames)).await;
+
+    let messages = recorder.messages();
+    assert!(matches!(messages[0], SignalingMessage::Error { .. }));
+    assert!(matches!(messages.last(), Some(SignalingMessage::PeerList { peers, .. }) if peers.contains(&"alice".to_string())));
+    assert_eq!(states.get_state("alice").await, Some(ConnectionState::New));
+
+    // Without a resume grace period the peer leaves when its socket ends
+    assert!(!handler.room_manager().has_peer(ROOM, "alice").await);
+    assert!(!recorder.closed.load(Ordering::Relaxed));
+}
+
+#[tokio::test]
+async fn leaving_closes_the_socket() {
+    // With resumption on, a second join would keep bob in the room
+    let handler = message_handler(Duration::from_secs(30));
+    let recorder = Arc::new(Recorder::default());
+
+    let connection = Connection::open(handler.clone(), Arc::new(ConnectionStateManager::new()), recorder.clone(), None, None).await.unwrap();
+    let leave = SignalingMessage::Disconnect { peer_id: "bob".to_string(), room_id: ROOM.to_string() };
+    // Frames after the Disconnect are never read
+    connection.serve(stream::iter(vec![frame(&join("bob")), frame(&leave), frame(&join("bob"))])).await;
+
+    assert!(recorder.closed.load(Ordering::Relaxed));
+    assert!(!handler.room_manager().has_peer(ROOM, "bob").await);
+    let joins = recorder.messages().into_iter().filter(|message| matches!(message, SignalingMessage::ResumeToken { .. })).count();
+    assert_eq!(joins, 1);
+}
+
+#[tokio::test]
+async fn connections_only_speak_for_their_own_peer() {
+    // Without tokens too; with a grace period alice stays after her socket ends
+    let handler = message_handler(Duration::from_secs(30));
+    let states = Arc::new(ConnectionStateManager::new());
+    let alice = Connection::open(handler.clone(), states.clone(), Arc::new(Recorder::default()), None, None).await.unwrap();
+    alice.serve(stream::iter(vec![frame(&join("alice"))])).await;
+
+    let recorder = Arc::new(Recorder::default());
+    let mallory = Connection::open(handler.clone(), states, recorder.clone(), None, None).await.unwrap();
+    let lock = SignalingMessage::LockRoom { room_id: ROOM.to_string(), peer_id: "alice".to_string(), locked: true };
+    let leave = SignalingMessage::Disconnect { peer_id: "alice".to_string(), room_id: ROOM.to_string() };
+    mallory.serve(stream::iter(vec![frame(&lock), frame(&join("mallory")), frame(&lock), frame(&leave)])).await;
+
+    let errors = recorder.messages().into_iter()
+        .filter(|message| matches!(message, SignalingMessage::Error { code: ErrorCode::Unauthorized, .. }))
+        .count();
+    assert_eq!(errors, 3);
+    assert!(!handler.room_manager().get_room(ROOM).await.unwrap().locked);
+    assert!(handler.room_manager().has_peer(ROOM, "alice").await);
+    assert!(!recorder.closed.load(Ordering::Relaxed));
+}
+
+#[tokio::test]
+async fn joined_connections_keep_their_identity() {
+    let handler = message_handler(Duration::from_secs(30));
+    let recorder = Arc::new(Recorder::default());
+
+    let connection = Connection::open(handler.clone(), Arc::new(ConnectionStateManager::new()), recorder.clone(), None, None).await.unwrap();
+    connection.serve(stream::iter(vec![frame(&join("carol")), frame(&join("dave"))])).await;
+
+    assert!(matches!(recorder.messages().last(), Some(SignalingMessage::Error { code: ErrorCode::InvalidMessage, .. })));
+    assert!(handler.room_manager().has_peer(ROOM, "carol").await);
+    assert!(!handler.room_manager().has_peer(ROOM, "dave").await);
+}
+
+#[tokio::test]
+async fn joining_as_a_connected_peer_leaves_it_connected() {
+    let handler = message_handler(Duration::ZERO);
+    let states = Arc::new(ConnectionStateManager::new());
+    let alice_socket = Arc::new(Recorder::default());
+    let alice = Connection::open(handler.clone(), states.clone(), alice_socket.clone(), None, None).await.unwrap();
+    // Alice's socket stays open
+    let serving = tokio::spawn(alice.serve(stream::iter(vec![frame(&join("alice"))]).chain(stream::pending())));
+    for _ in 0..100 {
+        if handler.room_manager().has_peer(ROOM, "alice").await {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(handler.room_manager().has_peer(ROOM, "alice").await);
+
+    // A second socket claims alice's id, while the room is locked and not
+    let mallory_socket = Arc::new(Recorder::default());
+    let mallory = Connection::open(handler.clone(), states.clone(), mallory_socket.clone(), None, None).await.unwrap();
+    handler.set_room_locked(ROOM, true).await.unwrap();
+    mallory.serve(stream::iter(vec![frame(&join("alice"))])).await;
+    handler.set_room_locked(ROOM, false).await.unwrap();
+    let mallory = Connection::open(handler.clone(), states.clone(), mallory_socket.clone(), None, None).await.unwrap();
+    mallory.serve(stream::iter(vec![frame(&join("alice"))])).await;
+    let refusals = mallory_socket.messages().into_iter()
+        .filter(|message| matches!(message, SignalingMessage::Error { code: ErrorCode::Unauthorized, .. }))
+        .count();
+    assert_eq!(refusals, 2);
+
+    // Alice still hears about the room
+    let bob = Connection::open(handler.clone(), states, Arc::new(Recorder::default()), None, None).await.unwrap();
+    bob.serve(stream::iter(vec![frame(&join("bob"))])).await;
+    let heard_bob = alice_socket.messages().into_iter()
+        .any(|message| matches!(message, SignalingMessage::PeerList { peers, .. } if peers.contains(&"bob".to_string())));
+    assert!(heard_bob, "alice was cut off from signaling");
+    assert!(handler.room_manager().has_peer(ROOM, "alice").await);
+
+    serving.abort();
+}
+
+#[tokio::test]
+async fn kicking_closes_the_socket() {
+    let handler = message_handler(Duration::from_secs(30));
+    let states = Arc::new(ConnectionStateManager::new());
+    let host = Connection::open(handler.clone(), states.clone(), Arc::new(Recorder::default()), None, None).await.unwrap();
+    host.serve(stream::iter(vec![frame(&join("host"))])).await;
+
+    let guest_socket = Arc::new(Recorder::default());
+    let guest = Connection::open(handler.clone(), states, guest_socket.clone(), None, None).await.unwrap();
+    let serving = tokio::spawn(guest.serve(stream::iter(vec![frame(&join("guest"))]).chain(stream::pending())));
+    for _ in 0..100 {
+        if handler.room_manager().has_peer(ROOM, "guest").await {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    handler.kick_peer(ROOM, "guest", "disruptive").await.unwrap();
+    assert!(guest_socket.closed.load(Ordering::Relaxed));
+    assert!(matches!(
+        guest_socket.messages().last(),
+        Some(SignalingMessage::ConnectionError { should_retry: false, .. })
+    ));
+    assert!(!handler.room_manager().has_peer(ROOM, "guest").await);
+    serving.abort();
+}
@@ -0,0 +1,433 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+use webrtc_server::room::state::MediaSettings;
+use webrtc_server::signaling::whip;
+
+mod common;
+use common::{client_peer_connection, message_handler};
+
+const ROOM: &str = "whip-room";
+
+fn opus_track(stream_id: &str) -> Arc<TrackLocalStaticRTP> {
+    Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability { mime_type: "audio/opus".to_string(), ..Default::default() },
+        "audio".to_string(),
+        stream_id.to_string(),
+    ))
+}
+
+/// Complete (non-trickle) offer of `client`, as WHIP/WHEP clients send it.
+async fn gathered_offer(client: &RTCPeerConnection) -> String {
+    let offer = client.create_offer(None).await.unwrap();
+    let mut gathered = client.gathering_complete_promise().await;
+    client.set_local_description(offer).await.unwrap();
+    let _ = gathered.recv().await;
+    client.local_description().await.unwrap().sdp
+}
+
+async fn post_offer<F>(routes: &F, path: &str, offer: String) -> warp::http::Response<bytes::Bytes>
+where
+    F: warp::Filter + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    warp::test::request()
+        .method("POST")
+        .path(path)
+        .header("content-type", "application/sdp")
+        .body(offer)
+        .reply(routes)
+        .await
+}
+
+async fn apply_answer(client: &RTCPeerConnection, response: &warp::http::Response<bytes::Bytes>) {
+    let answer = String::from_utf8(response.body().to_vec()).unwrap();
+    client
+        .set_remote_description(RTCSessionDescription::answer(answer).unwrap())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn whip_publisher_joins_and_leaves_room() {
+    let handler = message_handler();
+    let routes = whip::routes(handler.clone());
+
+    let encoder = client_peer_connection().await;
+    encoder.add_track(opus_track("obs") as Arc<dyn TrackLocal + Send + Sync>).await.unwrap();
+
+    let response = post_offer(&routes, &format!("/whip/{}", ROOM), gathered_offer(&encoder).await).await;
+    assert_eq!(response.status(), 201);
+    assert_eq!(response.headers()["content-type"], "application/sdp");
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    assert_eq!(handler.room_manager().get_room_peers(ROOM).await.len(), 1);
+
+    let answer = String::from_utf8(response.body().to_vec()).unwrap();
+    assert!(answer.contains("a=candidate:"), "answer should carry the server's candidates");
+    apply_answer(&encoder, &response).await;
+
+    let trickle = warp::test::request()
+        .method("PATCH")
+        .path(&location)
+        .header("content-type", "application/trickle-ice-sdpfrag")
+        .body("a=mid:0\r\na=end-of-candidates\r\n")
+        .reply(&routes)
+        .await;
+    assert_eq!(trickle.status(), 204);
+
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let patch = |if_match: &str, fragment: &'static str| {
+        warp::test::request()
+            .method("PATCH")
+            .path(&location)
+            .header("content-type", "application/trickle-ice-sdpfrag")
+            .header("if-match", if_match)
+            .body(fragment)
+    };
+    assert_eq!(patch(&etag, "a=mid:0\r\na=end-of-candidates\r\n").reply(&routes).await.status(), 204);
+    assert_eq!(patch("\"stale\"", "a=mid:0\r\na=end-of-candidates\r\n").reply(&routes).await.status(), 412);
+    // Credentials other than the session's are a stale fragment or, with
+    // `If-Match: *`, an ICE restart
+    let restart = "a=ice-ufrag:fresh\r\na=ice-pwd:freshfreshfreshfreshfresh\r\na=mid:0\r\n";
+    assert_eq!(patch(&etag, restart).reply(&routes).await.status(), 412);
+    assert_eq!(patch("*", restart).reply(&routes).await.status(), 501);
+
+    let delete = warp::test::request().method("DELETE").path(&location).reply(&routes).await;
+    assert_eq!(delete.status(), 200);
+    assert!(handler.room_manager().get_room_peers(ROOM).await.is_empty());
+
+    let again = warp::test::request().method("DELETE").path(&location).reply(&routes).await;
+    assert_eq!(again.status(), 404);
+
+    encoder.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn whep_viewer_receives_publisher_that_starts_later() {
+    let handler = message_handler();
+    let whip_routes = whip::routes(handler.clone());
+    let whep_routes = whip::whep_routes(handler.clone());
+
+    // The viewer is in the room before anyone publishes
+    let viewer = client_peer_connection().await;
+    viewer
+        .add_transceiver_from_kind(
+            RTPCodecType::Audio,
+            Some(RTCRtpTransceiverInit {
+                direction: RTCRtpTransceiverDirection::Recvonly,
+                send_encodings: Vec::new(),
+            }),
+        )
+        .await
+        .unwrap();
+    let (payload_tx, mut payload_rx) = mpsc::channel::<Vec<u8>>(16);
+    viewer.on_track(Box::new(move |track, _, _| {
+        let payload_tx = payload_tx.clone();
+        Box::pin(async move {
+            while let Ok((packet, _)) = track.read_rtp().await {
+                if payload_tx.send(packet.payload.to_vec()).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }));
+
+    let response = post_offer(&whep_routes, &format!("/whep/{}", ROOM), gathered_offer(&viewer).await).await;
+    assert_eq!(response.status(), 201);
+    assert!(response.headers()["location"].to_str().unwrap().starts_with("/whep/"));
+    apply_answer(&viewer, &response).await;
+
+    let encoder = client_peer_connection().await;
+    let audio = opus_track("obs");
+    encoder.add_track(Arc::clone(&audio) as Arc<dyn TrackLocal + Send + Sync>).await.unwrap();
+    let response = post_offer(&whip_routes, &format!("/whip/{}", ROOM), gathered_offer(&encoder).await).await;
+    assert_eq!(response.status(), 201);
+    apply_answer(&encoder, &response).await;
+
+    let publishing = tokio::spawn(async move {
+        let mut sequence_number: u16 = 0;
+        loop {
+            let packet = webrtc::rtp::packet::Packet {
+                header: webrtc::rtp::header::Header {
+                    version: 2,
+                    sequence_number,
+                    timestamp: sequence_number as u32 * 960,
+                    ..Default::default()
+                },
+                payload: vec![0xCD; 40].into(),
+            };
+            let _ = audio.write_rtp(&packet).await;
+            sequence_number = sequence_number.wrapping_add(1);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+
+    let payload = tokio::time::timeout(Duration::from_secs(10), payload_rx.recv())
+        .await
+        .expect("viewer never received the publisher's media")
+        .unwrap();
+    assert_eq!(payload, vec![0xCD; 40]);
+
+    publishing.abort();
+    encoder.close().await.unwrap();
+    viewer.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn whep_viewers_count_toward_room_capacity() {
+    let handler = message_handler();
+    let routes = whip::whep_routes(handler.clone());
+    handler
+        .room_manager()
+        .create_room_with_settings(ROOM.to_string(), MediaSettings { max_participants: 1, ..Default::default() })
+        .await
+        .unwrap();
+
+    let mut viewers = Vec::new();
+    let mut statuses = Vec::new();
+    for _ in 0..2 {
+        let viewer = client_peer_connection().await;
+        viewer
+            .add_transceiver_from_kind(
+                RTPCodecType::Video,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Recvonly,
+                    send_encodings: Vec::new(),
+                }),
+            )
+            .await
+            .unwrap();
+        let response = post_offer(&routes, &format!("/whep/{}", ROOM), gathered_offer(&viewer).await).await;
+        statuses.push(response.status().as_u16());
+        viewers.push(viewer);
+    }
+    assert_eq!(statuses, vec![201, 503]);
+
+    for viewer in viewers {
+        viewer.close().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn http_peers_are_subject_to_room_admission() {
+    let handler = message_handler();
+    let routes = whip::whep_routes(handler.clone());
+    let rooms = handler.room_manager();
+    rooms.create_room(ROOM.to_string()).await.unwrap();
+    let banned: std::net::SocketAddr = "203.0.113.7:40000".parse().unwrap();
+
+    let viewer = client_peer_connection().await;
+    viewer
+        .add_transceiver_from_kind(
+            RTPCodecType::Video,
+            Some(RTCRtpTransceiverInit {
+                direction: RTCRtpTransceiverDirection::Recvonly,
+                send_encodings: Vec::new(),
+            }),
+        )
+        .await
+        .unwrap();
+    let offer = gathered_offer(&viewer).await;
+    let post = |remote: std::net::SocketAddr| {
+        warp::test::request()
+            .method("POST")
+            .path(&format!("/whep/{}", ROOM))
+            .header("content-type", "application/sdp")
+            .remote_addr(remote)
+            .body(offer.clone())
+            .reply(&routes)
+    };
+    let allowed: std::net::SocketAddr = "198.51.100.1:40000".parse().unwrap();
+
+    handler.ban_address(ROOM, banned.ip(), "spam").await.unwrap();
+    assert_eq!(post(banned).await.status().as_u16(), 403);
+    handler.set_room_locked(ROOM, true).await.unwrap();
+    assert_eq!(post(allowed).await.status().as_u16(), 403);
+    handler.set_room_locked(ROOM, false).await.unwrap();
+    rooms.set_lobby_enabled(ROOM, true).await.unwrap();
+    assert_eq!(post(allowed).await.status().as_u16(), 403);
+    assert!(rooms.get_room_peers(ROOM).await.is_empty());
+
+    rooms.set_lobby_enabled(ROOM, false).await.unwrap();
+    assert_eq!(post(allowed).await.status().as_u16(), 201);
+    viewer.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn whip_publisher_trickles_candidates_after_its_offer() {
+    let handler = message_handler();
+    let routes = whip::routes(handler.clone());
+
+    let encoder = client_peer_connection().await;
+    encoder.add_track(opus_track("obs") as Arc<dyn TrackLocal + Send + Sync>).await.unwrap();
+    let (candidate_tx, mut candidate_rx) = mpsc::unbounded_channel();
+    encoder.on_ice_candidate(Box::new(move |candidate| {
+        let candidate_tx = candidate_tx.clone();
+        Box::pin(async move {
+            let _ = candidate_tx.send(candidate.map(|candidate| candidate.to_json().unwrap().candidate));
+        })
+    }));
+    let (connected_tx, mut connected_rx) = mpsc::channel(1);
+    encoder.on_peer_connection_state_change(Box::new(move |state| {
+        let connected_tx = connected_tx.clone();
+        Box::pin(async move {
+            if state == RTCPeerConnectionState::Connected {
+                let _ = connected_tx.try_send(());
+            }
+        })
+    }));
+
+    // The offer goes out before any candidate is gathered
+    let offer = encoder.create_offer(None).await.unwrap();
+    encoder.set_local_description(offer.clone()).await.unwrap();
+    assert!(!offer.sdp.contains("a=candidate:"));
+    let response = post_offer(&routes, &format!("/whip/{}", ROOM), offer.sdp.clone()).await;
+    assert_eq!(response.status(), 201);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    // Without the server's candidates only the server can start connectivity
+    // checks, toward the candidates it is about to be sent
+    let answer = String::from_utf8(response.body().to_vec()).unwrap();
+    let answer: String = answer
+        .lines()
+        .filter(|line| !line.starts_with("a=candidate:"))
+        .map(|line| format!("{}\r\n", line))
+        .collect();
+    encoder.set_remote_description(RTCSessionDescription::answer(answer).unwrap()).await.unwrap();
+
+    let credentials: Vec<&str> = offer.sdp.lines().filter(|line| line.starts_with("a=ice-")).collect();
+    let mut fragment = format!("{}\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=mid:0\r\n", credentials.join("\r\n"));
+    while let Some(Some(candidate)) = candidate_rx.recv().await {
+        fragment.push_str(&format!("a={}\r\n", candidate));
+    }
+    fragment.push_str("a=end-of-candidates\r\n");
+    assert!(fragment.contains("a=candidate:"));
+
+    let trickle = warp::test::request()
+        .method("PATCH")
+        .path(&location)
+        .header("content-type", "application/trickle-ice-sdpfrag")
+        .body(fragment)
+        .reply(&routes)
+        .await;
+    assert_eq!(trickle.status(), 204);
+    tokio::time::timeout(Duration::from_secs(10), connected_rx.recv())
+        .await
+        .expect("the encoder never connected over its trickled candidates");
+
+    let wrong_type = warp::test::request()
+        .method("PATCH")
+        .path(&location)
+        .header("content-type", "application/sdp")
+        .body("a=end-of-candidates\r\n")
+        .reply(&routes)
+        .await;
+    assert_eq!(wrong_type.status(), 415);
+
+    encoder.close().await.unwrap();
+}
+
+/// Ends or trickles to the session of `peer_id` the way another room
+/// member could, knowing only the id the peer list shows.
+async fn hijack<F>(routes: &F, protocol: &str, peer_id: &str) -> Vec<u16>
+where
+    F: warp::Filter + 'static,
+    F::Extract: warp::Reply + Send,
+{
+    let path = format!("/{}/{}/{}", protocol, ROOM, peer_id);
+    let trickle = warp::test::request()
+        .method("PATCH")
+        .path(&path)
+        .header("content-type", "application/trickle-ice-sdpfrag")
+        .body("a=mid:0\r\na=end-of-candidates\r\n")
+        .reply(routes)
+        .await;
+    let delete = warp::test::request().method("DELETE").path(&path).reply(routes).await;
+    vec![trickle.status().as_u16(), delete.status().as_u16()]
+}
+
+#[tokio::test]
+async fn whip_sessions_are_not_reachable_through_the_peer_id() {
+    let handler = message_handler();
+    let routes = whip::routes(handler.clone());
+
+    let encoder = client_peer_connection().await;
+    encoder.add_track(opus_track("obs") as Arc<dyn TrackLocal + Send + Sync>).await.unwrap();
+    let response = post_offer(&routes, &format!("/whip/{}", ROOM), gathered_offer(&encoder).await).await;
+    assert_eq!(response.status(), 201);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+
+    let peers = handler.room_manager().get_room_peers(ROOM).await;
+    assert_eq!(peers.len(), 1);
+    assert!(!location.contains(&peers[0]), "the resource URL must not be derived from the peer id");
+    assert_eq!(hijack(&routes, "whip", &peers[0]).await, vec![404, 404]);
+    assert_eq!(handler.room_manager().get_room_peers(ROOM).await, peers);
+
+    let delete = warp::test::request().method("DELETE").path(&location).reply(&routes).await;
+    assert_eq!(delete.status(), 200);
+    encoder.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn whep_sessions_are_not_reachable_through_the_peer_id() {
+    let handler = message_handler();
+    let routes = whip::whep_routes(handler.clone());
+
+    let viewer = client_peer_connection().await;
+    viewer
+        .add_transceiver_from_kind(
+            RTPCodecType::Audio,
+            Some(RTCRtpTransceiverInit {
+                direction: RTCRtpTransceiverDirection::Recvonly,
+                send_encodings: Vec::new(),
+            }),
+        )
+        .await
+        .unwrap();
+    let response = post_offer(&routes, &format!("/whep/{}", ROOM), gathered_offer(&viewer).await).await;
+    assert_eq!(response.status(), 201);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+
+    let peers = handler.room_manager().get_room_peers(ROOM).await;
+    assert_eq!(peers.len(), 1);
+    assert!(peers[0].starts_with("whep-"));
+    assert_eq!(hijack(&routes, "whep", &peers[0]).await, vec![404, 404]);
+    assert_eq!(handler.room_manager().get_room_peers(ROOM).await, peers);
+
+    let delete = warp::test::request().method("DELETE").path(&location).reply(&routes).await;
+    assert_eq!(delete.status(), 200);
+    assert!(handler.room_manager().get_room_peers(ROOM).await.is_empty());
+    viewer.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn stale_whip_sessions_are_removed_like_leaving_ones() {
+    let handler = message_handler();
+    let routes = whip::routes(handler.clone());
+
+    let encoder = client_peer_connection().await;
+    encoder.add_track(opus_track("obs") as Arc<dyn TrackLocal + Send + Sync>).await.unwrap();
+    let response = post_offer(&routes, &format!("/whip/{}", ROOM), gathered_offer(&encoder).await).await;
+    assert_eq!(response.status(), 201);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let resource_id = location.rsplit('/').next().unwrap();
+    let peer_id = handler.http_resource_peer(resource_id).await.unwrap();
+
+    // The server side of the session fails without the publisher saying goodbye
+    let relay = handler.room_manager().relay_manager().get_relay(ROOM, &peer_id).await.unwrap();
+    relay.peer_connection.close().await.unwrap();
+    handler.remove_stale_peers().await;
+
+    assert!(handler.room_manager().get_room_peers(ROOM).await.is_empty());
+    assert_eq!(handler.http_resource_peer(resource_id).await, None);
+    let delete = warp::test::request().method("DELETE").path(&location).reply(&routes).await;
+    assert_eq!(delete.status(), 404);
+    encoder.close().await.unwrap();
+}